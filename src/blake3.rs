@@ -0,0 +1,257 @@
+//! Minimal streaming BLAKE3 (default hash mode, 32-byte output) for `--hash-algo blake3`.
+//!
+//! A straight port of the reference implementation: inputs are split into 1 KiB chunks whose
+//! chaining values are merged pairwise into a binary tree via a stack.
+
+use std::io;
+use std::path::Path;
+
+use crate::sha256::{hash_file_with, to_hex, StreamHasher};
+
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+const IV: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    // Columns, then diagonals.
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn compress(chaining_value: &[u32; 8], block: &[u32; 16], counter: u64, block_len: u32, flags: u32) -> [u32; 16] {
+    let cv = chaining_value;
+    #[rustfmt::skip]
+    let mut state = [
+        cv[0], cv[1], cv[2], cv[3], cv[4], cv[5], cv[6], cv[7],
+        IV[0], IV[1], IV[2], IV[3], counter as u32, (counter >> 32) as u32, block_len, flags,
+    ];
+    let mut m = *block;
+    for i in 0..7 {
+        round(&mut state, &m);
+        if i < 6 {
+            m = MSG_PERMUTATION.map(|j| m[j]);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+fn first_8(words: [u32; 16]) -> [u32; 8] {
+    words[..8].try_into().unwrap()
+}
+
+fn block_words(bytes: &[u8; BLOCK_LEN]) -> [u32; 16] {
+    let mut words = [0u32; 16];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+/// A compression whose result is either a chaining value or, for the root node, the output.
+struct Output {
+    chaining_value: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8(compress(&self.chaining_value, &self.block, self.counter, self.block_len, self.flags))
+    }
+
+    fn root_hash(&self) -> [u8; 32] {
+        let words = compress(&self.chaining_value, &self.block, 0, self.block_len, self.flags | ROOT);
+        let mut out = [0u8; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+}
+
+struct ChunkState {
+    chaining_value: [u32; 8],
+    chunk_counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    fn new(chunk_counter: u64) -> Self {
+        Self { chaining_value: IV, chunk_counter, block: [0; BLOCK_LEN], block_len: 0, blocks_compressed: 0 }
+    }
+
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // The last block of a chunk is compressed by `output`, with CHUNK_END set.
+            if self.block_len == BLOCK_LEN {
+                let words = block_words(&self.block);
+                let flags = self.start_flag();
+                self.chaining_value = first_8(compress(&self.chaining_value, &words, self.chunk_counter, BLOCK_LEN as u32, flags));
+                self.blocks_compressed += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+            let take = (BLOCK_LEN - self.block_len).min(input.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&input[..take]);
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        Output {
+            chaining_value: self.chaining_value,
+            block: block_words(&self.block),
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+fn parent_output(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block = [0u32; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+    Output { chaining_value: IV, block, counter: 0, block_len: BLOCK_LEN as u32, flags: PARENT }
+}
+
+pub struct Blake3 {
+    chunk: ChunkState,
+    /// Chaining values of completed subtrees, one per set bit of the chunk count.
+    stack: Vec<[u32; 8]>,
+}
+
+impl Blake3 {
+    pub fn new() -> Self {
+        Self { chunk: ChunkState::new(0), stack: Vec::new() }
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // A full chunk is only finished once more input arrives, as the last one is the root's.
+            if self.chunk.len() == CHUNK_LEN {
+                let mut cv = self.chunk.output().chaining_value();
+                let mut total_chunks = self.chunk.chunk_counter + 1;
+                while total_chunks & 1 == 0 {
+                    cv = parent_output(self.stack.pop().expect("a completed subtree"), cv).chaining_value();
+                    total_chunks >>= 1;
+                }
+                self.stack.push(cv);
+                self.chunk = ChunkState::new(self.chunk.chunk_counter + 1);
+            }
+            let take = (CHUNK_LEN - self.chunk.len()).min(input.len());
+            self.chunk.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        let mut output = self.chunk.output();
+        for cv in self.stack.iter().rev() {
+            output = parent_output(*cv, output.chaining_value());
+        }
+        output.root_hash()
+    }
+}
+
+impl StreamHasher for Blake3 {
+    fn new() -> Self {
+        Blake3::new()
+    }
+    fn update(&mut self, data: &[u8]) {
+        Blake3::update(self, data)
+    }
+    fn finish_hex(self) -> String {
+        to_hex(&self.finalize())
+    }
+}
+
+/// Hashes a file with BLAKE3.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    hash_file_with::<Blake3>(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The digest of the official test input of `len` bytes (`i % 251`), fed whole and in uneven
+    /// pieces, which must agree.
+    fn digest(len: usize) -> String {
+        let input: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let mut whole = Blake3::new();
+        whole.update(&input);
+        let mut pieces = Blake3::new();
+        for piece in input.chunks(100) {
+            pieces.update(piece);
+        }
+        let whole = to_hex(&whole.finalize());
+        assert_eq!(to_hex(&pieces.finalize()), whole, "{} bytes", len);
+        whole
+    }
+
+    /// From the BLAKE3 reference `test_vectors.json`, across the chunk (1024) and block (64) boundaries.
+    #[test]
+    fn official_vectors() {
+        let cases = [
+            (0, "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+            (1, "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"),
+            (63, "e9bc37a594daad83be9470df7f7b3798297c3d834ce80ba85d6e207627b7db7b"),
+            (64, "4eed7141ea4a5cd4b788606bd23f46e212af9cacebacdc7d1f4c6dc7f2511b98"),
+            (1023, "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11"),
+            (1024, "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"),
+            (1025, "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"),
+            (2048, "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"),
+            (3072, "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2"),
+        ];
+        for (len, expected) in cases {
+            assert_eq!(digest(len), expected, "{} bytes", len);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Minimal ELF reader for the dynamic-linking details `--runtime-check-deps` needs.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+
+/// The program interpreter and `DT_NEEDED` libraries of an ELF file.
+pub struct DynamicInfo {
+    pub interpreter: Option<String>,
+    pub needed: Vec<String>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    little_endian: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.data.get(offset..offset.checked_add(N)?)?.try_into().ok()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u64> {
+        let b = self.bytes::<2>(offset)?;
+        Some(if self.little_endian { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) } as u64)
+    }
+
+    fn u32(&self, offset: usize) -> Option<u64> {
+        let b = self.bytes::<4>(offset)?;
+        Some(if self.little_endian { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) } as u64)
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let b = self.bytes::<8>(offset)?;
+        Some(if self.little_endian { u64::from_le_bytes(b) } else { u64::from_be_bytes(b) })
+    }
+
+    /// A native-width word: 8 bytes for ELF64, 4 for ELF32.
+    fn word(&self, offset: usize) -> Option<u64> {
+        if self.is_64 {
+            self.u64(offset)
+        } else {
+            self.u32(offset)
+        }
+    }
+
+    fn c_str(&self, offset: usize) -> Option<String> {
+        let rest = self.data.get(offset..)?;
+        let end = rest.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+}
+
+/// A program header's type, file offset, virtual address and size in the file.
+struct Segment {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+/// Read the interpreter and needed libraries of `path`; `Ok(None)` if it isn't an ELF file.
+pub fn dynamic_info(path: &Path) -> io::Result<Option<DynamicInfo>> {
+    let data = fs::read(path)?;
+    Ok(parse(&data))
+}
+
+fn parse(data: &[u8]) -> Option<DynamicInfo> {
+    if data.get(..4)? != b"\x7fELF" {
+        return None;
+    }
+    let r = Reader { data, is_64: *data.get(4)? == 2, little_endian: *data.get(5)? == 1 };
+    let (phoff, phentsize, phnum) = if r.is_64 {
+        (r.u64(0x20)?, r.u16(0x36)?, r.u16(0x38)?)
+    } else {
+        (r.u32(0x1c)?, r.u16(0x2a)?, r.u16(0x2c)?)
+    };
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let base = usize::try_from(phoff.checked_add(i.checked_mul(phentsize)?)?).ok()?;
+        let at = |field: usize| base.checked_add(field);
+        let segment = if r.is_64 {
+            Segment { kind: r.u32(base)? as u32, offset: r.u64(at(8)?)?, vaddr: r.u64(at(16)?)?, filesz: r.u64(at(32)?)? }
+        } else {
+            Segment { kind: r.u32(base)? as u32, offset: r.u32(at(4)?)?, vaddr: r.u32(at(8)?)?, filesz: r.u32(at(16)?)? }
+        };
+        segments.push(segment);
+    }
+
+    let interpreter = segments.iter().find(|s| s.kind == PT_INTERP).and_then(|s| r.c_str(usize::try_from(s.offset).ok()?));
+
+    // DT_NEEDED values are offsets into the string table, whose address must be mapped back
+    // to a file offset through the PT_LOAD segment containing it.
+    let mut needed = Vec::new();
+    if let Some(dynamic) = segments.iter().find(|s| s.kind == PT_DYNAMIC) {
+        let entry_size = if r.is_64 { 16 } else { 8 };
+        let mut needed_offsets = Vec::new();
+        let mut strtab = None;
+        for i in 0..dynamic.filesz / entry_size {
+            let base = usize::try_from(dynamic.offset.checked_add(i.checked_mul(entry_size)?)?).ok()?;
+            let (tag, value) = (r.word(base)?, r.word(base.checked_add(entry_size as usize / 2)?)?);
+            match tag {
+                0 => break,
+                DT_NEEDED => needed_offsets.push(value),
+                DT_STRTAB => strtab = Some(value),
+                _ => {}
+            }
+        }
+        let strtab_offset = strtab.and_then(|addr| {
+            segments
+                .iter()
+                .find(|s| s.kind == PT_LOAD && addr >= s.vaddr && s.vaddr.checked_add(s.filesz).is_some_and(|end| addr < end))
+                .and_then(|s| (addr - s.vaddr).checked_add(s.offset))
+        });
+        if let Some(strtab_offset) = strtab_offset {
+            needed = needed_offsets
+                .iter()
+                .filter_map(|off| r.c_str(usize::try_from(strtab_offset.checked_add(*off)?).ok()?))
+                .collect();
+        }
+    }
+    Some(DynamicInfo { interpreter, needed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the fixture's fields live, for tests that corrupt them.
+    const PHOFF: usize = 0x20;
+    const LOAD_VADDR: usize = 64 + 56 + 16;
+    const NEEDED_VALUE: usize = 248 + 8;
+    const STRTAB_VADDR: u64 = 0x1000;
+
+    fn put(data: &mut [u8], offset: usize, value: u64, width: usize) {
+        data[offset..offset + width].copy_from_slice(&value.to_le_bytes()[..width]);
+    }
+
+    /// A little-endian ELF64 file with PT_INTERP, PT_LOAD and PT_DYNAMIC segments, needing `libc.so.6`.
+    fn elf64() -> Vec<u8> {
+        let mut data = vec![0; 306];
+        data[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        put(&mut data, PHOFF, 64, 8);
+        put(&mut data, 0x36, 56, 2);
+        put(&mut data, 0x38, 3, 2);
+        // (type, offset, vaddr, filesz) for each program header.
+        let len = data.len() as u64;
+        for (i, (kind, offset, vaddr, filesz)) in [(PT_INTERP, 232, 0, 11), (PT_LOAD, 0, STRTAB_VADDR, len), (PT_DYNAMIC, 248, 0, 48)].into_iter().enumerate() {
+            let base = 64 + i * 56;
+            put(&mut data, base, kind as u64, 4);
+            put(&mut data, base + 8, offset, 8);
+            put(&mut data, base + 16, vaddr, 8);
+            put(&mut data, base + 32, filesz, 8);
+        }
+        data[232..243].copy_from_slice(b"/lib/ld.so\0");
+        put(&mut data, 248, DT_NEEDED, 8);
+        put(&mut data, 264, DT_STRTAB, 8);
+        put(&mut data, 272, STRTAB_VADDR + 296, 8);
+        data[296..306].copy_from_slice(b"libc.so.6\0");
+        data
+    }
+
+    #[test]
+    fn reads_the_interpreter_and_needed_libraries() {
+        let info = parse(&elf64()).unwrap();
+        assert_eq!(info.interpreter.as_deref(), Some("/lib/ld.so"));
+        assert_eq!(info.needed, ["libc.so.6"]);
+        assert!(parse(b"#!/bin/sh\n").is_none());
+    }
+
+    #[test]
+    fn truncated_files_are_not_read_past_their_end() {
+        let data = elf64();
+        for len in 0..data.len() {
+            let info = parse(&data[..len]);
+            assert!(len >= 64 + 3 * 56 || info.is_none(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn overflowing_offsets_are_rejected() {
+        let cases: [(&str, usize, u64); 4] = [
+            ("program header offset", PHOFF, u64::MAX - 8),
+            ("program header offset near usize::MAX", PHOFF, usize::MAX as u64 - 20),
+            ("PT_LOAD end", LOAD_VADDR, u64::MAX - 4),
+            ("DT_NEEDED string offset", NEEDED_VALUE, u64::MAX - 100),
+        ];
+        for (name, offset, value) in cases {
+            let mut data = elf64();
+            put(&mut data, offset, value, 8);
+            if let Some(info) = parse(&data) {
+                assert!(info.needed.is_empty(), "{}: {:?}", name, info.needed);
+            }
+        }
+    }
+}
@@ -0,0 +1,666 @@
+//! Minimal jq-style filters over JSON values for `--manifest-filter`.
+//!
+//! Supports paths (`.a.b`, `."key"`, `.[0]`, `.[-1]`, `.[1:]`, `.[]`), the optional `?` that turns
+//! an error into no output, pipes and commas, array
+//! construction, literals, comparisons, `and`/`or`, and the builtins `select`, `map`, `length`,
+//! `first`, `last`, `reverse`, `keys`, `not`, `empty`, `contains`, `startswith` and `endswith`.
+//! As in jq, every expression yields zero or more outputs for each input.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use serde_json::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Dot,
+    /// `.name`, lexed as one token so `.a.b` needs no lookahead.
+    Field(String),
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(&'static str),
+    Pipe,
+    Comma,
+    Colon,
+    Question,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        i += 1;
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '.' if chars.get(i).is_some_and(|&c| c.is_ascii_alphabetic() || c == '_') => {
+                while chars.get(i).is_some_and(|&c| is_ident(c)) {
+                    i += 1;
+                }
+                Token::Field(chars[start + 1..i].iter().collect())
+            }
+            '.' => Token::Dot,
+            '|' => Token::Pipe,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            '?' => Token::Question,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '=' | '!' | '<' | '>' => {
+                let op = match (c, chars.get(i)) {
+                    ('=', Some('=')) => "==",
+                    ('!', Some('=')) => "!=",
+                    ('<', Some('=')) => "<=",
+                    ('>', Some('=')) => ">=",
+                    ('<', _) => "<",
+                    ('>', _) => ">",
+                    _ => return Err(format!("unexpected '{}' at offset {}", c, start)),
+                };
+                i += op.len() - 1;
+                Token::Op(op)
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.get(i) {
+                        None => return Err("unterminated string".to_string()),
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push(match chars.get(i + 1) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some(&c @ ('"' | '\\' | '/')) => c,
+                                _ => return Err(format!("unsupported escape at offset {}", i)),
+                            });
+                            i += 2;
+                        }
+                        Some(&c) => {
+                            text.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+                Token::Str(text)
+            }
+            c if c.is_ascii_digit() || (c == '-' && chars.get(i).is_some_and(|c| c.is_ascii_digit())) => {
+                while chars.get(i).is_some_and(|&c| c.is_ascii_digit() || c == '.') {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                Token::Num(number.parse().map_err(|_| format!("invalid number '{}'", number))?)
+            }
+            c if is_ident(c) => {
+                while chars.get(i).is_some_and(|&c| is_ident(c)) {
+                    i += 1;
+                }
+                Token::Ident(chars[start..i].iter().collect())
+            }
+            other => return Err(format!("unexpected '{}' at offset {}", other, start)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Identity,
+    Literal(Value),
+    Field(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    Iterate(Box<Expr>),
+    /// `expr?`: the outputs of `expr`, or none if it fails.
+    Try(Box<Expr>),
+    Array(Option<Box<Expr>>),
+    Pipe(Box<Expr>, Box<Expr>),
+    Comma(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, &'static str, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.next() {
+            Some(found) if found == token => Ok(()),
+            Some(found) => Err(format!("expected {:?}, found {:?}", token, found)),
+            None => Err(format!("expected {:?} at the end", token)),
+        }
+    }
+
+    fn pipe(&mut self) -> Result<Expr, String> {
+        let mut expr = self.comma()?;
+        while self.eat(&Token::Pipe) {
+            expr = Expr::Pipe(Box::new(expr), Box::new(self.comma()?));
+        }
+        Ok(expr)
+    }
+
+    fn comma(&mut self) -> Result<Expr, String> {
+        let mut expr = self.or()?;
+        while self.eat(&Token::Comma) {
+            expr = Expr::Comma(Box::new(expr), Box::new(self.or()?));
+        }
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Ident("or".to_string())) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.compare()?;
+        while self.eat(&Token::Ident("and".to_string())) {
+            expr = Expr::And(Box::new(expr), Box::new(self.compare()?));
+        }
+        Ok(expr)
+    }
+
+    fn compare(&mut self) -> Result<Expr, String> {
+        let left = self.postfix()?;
+        if let Some(&Token::Op(op)) = self.peek() {
+            self.pos += 1;
+            return Ok(Expr::Compare(Box::new(left), op, Box::new(self.postfix()?)));
+        }
+        Ok(left)
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        loop {
+            match self.peek() {
+                Some(Token::Field(name)) => {
+                    let name = name.clone();
+                    self.pos += 1;
+                    expr = Expr::Field(Box::new(expr), name);
+                }
+                Some(Token::Dot) if matches!(self.tokens.get(self.pos + 1), Some(Token::Str(_))) => {
+                    let Some(Token::Str(name)) = self.tokens.get(self.pos + 1).cloned() else { unreachable!() };
+                    self.pos += 2;
+                    expr = Expr::Field(Box::new(expr), name);
+                }
+                Some(Token::LBracket) => {
+                    self.pos += 1;
+                    expr = self.bracket(expr)?;
+                }
+                Some(Token::Question) => {
+                    self.pos += 1;
+                    expr = Expr::Try(Box::new(expr));
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    /// The inside of `[...]` after a value: iteration, an index or a slice.
+    fn bracket(&mut self, base: Expr) -> Result<Expr, String> {
+        if self.eat(&Token::RBracket) {
+            return Ok(Expr::Iterate(Box::new(base)));
+        }
+        let from = if self.peek() == Some(&Token::Colon) { None } else { Some(Box::new(self.pipe()?)) };
+        if self.eat(&Token::Colon) {
+            let to = if self.peek() == Some(&Token::RBracket) { None } else { Some(Box::new(self.pipe()?)) };
+            self.expect(Token::RBracket)?;
+            return Ok(Expr::Slice(Box::new(base), from, to));
+        }
+        self.expect(Token::RBracket)?;
+        Ok(Expr::Index(Box::new(base), from.expect("an index expression")))
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Dot) => match self.peek() {
+                // `."key"`, for keys that aren't identifiers.
+                Some(Token::Str(name)) => {
+                    let name = name.clone();
+                    self.pos += 1;
+                    Ok(Expr::Field(Box::new(Expr::Identity), name))
+                }
+                _ => Ok(Expr::Identity),
+            },
+            Some(Token::Field(name)) => Ok(Expr::Field(Box::new(Expr::Identity), name)),
+            Some(Token::Str(text)) => Ok(Expr::Literal(Value::String(text))),
+            // Integral literals stay integers, so `0` can be used as a layer index.
+            Some(Token::Num(n)) if n.fract() == 0.0 && n.abs() < 1e15 => Ok(Expr::Literal(Value::from(n as i64))),
+            Some(Token::Num(n)) => Ok(Expr::Literal(serde_json::Number::from_f64(n).map_or(Value::Null, Value::Number))),
+            Some(Token::LParen) => {
+                let expr = self.pipe()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::LBracket) => {
+                if self.eat(&Token::RBracket) {
+                    return Ok(Expr::Array(None));
+                }
+                let expr = self.pipe()?;
+                self.expect(Token::RBracket)?;
+                Ok(Expr::Array(Some(Box::new(expr))))
+            }
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ => {
+                    let mut args = Vec::new();
+                    if self.eat(&Token::LParen) {
+                        args.push(self.pipe()?);
+                        self.expect(Token::RParen)?;
+                    }
+                    let arity = match name.as_str() {
+                        "select" | "map" | "contains" | "startswith" | "endswith" => 1,
+                        "length" | "first" | "last" | "reverse" | "keys" | "not" | "empty" => 0,
+                        _ => return Err(format!("unknown function '{}'", name)),
+                    };
+                    if args.len() != arity {
+                        return Err(format!("{} takes {} argument(s)", name, arity));
+                    }
+                    Ok(Expr::Call(name, args))
+                }
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+/// jq's total order: null < false < true < numbers < strings < arrays < objects.
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    let rank = |v: &Value| match v {
+        Value::Null => 0,
+        Value::Bool(false) => 1,
+        Value::Bool(true) => 2,
+        Value::Number(_) => 3,
+        Value::String(_) => 4,
+        Value::Array(_) => 5,
+        Value::Object(_) => 6,
+    };
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64().partial_cmp(&y.as_f64()).unwrap_or(Ordering::Equal),
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        (Value::Array(x), Value::Array(y)) => {
+            x.iter().zip(y).map(|(x, y)| compare_values(x, y)).find(|o| o.is_ne()).unwrap_or_else(|| x.len().cmp(&y.len()))
+        }
+        _ if rank(a) == rank(b) => if a == b { Ordering::Equal } else { a.to_string().cmp(&b.to_string()) },
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Resolves a possibly negative index against a length, clamped to `0..=len`.
+fn clamp_index(index: f64, len: usize) -> usize {
+    let index = index as i64;
+    let resolved = if index < 0 { len as i64 + index } else { index };
+    resolved.clamp(0, len as i64) as usize
+}
+
+fn eval(expr: &Expr, input: &Value) -> Result<Vec<Value>, String> {
+    Ok(match expr {
+        Expr::Identity => vec![input.clone()],
+        Expr::Literal(value) => vec![value.clone()],
+        Expr::Field(base, name) => {
+            let mut out = Vec::new();
+            for value in eval(base, input)? {
+                out.push(match value {
+                    Value::Object(mut object) => object.remove(name).unwrap_or(Value::Null),
+                    Value::Null => Value::Null,
+                    other => return Err(format!("can't index {} with \"{}\"", type_name(&other), name)),
+                });
+            }
+            out
+        }
+        Expr::Index(base, index) => {
+            let mut out = Vec::new();
+            for value in eval(base, input)? {
+                for index in eval(index, input)? {
+                    out.push(match (&value, &index) {
+                        (Value::Array(items), Value::Number(n)) => {
+                            let n = n.as_f64().unwrap_or(0.0) as i64;
+                            let resolved = if n < 0 { items.len() as i64 + n } else { n };
+                            usize::try_from(resolved).ok().and_then(|i| items.get(i)).cloned().unwrap_or(Value::Null)
+                        }
+                        (Value::Object(object), Value::String(key)) => object.get(key).cloned().unwrap_or(Value::Null),
+                        (Value::Null, _) => Value::Null,
+                        _ => return Err(format!("can't index {} with {}", type_name(&value), type_name(&index))),
+                    });
+                }
+            }
+            out
+        }
+        Expr::Slice(base, from, to) => {
+            let bound = |bound: &Option<Box<Expr>>| -> Result<Option<f64>, String> {
+                match bound {
+                    None => Ok(None),
+                    Some(expr) => match eval(expr, input)?.as_slice() {
+                        [Value::Number(n)] => Ok(n.as_f64()),
+                        _ => Err("slice bounds must be single numbers".to_string()),
+                    },
+                }
+            };
+            let (from, to) = (bound(from)?, bound(to)?);
+            let mut out = Vec::new();
+            for value in eval(base, input)? {
+                out.push(match value {
+                    Value::Array(items) => {
+                        let start = from.map_or(0, |f| clamp_index(f, items.len()));
+                        let end = to.map_or(items.len(), |t| clamp_index(t, items.len())).max(start);
+                        Value::Array(items[start..end].to_vec())
+                    }
+                    Value::String(text) => {
+                        let chars: Vec<char> = text.chars().collect();
+                        let start = from.map_or(0, |f| clamp_index(f, chars.len()));
+                        let end = to.map_or(chars.len(), |t| clamp_index(t, chars.len())).max(start);
+                        Value::String(chars[start..end].iter().collect())
+                    }
+                    Value::Null => Value::Null,
+                    other => return Err(format!("can't slice {}", type_name(&other))),
+                });
+            }
+            out
+        }
+        Expr::Iterate(base) => {
+            let mut out = Vec::new();
+            for value in eval(base, input)? {
+                match value {
+                    Value::Array(items) => out.extend(items),
+                    Value::Object(object) => out.extend(object.into_iter().map(|(_, v)| v)),
+                    other => return Err(format!("can't iterate over {}", type_name(&other))),
+                }
+            }
+            out
+        }
+        Expr::Try(inner) => eval(inner, input).unwrap_or_default(),
+        Expr::Array(None) => vec![Value::Array(Vec::new())],
+        Expr::Array(Some(inner)) => vec![Value::Array(eval(inner, input)?)],
+        Expr::Pipe(left, right) => {
+            let mut out = Vec::new();
+            for value in eval(left, input)? {
+                out.extend(eval(right, &value)?);
+            }
+            out
+        }
+        Expr::Comma(left, right) => {
+            let mut out = eval(left, input)?;
+            out.extend(eval(right, input)?);
+            out
+        }
+        Expr::Compare(left, op, right) => {
+            let mut out = Vec::new();
+            for r in eval(right, input)? {
+                for l in eval(left, input)? {
+                    let ordering = compare_values(&l, &r);
+                    out.push(Value::Bool(match *op {
+                        "==" => ordering.is_eq(),
+                        "!=" => ordering.is_ne(),
+                        "<" => ordering.is_lt(),
+                        "<=" => ordering.is_le(),
+                        ">" => ordering.is_gt(),
+                        _ => ordering.is_ge(),
+                    }));
+                }
+            }
+            out
+        }
+        Expr::And(left, right) | Expr::Or(left, right) => {
+            let is_and = matches!(expr, Expr::And(..));
+            let mut out = Vec::new();
+            for l in eval(left, input)? {
+                // Short-circuits like jq: `false and x` and `true or x` don't evaluate x.
+                if truthy(&l) != is_and {
+                    out.push(Value::Bool(!is_and));
+                    continue;
+                }
+                out.extend(eval(right, input)?.iter().map(|r| Value::Bool(truthy(r))));
+            }
+            out
+        }
+        Expr::Call(name, args) => call(name, args, input)?,
+    })
+}
+
+fn call(name: &str, args: &[Expr], input: &Value) -> Result<Vec<Value>, String> {
+    let string_test = |test: fn(&str, &str) -> bool| -> Result<Vec<Value>, String> {
+        let mut out = Vec::new();
+        for needle in eval(&args[0], input)? {
+            match (input, &needle) {
+                (Value::String(text), Value::String(needle)) => out.push(Value::Bool(test(text, needle))),
+                _ => return Err(format!("{} needs strings, not {} and {}", name, type_name(input), type_name(&needle))),
+            }
+        }
+        Ok(out)
+    };
+    Ok(match name {
+        "select" => {
+            let matches = eval(&args[0], input)?.iter().filter(|v| truthy(v)).count();
+            vec![input.clone(); matches]
+        }
+        "map" => match input {
+            Value::Array(items) => {
+                let mut out = Vec::new();
+                for item in items {
+                    out.extend(eval(&args[0], item)?);
+                }
+                vec![Value::Array(out)]
+            }
+            other => return Err(format!("can't map over {}", type_name(other))),
+        },
+        "contains" => return string_test(|text, needle| text.contains(needle)),
+        "startswith" => return string_test(|text, needle| text.starts_with(needle)),
+        "endswith" => return string_test(|text, needle| text.ends_with(needle)),
+        "length" => vec![match input {
+            Value::Null => Value::from(0),
+            Value::Bool(_) => return Err("boolean has no length".to_string()),
+            Value::Number(n) => Value::from(n.as_f64().unwrap_or(0.0).abs()),
+            Value::String(text) => Value::from(text.chars().count()),
+            Value::Array(items) => Value::from(items.len()),
+            Value::Object(object) => Value::from(object.len()),
+        }],
+        "first" | "last" => match input {
+            Value::Array(items) => vec![if name == "first" { items.first() } else { items.last() }.cloned().unwrap_or(Value::Null)],
+            other => return Err(format!("{} needs an array, not {}", name, type_name(other))),
+        },
+        "reverse" => match input {
+            Value::Array(items) => vec![Value::Array(items.iter().rev().cloned().collect())],
+            Value::Null => vec![Value::Array(Vec::new())],
+            other => return Err(format!("can't reverse {}", type_name(other))),
+        },
+        "keys" => match input {
+            Value::Object(object) => {
+                let mut keys: Vec<&String> = object.keys().collect();
+                keys.sort();
+                vec![Value::Array(keys.into_iter().map(|k| Value::String(k.clone())).collect())]
+            }
+            Value::Array(items) => vec![Value::Array((0..items.len()).map(Value::from).collect())],
+            other => return Err(format!("{} has no keys", type_name(other))),
+        },
+        "not" => vec![Value::Bool(!truthy(input))],
+        _ => Vec::new(),
+    })
+}
+
+/// A parsed filter expression.
+#[derive(Debug)]
+pub struct Filter {
+    expr: Expr,
+    source: String,
+}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(input)?, pos: 0 };
+        let expr = parser.pipe()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?}", token));
+        }
+        Ok(Self { expr, source: input.trim().to_string() })
+    }
+
+    /// All outputs of the filter for `input`, in order.
+    pub fn run(&self, input: &Value) -> Result<Vec<Value>, String> {
+        eval(&self.expr, input)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn run(filter: &str, input: &Value) -> Result<Vec<Value>, String> {
+        Filter::parse(filter)?.run(input)
+    }
+
+    #[test]
+    fn filters_yield_their_outputs_in_order() {
+        let input = json!({
+            "Layers": ["a.tar", "b.tar", "c.tar"],
+            "Config": "config.json",
+            "dotted.key": 1,
+            "RepoTags": null,
+            "sizes": [{ "name": "a", "size": 3 }, { "name": "b", "size": 10 }, { "name": "c", "size": 7 }],
+        });
+        let cases: &[(&str, Value)] = &[
+            (".", json!([input.clone()])),
+            (".Config", json!(["config.json"])),
+            (".missing", json!([null])),
+            (".missing.deeper", json!([null])),
+            ("._x", json!([null])),
+            (r#"."dotted.key""#, json!([1])),
+            (".Layers[0]", json!(["a.tar"])),
+            (".Layers[-1]", json!(["c.tar"])),
+            (".Layers[5]", json!([null])),
+            (".Layers[1:]", json!([["b.tar", "c.tar"]])),
+            (".Layers[:-1]", json!([["a.tar", "b.tar"]])),
+            (".Layers[]", json!(["a.tar", "b.tar", "c.tar"])),
+            (".Config[0:6]", json!(["config"])),
+            (".Layers | length", json!([3])),
+            (".Layers | first, last", json!(["a.tar", "c.tar"])),
+            (".Layers | reverse | .[0]", json!(["c.tar"])),
+            ("[.sizes[] | .size]", json!([[3, 10, 7]])),
+            (".sizes | map(.name)", json!([["a", "b", "c"]])),
+            (".sizes[] | select(.size > 5) | .name", json!(["b", "c"])),
+            (r#".sizes[] | select(.size >= 3 and .name != "a") | .name"#, json!(["b", "c"])),
+            (r#".sizes[] | select(.name == "a" or .size == 7) | .size"#, json!([3, 7])),
+            (r#".Layers[] | select(startswith("b") | not)"#, json!(["a.tar", "c.tar"])),
+            (r#".Layers[] | select(endswith(".tar") and contains("c"))"#, json!(["c.tar"])),
+            (".RepoTags | length", json!([0])),
+            (".RepoTags[]?", json!([])),
+            (".Config.name?", json!([])),
+            (".Config.name?, .Config", json!(["config.json"])),
+            ("(.Layers, .Config) | .[0]?", json!(["a.tar"])),
+            (".sizes | keys", json!([[0, 1, 2]])),
+            ("empty, 1", json!([1])),
+            ("[]", json!([[]])),
+            (r#"null < false, 2 < "2", [1] > [0, 9]"#, json!([true, true, true])),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(&Value::Array(run(filter, &input).unwrap()), expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn parse_errors_say_what_went_wrong() {
+        let cases = [
+            (".a ==", "unexpected end of expression"),
+            (".a = 1", "unexpected '=' at offset 3"),
+            (".a $", "unexpected '$' at offset 3"),
+            (r#"."abc"#, "unterminated string"),
+            (r#"."a\q""#, "unsupported escape at offset 3"),
+            ("1.2.3", "invalid number '1.2.3'"),
+            ("frobnicate", "unknown function 'frobnicate'"),
+            ("select", "select takes 1 argument(s)"),
+            ("length(1)", "length takes 0 argument(s)"),
+            (".[0", "expected RBracket at the end"),
+            ("(.a", "expected RParen at the end"),
+            (".a )", "unexpected RParen"),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(Filter::parse(filter).unwrap_err(), expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn evaluation_errors_name_the_types_involved() {
+        let input = json!({ "name": "x", "n": 1, "flag": true, "items": [1] });
+        let cases = [
+            (".name.first", "can't index string with \"first\""),
+            (r#".items["a"]"#, "can't index array with string"),
+            (".n[1:]", "can't slice number"),
+            (".items[.name:]", "slice bounds must be single numbers"),
+            (".n[]", "can't iterate over number"),
+            (".name | map(.)", "can't map over string"),
+            (r#".n | startswith("a")"#, "startswith needs strings, not number and string"),
+            (".flag | length", "boolean has no length"),
+            (".name | first", "first needs an array, not string"),
+            (".n | keys", "number has no keys"),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(run(filter, &input).unwrap_err(), expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn display_is_the_trimmed_source() {
+        assert_eq!(Filter::parse("  .Layers[0] ").unwrap().to_string(), ".Layers[0]");
+    }
+}
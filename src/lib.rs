@@ -0,0 +1,9205 @@
+//! Extract an Encore app's build output from its Docker image into a bare-metal layout.
+//!
+//! The `encorebaremetal` binary is a thin wrapper over [`cli_main`]; embedders call [`extract_encore`]
+//! and get feedback through [`ProgressEvent`]s instead of parsing stdout.
+
+mod blake3;
+mod elf;
+mod jq;
+mod sha256;
+mod sha512;
+mod version;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use filetime::FileTime;
+use flate2::bufread::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, from_reader};
+use tempfile::Builder;
+use walkdir::WalkDir;
+
+struct Config {
+    debug: bool,
+    /// Fail the run when the output ends up without any copied component.
+    fail_if_empty: bool,
+    /// Minimum number of copied files required by `--fail-if-empty`.
+    min_files: u64,
+    /// Read the saved image tar from stdin instead of building it.
+    tar_stdin: bool,
+    /// Content index used by `--dedupe` to hardlink identical files, keyed by sha256.
+    dedupe: Option<Mutex<HashMap<String, PathBuf>>>,
+    /// Overlay every layer in manifest order instead of picking the largest one.
+    all_layers: bool,
+    /// Expression picking the layers to extract, replacing the built-in selection (`--manifest-filter`).
+    manifest_filter: Option<jq::Filter>,
+    /// What happens when several overlaid layers provide the same path (`--merge-strategy`).
+    merge_strategy: MergeStrategy,
+    /// Last unchanged layer from a previous run; it and the layers below it are skipped, leaving
+    /// symlinks into them dangling.
+    since: Option<String>,
+    /// Carry source access/modification times over to copied files and directories.
+    copy_timestamps: bool,
+    /// Recreate the holes of sparse files instead of writing their zero runs out (`--copy-sparse`).
+    copy_sparse: bool,
+    /// Carry extended attributes, and with them POSIX ACLs, over to copied files (`--copy-attributes`).
+    copy_attributes: bool,
+    /// Set once the output filesystem has turned out not to support extended attributes.
+    attributes_unsupported: AtomicBool,
+    /// Only copy files modified at or after this time (`--since-timestamp`), for delta trees.
+    since_timestamp: Option<SystemTime>,
+    /// Skip copying files larger than this many bytes (`--copy-exclude-larger-than`).
+    max_file_size: Option<u64>,
+    /// Output paths left out of the copy, from `--exclude` and `--exclude-from` files.
+    exclusions: Vec<ExcludePattern>,
+    /// Also tag the built image as `<repo>:sha-<short layer digest>`.
+    tag_with_digest: bool,
+    /// Container CLI images are saved, pulled and removed with (`--runtime`).
+    runtime: ContainerRuntime,
+    /// Explicit `--component` allowlist; `None` copies the default components.
+    components: Option<Vec<String>>,
+    /// Components that must be found (`--require`); a missing one fails the copy instead of warning.
+    required: Vec<String>,
+    /// Components packed into `base.tar.gz` by `--split-archives` (`--split-base-components`).
+    split_base: Vec<String>,
+    /// Object-storage destination the output is uploaded to (`--s3-output`), as `s3://bucket[/prefix]`.
+    #[cfg(feature = "s3")]
+    s3_output: Option<String>,
+    /// Endpoint of an S3-compatible store other than AWS (`--s3-endpoint-url`).
+    #[cfg(feature = "s3")]
+    s3_endpoint: Option<String>,
+    /// User-defined `--label key=value` pairs recorded in the extraction metadata and the report.
+    labels: BTreeMap<String, String>,
+    /// Only copy the artifacts of this app from `workspace/apps/<app>`.
+    app: Option<String>,
+    /// Copy every `encore` directory in the layer into its own `<output>/<app>/` (`--all-apps`).
+    all_apps: bool,
+    /// Expected sha256 of the saved image tar, checked before extraction.
+    expect_tar_sha256: Option<String>,
+    /// Print the sha256 of the saved image tar.
+    print_tar_sha256: bool,
+    /// Merge into an existing output, touching only files recorded by the previous extraction.
+    merge: bool,
+    /// Leave output files already identical to the incoming ones in place (`--incremental`).
+    incremental: bool,
+    /// Write the output as an OCI image layout rather than a directory tree (`--output-format oci`).
+    oci_layout: bool,
+    /// How the components are arranged in the output directory (`--layout`).
+    output_layout: OutputLayout,
+    /// Output files written by the previous extraction, populated in `--merge` mode.
+    managed: Option<HashSet<PathBuf>>,
+    /// What to do with an existing output file that differs from the incoming one (`--on-conflict`).
+    on_conflict: Option<ConflictPolicy>,
+    /// `--strict`: checks skipped for a missing tool (`node`, say) are reported as warnings.
+    strict: bool,
+    /// What a per-file copy failure does to the copy (`--keep-going` collects them).
+    copy_failures: FailurePolicy,
+    /// How `--archive` and `--split-archives` are compressed (`--compress-format`).
+    archive_format: ArchiveFormat,
+    /// Level for `archive_format` (`--compress-level`), in the range it allows.
+    compression_level: u32,
+    /// Percentage below the largest layer's size within which an `encore/` layer is preferred.
+    layer_size_band: u64,
+    /// Leading path removed from every destination path relative to the output directory.
+    strip_prefix: Option<PathBuf>,
+    /// `--rewrite-paths from=to` rules, applied after `--strip-prefix`; the first matching one wins.
+    rewrites: Vec<(PathBuf, PathBuf)>,
+    /// Source of each destination written so far, to detect collisions after stripping.
+    destinations: Mutex<HashMap<PathBuf, PathBuf>>,
+    /// Warnings collected during the run, reported together at the end.
+    diagnostics: Mutex<Vec<Diagnostic>>,
+    /// Log every copy decision, independently of `DEBUG`.
+    trace_copy: bool,
+    /// `--verbose-copy`: also trace each directory and every copied file's size (implies `trace_copy`).
+    verbose_copy: bool,
+    /// Write layer hardlinks out as independent copies instead of recreating the links.
+    dereference_hardlinks: bool,
+    /// Style the status lines, warnings and report on stderr with ANSI colors.
+    color: bool,
+    /// How the end-of-run summary is printed (`--report-format`, `--json` for `json`).
+    report_format: ReportFormat,
+    /// `--print-entrypoint`: print the extracted service's run command to stdout, in this format.
+    print_entrypoint: Option<EntrypointFormat>,
+    /// Receives phase transitions and running copy totals; `--progress-json` installs one that
+    /// prints JSON lines on stderr.
+    on_progress: Option<Box<ProgressCallback>>,
+    /// Files and bytes copied so far, for the `ProgressEvent::Copied` totals.
+    progress_files: AtomicU64,
+    progress_bytes: AtomicU64,
+    /// Drop `..` and root components from layer entry paths instead of refusing the layer.
+    strip_unsafe_paths: bool,
+    /// Ownership applied to copied files and directories (`--owner`/`--group`).
+    owner: Option<u32>,
+    group: Option<u32>,
+    /// `--quiet`: no status lines or text summary on success; errors still go to stderr.
+    quiet: bool,
+    /// Extensions whose CRLF line endings are converted to LF (`--normalize-line-endings`).
+    normalize_extensions: Option<HashSet<String>>,
+    /// Variables from `--env-file`, set only on the `encore build docker` child.
+    build_env: Vec<(String, String)>,
+    /// Overrides merged into the runtime env file written to the output (`--runtime-env-file`,
+    /// then `--runtime-env KEY=VALUE`, later values winning).
+    runtime_env: Vec<(String, String)>,
+    /// Arguments passed to `encore` to build the image, with `{tag}` standing for the image tag
+    /// (`--encore-build-cmd` or `ENCORE_BUILD_CMD`, then any `--build-args`).
+    encore_build_cmd: Vec<String>,
+    /// Periodic stderr progress lines for non-interactive logs (`--progress-every`).
+    heartbeat: Option<Heartbeat>,
+    /// Redraw a percentage and ETA on stderr while reading a layer; only on a terminal, and off
+    /// under `--quiet` and `--progress-every`, whose lines it would garble.
+    layer_progress: bool,
+    /// Walk and account for every component without writing the output (`--no-write`, `--output-dir -`).
+    no_write: bool,
+    /// Content-addressed output (`--cas-output`), set up once the output directory exists.
+    cas: Option<CasStore>,
+    /// Timestamp given to every output file and archive entry by `--deterministic`, resolved
+    /// once the image config has been read.
+    deterministic: Option<SystemTime>,
+    /// Digest for the output integrity artifacts: the output manifest and CAS objects (`--hash-algo`).
+    hash_algo: HashAlgo,
+    /// Paths the extracted layers must contain (`--layer-contains`), relative to the image root.
+    layer_contains: Vec<String>,
+    /// Refuse layer symlinks whose target resolves outside the extraction root. Set by
+    /// `--fail-on-symlink-escape`, and always for images that weren't built locally.
+    symlink_guard: bool,
+    /// Read buffer for layer extraction, `--tar-blocking-factor` 512-byte records.
+    tar_buffer_size: usize,
+    /// How many directories below the layer root the fallback `encore` search descends (`--search-max-depth`).
+    search_max_depth: usize,
+    /// Only extract layer entries under this path, relative to the image root (`--extract-path-prefix`).
+    extract_prefix: Option<PathBuf>,
+    /// Paths under the `encore` directory to copy instead of the components (`--only-path`).
+    only_paths: Vec<PathBuf>,
+    /// External decompressor (program and arguments) for layers that are neither gzip nor a
+    /// plain tar (`--tar-filter`).
+    tar_filter: Option<Vec<String>>,
+    /// Decoder forced on every layer, skipping magic-byte detection (`--layer-compression`).
+    layer_compression: Option<LayerCompression>,
+    /// File name the image manifest is searched for under, before falling back to an OCI
+    /// `index.json` (`--manifest-name`).
+    manifest_name: String,
+    /// Times a `docker save` tar that fails `probe_image_tar` is saved again (`--retries`).
+    save_retries: u32,
+    /// Worker threads per copied directory, and across all components the number of files
+    /// being copied at once (`--copy-jobs`).
+    copy_jobs: usize,
+    copy_slots: CopySlots,
+    /// Read buffer for copies made through userspace (`--copy-buffer`). Plain file copies are
+    /// left to `fs::copy`, which copies in the kernel, unless it is given.
+    copy_buffer: Option<usize>,
+    /// Copy components concurrently, unless `--no-component-parallel` copies them one after another.
+    component_parallel: bool,
+}
+
+/// Counting semaphore bounding concurrent file copies, so parallel components share the
+/// `--copy-jobs` budget instead of multiplying it.
+struct CopySlots {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+impl CopySlots {
+    fn new(slots: usize) -> Self {
+        Self { free: Mutex::new(slots), released: Condvar::new() }
+    }
+
+    /// Run `op` once a slot is free.
+    fn run<T>(&self, op: impl FnOnce() -> T) -> T {
+        let mut free = self.released.wait_while(self.free.lock().unwrap(), |free| *free == 0).unwrap();
+        *free -= 1;
+        drop(free);
+        let result = op();
+        *self.free.lock().unwrap() += 1;
+        self.released.notify_one();
+        result
+    }
+}
+
+/// Digest algorithm selected by `--hash-algo`.
+///
+/// It only applies to what this tool produces: image tar and layer digests are checked as sha256,
+/// the algorithm image manifests are written in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum HashAlgo {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgo {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(format!("Unknown --hash-algo '{}' (expected sha256, sha512 or blake3)", name)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    fn file_hasher(self) -> fn(&Path) -> io::Result<String> {
+        match self {
+            Self::Sha256 => sha256::hash_file,
+            Self::Sha512 => sha512::hash_file,
+            Self::Blake3 => blake3::hash_file,
+        }
+    }
+
+    fn hash_file(self, path: &Path) -> io::Result<String> {
+        self.file_hasher()(path)
+    }
+
+    fn hash_files(self, paths: &[PathBuf]) -> io::Result<Vec<String>> {
+        sha256::hash_files_with(paths, self.file_hasher())
+    }
+}
+
+/// `--cas-output` layout: every file stored once as `objects/<digest>` under `root`, with the
+/// logical path of each copy recorded for `tree.json`.
+struct CasStore {
+    root: PathBuf,
+    algo: HashAlgo,
+    /// Logical path relative to `root` -> content digest.
+    tree: Mutex<BTreeMap<String, String>>,
+}
+
+impl CasStore {
+    const TREE: &'static str = "tree.json";
+
+    fn new(root: &Path, algo: HashAlgo) -> io::Result<Self> {
+        let objects = root.join("objects");
+        fs::create_dir_all(&objects).map_err(io_context(format!("Failed to create directory {}", objects.display())))?;
+        Ok(Self { root: root.to_path_buf(), algo, tree: Mutex::new(BTreeMap::new()) })
+    }
+
+    /// Store `src` by content unless an identical object exists; returns the object path and
+    /// whether it was newly written.
+    fn store(&self, src: &Path, dst: &Path) -> io::Result<(PathBuf, bool)> {
+        let digest = self.algo.hash_file(src).map_err(io_context(format!("Failed to hash {}", src.display())))?;
+        let object = self.root.join("objects").join(&digest);
+        let fresh = !object.exists();
+        if fresh {
+            // Copy beside the object and rename, so an interrupted copy never looks complete. The
+            // staging name is unique, as parallel copies may store the same content at once.
+            let staging = Builder::new().suffix(".partial").tempfile_in(self.root.join("objects"))?;
+            fs::copy(src, staging.path())
+                .map_err(io_context(format!("Failed to copy {} to {}", src.display(), staging.path().display())))?;
+            staging.persist(&object).map_err(|e| io_context(format!("Failed to store {}", object.display()))(e.error))?;
+        }
+        let logical = dst.strip_prefix(&self.root).unwrap_or(dst).to_string_lossy().into_owned();
+        self.tree.lock().unwrap().insert(logical, digest);
+        Ok((object, fresh))
+    }
+
+    /// Write the logical path -> digest index as `tree.json`.
+    fn write_tree(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let path = self.root.join(Self::TREE);
+        let tree = self.tree.lock().unwrap();
+        fs::write(&path, serde_json::to_string_pretty(&*tree)? + "\n")
+            .map_err(io_context(format!("Failed to write {}", path.display())))?;
+        Ok(path)
+    }
+}
+
+/// Rate-limited progress reporting for `--progress-every`.
+struct Heartbeat {
+    every: Duration,
+    last: Mutex<Instant>,
+    /// Files copied so far, and the total expected across all components.
+    copied: AtomicU64,
+    total: AtomicU64,
+}
+
+/// Progress reported to `Config::on_progress`, for embedders that want feedback without parsing stdout.
+pub enum ProgressEvent<'a> {
+    /// A pipeline phase was reached (`build_started`, `layers_extracted`, `done`, ...), with its details.
+    Phase { phase: &'a str, fields: &'a Value },
+    /// Running totals across all components, every `PROGRESS_EVERY_FILES` copied files.
+    Copied { files: u64, bytes: u64 },
+    /// The run succeeded, with the final totals; always the last event of a successful run.
+    Finished { files: u64, bytes: u64 },
+}
+
+/// The `on_progress` callback `extract_encore` and `--progress-json` report through.
+pub type ProgressCallback = dyn Fn(&ProgressEvent<'_>) + Send + Sync;
+
+/// How many copied files apart `ProgressEvent::Copied` updates are sent.
+const PROGRESS_EVERY_FILES: u64 = 1000;
+
+/// The `--progress-json` callback: one JSON object per line on stderr, with a timestamp and phase.
+fn print_progress_json(event: &ProgressEvent<'_>) {
+    let mut line = serde_json::Map::new();
+    line.insert("timestamp".to_string(), Value::String(rfc3339(SystemTime::now())));
+    match event {
+        ProgressEvent::Phase { phase, fields } => {
+            line.insert("phase".to_string(), Value::String(phase.to_string()));
+            if let Value::Object(fields) = fields {
+                line.extend(fields.clone());
+            }
+        }
+        ProgressEvent::Copied { files, bytes } | ProgressEvent::Finished { files, bytes } => {
+            let phase = if matches!(event, ProgressEvent::Copied { .. }) { "copying" } else { "finished" };
+            line.insert("phase".to_string(), Value::String(phase.to_string()));
+            line.insert("files".to_string(), Value::from(*files));
+            line.insert("bytes".to_string(), Value::from(*bytes));
+        }
+    }
+    eprintln!("{}", Value::Object(line));
+}
+
+/// Text extensions normalized by `--normalize-line-endings` unless `--text-extensions` overrides them.
+const DEFAULT_TEXT_EXTENSIONS: &str = "json,js,mjs,cjs,ts,sh,txt,yaml,yml,toml,env,md";
+
+/// A warning raised during the run, e.g. a missing component or a skipped file.
+#[derive(Clone, Serialize)]
+struct Diagnostic {
+    kind: &'static str,
+    message: String,
+}
+
+impl Config {
+    fn new(args: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let debug = env::var("DEBUG").map(|v| v == "1").unwrap_or(false);
+        let fail_if_empty = has_flag(args, "--fail-if-empty");
+        let min_files = match arg_value(args, "--min-files") {
+            Some(v) => v.parse().map_err(|_| format!("Invalid --min-files value: {}", v))?,
+            None => 1,
+        };
+        let tar_stdin = has_flag(args, "--tar-stdin");
+        let dedupe = has_flag(args, "--dedupe").then(|| Mutex::new(HashMap::new()));
+        let since = arg_value(args, "--since").map(|d| strip_digest_prefix(&d).to_string());
+        let all_layers = has_flag(args, "--all-layers") || since.is_some();
+        let manifest_filter = match arg_value(args, "--manifest-filter") {
+            Some(_) if all_layers => return Err("--manifest-filter can't be combined with --all-layers or --since".into()),
+            Some(expr) => Some(jq::Filter::parse(&expr).map_err(|e| format!("Invalid --manifest-filter '{}': {}", expr, e))?),
+            None => None,
+        };
+        let since_timestamp = match arg_value(args, "--since-timestamp") {
+            Some(v) => Some(parse_rfc3339(&v).map_err(|e| format!("Invalid --since-timestamp value: {}", e))?),
+            None => None,
+        };
+        let max_file_size = match arg_value(args, "--copy-exclude-larger-than") {
+            Some(v) => Some(parse_size(&v).map_err(|e| format!("Invalid --copy-exclude-larger-than value: {}", e))?),
+            None => None,
+        };
+        let copy_buffer = match arg_value(args, "--copy-buffer") {
+            Some(v) => match parse_size(&v) {
+                Ok(bytes) if (512..=1 << 30).contains(&bytes) => Some(bytes as usize),
+                Ok(_) => return Err(format!("Invalid --copy-buffer value: {} (expected 512 bytes to 1 GiB)", v).into()),
+                Err(e) => return Err(format!("Invalid --copy-buffer value: {}", e).into()),
+            },
+            None => None,
+        };
+        let mut exclusions = Vec::new();
+        for path in arg_values(args, "--exclude-from") {
+            exclusions.extend(read_exclude_file(Path::new(&path))?);
+        }
+        for pattern in arg_values(args, "--exclude") {
+            exclusions.push(ExcludePattern::parse(&pattern).map_err(|e| format!("Invalid --exclude pattern: {}", e))?);
+        }
+        let merge_strategy = match arg_value(args, "--merge-strategy").as_deref() {
+            None | Some("overlay") => MergeStrategy::Overlay,
+            Some("error-on-conflict") => MergeStrategy::ErrorOnConflict,
+            Some("first-wins") => MergeStrategy::FirstWins,
+            Some(other) => {
+                return Err(format!("Unknown --merge-strategy '{}' (expected overlay, error-on-conflict or first-wins)", other).into());
+            }
+        };
+        let report_format = match arg_value(args, "--report-format").as_deref() {
+            None if has_flag(args, "--json") => ReportFormat::Json,
+            None | Some("text") => ReportFormat::Text,
+            Some("json") => ReportFormat::Json,
+            Some("yaml") => ReportFormat::Yaml,
+            Some(other) => return Err(format!("Unknown --report-format '{}' (expected text, json or yaml)", other).into()),
+        };
+        let print_entrypoint = match arg_value(args, "--entrypoint-format") {
+            Some(_) if !has_flag(args, "--print-entrypoint") => return Err("--entrypoint-format requires --print-entrypoint".into()),
+            Some(name) => Some(EntrypointFormat::parse(&name)?),
+            None => has_flag(args, "--print-entrypoint").then_some(EntrypointFormat::Shell),
+        };
+        match arg_value(args, "--metadata-format").as_deref() {
+            Some(_) if !has_flag(args, "--metadata-out") => return Err("--metadata-format requires --metadata-out".into()),
+            None | Some("json" | "yaml") => {}
+            Some(other) => return Err(format!("Unknown --metadata-format '{}' (expected json or yaml)", other).into()),
+        }
+        // Both would be printed on stdout.
+        if print_entrypoint.is_some() && report_format != ReportFormat::Text {
+            return Err("--print-entrypoint can't be combined with a --report-format other than text".into());
+        }
+        let copy_timestamps = has_flag(args, "--copy-timestamps");
+        // `--archive-format` and `--compression-level` are the flags' earlier names, still accepted.
+        let archive_format = match arg_value(args, "--compress-format").or_else(|| arg_value(args, "--archive-format")) {
+            Some(name) => ArchiveFormat::parse(&name)?,
+            None => ArchiveFormat::Gzip,
+        };
+        let compression_level = match (arg_value(args, "--compress-level").or_else(|| arg_value(args, "--compression-level")), archive_format.levels()) {
+            (Some(_), None) => return Err(format!("--compress-level doesn't apply to --compress-format {}", archive_format.name()).into()),
+            (Some(v), Some((min, max))) => match v.parse::<u32>() {
+                Ok(level) if (min..=max).contains(&level) => level,
+                _ => {
+                    return Err(
+                        format!("Invalid --compress-level value: {} (expected {}-{} for {})", v, min, max, archive_format.name()).into()
+                    )
+                }
+            },
+            (None, _) => archive_format.default_level(),
+        };
+        let layer_size_band = match arg_value(args, "--layer-size-band") {
+            Some(v) => match v.parse::<u64>() {
+                Ok(percent) if percent <= 100 => percent,
+                _ => return Err(format!("Invalid --layer-size-band value: {} (expected 0-100)", v).into()),
+            },
+            None => 10,
+        };
+        let strip_unsafe_paths = match arg_value(args, "--sanitize-paths").as_deref() {
+            None | Some("reject") => false,
+            Some("strip") => true,
+            Some(other) => return Err(format!("Invalid --sanitize-paths value: {} (expected reject or strip)", other).into()),
+        };
+        let owner = arg_value(args, "--owner").map(|o| resolve_id(&o, false)).transpose()?;
+        let group = arg_value(args, "--group").map(|g| resolve_id(&g, true)).transpose()?;
+        let extract_only = arg_value(args, "--extract-only");
+        let mut rewrites = Vec::new();
+        for rule in arg_values(args, "--rewrite-paths") {
+            let (from, to) = rule
+                .split_once('=')
+                .map(|(from, to)| (from.trim_matches('/'), to.trim_matches('/')))
+                .filter(|(from, _)| !from.is_empty())
+                .ok_or_else(|| format!("Invalid --rewrite-paths value: {} (expected from=to)", rule))?;
+            rewrites.push((PathBuf::from(from), PathBuf::from(to)));
+        }
+        let no_write = has_flag(args, "--no-write") || arg_value(args, "--output-dir").as_deref() == Some("-");
+        if has_flag(args, "--cas-output") {
+            // These either expect files at their logical paths or rewrite a tree the store doesn't have.
+            let needs_tree = [
+                "--no-write",
+                "--merge",
+                "--watch",
+                "--extract-only",
+                "--dedupe",
+                "--normalize-line-endings",
+                "--verify-entrypoint",
+                "--runtime-check-deps",
+                "--encore-version",
+                "--validate-infra",
+                "--dockerfile-out",
+                "--report-duplicates",
+                "--emit-systemd",
+                "--runtime-env",
+                "--runtime-env-file",
+                "--split-archives",
+                "--copy-attributes",
+                "--incremental",
+            ];
+            if let Some(flag) = needs_tree.iter().find(|flag| has_flag(args, flag)) {
+                return Err(format!("{} can't be combined with --cas-output", flag).into());
+            }
+        }
+        if no_write {
+            let needs_output = [
+                "--merge",
+                "--watch",
+                "--extract-only",
+                "--output-manifest",
+                "--verify-entrypoint",
+                "--runtime-check-deps",
+                "--encore-version",
+                "--validate-infra",
+                "--dockerfile-out",
+                "--report-duplicates",
+                "--emit-systemd",
+                "--runtime-env",
+                "--runtime-env-file",
+                "--archive",
+                "--split-archives",
+                "--s3-output",
+                "--compare-with-image",
+                "--owner",
+                "--group",
+                "--incremental",
+            ];
+            if let Some(flag) = needs_output.iter().find(|flag| has_flag(args, flag)) {
+                return Err(format!("{} needs a written output and can't be combined with --no-write", flag).into());
+            }
+        }
+        // A file left in place isn't recorded in the dedupe index, so later duplicates of it would be copied.
+        if has_flag(args, "--incremental") && has_flag(args, "--dedupe") {
+            return Err("--incremental can't be combined with --dedupe".into());
+        }
+        for report in ["--print-layer-tree", "--report-layer-histogram"].into_iter().filter(|flag| has_flag(args, flag)) {
+            if let Some(flag) = ["--extract-only", "--watch"].iter().find(|flag| has_flag(args, flag)) {
+                return Err(format!("{} can't be combined with {}", report, flag).into());
+            }
+        }
+        let layer_contains = arg_values(args, "--layer-contains");
+        if let Some(path) = layer_contains.iter().find(|path| {
+            let relative = Path::new(path.trim_start_matches('/'));
+            relative.as_os_str().is_empty() || relative.components().any(|c| c == std::path::Component::ParentDir)
+        }) {
+            return Err(format!("Invalid --layer-contains path '{}'", path).into());
+        }
+        let mut encore_build_cmd = match arg_value(args, "--encore-build-cmd") {
+            Some(template) => parse_build_template(&template, "--encore-build-cmd")?,
+            None => match env::var("ENCORE_BUILD_CMD").ok().filter(|v| !v.trim().is_empty()) {
+                Some(template) => parse_build_template(&template, "ENCORE_BUILD_CMD")?,
+                None => DEFAULT_ENCORE_BUILD_CMD.iter().map(|word| word.to_string()).collect(),
+            },
+        };
+        for value in arg_values(args, "--build-args") {
+            encore_build_cmd.extend(split_build_args(&value)?);
+        }
+        let tar_buffer_size = match arg_value(args, "--tar-blocking-factor") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(factor) if (1..=65536).contains(&factor) => factor * 512,
+                _ => return Err(format!("Invalid --tar-blocking-factor value: {} (expected 1-65536)", v).into()),
+            },
+            None => DEFAULT_TAR_BLOCKING_FACTOR * 512,
+        };
+        let search_max_depth = match arg_value(args, "--search-max-depth") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(depth) if depth > 0 => depth,
+                _ => return Err(format!("Invalid --search-max-depth value: {} (expected a number > 0)", v).into()),
+            },
+            None => DEFAULT_SEARCH_MAX_DEPTH,
+        };
+        let extract_prefix = match arg_value(args, "--extract-path-prefix") {
+            Some(prefix) => {
+                let relative = PathBuf::from(prefix.trim_start_matches('/'));
+                if relative.as_os_str().is_empty() || relative.components().any(|c| c == std::path::Component::ParentDir) {
+                    return Err(format!("Invalid --extract-path-prefix '{}'", prefix).into());
+                }
+                Some(relative)
+            }
+            None => None,
+        };
+        let mut only_paths: Vec<PathBuf> = Vec::new();
+        for path in arg_values(args, "--only-path") {
+            let relative = PathBuf::from(path.trim_matches('/'));
+            if relative.as_os_str().is_empty() || relative.components().any(|c| c == std::path::Component::ParentDir) {
+                return Err(format!("Invalid --only-path '{}' (expected a path inside the encore directory)", path).into());
+            }
+            only_paths.push(relative);
+        }
+        // A path inside another one is already copied with it.
+        only_paths.sort();
+        only_paths.dedup_by(|path, outer| path.starts_with(outer));
+        let tar_filter = match arg_value(args, "--tar-filter") {
+            Some(command) => {
+                let words: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+                if words.is_empty() {
+                    return Err("--tar-filter needs a program".into());
+                }
+                Some(words)
+            }
+            None => None,
+        };
+        let layer_compression = arg_value(args, "--layer-compression").map(|name| LayerCompression::parse(&name)).transpose()?;
+        if layer_compression.is_some() && tar_filter.is_some() {
+            return Err("--layer-compression can't be combined with --tar-filter".into());
+        }
+        let save_retries = match arg_value(args, "--retries") {
+            Some(v) => v.parse::<u32>().map_err(|_| format!("Invalid --retries value: {} (expected a number >= 0)", v))?,
+            None => DEFAULT_SAVE_RETRIES,
+        };
+        let manifest_name = arg_value(args, "--manifest-name").unwrap_or_else(|| "manifest.json".to_string());
+        if has_flag(args, "--manifest-name") {
+            if has_flag(args, "--manifest-path") {
+                return Err("--manifest-name can't be combined with --manifest-path".into());
+            }
+            if manifest_name.is_empty() || manifest_name.contains('/') || manifest_name == "." || manifest_name == ".." {
+                return Err(format!("--manifest-name takes a file name, not a path ('{}'; use --manifest-path for a path)", manifest_name).into());
+            }
+        }
+        if has_flag(args, "--all-apps") {
+            // These read or describe a single app at the top of the output.
+            let single_app = [
+                "--app",
+                "--extract-only",
+                "--verify-entrypoint",
+                "--encore-version",
+                "--validate-infra",
+                "--dockerfile-out",
+                "--emit-systemd",
+                "--runtime-env",
+                "--runtime-env-file",
+                "--print-entrypoint",
+                "--metadata-out",
+            ];
+            if let Some(flag) = single_app.iter().find(|flag| has_flag(args, flag)) {
+                return Err(format!("--all-apps copies each app into its own directory and can't be combined with {}", flag).into());
+            }
+            if arg_value(args, "--output-format").as_deref() == Some("oci") {
+                return Err("--all-apps can't be combined with --output-format oci".into());
+            }
+        }
+        if has_flag(args, "--wait") && has_flag(args, "--no-wait") {
+            return Err("--wait and --no-wait can't be combined".into());
+        }
+        // `--component-parallel` predates concurrent copies being the default and is still accepted.
+        if has_flag(args, "--component-parallel") && has_flag(args, "--no-component-parallel") {
+            return Err("--component-parallel and --no-component-parallel can't be combined".into());
+        }
+        if has_flag(args, "--jobs") && has_flag(args, "--copy-jobs") {
+            return Err("--jobs and --copy-jobs can't be combined".into());
+        }
+        let jobs_flag = if has_flag(args, "--jobs") { "--jobs" } else { "--copy-jobs" };
+        // Copies are I/O bound, so the default is one job per core; spinning disks want 1-2.
+        let copy_jobs = match arg_value(args, jobs_flag) {
+            Some(v) => match v.parse::<usize>() {
+                Ok(jobs) if jobs > 0 => jobs,
+                _ => return Err(format!("Invalid {} value: {} (expected a number > 0)", jobs_flag, v).into()),
+            },
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let heartbeat = match arg_value(args, "--progress-every") {
+            Some(v) => match v.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Some(Heartbeat {
+                    every: Duration::from_secs(seconds),
+                    last: Mutex::new(Instant::now()),
+                    copied: AtomicU64::new(0),
+                    total: AtomicU64::new(0),
+                }),
+                _ => return Err(format!("Invalid --progress-every value: {} (expected seconds > 0)", v).into()),
+            },
+            None => None,
+        };
+        // `--manifest-only` is shorthand for the `manifest-only` profile.
+        let output_format = match arg_value(args, "--output-format") {
+            Some(_) if has_flag(args, "--manifest-only") => {
+                return Err("--manifest-only can't be combined with --output-format".into());
+            }
+            Some(format) => Some(format),
+            None => has_flag(args, "--manifest-only").then(|| "manifest-only".to_string()),
+        };
+        let names = match &extract_only {
+            Some(_) if has_flag(args, "--component") || output_format.is_some() => {
+                return Err("--extract-only can't be combined with --component or --output-format".into());
+            }
+            Some(name) => vec![name.clone()],
+            None => arg_values(args, "--component"),
+        };
+        if let Some(unknown) = names.iter().find(|n| !COMPONENTS.iter().any(|c| c.name == n.as_str())) {
+            return Err(format!("Unknown component '{}' (see --list-components)", unknown).into());
+        }
+        if has_flag(args, "--only-path") {
+            if let Some(flag) = ["--extract-only", "--component", "--output-format", "--manifest-only", "--require"].iter().find(|flag| has_flag(args, flag)) {
+                return Err(format!("--only-path can't be combined with {}", flag).into());
+            }
+        }
+        let oci_layout = arg_value(args, "--output-format").as_deref() == Some("oci");
+        let output_layout = match arg_value(args, "--layout") {
+            Some(name) => OutputLayout::parse(&name)?,
+            None => OutputLayout::Flat,
+        };
+        if output_layout == OutputLayout::Rootfs {
+            if oci_layout {
+                return Err("--layout rootfs can't be combined with --output-format oci".into());
+            }
+            if has_flag(args, "--all-apps") {
+                return Err("--layout rootfs can't be combined with --all-apps".into());
+            }
+        }
+        if oci_layout {
+            // The layout is packaged from a staging tree, so these have no output tree to work on.
+            let needs_tree =
+                ["--cas-output", "--no-write", "--merge", "--watch", "--extract-only", "--dockerfile-out", "--emit-systemd", "--print-entrypoint", "--metadata-out"];
+            if let Some(flag) = needs_tree.iter().find(|flag| has_flag(args, flag)) {
+                return Err(format!("--output-format oci can't be combined with {}", flag).into());
+            }
+            if arg_value(args, "--output-dir").as_deref() == Some("-") {
+                return Err("--output-format oci can't be combined with --output-dir -".into());
+            }
+        }
+        let components = match output_format {
+            Some(_) if !names.is_empty() && has_flag(args, "--manifest-only") => {
+                return Err("--manifest-only and --component can't be combined".into());
+            }
+            Some(_) if !names.is_empty() => {
+                return Err("--output-format and --component can't be combined".into());
+            }
+            Some(profile) => profile_components(&profile)?,
+            None => (!names.is_empty()).then_some(names),
+        };
+        let required = arg_values(args, "--require");
+        for name in &required {
+            let component = COMPONENTS
+                .iter()
+                .find(|c| c.name == name.as_str())
+                .ok_or_else(|| format!("Unknown component '{}' in --require (see --list-components)", name))?;
+            let copied = components.as_ref().map_or(component.default, |names| names.contains(name));
+            if !copied {
+                return Err(format!("--require {} names a component this run doesn't copy", name).into());
+            }
+        }
+        let split_base: Vec<String> = match arg_value(args, "--split-base-components") {
+            Some(list) => list.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect(),
+            None => DEFAULT_SPLIT_BASE.iter().map(|name| name.to_string()).collect(),
+        };
+        if let Some(name) = split_base.iter().find(|name| !COMPONENTS.iter().any(|c| c.name == name.as_str())) {
+            return Err(format!("Unknown component '{}' in --split-base-components (see --list-components)", name).into());
+        }
+        #[cfg(feature = "s3")]
+        let s3_output = arg_value(args, "--s3-output").map(|url| parse_s3_url(&url)).transpose()?;
+        #[cfg(feature = "s3")]
+        let s3_endpoint = arg_value(args, "--s3-endpoint-url");
+        #[cfg(feature = "s3")]
+        if s3_endpoint.is_some() && s3_output.is_none() {
+            return Err("--s3-endpoint-url requires --s3-output".into());
+        }
+        #[cfg(not(feature = "s3"))]
+        if let Some(flag) = ["--s3-output", "--s3-endpoint-url"].iter().find(|flag| has_flag(args, flag)) {
+            return Err(format!("{} needs encorebaremetal built with the s3 feature", flag).into());
+        }
+        let mut labels = BTreeMap::new();
+        for label in arg_values(args, "--label") {
+            let (key, value) = label
+                .split_once('=')
+                .filter(|(key, _)| !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || "._-/".contains(c)))
+                .ok_or_else(|| format!("Invalid --label '{}' (expected key=value)", label))?;
+            if labels.insert(key.to_string(), value.to_string()).is_some() {
+                return Err(format!("Duplicate --label key '{}'", key).into());
+            }
+        }
+        let expect_tar_sha256 = arg_value(args, "--expect-tar-sha256").map(|v| v.to_lowercase());
+        if let Some(hex) = &expect_tar_sha256 {
+            if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Invalid --expect-tar-sha256 value: {}", hex).into());
+            }
+        }
+        Ok(Self {
+            debug,
+            fail_if_empty,
+            min_files,
+            tar_stdin,
+            dedupe,
+            all_layers,
+            manifest_filter,
+            merge_strategy,
+            since,
+            copy_timestamps,
+            copy_sparse: has_flag(args, "--copy-sparse"),
+            copy_attributes: has_flag(args, "--copy-attributes"),
+            attributes_unsupported: AtomicBool::new(false),
+            since_timestamp,
+            max_file_size,
+            exclusions,
+            tag_with_digest: has_flag(args, "--tag-with-digest"),
+            runtime: match arg_value(args, "--runtime") {
+                Some(name) => ContainerRuntime::parse(&name)?,
+                None => ContainerRuntime::Docker,
+            },
+            components,
+            required,
+            split_base,
+            #[cfg(feature = "s3")]
+            s3_output,
+            #[cfg(feature = "s3")]
+            s3_endpoint,
+            labels,
+            app: arg_value(args, "--app"),
+            all_apps: has_flag(args, "--all-apps"),
+            expect_tar_sha256,
+            print_tar_sha256: has_flag(args, "--print-tar-sha256"),
+            // Watch mode re-extracts into the same output, so it always takes the incremental path.
+            merge: has_flag(args, "--merge") || has_flag(args, "--watch"),
+            incremental: has_flag(args, "--incremental"),
+            managed: None,
+            on_conflict: match arg_value(args, "--on-conflict").as_deref() {
+                None => None,
+                Some("overwrite") => Some(ConflictPolicy::Overwrite),
+                Some("skip") => Some(ConflictPolicy::Skip),
+                Some("rename") => Some(ConflictPolicy::Rename),
+                Some("error") => Some(ConflictPolicy::Error),
+                Some(other) => {
+                    return Err(format!("Unknown --on-conflict '{}' (expected overwrite, skip, rename or error)", other).into());
+                }
+            },
+            strict: has_flag(args, "--strict"),
+            copy_failures: if has_flag(args, "--keep-going") { FailurePolicy::Collect } else { FailurePolicy::FailFast },
+            archive_format,
+            compression_level,
+            layer_size_band,
+            strip_prefix: arg_value(args, "--strip-prefix").map(|p| PathBuf::from(p.trim_matches('/'))),
+            rewrites,
+            destinations: Mutex::new(HashMap::new()),
+            diagnostics: Mutex::new(Vec::new()),
+            trace_copy: has_flag(args, "--trace-copy") || has_flag(args, "--verbose-copy"),
+            verbose_copy: has_flag(args, "--verbose-copy"),
+            dereference_hardlinks: has_flag(args, "--dereference-hardlinks"),
+            color: color_enabled(args, io::stderr().is_terminal()),
+            report_format,
+            print_entrypoint,
+            on_progress: has_flag(args, "--progress-json").then(|| Box::new(print_progress_json) as Box<ProgressCallback>),
+            progress_files: AtomicU64::new(0),
+            progress_bytes: AtomicU64::new(0),
+            strip_unsafe_paths,
+            owner,
+            group,
+            oci_layout,
+            output_layout,
+            quiet: has_flag(args, "--quiet"),
+            normalize_extensions: has_flag(args, "--normalize-line-endings").then(|| {
+                let extensions = arg_value(args, "--text-extensions").unwrap_or_else(|| DEFAULT_TEXT_EXTENSIONS.to_string());
+                extensions.split(',').map(|e| e.trim().trim_start_matches('.').to_lowercase()).filter(|e| !e.is_empty()).collect()
+            }),
+            build_env: match expanded_arg(args, "--env-file")? {
+                Some(path) => parse_env_file(Path::new(&path))?,
+                None => Vec::new(),
+            },
+            runtime_env: {
+                let mut vars = match expanded_arg(args, "--runtime-env-file")? {
+                    Some(path) => parse_env_file(Path::new(&path))?,
+                    None => Vec::new(),
+                };
+                for assignment in arg_values(args, "--runtime-env") {
+                    match assignment.split_once('=') {
+                        Some((key, value)) if is_env_name(key) => vars.push((key.to_string(), value.to_string())),
+                        _ => return Err(format!("Invalid --runtime-env value '{}' (expected KEY=VALUE)", assignment).into()),
+                    }
+                }
+                vars
+            },
+            encore_build_cmd,
+            layer_progress: heartbeat.is_none() && !has_flag(args, "--quiet") && io::stderr().is_terminal(),
+            heartbeat,
+            tar_buffer_size,
+            search_max_depth,
+            extract_prefix,
+            only_paths,
+            tar_filter,
+            layer_compression,
+            manifest_name,
+            save_retries,
+            no_write,
+            cas: None,
+            deterministic: None,
+            layer_contains,
+            hash_algo: match arg_value(args, "--hash-algo") {
+                Some(name) => HashAlgo::parse(&name)?,
+                None => HashAlgo::Sha256,
+            },
+            symlink_guard: has_flag(args, "--fail-on-symlink-escape"),
+            copy_jobs,
+            copy_slots: CopySlots::new(copy_jobs),
+            copy_buffer,
+            component_parallel: !has_flag(args, "--no-component-parallel"),
+        })
+    }
+    /// Apply `--strip-prefix`, then the first matching `--rewrite-paths` rule, to a destination path under `root`.
+    fn relocate(&self, root: &Path, path: &Path) -> PathBuf {
+        let Ok(mut rel) = path.strip_prefix(root) else {
+            return path.to_path_buf();
+        };
+        if let Some(rest) = self.strip_prefix.as_deref().and_then(|prefix| rel.strip_prefix(prefix).ok()) {
+            rel = rest;
+        }
+        let rewrite = |(from, to): &(PathBuf, PathBuf)| {
+            let rest = rel.strip_prefix(from).ok()?;
+            Some(if rest.as_os_str().is_empty() { to.clone() } else { to.join(rest) })
+        };
+        match self.rewrites.iter().find_map(rewrite) {
+            Some(rewritten) => root.join(rewritten),
+            None => root.join(rel),
+        }
+    }
+    /// Record that `src` is written to `dst`, failing if stripping or rewriting made another source land there.
+    fn claim_destination(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        if self.strip_prefix.is_none() && self.rewrites.is_empty() {
+            return Ok(());
+        }
+        let mut destinations = self.destinations.lock().unwrap();
+        match destinations.get(dst) {
+            Some(other) if other != src => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} and {} both map to {} after --strip-prefix/--rewrite-paths",
+                    other.display(),
+                    src.display(),
+                    dst.display()
+                ),
+            )),
+            _ => {
+                destinations.insert(dst.to_path_buf(), src.to_path_buf());
+                Ok(())
+            }
+        }
+    }
+    /// Whether the image tar needs hashing for `--print-tar-sha256` or `--expect-tar-sha256`.
+    fn hashes_tar(&self) -> bool {
+        self.print_tar_sha256 || self.expect_tar_sha256.is_some()
+    }
+    /// Whether `--since-timestamp` excludes `src` for having been last modified before the cutoff.
+    fn unchanged_since_cutoff(&self, src: &Path) -> io::Result<bool> {
+        let Some(cutoff) = self.since_timestamp else {
+            return Ok(false);
+        };
+        let modified = fs::metadata(src).and_then(|m| m.modified()).map_err(io_context(format!("Failed to read {}", src.display())))?;
+        if modified < cutoff {
+            self.trace("skip", format_args!("{} (unchanged since --since-timestamp)", src.display()));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+    /// Whether an `--exclude` pattern matches `relative`, a path relative to the output directory.
+    fn excluded(&self, relative: &Path) -> bool {
+        self.exclusions.iter().any(|pattern| pattern.matches(relative))
+    }
+    /// The size of `src` if `--copy-exclude-larger-than` excludes it for being over the limit.
+    fn oversized(&self, src: &Path) -> io::Result<Option<u64>> {
+        let Some(limit) = self.max_file_size else {
+            return Ok(None);
+        };
+        let size = fs::metadata(src).map_err(io_context(format!("Failed to read {}", src.display())))?.len();
+        if size > limit {
+            self.log_fmt(format_args!("Skipping oversized: {} ({})", src.display(), format_bytes(size)));
+            self.trace("skip", format_args!("{} (larger than --copy-exclude-larger-than)", src.display()));
+            return Ok(Some(size));
+        }
+        Ok(None)
+    }
+    /// Whether `component` should be copied, honoring the `--component` allowlist.
+    fn copies_component(&self, component: &Component) -> bool {
+        match &self.components {
+            Some(names) => names.iter().any(|n| n == component.name),
+            None => component.default,
+        }
+    }
+    /// The buffer size for copies made through userspace.
+    fn copy_buffer_size(&self) -> usize {
+        self.copy_buffer.unwrap_or(DEFAULT_COPY_BUFFER)
+    }
+    /// Report a component that wasn't found: an error if it is `--require`d, a warning otherwise.
+    fn missing_component(&self, name: &str, message: String) -> io::Result<()> {
+        if self.required.iter().any(|r| r == name) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} (--require {})", message, name)));
+        }
+        self.warn("missing-component", message);
+        Ok(())
+    }
+    /// Record a warning for the end-of-run summary; it is only printed inline in debug mode.
+    fn warn(&self, kind: &'static str, message: String) {
+        self.log_fmt(format_args!("Warning: {}", message));
+        self.diagnostics.lock().unwrap().push(Diagnostic { kind, message });
+    }
+    fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.lock().unwrap().clone()
+    }
+    /// Log a single copy decision for `--trace-copy`.
+    fn trace(&self, action: &str, args: std::fmt::Arguments<'_>) {
+        if self.trace_copy {
+            eprintln!("[{}] {}", action, args);
+        } else {
+            log_to_file(format_args!("[{}] {}", action, args));
+        }
+    }
+    /// Report a phase transition to the progress callback, with the details in the `fields` object.
+    fn event(&self, phase: &str, fields: Value) {
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(&ProgressEvent::Phase { phase, fields: &fields });
+        }
+    }
+    /// Whether files are written at their logical paths, so destination directories are needed.
+    fn writes_tree(&self) -> bool {
+        !self.no_write && self.cas.is_none()
+    }
+    /// Print a `--progress-every` line on stderr if the interval has passed since the last one.
+    fn beat(&self, args: std::fmt::Arguments<'_>) {
+        let Some(heartbeat) = &self.heartbeat else {
+            return;
+        };
+        let mut last = heartbeat.last.lock().unwrap();
+        if last.elapsed() >= heartbeat.every {
+            *last = Instant::now();
+            eprintln!("{}", args);
+        }
+    }
+    /// Count one copied file toward the `--progress-every` total.
+    fn file_copied(&self, bytes: u64) {
+        if let Some(on_progress) = &self.on_progress {
+            let files = self.progress_files.fetch_add(1, Ordering::Relaxed) + 1;
+            let bytes = self.progress_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+            if files.is_multiple_of(PROGRESS_EVERY_FILES) {
+                on_progress(&ProgressEvent::Copied { files, bytes });
+            }
+        }
+        if let Some(heartbeat) = &self.heartbeat {
+            let copied = heartbeat.copied.fetch_add(1, Ordering::Relaxed) + 1;
+            let total = heartbeat.total.load(Ordering::Relaxed);
+            self.beat(format_args!("copied {}/{} files...", thousands(copied), thousands(total)));
+        }
+    }
+    fn log(&self, message: &str) {
+        if self.debug {
+            eprintln!("{}", message);
+        } else {
+            log_to_file(format_args!("{}", message));
+        }
+    }
+    /// Print a status line. Like every diagnostic it goes to stderr, leaving stdout to the output
+    /// that was asked for (reports, `--stdout` data); with `--quiet` it only reaches the `--log-file`.
+    fn status(&self, args: std::fmt::Arguments<'_>) {
+        if self.quiet {
+            log_to_file(args);
+        } else {
+            eprintln!("{}", args);
+        }
+    }
+    fn log_fmt(&self, args: std::fmt::Arguments<'_>) {
+        if self.debug {
+            eprintln!("{}", args);
+        } else {
+            log_to_file(args);
+        }
+    }
+}
+
+/// Resolve a numeric id or a user (or, with `group`, group) name to its id.
+fn resolve_id(value: &str, group: bool) -> Result<u32, Box<dyn std::error::Error>> {
+    if let Ok(id) = value.parse() {
+        return Ok(id);
+    }
+    let name = std::ffi::CString::new(value)?;
+    // SAFETY: name is NUL-terminated; the returned entries are only read before the next lookup.
+    let id = unsafe {
+        if group {
+            let entry = libc::getgrnam(name.as_ptr());
+            (!entry.is_null()).then(|| (*entry).gr_gid)
+        } else {
+            let entry = libc::getpwnam(name.as_ptr());
+            (!entry.is_null()).then(|| (*entry).pw_uid)
+        }
+    };
+    id.ok_or_else(|| format!("Unknown {} '{}'", if group { "group" } else { "user" }, value).into())
+}
+
+/// Apply `--owner`/`--group` to the written files and the directories leading to them.
+fn apply_ownership(output_dir: &Path, written: &[PathBuf], config: &Config) -> io::Result<()> {
+    if config.owner.is_none() && config.group.is_none() {
+        return Ok(());
+    }
+    // SAFETY: geteuid has no preconditions.
+    if unsafe { libc::geteuid() } != 0 {
+        config.warn("ownership", "Skipping --owner/--group: changing ownership requires running as root".to_string());
+        return Ok(());
+    }
+    let mut paths: BTreeSet<&Path> = BTreeSet::new();
+    for file in written {
+        paths.insert(file);
+        paths.extend(file.ancestors().skip(1).take_while(|dir| dir.starts_with(output_dir)));
+    }
+    for path in &paths {
+        std::os::unix::fs::lchown(path, config.owner, config.group)
+            .map_err(io_context(format!("Failed to change ownership of {}", path.display())))?;
+    }
+    config.log_fmt(format_args!("Changed ownership of {} paths", paths.len()));
+    Ok(())
+}
+
+/// Formats a time as an RFC 3339 UTC timestamp with millisecond precision.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parses an RFC 3339 timestamp such as `2024-05-01T12:00:00Z` or `2024-05-01T14:00:00.5+02:00`.
+fn parse_rfc3339(input: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("'{}' (expected an RFC 3339 timestamp like 2024-05-01T12:00:00Z)", input);
+    // A fixed-width run of ASCII digits.
+    let field = |s: &str, width: usize| {
+        if s.len() == width && s.bytes().all(|b| b.is_ascii_digit()) { s.parse::<i64>().map_err(|_| invalid()) } else { Err(invalid()) }
+    };
+    let (date, time) = input.split_once(['T', 't', ' ']).ok_or_else(invalid)?;
+    let [year, month, day] = <[&str; 3]>::try_from(date.split('-').collect::<Vec<_>>()).map_err(|_| invalid())?;
+    let (year, month, day) = (field(year, 4)?, field(month, 2)?, field(day, 2)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+
+    // The offset is `Z` or `±HH:MM`; whatever precedes it is `HH:MM:SS[.fraction]`.
+    let (clock, offset) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, 0)
+    } else {
+        let split = time.rfind(['+', '-']).ok_or_else(invalid)?;
+        let (hours, minutes) = time[split + 1..].split_once(':').ok_or_else(invalid)?;
+        let offset = field(hours, 2)? * 3_600 + field(minutes, 2)? * 60;
+        (&time[..split], if time[split..].starts_with('-') { -offset } else { offset })
+    };
+    let (clock, fraction) = match clock.split_once('.') {
+        Some((_, "")) => return Err(invalid()),
+        Some((clock, fraction)) => (clock, fraction),
+        None => (clock, ""),
+    };
+    let [hour, minute, second] = <[&str; 3]>::try_from(clock.split(':').collect::<Vec<_>>()).map_err(|_| invalid())?;
+    let (hour, minute, second) = (field(hour, 2)?, field(minute, 2)?, field(second, 2)?);
+    // Second 60 is a leap second, which the Unix clock folds into the next one.
+    if hour > 23 || minute > 59 || second > 60 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let nanos = format!("{:0<9}", fraction).get(..9).and_then(|n| n.parse::<u32>().ok()).unwrap_or(0);
+
+    // Days since 1970-01-01 from the civil date (the inverse of the algorithm in `rfc3339`).
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3_600 + minute * 60 + second - offset;
+
+    let since_epoch = Duration::new(secs.unsigned_abs(), 0);
+    let time = if secs >= 0 { SystemTime::UNIX_EPOCH + since_epoch } else { SystemTime::UNIX_EPOCH - since_epoch };
+    Ok(time + Duration::from_nanos(u64::from(nanos)))
+}
+
+/// Whether to color a stream: only terminals, and never under `--no-color` or a non-empty `NO_COLOR`.
+fn color_enabled(args: &[String], terminal: bool) -> bool {
+    terminal && !has_flag(args, "--no-color") && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+/// Wraps `text` in the ANSI SGR `code` (e.g. "32" for green) when `enabled`.
+fn paint(text: &str, code: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Returns the value following `flag` (or given as `flag=value`) on the command line, if any.
+fn arg_value(args: &[String], flag: &str) -> Option<String> {
+    arg_values(args, flag).into_iter().next()
+}
+
+/// An `--exclude` pattern, where `*` and `?` match within one path component.
+///
+/// A pattern without a `/` matches any component of a path (`*.map`, `node_modules`); one with a
+/// `/` is anchored at the output directory (`encore/runtimes/js/test`). Either way a matching
+/// directory excludes everything below it.
+struct ExcludePattern {
+    components: Vec<String>,
+    anchored: bool,
+}
+
+impl ExcludePattern {
+    fn parse(pattern: &str) -> Result<Self, String> {
+        let trimmed = pattern.trim().trim_matches('/');
+        let components: Vec<String> = trimmed.split('/').filter(|c| !c.is_empty() && *c != ".").map(str::to_string).collect();
+        if components.is_empty() || components.iter().any(|c| c == "..") {
+            return Err(format!("'{}'", pattern));
+        }
+        Ok(Self { anchored: trimmed.contains('/'), components })
+    }
+
+    fn matches(&self, relative: &Path) -> bool {
+        let names: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+        if self.anchored {
+            names.len() >= self.components.len() && self.components.iter().zip(&names).all(|(p, name)| glob_match(p, name))
+        } else {
+            names.iter().any(|name| glob_match(&self.components[0], name))
+        }
+    }
+}
+
+/// Match `text` against a glob with `*` (any run of characters) and `?` (any one character).
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it is currently matched up to.
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Read `--exclude-from` patterns, one per line, skipping blank lines and `#` comments.
+fn read_exclude_file(path: &Path) -> Result<Vec<ExcludePattern>, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path).map_err(io_context(format!("Failed to read exclude file {}", path.display())))?;
+    let mut patterns = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let pattern = ExcludePattern::parse(line).map_err(|e| format!("{}:{}: invalid pattern {}", path.display(), number + 1, e))?;
+        patterns.push(pattern);
+    }
+    Ok(patterns)
+}
+
+/// Read `KEY=value` pairs from an env file.
+///
+/// Blank lines and `#` comments are skipped and an `export ` prefix is allowed. Double-quoted
+/// values understand `\n`, `\"` and `\\` escapes, single-quoted values are taken literally, and
+/// unquoted values end at a ` #` comment.
+fn parse_env_file(path: &Path) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path).map_err(io_context(format!("Failed to read env file {}", path.display())))?;
+    let mut vars = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let invalid = |reason: &str| format!("{}:{}: {}", path.display(), number + 1, reason);
+        let (key, raw) = line.split_once('=').ok_or_else(|| invalid("expected KEY=value"))?;
+        let key = key.trim();
+        if !is_env_name(key) {
+            return Err(invalid(&format!("invalid variable name '{}'", key)).into());
+        }
+        let raw = raw.trim();
+        let value = if let Some(quoted) = raw.strip_prefix('"') {
+            let body = quoted.strip_suffix('"').ok_or_else(|| invalid("unterminated double quote"))?;
+            let mut value = String::new();
+            let mut chars = body.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    value.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(other) => value.push(other),
+                    None => value.push('\\'),
+                }
+            }
+            value
+        } else if let Some(quoted) = raw.strip_prefix('\'') {
+            quoted.strip_suffix('\'').ok_or_else(|| invalid("unterminated single quote"))?.to_string()
+        } else {
+            raw.split_once(" #").map_or(raw, |(value, _comment)| value).trim_end().to_string()
+        };
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+/// Whether `key` is a valid environment variable name: letters, digits and `_`, not starting with a digit.
+fn is_env_name(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && !key.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Expand `${VAR}` references against the process environment.
+///
+/// Undefined variables are an error unless `allow_unset` is set, in which case they expand to "".
+fn expand_env(input: &str, allow_unset: bool) -> Result<String, Box<dyn std::error::Error>> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated variable reference in '{}'", input))?;
+        let name = &rest[start + 2..start + end];
+        if !is_env_name(name) {
+            return Err(format!("Invalid variable name '{}' in '{}'", name, input).into());
+        }
+        match env::var(name) {
+            Ok(value) => out.push_str(&value),
+            Err(_) if allow_unset => {}
+            Err(_) => {
+                return Err(format!("Environment variable {} used in '{}' is not set (pass --allow-unset to allow)", name, input).into())
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Returns the value following `flag` with `${VAR}` references expanded.
+fn expanded_arg(args: &[String], flag: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let allow_unset = has_flag(args, "--allow-unset");
+    arg_value(args, flag).map(|v| expand_env(&v, allow_unset)).transpose()
+}
+
+/// Returns every value given for a repeatable `flag`.
+fn arg_values(args: &[String], flag: &str) -> Vec<String> {
+    let prefix = format!("{}=", flag);
+    let mut values = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            values.push(value.to_string());
+        } else if arg == flag {
+            values.extend(args.get(i + 1).cloned());
+        }
+    }
+    values
+}
+
+/// Returns true if `flag` was passed on the command line, bare or as `flag=value`.
+fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|a| a == flag || a.strip_prefix(flag).is_some_and(|rest| rest.starts_with('=')))
+}
+
+/// Pids of the subprocesses running right now, for `--timeout` to kill.
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Set once the `--timeout` budget is spent.
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// Exit status of a run stopped by `--timeout`, as for timeout(1).
+const EXIT_TIMEOUT: i32 = 124;
+
+/// Paths the running pipeline would remove on `--timeout`, removed by `run_with_timeout` itself
+/// when the run is still stuck (on a blocking read, say) once the grace period is over.
+static TIMEOUT_REMOVALS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Fail once the `--timeout` budget is spent or Ctrl-C stopped a `--watch` run, so in-process
+/// loops stop at their next step.
+fn check_timeout() -> io::Result<()> {
+    if TIMED_OUT.load(Ordering::SeqCst) {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "--timeout ran out"));
+    }
+    // Not `ErrorKind::Interrupted`, which readers retry.
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(io::Error::other("interrupted by Ctrl-C"));
+    }
+    Ok(())
+}
+
+/// A reader that fails with `check_timeout` once the run is stopped.
+struct TimeoutReader<R>(R);
+
+impl<R: Read> Read for TimeoutReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check_timeout()?;
+        self.0.read(buf)
+    }
+}
+
+/// Spawn `cmd`, recording it in `CHILDREN` until `untrack` is called after it is reaped.
+///
+/// Nothing is spawned once the run is stopped, since `kill_children` may already have run.
+fn spawn_tracked(cmd: &mut Command) -> io::Result<std::process::Child> {
+    check_timeout()?;
+    let child = cmd.spawn()?;
+    CHILDREN.lock().unwrap().push(child.id());
+    Ok(child)
+}
+
+fn untrack(pid: u32) {
+    CHILDREN.lock().unwrap().retain(|child| *child != pid);
+}
+
+/// Kill every tracked subprocess, so the stage waiting on one fails straight away.
+fn kill_children() {
+    for pid in CHILDREN.lock().unwrap_or_else(|e| e.into_inner()).drain(..) {
+        // SAFETY: kill has no memory-safety preconditions; the pid is a child not yet reaped.
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+}
+
+/// Runs a command with optional working directory and returns its output.
+///
+/// Arguments are `OsStr`s so paths reach the program as they are, UTF-8 or not.
+fn run_command<S: AsRef<OsStr>>(
+    program: &str,
+    args: &[S],
+    work_dir: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    command_output(program, args, work_dir).map(|_| ())
+}
+
+/// Like `run_command`, but returns the command's stdout.
+fn command_output<S: AsRef<OsStr>>(
+    program: &str,
+    args: &[S],
+    work_dir: Option<&Path>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    command_output_with_env(program, args, work_dir, &[])
+}
+
+/// Like `command_output`, with extra variables set on the child's environment only.
+fn command_output_with_env<S: AsRef<OsStr>>(
+    program: &str,
+    args: &[S],
+    work_dir: Option<&Path>,
+    env: &[(String, String)],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    cmd.envs(env.iter().map(|(key, value)| (key, value)));
+    if let Some(dir) = work_dir {
+        cmd.current_dir(dir);
+    }
+    cmd.stdin(std::process::Stdio::null()).stdout(std::process::Stdio::piped()).stderr(std::process::Stdio::piped());
+    let child = spawn_tracked(&mut cmd)?;
+    let pid = child.id();
+    let output = child.wait_with_output();
+    untrack(pid);
+    let output = output?;
+    let args: Vec<String> = args.iter().map(|arg| arg.as_ref().to_string_lossy().into_owned()).collect();
+    // The console never sees a captured stderr, but a support log should.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        log_to_file(format_args!("[stderr] {} {:?}: {}", program, args, stderr.trim_end()));
+    }
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(EncoreError::CommandFailed {
+            program: program.to_string(),
+            args,
+            code: output.status.code(),
+            signal: output.status.signal(),
+            stderr: stderr.into_owned(),
+        }
+        .into())
+    }
+}
+
+/// Errors resolving the tools the pipeline shells out to, the image it builds, or the sources it copies from.
+#[derive(Debug)]
+enum EncoreError {
+    /// `encore` couldn't be resolved to an executable file; holds the reason.
+    EncoreNotFound(String),
+    /// No executable for the `--runtime` on PATH.
+    DockerNotFound(ContainerRuntime),
+    /// `docker info` (or `podman info`) failed, so the daemon is down or unreachable; holds its
+    /// error output.
+    DockerUnreachable(ContainerRuntime, String),
+    /// `--image` isn't a valid Docker image reference; holds the rule it breaks.
+    InvalidImageReference { reference: String, reason: String },
+    /// A copy source is missing from the extracted layer; the component is filled in by
+    /// `copy_encore_components`, which knows which one was being copied.
+    SourceNotFound { path: PathBuf, component: Option<&'static str> },
+    /// The extracted layers hold neither an `encore` nor a `.encore` directory; holds how many
+    /// files they did hold.
+    NoEncoreArtifacts { files: u64 },
+    /// `--on-conflict error` met an output file that differs from the incoming one.
+    DestinationExists { path: PathBuf },
+    /// A subprocess run by `command_output` exited unsuccessfully: with `code`, or killed by
+    /// `signal`. Holds its captured stderr.
+    CommandFailed { program: String, args: Vec<String>, code: Option<i32>, signal: Option<i32>, stderr: String },
+}
+
+impl std::fmt::Display for EncoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncoreError::EncoreNotFound(reason) => write!(f, "Failed to locate 'encore': {}", reason),
+            EncoreError::DockerNotFound(ContainerRuntime::Docker) => write!(
+                f,
+                "Failed to locate 'docker' on PATH; install Docker (https://docs.docker.com/get-docker/) or pipe a saved image in with --tar-stdin"
+            ),
+            EncoreError::DockerNotFound(ContainerRuntime::Podman) => write!(
+                f,
+                "Failed to locate 'podman' on PATH (--runtime podman); install Podman (https://podman.io/docs/installation) or pipe a saved image in with --tar-stdin"
+            ),
+            EncoreError::DockerUnreachable(ContainerRuntime::Docker, reason) => write!(f, "Docker daemon is not reachable: {}", reason),
+            EncoreError::DockerUnreachable(ContainerRuntime::Podman, reason) => write!(f, "Podman is not usable: {}", reason),
+            EncoreError::InvalidImageReference { reference, reason } => {
+                write!(f, "Invalid --image '{}': {}", reference, reason)
+            }
+            EncoreError::SourceNotFound { path, component: Some(component) } => write!(
+                f,
+                "Source of component {} not found: {} (the image doesn't contain it; leave it out of --component/--require)",
+                component,
+                path.display()
+            ),
+            EncoreError::SourceNotFound { path, component: None } => write!(f, "Source not found: {}", path.display()),
+            EncoreError::NoEncoreArtifacts { files } => write!(
+                f,
+                "No Encore artifacts in the extracted layers ({} files, but no 'encore' or '.encore' directory); the build output may be in another layer, try --all-layers or --manifest-filter",
+                files
+            ),
+            EncoreError::DestinationExists { path } => write!(
+                f,
+                "Output file {} already exists and differs from the extracted file (--on-conflict error)",
+                path.display()
+            ),
+            EncoreError::CommandFailed { program, args, code, signal, stderr } => {
+                let status = match (code, signal) {
+                    (Some(code), _) => format!("exit code {}", code),
+                    (None, Some(signal)) => format!("killed by signal {}", signal),
+                    (None, None) => "no exit status".to_string(),
+                };
+                write!(f, "Command {} {:?} failed ({}): {}", program, args, status, stderr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncoreError {}
+
+/// Locate the 'encore' executable on PATH.
+fn locate_encore() -> Result<String, Box<dyn std::error::Error>> {
+    match find_on_path("encore") {
+        Some(path) => Ok(path.to_string_lossy().into_owned()),
+        None => Err(EncoreError::EncoreNotFound("not found on PATH".to_string()).into()),
+    }
+}
+
+/// `--runtime`: the container CLI the image is saved, pulled, inspected and removed with.
+///
+/// Podman's `save`, `pull`, `tag` and `image` subcommands take Docker's arguments, so only the
+/// program and the `info` probe differ. `encore build docker` builds with Docker either way.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ContainerRuntime {
+    Docker,
+    Podman,
+}
+
+impl ContainerRuntime {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "docker" => Ok(Self::Docker),
+            "podman" => Ok(Self::Podman),
+            _ => Err(format!("Unknown --runtime '{}' (expected docker or podman)", name)),
+        }
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+        }
+    }
+
+    /// `info` arguments printing the version of the daemon (Docker) or of Podman itself.
+    fn info_args(self) -> [&'static str; 3] {
+        match self {
+            Self::Docker => ["info", "--format", "{{.ServerVersion}}"],
+            Self::Podman => ["info", "--format", "{{.Version.Version}}"],
+        }
+    }
+}
+
+/// Check that the `--runtime` executable is on PATH, so a missing install isn't reported as a
+/// bare spawn error from the first docker command.
+fn ensure_docker_available(runtime: ContainerRuntime) -> Result<(), EncoreError> {
+    if find_on_path(runtime.program()).is_none() {
+        return Err(EncoreError::DockerNotFound(runtime));
+    }
+    Ok(())
+}
+
+/// The first executable file named `program` in a `PATH` directory.
+fn find_on_path(program: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path).map(|dir| dir.join(program)).find(|candidate| {
+        fs::metadata(candidate).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    })
+}
+
+/// Probe the Docker daemon with `docker info`, so a stopped daemon fails the run up front.
+fn check_docker_daemon(runtime: ContainerRuntime) -> Result<(), EncoreError> {
+    ensure_docker_available(runtime)?;
+    let program = runtime.program();
+    // Run as a tracked subprocess, so `--timeout` can kill a daemon probe that hangs.
+    let e = match command_output(program, &runtime.info_args(), None) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    Err(EncoreError::DockerUnreachable(runtime, match e.downcast_ref::<EncoreError>() {
+        Some(EncoreError::CommandFailed { stderr, .. }) if !stderr.trim().is_empty() => stderr.trim().to_string(),
+        Some(failed @ EncoreError::CommandFailed { .. }) => failed.to_string(),
+        _ => format!("failed to run {}: {}", program, e),
+    }))
+}
+
+/// `encore` arguments that build the image when `--encore-build-cmd` isn't given.
+const DEFAULT_ENCORE_BUILD_CMD: &[&str] = &["build", "docker", "{tag}"];
+
+/// Split an `--encore-build-cmd` template such as `build docker --push {tag}` into arguments.
+///
+/// Words are separated by whitespace, with no shell quoting. A leading `encore` is dropped,
+/// since the binary is always the one `locate_encore` finds. `source` names where the template
+/// came from, the flag or `ENCORE_BUILD_CMD`.
+fn parse_build_template(template: &str, source: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut words: Vec<String> = template.split_whitespace().map(str::to_string).collect();
+    if words.first().is_some_and(|word| word == "encore") {
+        words.remove(0);
+    }
+    if !words.iter().any(|word| word.contains("{tag}")) {
+        return Err(format!("{} '{}' must contain {{tag}} for the image tag", source, template).into());
+    }
+    Ok(words)
+}
+
+/// Split a `--build-args` value such as `--config "prod env.json"` into the arguments appended
+/// to the build command.
+///
+/// Words are separated by whitespace unless quoted: single quotes are taken literally, while
+/// inside double quotes and unquoted a backslash escapes the next character.
+fn split_build_args(value: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let invalid = |problem: &str| format!("Invalid --build-args '{}': {}", value, problem);
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(invalid("unterminated single quote").into()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err(invalid("unterminated double quote").into()),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).push(chars.next().ok_or_else(|| invalid("trailing backslash"))?),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Build the docker image using the 'encore' executable.
+fn docker_build(image_tag: &str, config: &Config) -> Result<String, Box<dyn std::error::Error>> {
+    config.status(format_args!("Building Docker image {}...", image_tag));
+    let encore_path = locate_encore()?;
+
+    if !config.build_env.is_empty() {
+        let keys: Vec<&str> = config.build_env.iter().map(|(key, _)| key.as_str()).collect();
+        config.log_fmt(format_args!("Build environment from --env-file: {}", keys.join(", ")));
+    }
+    let args: Vec<String> = config.encore_build_cmd.iter().map(|word| word.replace("{tag}", image_tag)).collect();
+    config.status(format_args!("Running: encore {}", args.join(" ")));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    command_output_with_env(&encore_path, &args, None, &config.build_env)?;
+    config.log("Docker image built successfully.");
+    Ok(encore_path)
+}
+
+/// Save the docker image to a tar file.
+fn docker_save(image_tag: &str, tar_path: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    config.log_fmt(format_args!("Saving Docker image to {}...", tar_path.display()));
+    run_command(config.runtime.program(), &[OsStr::new("save"), OsStr::new("-o"), tar_path.as_os_str(), OsStr::new(image_tag)], None)?;
+    config.status(format_args!("Saved Docker image successfully."));
+    Ok(())
+}
+
+/// Re-saves after a malformed `docker save` tar, unless `--retries` says otherwise.
+const DEFAULT_SAVE_RETRIES: u32 = 1;
+
+/// Check that a saved image tar reads through to the end and that the members its
+/// `manifest.json` lists are all there, without unpacking it.
+///
+/// A truncated save (the disk filling up mid-save, say) otherwise only shows up as a generic
+/// `tar xf` failure, or as a missing blob once the tar has been unpacked.
+fn probe_image_tar(tar_path: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut archive = tar::Archive::new(BufReader::new(File::open(tar_path)?));
+    let mut members = HashSet::new();
+    let mut manifest = None;
+    for entry in archive.entries()? {
+        let mut entry = entry.map_err(|e| format!("Image tar {} is truncated or corrupt: {}", tar_path.display(), e))?;
+        let path = entry.path()?.components().filter(|c| *c != std::path::Component::CurDir).collect::<PathBuf>();
+        if path == Path::new(&config.manifest_name) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).map_err(|e| format!("Image tar {} is truncated or corrupt: {}", tar_path.display(), e))?;
+            manifest = Some(contents);
+        }
+        members.insert(path);
+    }
+    // An OCI archive (`podman save --format oci-archive`) has only the index; it read through.
+    if manifest.is_none() && members.iter().any(|member| member.file_name().is_some_and(|n| n == "index.json")) {
+        return Ok(());
+    }
+    let contents = manifest.ok_or_else(|| format!("Image tar {} has no {}", tar_path.display(), config.manifest_name))?;
+    let probe_dir = tempfile::tempdir()?;
+    let manifest_path = probe_dir.path().join(&config.manifest_name);
+    fs::write(&manifest_path, contents)?;
+    // An OCI index only leads to the image manifest through blobs still in the tar.
+    if is_oci_index(&manifest_path) {
+        return Ok(());
+    }
+    let manifest = read_manifest(&manifest_path).map_err(|e| format!("Image tar {} has an unreadable {}: {}", tar_path.display(), config.manifest_name, e))?;
+    let listed = std::iter::once(manifest.config_path.clone()).chain(manifest.layers.iter().map(|digest| manifest.blob(digest)));
+    for path in listed {
+        let member = path.strip_prefix(probe_dir.path()).unwrap_or(&path);
+        if !members.contains(member) {
+            return Err(format!("Image tar {} is missing {}, which its manifest lists", tar_path.display(), member.display()).into());
+        }
+    }
+    config.log_fmt(format_args!("Image tar {} is complete ({} members)", tar_path.display(), thousands(members.len() as u64)));
+    Ok(())
+}
+
+/// `--pull-policy`: where the image comes from, after Kubernetes' `imagePullPolicy`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum PullPolicy {
+    /// Build it with `encore build docker`; the default for tag references.
+    Build,
+    /// Pull it from the registry; the default for digest references, which can't be built.
+    Always,
+    /// Pull it only when it isn't present locally.
+    IfNotPresent,
+    /// Use the local image, failing if there is none.
+    Never,
+}
+
+impl PullPolicy {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "build" => Ok(Self::Build),
+            "always" => Ok(Self::Always),
+            "if-not-present" => Ok(Self::IfNotPresent),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("Unknown --pull-policy '{}' (expected always, if-not-present, never or build)", name)),
+        }
+    }
+}
+
+/// Whether the Docker daemon has an image for `image_ref` locally.
+fn docker_image_exists(image_ref: &str, runtime: ContainerRuntime) -> bool {
+    command_output(runtime.program(), &["image", "inspect", image_ref], None).is_ok()
+}
+
+/// Pull an image from its registry, for `--pull-policy always`/`if-not-present` and digest references.
+fn docker_pull(image_ref: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    config.status(format_args!("Pulling Docker image {}...", image_ref));
+    run_command(config.runtime.program(), &["pull", image_ref], None)?;
+    config.log("Docker image pulled successfully.");
+    Ok(())
+}
+
+/// Check the cosign signature of `image_ref` in its registry against the public `key`, before
+/// the image is pulled or used, for `--verify-signatures`.
+#[cfg(feature = "cosign")]
+fn verify_signature(image_ref: &str, key: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if !key.is_file() {
+        return Err(format!("Cosign public key {} doesn't exist", key.display()).into());
+    }
+    config.status(format_args!("Verifying cosign signature of {}...", image_ref));
+    if let Err(e) = command_output("cosign", &[OsStr::new("verify"), OsStr::new("--key"), key.as_os_str(), OsStr::new(image_ref)], None) {
+        if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) {
+            return Err("cosign is not installed or not on PATH (needed by --verify-signatures)".into());
+        }
+        return Err(format!("Signature verification failed for {}: {}", image_ref, e).into());
+    }
+    config.status(format_args!("Signature of {} verified with {}.", image_ref, key.display()));
+    Ok(())
+}
+
+/// Prune dangling images so the build doesn't run out of disk on long-lived runners.
+fn docker_prune_images(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    config.log("Pruning dangling Docker images...");
+    let output = command_output(config.runtime.program(), &["image", "prune", "-f"], None)?;
+    match output.lines().find_map(|line| line.strip_prefix("Total reclaimed space:")) {
+        Some(reclaimed) => config.status(format_args!("Pruned dangling images, reclaimed {}.", reclaimed.trim())),
+        None => config.status(format_args!("Pruned dangling images.")),
+    }
+    Ok(())
+}
+
+/// Remove docker images.
+fn docker_remove(images: &[&str], config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if images.is_empty() {
+        return Ok(());
+    }
+    config.status(format_args!("Removing Docker images {} created by this run (pass --keep-image to keep them)", images.join(" and ")));
+    let mut args = vec!["image", "rm"];
+    args.extend_from_slice(images);
+    run_command(config.runtime.program(), &args, None)?;
+    config.status(format_args!("Docker images removed successfully."));
+    Ok(())
+}
+
+/// Split a `repo@sha256:<hex>` reference into repository and digest.
+///
+/// Returns `None` for tag references, and an error for a malformed digest.
+fn parse_digest_reference(image: &str) -> Result<Option<(&str, &str)>, Box<dyn std::error::Error>> {
+    let Some((repo, digest)) = image.split_once('@') else {
+        return Ok(None);
+    };
+    let hex = digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| format!("Unsupported digest algorithm in {} (expected @sha256:<hex>)", image))?;
+    if repo.is_empty() || hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()) {
+        return Err(format!("Invalid digest reference {} (expected repo@sha256:<64 lowercase hex chars>)", image).into());
+    }
+    Ok(Some((repo, digest)))
+}
+
+/// Repository part of an image reference, without its tag or digest.
+fn image_repository(image_tag: &str) -> &str {
+    let image_tag = image_tag.split_once('@').map_or(image_tag, |(repo, _)| repo);
+    let name_start = image_tag.rfind('/').map_or(0, |i| i + 1);
+    match image_tag[name_start..].find(':') {
+        Some(i) => &image_tag[..name_start + i],
+        None => image_tag,
+    }
+}
+
+/// Tag the image with a tag derived from the extracted layer digest, e.g. `myapp:sha-0123456789ab`.
+fn docker_tag_digest(image_tag: &str, layer_digest: &str, config: &Config) -> Result<String, Box<dyn std::error::Error>> {
+    let short = &layer_digest[..layer_digest.len().min(12)];
+    let digest_tag = format!("{}:sha-{}", image_repository(image_tag), short);
+    config.log_fmt(format_args!("Tagging {} as {}", image_tag, digest_tag));
+    run_command(config.runtime.program(), &["tag", image_tag, &digest_tag], None)?;
+    config.status(format_args!("Tagged Docker image as {}.", digest_tag));
+    Ok(digest_tag)
+}
+
+/// `statvfs` of the filesystem holding `path`.
+///
+/// `path` may not exist yet; its nearest existing ancestor is queried instead.
+fn volume_stats(path: &Path) -> io::Result<libc::statvfs> {
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(Path::new("."));
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_encoded_bytes()).map_err(io::Error::other)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a valid NUL-terminated string and stat is a properly sized out-parameter.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io_context(format!("Failed to query free space for {}", existing.display()))(
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(stat)
+}
+
+/// Free bytes available to unprivileged users on the filesystem holding `path`.
+fn free_space(path: &Path) -> io::Result<u64> {
+    let stat = volume_stats(path)?;
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free inodes available to unprivileged users on the filesystem holding `path`, or `None` for
+/// filesystems without a fixed inode table (btrfs, ZFS, ...), which report a total of zero.
+fn free_inodes(path: &Path) -> io::Result<Option<u64>> {
+    let stat = volume_stats(path)?;
+    Ok((stat.f_files != 0).then_some(stat.f_favail as u64))
+}
+
+/// Free inodes below which `check_disk_space` counts the layers' entries. Above it a layer would
+/// need over a million entries to run the volume out, which isn't worth an extra pass over every
+/// layer to rule out.
+const INODE_CHECK_THRESHOLD: u64 = 1_000_000;
+
+/// Headroom applied to compressed layer sizes when estimating how much space extraction needs.
+const DISK_SAFETY_FACTOR: u64 = 3;
+
+/// Device id of the filesystem holding `path`, or of its nearest existing ancestor.
+fn volume_id(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(Path::new("."));
+    Ok(fs::metadata(existing)?.dev())
+}
+
+/// Refuse to start extracting when the scratch or output volume can't hold the selected layers.
+///
+/// Each layer is unpacked into scratch space and then copied to the output, so both need roughly
+/// the layer size times `DISK_SAFETY_FACTOR`; when they share a volume the requirements add up.
+///
+/// Inodes are checked the same way, with one per layer entry, but only on a volume with fewer
+/// than `INODE_CHECK_THRESHOLD` free: counting the entries means reading every layer through.
+fn check_disk_space(layers: &[PathBuf], scratch_dir: &Path, output_dir: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut layer_bytes = 0;
+    for layer in layers {
+        layer_bytes += fs::metadata(layer).map_err(io_context(format!("Failed to stat layer {}", layer.display())))?.len();
+    }
+    let needed = layer_bytes.saturating_mul(DISK_SAFETY_FACTOR);
+    let mut volumes: Vec<(u64, &Path, u64)> = Vec::new();
+    for dir in [scratch_dir, output_dir] {
+        let id = volume_id(dir)?;
+        match volumes.iter_mut().find(|(volume, _, _)| *volume == id) {
+            Some(entry) => entry.2 += needed,
+            None => volumes.push((id, dir, needed)),
+        }
+    }
+    for (_, dir, needed) in volumes {
+        let free = free_space(dir)?;
+        config.log_fmt(format_args!("Disk check for {}: need ~{}, have {}", dir.display(), format_bytes(needed), format_bytes(free)));
+        if free < needed {
+            return Err(format!(
+                "Not enough disk space on the volume holding {}: need ~{}, have {} (use --ignore-disk-check to proceed anyway)",
+                dir.display(),
+                format_bytes(needed),
+                format_bytes(free)
+            )
+            .into());
+        }
+    }
+    check_free_inodes(layers, scratch_dir, output_dir, config)
+}
+
+/// The inode half of `check_disk_space`.
+fn check_free_inodes(layers: &[PathBuf], scratch_dir: &Path, output_dir: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut volumes: Vec<(u64, &Path, u64, u64)> = Vec::new();
+    for dir in [scratch_dir, output_dir] {
+        let id = volume_id(dir)?;
+        match volumes.iter_mut().find(|(volume, ..)| *volume == id) {
+            Some(entry) => entry.3 += 1,
+            None => {
+                if let Some(free) = free_inodes(dir)?.filter(|free| *free < INODE_CHECK_THRESHOLD) {
+                    volumes.push((id, dir, free, 1));
+                }
+            }
+        }
+    }
+    if volumes.is_empty() {
+        return Ok(());
+    }
+    let mut entries = 0u64;
+    for layer in layers {
+        for_each_layer_entry(layer, config, |_| {
+            entries += 1;
+            Ok(())
+        })?;
+    }
+    for (_, dir, free, copies) in volumes {
+        let needed = entries * copies;
+        config.log_fmt(format_args!("Inode check for {}: need ~{}, have {}", dir.display(), thousands(needed), thousands(free)));
+        if free < needed {
+            return Err(format!(
+                "Not enough free inodes on the volume holding {}: need ~{} for the layers' files, have {}; free space is not the limit (use --ignore-disk-check to proceed anyway)",
+                dir.display(),
+                thousands(needed),
+                thousands(free)
+            )
+            .into());
+        }
+        if free / 2 < needed {
+            config.warn(
+                "disk-space",
+                format!("Extraction will use over half the free inodes on the volume holding {} ({} of {})", dir.display(), thousands(needed), thousands(free)),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Formats a byte count with a binary unit, e.g. "1.5 GiB".
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Parses a human-readable size such as `512`, `10MiB`, `1.5G` or `200kb`.
+///
+/// Single-letter and `iB` units are binary (`10M` is 10 MiB), `kB`-style units are decimal.
+fn parse_size(input: &str) -> Result<u64, String> {
+    let trimmed = input.trim();
+    let split = trimmed.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("'{}' is not a size", input))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        other => return Err(format!("unknown unit '{}' in '{}'", other, input)),
+    };
+    let bytes = number * multiplier as f64;
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("'{}' is too large", input));
+    }
+    Ok(bytes as u64)
+}
+
+/// Parses a duration such as `90`, `90s`, `15m`, `1.5h` or `500ms`; a bare number is seconds.
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let trimmed = input.trim();
+    let split = trimmed.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("'{}' is not a duration", input))?;
+    let seconds = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1.0,
+        "ms" => 0.001,
+        "m" | "min" => 60.0,
+        "h" => 3_600.0,
+        other => return Err(format!("unknown unit '{}' in '{}' (expected ms, s, m or h)", other, input)),
+    };
+    Duration::try_from_secs_f64(number * seconds).map_err(|_| format!("'{}' is too long", input))
+}
+
+/// Format a count with comma thousands separators, e.g. `31,000`.
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+/// `--min-free-space` for the prerequisite checks, a size such as `2G`; 1 GiB unless given.
+fn min_free_space(args: &[String]) -> Result<u64, String> {
+    match arg_value(args, "--min-free-space") {
+        Some(v) => parse_size(&v).map_err(|e| format!("Invalid --min-free-space value: {}", e)),
+        None => Ok(1 << 30),
+    }
+}
+
+/// Check that the environment can run the full pipeline, printing a pass/fail line per check.
+///
+/// Only critical checks fail the probe: what the run as configured can't do without. The rest
+/// (a tool only some images need, a low inode count) print as warnings.
+fn probe_prerequisites(output_dir: &Path, scratch_dir: &Path, min_free: u64, args: &[String], config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    // The check lines are the command's output, so they go to stdout and are colored for it.
+    let color = color_enabled(args, io::stdout().is_terminal());
+    // A piped-in tar needs neither encore nor the container runtime, and only a build needs encore.
+    let acquires = !config.tar_stdin;
+    let builds = acquires && arg_value(args, "--pull-policy").is_none_or(|policy| policy == "build");
+    let mut checks: Vec<(&str, Result<String, String>, bool)> = Vec::new();
+    let encore = locate_encore().map_err(|e| e.to_string()).map(|path| match command_output(&path, &["version"], None) {
+        Ok(version) => format!("{} ({})", path, version.trim()),
+        Err(e) => format!("{} (version unknown: {})", path, e.to_string().trim()),
+    });
+    checks.push(("encore on PATH", encore, builds));
+    checks.push((
+        match config.runtime {
+            ContainerRuntime::Docker => "docker daemon",
+            ContainerRuntime::Podman => "podman",
+        },
+        check_docker_daemon(config.runtime).map(|_| "reachable".to_string()).map_err(|e| e.to_string()),
+        acquires,
+    ));
+    for (name, dir) in [("scratch free space", scratch_dir), ("output free space", output_dir)] {
+        let result = match free_space(dir) {
+            Ok(free) if free >= min_free => Ok(format!("{} available (need {})", format_bytes(free), format_bytes(min_free))),
+            Ok(free) => Err(format!("{} available, need {}", format_bytes(free), format_bytes(min_free))),
+            Err(e) => Err(e.to_string()),
+        };
+        checks.push((name, result, true));
+    }
+    let inodes = match free_inodes(scratch_dir) {
+        Ok(None) => Ok("not limited by the filesystem".to_string()),
+        Ok(Some(free)) if free >= INODE_CHECK_THRESHOLD => Ok(format!("{} free", thousands(free))),
+        Ok(Some(free)) => Err(format!("only {} free; an image with many files may run out", thousands(free))),
+        Err(e) => Err(e.to_string()),
+    };
+    checks.push(("scratch free inodes", inodes, false));
+    let writable_dir = output_dir.ancestors().find(|p| p.exists()).unwrap_or(Path::new("."));
+    checks.push((
+        "output dir writable",
+        tempfile::NamedTempFile::new_in(writable_dir)
+            .map(|_| writable_dir.display().to_string())
+            .map_err(|e| format!("{}: {}", writable_dir.display(), e)),
+        true,
+    ));
+
+    // External programs: `tar` unpacks a saved image, the rest only run for the options using them.
+    let zstd = match config.archive_format {
+        ArchiveFormat::Zstd => ("zstd", "compresses --compress-format zstd", true),
+        _ => ("zstd", "decompresses zstd layers", false),
+    };
+    let mut tools: Vec<(&str, &str, bool)> = vec![("tar", "unpacks the saved image", acquires), zstd];
+    #[cfg(feature = "s3")]
+    if config.s3_output.is_some() {
+        tools.push(("aws", "uploads --s3-output", true));
+    }
+    #[cfg(feature = "cosign")]
+    if has_flag(args, "--verify-signatures") {
+        tools.push(("cosign", "checks --verify-signatures", true));
+    }
+    if let Some(filter) = &config.tar_filter {
+        tools.push((filter[0].as_str(), "runs --tar-filter", true));
+    }
+    for (program, purpose, critical) in tools {
+        let found = find_on_path(program).map(|path| path.display().to_string()).ok_or_else(|| format!("not found on PATH ({})", purpose));
+        checks.push((program, found, critical));
+    }
+
+    let mut failed = 0;
+    for (name, result, critical) in &checks {
+        match result {
+            Ok(detail) => println!("{} {}: {}", paint("[PASS]", "32", color), name, detail),
+            Err(detail) if *critical => {
+                failed += 1;
+                println!("{} {}: {}", paint("[FAIL]", "31", color), name, detail.trim());
+            }
+            Err(detail) => println!("{} {}: {}", paint("[WARN]", "33", color), name, detail.trim()),
+        }
+    }
+    let critical = checks.iter().filter(|(_, _, critical)| *critical).count();
+    if failed > 0 {
+        return Err(format!("{} of {} critical prerequisite checks failed", failed, critical).into());
+    }
+    println!("All critical prerequisite checks passed.");
+    Ok(())
+}
+
+/// `doctor`: run the prerequisite checks of `--probe-only` without an image, for the output
+/// directory, runtime and options given.
+fn doctor(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::new(args)?;
+    let current_dir = env::current_dir()?;
+    let output_dir = match expanded_arg(args, "--output-dir")?.filter(|dir| dir != "-") {
+        Some(dir) => current_dir.join(dir),
+        None => current_dir.join("encore_prod"),
+    };
+    probe_prerequisites(&output_dir, &current_dir, min_free_space(args)?, args, &config)
+}
+
+/// Unpack an image tar streamed on stdin (e.g. `docker save img | encorebaremetal --tar-stdin`).
+///
+/// The stream can't be seeked, so the whole tar is unpacked into `dest` first; `manifest.json`
+/// and the `blobs/sha256` entries are only resolved afterwards, whatever order they arrived in.
+///
+/// Returns the sha256 of the stream when `--print-tar-sha256`/`--expect-tar-sha256` need it.
+fn unpack_stdin(dest: &Path, config: &Config) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    config.log_fmt(format_args!("Unpacking image tar from stdin into {}...", dest.display()));
+    let stdin = io::stdin();
+    let mut archive = tar::Archive::new(sha256::HashReader::new(TimeoutReader(stdin.lock())));
+    archive.unpack(dest).map_err(|e| {
+        format!(
+            "Failed to unpack image tar from stdin (the stream is unpacked to {} before manifest.json is read): {}",
+            dest.display(),
+            e
+        )
+    })?;
+    let digest = if config.hashes_tar() {
+        Some(archive.into_inner().finish()?)
+    } else {
+        None
+    };
+    config.status(format_args!("Read image tar from stdin successfully."));
+    Ok(digest)
+}
+
+/// Print and/or verify the image tar's sha256 against `--expect-tar-sha256`.
+fn check_tar_sha256(digest: &str, source: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if config.print_tar_sha256 {
+        config.status(format_args!("Image tar sha256: {}", digest));
+    }
+    match &config.expect_tar_sha256 {
+        Some(expected) if expected != digest => Err(format!(
+            "Checksum mismatch for {}: expected sha256 {}, got {}",
+            source, expected, digest
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Find the image manifest in the unpacked image tar.
+///
+/// An explicit `--manifest-path` (relative to the unpacked tar) wins; otherwise `manifest.json` (or
+/// the `--manifest-name` given) and then an OCI `index.json` are searched for at the root and up to
+/// two directory levels below it.
+fn locate_manifest(
+    image_dir: &Path,
+    override_path: Option<&str>,
+    config: &Config,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(path) = override_path {
+        let path = image_dir.join(path);
+        if !path.is_file() {
+            return Err(format!("Manifest given by --manifest-path not found: {}", path.display()).into());
+        }
+        return Ok(path);
+    }
+    let name = config.manifest_name.as_str();
+    let default = image_dir.join(name);
+    if default.is_file() {
+        return Ok(default);
+    }
+    let mut candidates: Vec<(usize, bool, PathBuf)> = WalkDir::new(image_dir)
+        .min_depth(1)
+        .max_depth(3)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && (e.file_name() == name || e.file_name() == "index.json"))
+        .map(|e| (e.depth(), e.file_name() != name, e.into_path()))
+        .collect();
+    // Prefer the shallowest match, and the named manifest over an OCI index at the same depth.
+    candidates.sort();
+    if candidates.is_empty() && image_dir.join("repositories").is_file() {
+        return Err("Unsupported manifest format v1: the tar has only a `repositories` file (Docker < 1.10); \
+                    re-save the image with a newer Docker"
+            .into());
+    }
+    let (_, _, path) = candidates.into_iter().next().ok_or_else(|| {
+        format!(
+            "No {} or index.json found within two levels of {} (use --manifest-path to point at it)",
+            name,
+            image_dir.display()
+        )
+    })?;
+    // A nested layout is unusual enough to always mention; blob paths resolve against its directory.
+    config.status(format_args!("Using manifest found at {}", path.strip_prefix(image_dir).unwrap_or(&path).display()));
+    Ok(path)
+}
+
+/// Returns true if the manifest is an OCI image index rather than docker's manifest.json.
+///
+/// The usual names settle it; a manifest under another `--manifest-name` is an index if it is a
+/// JSON object listing `manifests`, as docker's is a list.
+fn is_oci_index(manifest_path: &Path) -> bool {
+    match manifest_path.file_name().and_then(|n| n.to_str()) {
+        Some("index.json") => true,
+        Some("manifest.json") => false,
+        _ => File::open(manifest_path)
+            .ok()
+            .and_then(|file| from_reader::<_, Value>(BufReader::new(file)).ok())
+            .is_some_and(|document| document.get("manifests").is_some_and(Value::is_array)),
+    }
+}
+
+/// Resolve an OCI `index.json` to the first image manifest it references.
+fn oci_image_manifest(index_path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
+    let blobs = index_path.parent().unwrap_or(Path::new(".")).join("blobs/sha256");
+    let mut document: Value = from_reader(File::open(index_path)?)?;
+    // Follow nested indexes (e.g. multi-platform images) down to the first image manifest.
+    while document.get("layers").is_none() {
+        let digest = document["manifests"][0]["digest"]
+            .as_str()
+            .ok_or("No manifests found in OCI index")?;
+        document = from_reader(File::open(blobs.join(strip_digest_prefix(digest)))?)?;
+    }
+    Ok(document)
+}
+
+/// Image tar layouts `parse_manifest` understands:
+///
+/// | Format         | Written by            | Layer paths             | Layer sizes        |
+/// |----------------|-----------------------|-------------------------|--------------------|
+/// | v1             | Docker < 1.10         | `repositories` only     | unsupported        |
+/// | v2 (legacy)    | Docker 1.10 - 24      | `<id>/layer.tar`        | file sizes         |
+/// | v2 (OCI blobs) | Docker 25+            | `blobs/sha256/<digest>` | `LayerSources`     |
+/// | OCI index      | skopeo, buildah, ...  | `index.json` -> blobs   | descriptor sizes   |
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ManifestFormat {
+    DockerLegacy,
+    DockerBlobs,
+    OciIndex,
+}
+
+/// What the pipeline reads from an image's `manifest.json` (or OCI `index.json`), parsed once.
+struct Manifest {
+    format: ManifestFormat,
+    /// Directory holding the manifest; blob paths are relative to it.
+    root: PathBuf,
+    /// Layer digests (layer ids for the legacy format), bottom layer first.
+    layers: Vec<String>,
+    /// Layer digests with their sizes, where the manifest records them.
+    layer_sizes: Vec<(String, u64)>,
+    /// Path of the image config blob.
+    config_path: PathBuf,
+    /// Tags the image was saved under.
+    repo_tags: Vec<String>,
+}
+
+impl Manifest {
+    fn blob(&self, digest: &str) -> PathBuf {
+        match self.format {
+            ManifestFormat::DockerLegacy => self.root.join(digest).join("layer.tar"),
+            ManifestFormat::DockerBlobs | ManifestFormat::OciIndex => {
+                self.root.join("blobs/sha256").join(strip_digest_prefix(digest))
+            }
+        }
+    }
+}
+
+/// Parse manifest.json (or an OCI index) for the layers, config blob and repo tags.
+fn parse_manifest(manifest_path: &Path) -> Result<Manifest, Box<dyn std::error::Error>> {
+    let manifest = read_manifest(manifest_path)?;
+    validate_manifest(&manifest)?;
+    Ok(manifest)
+}
+
+/// `parse_manifest` without checking the listed blobs exist, for `probe_image_tar`, whose tar
+/// hasn't been unpacked.
+fn read_manifest(manifest_path: &Path) -> Result<Manifest, Box<dyn std::error::Error>> {
+    let root = manifest_path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    if is_oci_index(manifest_path) {
+        let index: Value = from_reader(File::open(manifest_path)?)?;
+        match index["schemaVersion"].as_u64() {
+            Some(2) => {}
+            Some(version) => return Err(format!("Unsupported manifest format: OCI index schema v{}", version).into()),
+            None => return Err("Unsupported manifest format: OCI index has no schemaVersion".into()),
+        }
+        // OCI layouts carry the tag as a `ref.name` annotation on the index entry.
+        let repo_tags = index["manifests"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|m| {
+                let annotations = &m["annotations"];
+                annotations["io.containerd.image.name"].as_str().or(annotations["org.opencontainers.image.ref.name"].as_str())
+            })
+            .map(str::to_string)
+            .collect();
+        let document = oci_image_manifest(manifest_path)?;
+        let layer_sizes: Vec<(String, u64)> = document["layers"]
+            .as_array()
+            .ok_or("No layers found in OCI manifest")?
+            .iter()
+            .filter_map(|l| {
+                let digest = strip_digest_prefix(l["digest"].as_str()?).to_string();
+                Some((digest, l["size"].as_u64().unwrap_or(0)))
+            })
+            .collect();
+        let config_digest = document["config"]["digest"].as_str().ok_or("No config found in OCI manifest")?;
+        let manifest = Manifest {
+            format: ManifestFormat::OciIndex,
+            layers: layer_sizes.iter().map(|(digest, _)| digest.clone()).collect(),
+            config_path: root.join("blobs/sha256").join(strip_digest_prefix(config_digest)),
+            root,
+            layer_sizes,
+            repo_tags,
+        };
+        return Ok(manifest);
+    }
+
+    let file = File::open(manifest_path)?;
+    let document: Value = from_reader(file)?;
+    let entry = match &document {
+        Value::Array(entries) => entries.first().ok_or("Unsupported manifest format: manifest.json lists no images")?,
+        // A registry manifest rather than `docker save` output.
+        Value::Object(object) if object.contains_key("schemaVersion") => {
+            return Err(format!("Unsupported manifest format v{} (not a docker save manifest)", object["schemaVersion"]).into());
+        }
+        _ => return Err("Unsupported manifest format: manifest.json is not a list of images".into()),
+    };
+    let layer_paths: Vec<&str> = entry["Layers"]
+        .as_array()
+        .ok_or("Unsupported manifest format: image entry has no Layers")?
+        .iter()
+        .filter_map(|l| l.as_str())
+        .collect();
+    // An image without layers (`FROM scratch`) is told apart by its config path instead.
+    let format = match layer_paths.first() {
+        Some(path) if path.starts_with("blobs/") => ManifestFormat::DockerBlobs,
+        Some(path) if path.ends_with("/layer.tar") => ManifestFormat::DockerLegacy,
+        Some(path) => return Err(format!("Unsupported manifest format: unrecognized layer path '{}'", path).into()),
+        None if entry["Config"].as_str().is_some_and(|c| c.starts_with("blobs/")) => ManifestFormat::DockerBlobs,
+        None => ManifestFormat::DockerLegacy,
+    };
+    let layers: Vec<String> = layer_paths
+        .iter()
+        .map(|l| match format {
+            ManifestFormat::DockerLegacy => l.trim_end_matches("/layer.tar").to_string(),
+            _ => strip_digest_prefix(l.rsplit('/').next().unwrap_or(l)).to_string(),
+        })
+        .collect();
+    // Docker 25+ records layer sizes; older saves and BuildKit exports, which leave
+    // `LayerSources` out or empty, only have the files to go by.
+    let recorded: HashMap<&str, u64> = entry["LayerSources"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(digest, info)| Some((strip_digest_prefix(digest), info.get("size").and_then(|s| s.as_u64())?)))
+        .collect();
+    let layer_sizes = layers
+        .iter()
+        .zip(&layer_paths)
+        .filter_map(|(layer, path)| {
+            let size = match recorded.get(layer.as_str()) {
+                Some(size) => *size,
+                None => fs::metadata(root.join(path)).ok()?.len(),
+            };
+            Some((layer.clone(), size))
+        })
+        .collect();
+    let config = entry["Config"].as_str().ok_or("Unsupported manifest format: image entry has no Config")?;
+    let repo_tags = entry["RepoTags"].as_array().into_iter().flatten().filter_map(|t| t.as_str()).map(str::to_string).collect();
+    Ok(Manifest { format, config_path: root.join(config), root, layers, layer_sizes, repo_tags })
+}
+
+/// Check that the config blob and every layer blob the manifest lists exist, so a truncated
+/// or corrupted save fails before any extraction work instead of yielding an unloadable image.
+fn validate_manifest(manifest: &Manifest) -> Result<(), Box<dyn std::error::Error>> {
+    if !manifest.config_path.is_file() {
+        let config = manifest.config_path.file_name().map_or_else(|| manifest.config_path.display().to_string(), |n| n.to_string_lossy().into_owned());
+        return Err(format!(
+            "Image config {} listed in the manifest is missing from the image tar (expected {})",
+            config,
+            manifest.config_path.display()
+        )
+        .into());
+    }
+    for digest in &manifest.layers {
+        let blob = manifest.blob(digest);
+        if !blob.is_file() {
+            return Err(format!("Layer {} listed in the manifest is missing from the image tar (expected {})", digest, blob.display()).into());
+        }
+    }
+    Ok(())
+}
+
+impl ManifestFormat {
+    fn name(self) -> &'static str {
+        match self {
+            ManifestFormat::DockerLegacy => "docker-legacy",
+            ManifestFormat::DockerBlobs => "docker-blobs",
+            ManifestFormat::OciIndex => "oci-index",
+        }
+    }
+}
+
+/// The digest of the largest layer.
+///
+/// A padded base layer can outgrow the Encore layer, so among the layers within
+/// `--layer-size-band` percent of the largest, the first one containing `encore/` wins.
+fn largest_layer(manifest: &Manifest, config: &Config) -> Result<String, Box<dyn std::error::Error>> {
+    let layers = &manifest.layer_sizes;
+    let mut largest: Option<&(String, u64)> = None;
+    for layer in layers {
+        if largest.is_none_or(|(_, size)| layer.1 > *size) {
+            largest = Some(layer);
+        }
+    }
+    let (digest, largest_size) = largest.ok_or("No layer sources found")?;
+
+    let floor = largest_size - largest_size * config.layer_size_band / 100;
+    let contenders: Vec<&(String, u64)> = layers.iter().filter(|(_, size)| *size >= floor).collect();
+    if contenders.len() > 1 {
+        for (candidate, size) in contenders {
+            if layer_has_encore(&manifest.blob(candidate), config) {
+                config.log_fmt(format_args!("Selected encore layer ({} bytes, largest {}): {}", size, largest_size, candidate));
+                return Ok(candidate.clone());
+            }
+        }
+    }
+    config.log_fmt(format_args!("Selected largest layer ({} bytes): {}", largest_size, digest));
+    Ok(digest.clone())
+}
+
+/// Whether a layer blob lists any entry under `encore/`; unreadable layers count as not.
+fn layer_has_encore(layer_path: &Path, config: &Config) -> bool {
+    let Ok(reader) = open_layer(layer_path, config) else {
+        return false;
+    };
+    let mut archive = tar::Archive::new(reader);
+    let Ok(entries) = archive.entries() else {
+        return false;
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .any(|e| e.path().ok().and_then(|p| layer_relative(&p)).is_some_and(|p| p.starts_with("encore")))
+}
+
+/// The timestamp `--deterministic` applies: `SOURCE_DATE_EPOCH` when set, otherwise the image's
+/// `created` time, falling back to the Unix epoch for images that don't record one.
+fn deterministic_epoch(manifest: &Manifest) -> Result<SystemTime, Box<dyn std::error::Error>> {
+    if let Some(value) = env::var_os("SOURCE_DATE_EPOCH").filter(|v| !v.is_empty()) {
+        let value = value.to_string_lossy();
+        let secs = value.parse::<u64>().map_err(|_| format!("Invalid SOURCE_DATE_EPOCH value: {} (expected seconds)", value))?;
+        return Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    match read_image_config(manifest)?.get("created").and_then(Value::as_str) {
+        Some(created) => Ok(parse_rfc3339(created).map_err(|e| format!("Invalid image created time {}", e))?),
+        None => Ok(SystemTime::UNIX_EPOCH),
+    }
+}
+
+/// Give every file, directory and symlink in the output the `--deterministic` timestamp.
+fn normalize_output_times(output_dir: &Path, epoch: SystemTime) -> io::Result<()> {
+    let time = FileTime::from_system_time(epoch);
+    for entry in WalkDir::new(output_dir) {
+        let entry = entry.map_err(io::Error::from)?;
+        filetime::set_symlink_file_times(entry.path(), time, time)
+            .map_err(io_context(format!("Failed to set timestamps on {}", entry.path().display())))?;
+    }
+    Ok(())
+}
+
+/// Read the image config blob (architecture, os, entrypoint, env, ...) referenced by the manifest.
+fn read_image_config(manifest: &Manifest) -> Result<Value, Box<dyn std::error::Error>> {
+    let config_path = &manifest.config_path;
+    let file = File::open(config_path).map_err(|e| format!("Failed to open image config {}: {}", config_path.display(), e))?;
+    Ok(from_reader(file)?)
+}
+
+/// The host architecture in Docker's naming (`amd64`, `arm64`, ...).
+fn host_docker_arch() -> &'static str {
+    match env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        other => other,
+    }
+}
+
+/// Check the Encore version recorded in the copied `build-info.json` against `--encore-version`,
+/// warning on a mismatch or (with `--strict-version`) failing.
+fn check_encore_version(
+    output_dir: &Path,
+    requirement: &version::VersionReq,
+    strict: bool,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let build_info_path = config.relocate(output_dir, &output_dir.join("build-info.json"));
+    let file = File::open(&build_info_path)
+        .map_err(|e| format!("--encore-version needs {}: {}", build_info_path.display(), e))?;
+    let build_info: Value = from_reader(file)?;
+    let recorded = build_info["encore_compiler_version"]
+        .as_str()
+        .ok_or_else(|| format!("No encore_compiler_version in {}", build_info_path.display()))?;
+    let found = version::Version::parse(recorded)?;
+    config.log_fmt(format_args!("Encore version {} (required {})", found, requirement));
+    if requirement.matches(&found) {
+        return Ok(());
+    }
+    let message = format!("Encore version {} does not satisfy --encore-version {}", recorded, requirement);
+    if strict {
+        return Err(message.into());
+    }
+    config.warn("version-mismatch", message);
+    Ok(())
+}
+
+/// JSON value kinds the embedded infra config schema can require.
+#[derive(Clone, Copy)]
+enum JsonKind {
+    Object,
+    Array,
+    String,
+}
+
+impl JsonKind {
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            JsonKind::Object => value.is_object(),
+            JsonKind::Array => value.is_array(),
+            JsonKind::String => value.is_string(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            JsonKind::Object => "object",
+            JsonKind::Array => "array",
+            JsonKind::String => "string",
+        }
+    }
+}
+
+/// Name of a JSON value's type, for validation messages.
+fn json_kind_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Fields the runtime needs from `infra.config.json`, as `/`-separated paths where `*` stands
+/// for every element of an array; each must be present with one of the listed types.
+const INFRA_SCHEMA: &[(&str, &[JsonKind])] = &[
+    ("metadata", &[JsonKind::Object]),
+    ("metadata/app_id", &[JsonKind::String]),
+    ("metadata/env_name", &[JsonKind::String]),
+    ("sql_servers", &[JsonKind::Array]),
+    ("sql_servers/*/host", &[JsonKind::String]),
+    ("sql_servers/*/databases", &[JsonKind::Object]),
+    ("pubsub", &[JsonKind::Array]),
+    ("pubsub/*/type", &[JsonKind::String]),
+    ("pubsub/*/topics", &[JsonKind::Object]),
+    // Secrets are either inline or a reference to an environment variable holding them.
+    ("secrets", &[JsonKind::Object, JsonKind::String]),
+];
+
+/// Resolve a schema path against `value`, returning the concrete path and value (if present) of
+/// every field it names. Paths through a missing or non-array `*` parent name nothing.
+fn infra_fields<'a>(value: &'a Value, path: &str) -> Vec<(String, Option<&'a Value>)> {
+    let mut fields = vec![(String::new(), Some(value))];
+    for segment in path.split('/') {
+        let mut next = Vec::new();
+        for (prefix, value) in fields {
+            let Some(value) = value else { continue };
+            if segment == "*" {
+                for (i, item) in value.as_array().into_iter().flatten().enumerate() {
+                    next.push((format!("{}/{}", prefix, i), Some(item)));
+                }
+            } else {
+                next.push((format!("{}/{}", prefix, segment), value.get(segment)));
+            }
+        }
+        fields = next;
+    }
+    fields
+}
+
+/// Check the copied `infra.config.json` against `INFRA_SCHEMA`, failing with every missing or
+/// mistyped field (`--validate-infra`).
+fn validate_infra(output_dir: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let path = config.relocate(output_dir, &output_dir.join("infra.config.json"));
+    let file = File::open(&path).map_err(|e| format!("--validate-infra needs {}: {}", path.display(), e))?;
+    let infra: Value = from_reader(file).map_err(|e| format!("{} is not valid JSON: {}", path.display(), e))?;
+    let mut problems = Vec::new();
+    for (field, kinds) in INFRA_SCHEMA {
+        let expected = kinds.iter().map(|k| k.name()).collect::<Vec<_>>().join(" or ");
+        for (name, value) in infra_fields(&infra, field) {
+            match value {
+                None => problems.push(format!("{}: missing (expected {})", name, expected)),
+                Some(value) if !kinds.iter().any(|k| k.matches(value)) => {
+                    problems.push(format!("{}: expected {}, found {}", name, expected, json_kind_name(value)))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return Err(format!("{} failed validation:\n  - {}", path.display(), problems.join("\n  - ")).into());
+    }
+    config.status(format_args!("Validated infra config: {}", path.display()));
+    Ok(())
+}
+
+/// Compare the image's architecture and OS against the host, warning or (with `--strict-arch`) failing.
+fn check_runtime_arch(
+    manifest: &Manifest,
+    strict: bool,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image_config = read_image_config(manifest)?;
+    let arch = image_config["architecture"].as_str().unwrap_or("unknown");
+    let os = image_config["os"].as_str().unwrap_or("unknown");
+    let (host_arch, host_os) = (host_docker_arch(), env::consts::OS);
+    config.log_fmt(format_args!("Image platform {}/{}, host {}/{}", os, arch, host_os, host_arch));
+    if arch == host_arch && os == host_os {
+        return Ok(());
+    }
+    let message = format!(
+        "Image platform {}/{} does not match host {}/{}; extracted runtime binaries may not run",
+        os, arch, host_os, host_arch
+    );
+    if strict {
+        return Err(message.into());
+    }
+    config.warn("arch-mismatch", message);
+    Ok(())
+}
+
+/// Confirm the runtime launcher that starts the service was extracted and is executable.
+///
+/// The launcher is the first `/encore/...` path in the image config's Entrypoint/Cmd, mapped into
+/// the output directory; without one, the output's `runtimes` tree is searched for `encore-runtime`.
+fn verify_entrypoint(manifest: &Manifest, output_dir: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let from_image = read_image_config(manifest).ok().and_then(|image_config| {
+        let process = &image_config["config"];
+        let argv = process["Entrypoint"].as_array().into_iter().chain(process["Cmd"].as_array()).flatten();
+        // Shell-form commands arrive as one string, so look at each word.
+        argv.filter_map(|arg| arg.as_str())
+            .flat_map(|arg| arg.split_whitespace())
+            .find_map(|word| word.strip_prefix("/encore/").map(|rel| (word.to_string(), output_dir.join(rel))))
+    });
+    let (declared, launcher) = match from_image {
+        Some((declared, path)) => (declared, config.relocate(output_dir, &path)),
+        None => {
+            let runtimes = config.relocate(output_dir, &output_dir.join("runtimes"));
+            let found = WalkDir::new(&runtimes)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .find(|e| e.file_type().is_file() && e.file_name().to_string_lossy().starts_with("encore-runtime"))
+                .ok_or_else(|| format!("No runtime entrypoint declared in the image config or found under {}", runtimes.display()))?;
+            (found.path().display().to_string(), found.into_path())
+        }
+    };
+    let meta = fs::metadata(&launcher)
+        .map_err(|e| format!("Runtime entrypoint {} is missing from the output ({}): {}", declared, launcher.display(), e))?;
+    if !meta.is_file() {
+        return Err(format!("Runtime entrypoint {} is not a regular file", launcher.display()).into());
+    }
+    if meta.permissions().mode() & 0o111 == 0 {
+        return Err(format!(
+            "Runtime entrypoint {} is not executable (mode {:o})",
+            launcher.display(),
+            meta.permissions().mode() & 0o7777
+        )
+        .into());
+    }
+    config.status(format_args!("Verified runtime entrypoint: {}", launcher.display()));
+    verify_js_entrypoint(manifest, output_dir, config)
+}
+
+/// Confirm the service's JavaScript entrypoint was extracted and parses, with `node --check`.
+///
+/// The entrypoint is `build-info.json`'s `entrypoint` when it has one, else the first `.js`,
+/// `.mjs` or `.cjs` path in the image config's Entrypoint/Cmd, else a `combined/main.mjs` under
+/// the output's `artifacts`. Image paths under an app's `.encore` directory map to `artifacts`.
+/// Without `node` on PATH the check is skipped, with a warning in `--strict` mode.
+fn verify_js_entrypoint(manifest: &Manifest, output_dir: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let is_js = |word: &str| [".js", ".mjs", ".cjs"].iter().any(|ext| word.ends_with(ext));
+    let build_info = config.relocate(output_dir, &output_dir.join("build-info.json"));
+    let from_build_info = fs::read_to_string(&build_info)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+        .and_then(|info| info["entrypoint"].as_str().map(str::to_string));
+    let declared = from_build_info.or_else(|| {
+        let image_config = read_image_config(manifest).ok()?;
+        let process = &image_config["config"];
+        let argv = process["Entrypoint"].as_array().into_iter().chain(process["Cmd"].as_array()).flatten();
+        argv.filter_map(|arg| arg.as_str()).flat_map(|arg| arg.split_whitespace()).find(|word| is_js(word)).map(str::to_string)
+    });
+    let entrypoint = match &declared {
+        Some(declared) => {
+            let relative = match declared.split_once("/.encore/") {
+                Some((_, rest)) => Path::new("artifacts").join(rest),
+                None => PathBuf::from(declared.strip_prefix("/encore/").unwrap_or(declared.trim_start_matches('/'))),
+            };
+            config.relocate(output_dir, &output_dir.join(relative))
+        }
+        None => {
+            let artifacts = config.relocate(output_dir, &output_dir.join("artifacts"));
+            let found = WalkDir::new(&artifacts).into_iter().filter_map(|e| e.ok()).find(|e| {
+                e.file_type().is_file() && e.file_name() == "main.mjs" && e.path().parent().is_some_and(|p| p.ends_with("combined"))
+            });
+            match found {
+                Some(entry) => entry.into_path(),
+                None => {
+                    config.log_fmt(format_args!("No JavaScript entrypoint declared or found under {}", artifacts.display()));
+                    return Ok(());
+                }
+            }
+        }
+    };
+    let shown = declared.unwrap_or_else(|| entrypoint.display().to_string());
+    if !entrypoint.is_file() {
+        return Err(format!("JavaScript entrypoint {} is missing from the output ({})", shown, entrypoint.display()).into());
+    }
+    if let Err(e) = run_command("node", &[OsStr::new("--check"), entrypoint.as_os_str()], None) {
+        if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) {
+            let message = format!("node is not installed; skipped node --check of {}", entrypoint.display());
+            if config.strict {
+                config.warn("node-missing", message);
+            } else {
+                config.log(&message);
+            }
+            return Ok(());
+        }
+        return Err(format!("JavaScript entrypoint {} failed node --check: {}", entrypoint.display(), e).into());
+    }
+    config.status(format_args!("Verified JavaScript entrypoint: {}", entrypoint.display()));
+    Ok(())
+}
+
+/// C library a native binary links against, or the host provides.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Libc {
+    Glibc,
+    Musl,
+}
+
+impl std::fmt::Display for Libc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Libc::Glibc => "glibc",
+            Libc::Musl => "musl",
+        })
+    }
+}
+
+/// Detect the host libc from its dynamic loader; musl installs `/lib/ld-musl-<arch>.so.1`.
+fn host_libc() -> Option<Libc> {
+    let has_loader = |prefix: &str| {
+        ["/lib", "/lib64", "/usr/lib"].iter().any(|dir| {
+            fs::read_dir(dir)
+                .map(|entries| entries.filter_map(|e| e.ok()).any(|e| e.file_name().to_string_lossy().starts_with(prefix)))
+                .unwrap_or(false)
+        })
+    };
+    if has_loader("ld-musl-") {
+        Some(Libc::Musl)
+    } else if has_loader("ld-linux") || Path::new("/lib/x86_64-linux-gnu").is_dir() || Path::new("/lib/aarch64-linux-gnu").is_dir() {
+        Some(Libc::Glibc)
+    } else {
+        None
+    }
+}
+
+/// Classify a binary by its interpreter or needed libc soname.
+fn binary_libc(info: &elf::DynamicInfo) -> Option<Libc> {
+    let names = info.interpreter.iter().chain(&info.needed);
+    for name in names {
+        let file = name.rsplit('/').next().unwrap_or(name);
+        if file.starts_with("ld-musl-") || file.starts_with("libc.musl") {
+            return Some(Libc::Musl);
+        }
+        if file.starts_with("ld-linux") || file == "libc.so.6" {
+            return Some(Libc::Glibc);
+        }
+    }
+    None
+}
+
+/// Warn about `.node` native modules under the output's `runtimes` built for a different libc than the host's.
+fn check_runtime_deps(output_dir: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(host) = host_libc() else {
+        config.warn("native-libc", "Could not detect the host libc; skipping native module check".to_string());
+        return Ok(());
+    };
+    let runtimes = config.relocate(output_dir, &output_dir.join("runtimes"));
+    let mut checked = 0;
+    for entry in WalkDir::new(&runtimes).into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !entry.file_type().is_file() || path.extension().is_none_or(|ext| ext != "node") {
+            continue;
+        }
+        let info = elf::dynamic_info(path).map_err(io_context(format!("Failed to read native module {}", path.display())))?;
+        let Some(info) = info else {
+            config.log_fmt(format_args!("Skipping non-ELF native module {}", path.display()));
+            continue;
+        };
+        checked += 1;
+        match binary_libc(&info) {
+            Some(libc) if libc != host => config.warn(
+                "native-libc",
+                format!("Native module {} requires {} but the host uses {}; it will fail to load", path.display(), libc, host),
+            ),
+            libc => config.log_fmt(format_args!(
+                "Native module {} links {} ({})",
+                path.display(),
+                libc.map_or("no libc".to_string(), |l| l.to_string()),
+                info.needed.join(", ")
+            )),
+        }
+    }
+    config.status(format_args!("Checked {} native module(s) against host {}", checked, host));
+    Ok(())
+}
+
+/// Remove the "sha256:" prefix from a digest if present.
+fn strip_digest_prefix(digest: &str) -> &str {
+    digest.strip_prefix("sha256:").unwrap_or(digest)
+}
+
+/// The layers a `--manifest-filter` expression selects, in manifest order.
+///
+/// The expression runs against `{"layers": [...], "repo_tags": [...], "config": {...}}`, where
+/// each layer has its `index`, `digest`, `size` and the `created_by` step of the image history
+/// that produced it, and `config` is the image config. Every output must be a layer digest, a
+/// layer object or an index. For example:
+///
+/// - `.layers[-1]`: the top layer.
+/// - `[.layers[] | select(.created_by | contains("COPY"))] | last`: the last `COPY` layer.
+/// - `.layers[] | select(.size > 1000000)`: every layer over a megabyte, overlaid in order.
+fn filtered_layers(manifest: &Manifest, filter: &jq::Filter) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let image_config = read_image_config(manifest)?;
+    // History entries marked `empty_layer` (ENV, CMD, ...) don't produce a layer.
+    let steps: Vec<&Value> = image_config["history"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|step| !step["empty_layer"].as_bool().unwrap_or(false))
+        .collect();
+    let steps_match = steps.len() == manifest.layers.len();
+    let layers: Vec<Value> = manifest
+        .layers
+        .iter()
+        .enumerate()
+        .map(|(index, digest)| {
+            let size = manifest.layer_sizes.iter().find(|(d, _)| d == digest).map(|(_, size)| *size);
+            let created_by = steps.get(index).filter(|_| steps_match).map(|step| step["created_by"].clone());
+            serde_json::json!({ "index": index, "digest": digest, "size": size, "created_by": created_by })
+        })
+        .collect();
+    let document = serde_json::json!({ "layers": layers, "repo_tags": manifest.repo_tags, "config": image_config });
+
+    let outputs = filter.run(&document).map_err(|e| format!("--manifest-filter '{}' failed: {}", filter, e))?;
+    let mut selected = BTreeSet::new();
+    for output in outputs {
+        let index = match &output {
+            Value::Null => continue,
+            Value::Number(n) => n.as_u64().and_then(|i| usize::try_from(i).ok()).filter(|&i| i < manifest.layers.len()),
+            Value::String(digest) => manifest.layers.iter().position(|d| d == strip_digest_prefix(digest)),
+            Value::Object(layer) => layer.get("digest").and_then(Value::as_str).and_then(|digest| manifest.layers.iter().position(|d| d == strip_digest_prefix(digest))),
+            _ => None,
+        };
+        let index = index.ok_or_else(|| format!("--manifest-filter '{}' produced {}, which isn't a layer of the image", filter, output))?;
+        selected.insert(index);
+    }
+    if selected.is_empty() {
+        return Err(format!("--manifest-filter '{}' selected no layers", filter).into());
+    }
+    Ok(selected.into_iter().map(|index| manifest.layers[index].clone()).collect())
+}
+
+/// Drops the layers up to and including `since`, which are unchanged from a previous run; none
+/// are left when `since` is the top layer.
+///
+/// The dropped layers aren't extracted, so symlinks into them from the remaining layers dangle,
+/// and copying through one fails as if its target were missing.
+fn layers_after(layers: Vec<String>, since: Option<&str>, config: &Config) -> Vec<String> {
+    let Some(since) = since else {
+        return layers;
+    };
+    match layers.iter().position(|d| d == since) {
+        Some(pos) => {
+            config.log_fmt(format_args!("Skipping {} unchanged layers up to {}", pos + 1, since));
+            layers[pos + 1..].to_vec()
+        }
+        None => {
+            config.warn("layer", format!("--since layer {} is not in the manifest; extracting all layers", since));
+            layers
+        }
+    }
+}
+
+/// Pick the layer digests to extract: the largest layer, or every layer with `--all-layers`.
+fn select_layers(manifest: &Manifest, config: &Config) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if manifest.layers.is_empty() {
+        return Err("No layers found in manifest (pass --allow-empty to accept an image without layers)".into());
+    }
+    if let Some(filter) = &config.manifest_filter {
+        filtered_layers(manifest, filter)
+    } else if config.all_layers {
+        Ok(layers_after(manifest.layers.clone(), config.since.as_deref(), config))
+    } else {
+        Ok(vec![largest_layer(manifest, config)?])
+    }
+}
+
+/// Find the 'encore' directories in an extracted layer, falling back to a recursive search.
+/// With `--all-apps` the whole search depth is always walked, so every app's directory is found.
+fn find_encore_dirs(layer_dir: &Path, config: &Config) -> Vec<PathBuf> {
+    let encore_dir = layer_dir.join("encore");
+    if !config.all_apps && encore_dir.is_dir() {
+        return vec![encore_dir];
+    }
+    if !config.all_apps {
+        config.log("Error: 'encore' directory not found; searching recursively...");
+    }
+    let (found, truncated) = search_encore_dirs(layer_dir, config.search_max_depth, config.all_apps);
+    for dir in &found {
+        config.log_fmt(format_args!("Found encore at: {}", dir.display()));
+    }
+    if found.is_empty() && truncated {
+        config.warn(
+            "missing-encore",
+            format!(
+                "Could not locate any 'encore' directory within {} levels of the extracted layer; deeper directories weren't searched (raise --search-max-depth)",
+                config.search_max_depth
+            ),
+        );
+    } else if found.is_empty() {
+        config.warn("missing-encore", "Could not locate any 'encore' directory in the extracted layer".to_string());
+    }
+    found
+}
+
+/// Name the layout Encore wrote into the image, as `<manifest format>/<encore layout>`, so a
+/// surprising extraction can be traced to a toolchain change. The encore layouts are told apart by:
+///
+/// | Layout       | `encore/`                               | each app's `.encore/` |
+/// |--------------|-----------------------------------------|-----------------------|
+/// | `build-info` | `runtimes/` and `build-info.json`       | `build/`              |
+/// | `meta`       | `runtimes/` and `meta`, no `build-info` | `build/`              |
+/// | `artifacts`  | `runtimes/`                             | `artifacts/0/`        |
+///
+/// Anything else is `unrecognized`, with a warning, as the components may not be where the copy
+/// expects them.
+fn detect_layout_schema(manifest: &Manifest, encore_dir: &Path, config: &Config) -> io::Result<String> {
+    let apps_dir = encore_dir.parent().unwrap_or(encore_dir).join("workspace/apps");
+    let apps = find_app_configs(&apps_dir)?;
+    let every_app = |path: &str| !apps.is_empty() && apps.iter().all(|(_, dir)| dir.join(path).is_dir());
+    let runtimes = encore_dir.join("runtimes").is_dir();
+    let layout = if runtimes && encore_dir.join("build-info.json").is_file() && every_app("build") {
+        "build-info"
+    } else if runtimes && encore_dir.join("meta").is_file() && every_app("build") {
+        "meta"
+    } else if runtimes && every_app("artifacts/0") {
+        "artifacts"
+    } else {
+        config.warn(
+            "layout",
+            format!(
+                "Unrecognized Encore layout in {} (expected runtimes/ with build-info.json or meta, and a .encore/build per app); components may be missed",
+                encore_dir.display()
+            ),
+        );
+        "unrecognized"
+    };
+    let schema = format!("{}/{}", manifest.format.name(), layout);
+    config.log_fmt(format_args!("Layout schema: {}", schema));
+    Ok(schema)
+}
+
+/// Fail when nothing usable was extracted: no `encore` directory was found and no `.encore`
+/// directory exists anywhere in `layer_dir`, so the run would only leave an empty output behind.
+fn ensure_encore_artifacts(layer_dir: &Path, encore_dirs: &[PathBuf]) -> Result<(), EncoreError> {
+    if !encore_dirs.is_empty() {
+        return Ok(());
+    }
+    let mut files = 0;
+    for entry in WalkDir::new(layer_dir).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_dir() && entry.file_name() == ".encore" {
+            return Ok(());
+        }
+        if !entry.file_type().is_dir() {
+            files += 1;
+        }
+    }
+    Err(EncoreError::NoEncoreArtifacts { files })
+}
+
+/// Default `--search-max-depth`, deep enough for `encore` directories nested in an app workspace.
+const DEFAULT_SEARCH_MAX_DEPTH: usize = 8;
+
+/// Breadth-first search for `encore` directories holding at least one Encore component, at most
+/// `max_depth` levels below `layer_dir`. Also returns whether directories past the limit were left unread.
+///
+/// Each depth is read by a bounded pool of threads, and unless `all` is set the search stops at
+/// the first depth with a match, so a shallow hit never pays for walking a deep `node_modules`
+/// tree. Matches are never searched below.
+fn search_encore_dirs(layer_dir: &Path, max_depth: usize, all: bool) -> (Vec<PathBuf>, bool) {
+    let is_encore = |dir: &Path| is_encore_dir(dir, layer_dir);
+    let mut level = vec![layer_dir.to_path_buf()];
+    let mut all_found = Vec::new();
+    for _ in 0..max_depth {
+        if level.is_empty() {
+            break;
+        }
+        let workers = thread::available_parallelism().map_or(1, |n| n.get()).min(level.len());
+        let next_index = AtomicUsize::new(0);
+        let found = Mutex::new(Vec::new());
+        let next_level = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let i = next_index.fetch_add(1, Ordering::Relaxed);
+                    let Some(dir) = level.get(i) else { break };
+                    let Ok(entries) = fs::read_dir(dir) else { continue };
+                    // Symlinked directories aren't followed, matching the previous WalkDir default.
+                    let subdirs: Vec<PathBuf> = entries
+                        .filter_map(|e| e.ok())
+                        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                        .map(|e| e.path())
+                        .collect();
+                    let (matches, rest): (Vec<PathBuf>, Vec<PathBuf>) = subdirs.into_iter().partition(|d| is_encore(d));
+                    found.lock().unwrap().extend(matches);
+                    next_level.lock().unwrap().extend(rest);
+                });
+            }
+        });
+        let mut found = found.into_inner().unwrap();
+        found.sort();
+        all_found.extend(found);
+        if !all && !all_found.is_empty() {
+            return (all_found, false);
+        }
+        level = next_level.into_inner().unwrap();
+    }
+    (all_found, !level.is_empty())
+}
+
+/// Whether `dir`, found by name while searching `layer_dir`, is the `encore` directory of an Encore
+/// build rather than a package that happens to share the name: it must be outside `node_modules`
+/// and hold `runtimes/`, or sit beside an app's `.encore/manifest.json`, directly or under
+/// `workspace/apps`. Directories that fail this are searched below like any other.
+fn is_encore_dir(dir: &Path, layer_dir: &Path) -> bool {
+    if dir.file_name().is_none_or(|name| name != "encore") {
+        return false;
+    }
+    if dir.strip_prefix(layer_dir).unwrap_or(dir).components().any(|c| c.as_os_str() == "node_modules") {
+        return false;
+    }
+    let parent = dir.parent().unwrap_or(dir);
+    dir.join("runtimes").is_dir()
+        || parent.join(".encore/manifest.json").is_file()
+        || find_app_configs(&parent.join("workspace/apps")).is_ok_and(|apps| apps.iter().any(|(_, app)| app.join("manifest.json").is_file()))
+}
+
+/// Output directory names for `--all-apps`, one per `encore` directory. A nested app is named
+/// after the directories leading to it (`services/users/encore` is `services-users`); the one at
+/// the layer root after the single app in its `workspace/apps`, or `app`. Clashing names get a suffix.
+fn app_output_names(layer_dir: &Path, encore_dirs: &[PathBuf]) -> io::Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for encore_dir in encore_dirs {
+        let parent = encore_dir.parent().unwrap_or(encore_dir);
+        let relative = parent.strip_prefix(layer_dir).unwrap_or(parent);
+        let words: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+        let base = if !words.is_empty() {
+            words.join("-")
+        } else {
+            match find_app_configs(&parent.join("workspace/apps"))?.as_slice() {
+                [(name, _)] => name.clone(),
+                _ => "app".to_string(),
+            }
+        };
+        let mut name = base.clone();
+        for suffix in 2.. {
+            if !names.contains(&name) {
+                break;
+            }
+            name = format!("{}-{}", base, suffix);
+        }
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// Stream the tar entries of a layer blob through `visit`, reporting `--progress-every` heartbeats.
+fn for_each_layer_entry(
+    layer_path: &Path,
+    config: &Config,
+    mut visit: impl FnMut(tar::Entry<'_, Box<dyn Read>>) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::open(layer_path).map_err(io_context(format!("Failed to open layer {}", layer_path.display())))?;
+    // A duplicate shares the file offset with the decoder, and with a filter process reading it.
+    let progress = LayerProgress::new(&file, layer_path, config)?;
+    let mut archive = tar::Archive::new(decode_layer(file, layer_path, config)?);
+    let layer_name = layer_path.file_name().map(|n| n.to_string_lossy().chars().take(12).collect::<String>()).unwrap_or_default();
+    for (count, entry) in archive.entries()?.enumerate() {
+        config.beat(format_args!("read {} entries from layer {}...", thousands(count as u64), layer_name));
+        check_timeout()?;
+        visit(entry?)?;
+    }
+    if let Some(progress) = progress {
+        progress.finish(&layer_name);
+    }
+    Ok(())
+}
+
+/// Interval between redraws of the layer progress line.
+const LAYER_PROGRESS_EVERY: Duration = Duration::from_millis(250);
+
+/// Percentage and ETA of the compressed bytes read from a layer blob, redrawn on stderr by a
+/// thread of its own for `Config::layer_progress`, since one large entry can take most of a layer.
+///
+/// Compressed bytes are what the blob's size is known in, so the estimate holds for gzip and
+/// filtered layers alike; the decoders' read-ahead only makes it run slightly early.
+struct LayerProgress {
+    done: std::sync::Arc<AtomicBool>,
+    /// Taken when the thread is stopped, by `finish` or on drop.
+    thread: Option<thread::JoinHandle<bool>>,
+    total: u64,
+    started: Instant,
+}
+
+impl LayerProgress {
+    fn new(file: &File, layer_path: &Path, config: &Config) -> io::Result<Option<Self>> {
+        if !config.layer_progress {
+            return Ok(None);
+        }
+        let total = file.metadata()?.len();
+        let position = file.try_clone()?;
+        let name = layer_path.file_name().map(|n| n.to_string_lossy().chars().take(12).collect::<String>()).unwrap_or_default();
+        let done = std::sync::Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+        let thread = thread::spawn({
+            let done = done.clone();
+            move || {
+                let mut drawn = false;
+                // Layers read in under a redraw interval never show a line.
+                thread::sleep(LAYER_PROGRESS_EVERY);
+                while !done.load(Ordering::Relaxed) {
+                    let Ok(read) = (&position).stream_position() else { break };
+                    let fraction = (read as f64 / total.max(1) as f64).min(1.0);
+                    let elapsed = started.elapsed().as_secs_f64();
+                    let eta = if fraction > 0.0 { format!("{:.0}s", elapsed / fraction - elapsed) } else { "?".to_string() };
+                    eprint!(
+                        "\r\x1b[KReading layer {}: {:>3.0}% ({} of {}), ETA {}",
+                        name,
+                        fraction * 100.0,
+                        format_bytes(read.min(total)),
+                        format_bytes(total),
+                        eta
+                    );
+                    drawn = true;
+                    thread::sleep(LAYER_PROGRESS_EVERY);
+                }
+                if drawn {
+                    eprint!("\r\x1b[K");
+                }
+                drawn
+            }
+        });
+        Ok(Some(Self { done, thread: Some(thread), total, started }))
+    }
+
+    /// Stop redrawing, replacing a progress line that was shown with the layer's total time.
+    fn finish(mut self, layer_name: &str) {
+        if self.stop() {
+            eprintln!("Read layer {} ({}) in {:.1}s", layer_name, format_bytes(self.total), self.started.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Stop the thread and wait for it to clear its line; whether it had drawn one.
+    fn stop(&mut self) -> bool {
+        self.done.store(true, Ordering::Relaxed);
+        self.thread.take().is_some_and(|thread| thread.join().unwrap_or(false))
+    }
+}
+
+impl Drop for LayerProgress {
+    // A layer that fails to read stops its progress line too, before the error is printed.
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Width of the largest layer's bar in `--report-layer-histogram`.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Print every layer's size as a bar chart on stdout, largest first, for `--report-layer-histogram`.
+///
+/// Layers in `selected` (what `--all-layers`, `--since` or the largest-layer heuristic picked)
+/// are marked with `*`. Sizes come from the manifest, or from the blob on disk where it has none.
+fn print_layer_histogram(manifest: &Manifest, selected: &[String], config: &Config) -> io::Result<()> {
+    let mut layers = Vec::new();
+    for digest in &manifest.layers {
+        let size = match manifest.layer_sizes.iter().find(|(d, _)| d == digest) {
+            Some((_, size)) => *size,
+            None => fs::metadata(manifest.blob(digest)).map_err(io_context(format!("Failed to read layer {}", digest)))?.len(),
+        };
+        layers.push((digest, size));
+    }
+    layers.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+    let largest = layers.first().map_or(0, |&(_, size)| size).max(1);
+    let mut out = io::stdout().lock();
+    for (digest, size) in &layers {
+        let bar = (*size as f64 / largest as f64 * HISTOGRAM_WIDTH as f64).round() as usize;
+        let mark = if selected.contains(digest) { '*' } else { ' ' };
+        writeln!(out, "{} {:>10}  {:<width$}  {}", mark, format_bytes(*size), "#".repeat(bar), digest, width = HISTOGRAM_WIDTH)?;
+    }
+    config.status(format_args!("{} layers, {} selected (*)", layers.len(), selected.len()));
+    Ok(())
+}
+
+/// List a layer's entries on stdout without extracting it, for `--print-layer-tree`.
+///
+/// One line per entry: its type (`d`irectory, `l`ink, `h`ardlink, `-` file, `?` other), size
+/// and path as stored in the layer, with whiteouts left as-is.
+fn print_layer_tree(layer_path: &Path, digest: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = io::stdout().lock();
+    let (mut entries, mut bytes) = (0u64, 0u64);
+    for_each_layer_entry(layer_path, config, |entry| {
+        let kind = entry.header().entry_type();
+        let size = entry.header().size()?;
+        let path = entry.path()?;
+        let link = match kind {
+            tar::EntryType::Symlink => format!(" -> {}", entry.link_name()?.unwrap_or_default().display()),
+            tar::EntryType::Link => format!(" => {}", entry.link_name()?.unwrap_or_default().display()),
+            _ => String::new(),
+        };
+        let kind = match kind {
+            tar::EntryType::Directory => 'd',
+            tar::EntryType::Symlink => 'l',
+            tar::EntryType::Link => 'h',
+            tar::EntryType::Regular | tar::EntryType::Continuous => '-',
+            _ => '?',
+        };
+        writeln!(out, "{} {:>12} {}{}", kind, size, path.display(), link)?;
+        entries += 1;
+        bytes += size;
+        Ok(())
+    })?;
+    config.status(format_args!("{} entries, {} bytes in {}", thousands(entries), thousands(bytes), digest));
+    Ok(())
+}
+
+/// Extract a layer blob into `dest`, trying gzip first and falling back to a plain tar.
+///
+/// With a `merge`, the layer is overlaid onto what lower layers already put in `dest`: whiteouts
+/// delete from them and paths they provide are resolved by `--merge-strategy`. Without one, the
+/// layer is extracted on its own for the layer cache, whiteouts kept as plain marker files to be
+/// applied later by `link_tree`.
+///
+/// Entries outside `--extract-path-prefix` are streamed past unwritten, except into the cache,
+/// which always holds whole layers; `link_tree` filters those instead. A hardlink under the prefix
+/// whose target lies outside it is reported as unresolved.
+fn extract_layer(
+    layer_path: &Path,
+    dest: &Path,
+    mut merge: Option<&mut LayerMerge>,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    config.log_fmt(format_args!("Extracting layer from: {}", layer_path.display()));
+
+    // Hardlinks whose target hasn't been extracted yet; retried once the whole layer is out.
+    let mut pending = Vec::new();
+    // Paths this layer wrote, which its own opaque whiteouts must not remove.
+    let mut written = HashSet::new();
+    for_each_layer_entry(layer_path, config, |mut entry| {
+        if entry.header().entry_type() != tar::EntryType::Link {
+            let (relative, stripped) = sanitize_entry_path(&entry.path()?, layer_path, config)?;
+            if relative.as_os_str().is_empty() || (merge.is_some() && !under_extract_prefix(&relative, config)) {
+                return Ok(());
+            }
+            let path = dest.join(&relative);
+            if let Some(merge) = merge.as_deref_mut() {
+                if apply_whiteout(&path, &written, merge, config)? {
+                    return Ok(());
+                }
+                if entry.header().entry_type() != tar::EntryType::Directory && !merge.claim(&path) {
+                    return Ok(());
+                }
+            }
+            if config.symlink_guard && entry.header().entry_type() == tar::EntryType::Symlink {
+                let target = entry.link_name()?.ok_or("Symlink entry without a target")?;
+                check_symlink_target(dest, &relative, &target)?;
+            }
+            check_entry_parents(dest, &relative, &written)?;
+            written.insert(path.clone());
+            if stripped {
+                // unpack_in would refuse the original path, so unpack to the fixed one directly;
+                // `check_entry_parents` stands in for its containment check.
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                entry.unpack(&path)?;
+            } else {
+                entry.unpack_in(dest)?;
+            }
+            if config.copy_attributes && entry.header().entry_type() != tar::EntryType::Symlink {
+                restore_entry_attributes(&mut entry, &path, config)?;
+            }
+            return Ok(());
+        }
+        let (link, _) = sanitize_entry_path(&entry.path()?, layer_path, config)?;
+        let target = entry.link_name()?.ok_or("Hardlink entry without a target")?.into_owned();
+        let (target, _) = sanitize_entry_path(&target, layer_path, config)?;
+        if let Some(merge) = merge.as_deref_mut() {
+            if !under_extract_prefix(&link, config) || !merge.claim(&dest.join(&link)) {
+                return Ok(());
+            }
+        }
+        check_entry_parents(dest, &link, &written)?;
+        written.insert(dest.join(&link));
+        if !link_layer_entry(dest, &link, &target, config)? {
+            pending.push((link, target));
+        }
+        Ok(())
+    })?;
+    for (link, target) in pending {
+        if !link_layer_entry(dest, &link, &target, config)? {
+            config.warn(
+                "hardlink",
+                format!("Unresolved hardlink {} -> {}: target not present in the layer", link.display(), target.display()),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether a layer entry at `relative` falls under `--extract-path-prefix` (always, without one).
+///
+/// Whiteouts are kept when the path they hide overlaps the prefix, so a deletion of the prefix
+/// itself or of one of its parents still applies.
+fn under_extract_prefix(relative: &Path, config: &Config) -> bool {
+    let Some(prefix) = &config.extract_prefix else {
+        return true;
+    };
+    let name = relative.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let hidden = match name.strip_prefix(WHITEOUT_PREFIX) {
+        Some(_) if name == OPAQUE_WHITEOUT => relative.parent().map(Path::to_path_buf),
+        Some(hidden) => relative.parent().map(|dir| dir.join(hidden)),
+        None => None,
+    };
+    match hidden {
+        Some(hidden) => hidden.starts_with(prefix) || prefix.starts_with(&hidden),
+        None => relative.starts_with(prefix),
+    }
+}
+
+/// `--merge-strategy`: how a path provided by several overlaid layers is resolved.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum MergeStrategy {
+    /// The upper layer's entry replaces the lower one, as in the image's own filesystem.
+    Overlay,
+    /// Overlay, but collect every such path and fail once all layers are extracted.
+    ErrorOnConflict,
+    /// Keep the lowest layer's entry and skip later ones.
+    FirstWins,
+}
+
+/// Which layer provided each non-directory path while overlaying layers onto one tree.
+struct LayerMerge {
+    strategy: MergeStrategy,
+    /// Short digest of the layer being extracted.
+    layer: String,
+    owners: HashMap<PathBuf, String>,
+    /// Paths provided by more than one layer, with each providing layer in order.
+    conflicts: BTreeMap<PathBuf, Vec<String>>,
+}
+
+impl LayerMerge {
+    fn new(strategy: MergeStrategy) -> Self {
+        Self { strategy, layer: String::new(), owners: HashMap::new(), conflicts: BTreeMap::new() }
+    }
+
+    fn start_layer(&mut self, digest: &str) {
+        self.layer = strip_digest_prefix(digest).chars().take(12).collect();
+    }
+
+    /// Record that the current layer provides `path`; `false` if it must be skipped (`first-wins`).
+    fn claim(&mut self, path: &Path) -> bool {
+        if self.strategy == MergeStrategy::Overlay {
+            return true;
+        }
+        let Some(owner) = self.owners.get_mut(path) else {
+            self.owners.insert(path.to_path_buf(), self.layer.clone());
+            return true;
+        };
+        if *owner == self.layer {
+            return true;
+        }
+        self.conflicts.entry(path.to_path_buf()).or_insert_with(|| vec![owner.clone()]).push(self.layer.clone());
+        if self.strategy == MergeStrategy::FirstWins {
+            return false;
+        }
+        owner.clone_from(&self.layer);
+        true
+    }
+
+    /// Forget the owners of `path` and everything below it, after a whiteout removed them.
+    fn forget(&mut self, path: &Path) {
+        self.owners.retain(|owned, _| !owned.starts_with(path));
+    }
+
+    /// Forget what lower layers provided below `dir`, after an opaque whiteout cleared it.
+    fn forget_lower(&mut self, dir: &Path) {
+        let layer = &self.layer;
+        self.owners.retain(|owned, owner| !owned.starts_with(dir) || owner == layer);
+    }
+
+    /// Report the paths several layers provided; an error under `error-on-conflict`.
+    fn finish(&self, root: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+        if self.conflicts.is_empty() {
+            return Ok(());
+        }
+        let lines: Vec<String> = self
+            .conflicts
+            .iter()
+            .map(|(path, layers)| format!("  {} ({})", path.strip_prefix(root).unwrap_or(path).display(), layers.join(", ")))
+            .collect();
+        match self.strategy {
+            MergeStrategy::ErrorOnConflict => {
+                Err(format!("{} paths are provided by more than one layer:\n{}", lines.len(), lines.join("\n")).into())
+            }
+            _ => {
+                config.status(format_args!("Kept the lowest layer's copy of {} paths (--merge-strategy first-wins)", lines.len()));
+                config.log_fmt(format_args!("Skipped in upper layers:\n{}", lines.join("\n")));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Prefix of a whiteout entry, which deletes `<name>` from the layers below.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Whiteout that makes its directory opaque, hiding everything lower layers put in it.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Apply the whiteout at `marker` (its would-be path in the tree) if it is one, sparing `written`.
+///
+/// Whiteouts are deliberate deletions, so they apply under every `--merge-strategy`, and the
+/// removed paths no longer count as provided by their lower layer.
+fn apply_whiteout(marker: &Path, written: &HashSet<PathBuf>, merge: &mut LayerMerge, config: &Config) -> io::Result<bool> {
+    let (Some(name), Some(dir)) = (marker.file_name().and_then(|n| n.to_str()), marker.parent()) else {
+        return Ok(false);
+    };
+    let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) else {
+        return Ok(false);
+    };
+    if name == OPAQUE_WHITEOUT {
+        config.log_fmt(format_args!("Opaque whiteout: clearing lower-layer contents of {}", dir.display()));
+        remove_lower_entries(dir, written)?;
+        merge.forget_lower(dir);
+    } else {
+        config.log_fmt(format_args!("Whiteout: removing {}", dir.join(hidden).display()));
+        remove_path(&dir.join(hidden))?;
+        merge.forget(&dir.join(hidden));
+    }
+    Ok(true)
+}
+
+/// Remove everything under `dir` except the paths in `written` and the directories leading to them.
+fn remove_lower_entries(dir: &Path, written: &HashSet<PathBuf>) -> io::Result<()> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if !written.iter().any(|w| w.starts_with(&path)) {
+            remove_path(&path)?;
+        } else if path.symlink_metadata()?.is_dir() {
+            remove_lower_entries(&path, written)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove a file, symlink or directory tree; a missing path is not an error.
+fn remove_path(path: &Path) -> io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Default `--tar-blocking-factor`: 512 records of 512 bytes, a 256 KiB read buffer.
+const DEFAULT_TAR_BLOCKING_FACTOR: usize = 512;
+
+/// `--layer-compression`: the decoder to use for every layer, for registries whose layers
+/// detection gets wrong.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum LayerCompression {
+    Gzip,
+    /// Decoded by the external `zstd` program, as the build has no zstd decoder.
+    Zstd,
+    None,
+}
+
+impl LayerCompression {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            "none" => Ok(Self::None),
+            _ => Err(format!("Unknown --layer-compression '{}' (expected gzip, zstd or none)", name)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::None => "none",
+        }
+    }
+}
+
+/// Open a layer blob as a tar stream, transparently decompressing gzip layers.
+///
+/// Both the compressed file and the decompressed stream are read through `--tar-blocking-factor`
+/// buffers, so the tar crate's 512-byte record reads don't each reach the file or the inflater.
+/// A blob that is neither gzip nor a ustar archive goes through `--tar-filter` when one is set.
+///
+/// Nothing here or in `extract_layer` holds an entry in memory: entries are unpacked straight from
+/// the stream, so extracting a layer of any size needs only these buffers and the inflater's
+/// 32 KiB window.
+///
+/// `--layer-compression` replaces the detection: the blob is decoded as told, whatever it holds.
+fn open_layer(layer_path: &Path, config: &Config) -> io::Result<Box<dyn Read>> {
+    let file = File::open(layer_path).map_err(io_context(format!("Failed to open layer {}", layer_path.display())))?;
+    decode_layer(file, layer_path, config)
+}
+
+/// `--dump-layer`: copy the selected layers out as tars to inspect with `tar tf`, before they
+/// are extracted. One layer is written to `dest`; several go into `dest` as `<digest>.tar`
+/// (`<digest>` with `raw`).
+///
+/// Layers are decoded as extraction decodes them, so a gzip or zstd blob is written as the plain
+/// tar inside, unless `raw` (`--dump-layer-raw`) asks for the blob as stored.
+fn dump_layers(layer_digests: &[String], layer_paths: &[PathBuf], dest: &Path, raw: bool, config: &Config) -> io::Result<()> {
+    let targets: Vec<PathBuf> = match layer_digests {
+        [_] => vec![dest.to_path_buf()],
+        _ => {
+            fs::create_dir_all(dest).map_err(io_context(format!("Failed to create directory {}", dest.display())))?;
+            layer_digests.iter().map(|digest| dest.join(format!("{}{}", digest.trim_start_matches("sha256:"), if raw { "" } else { ".tar" }))).collect()
+        }
+    };
+    for ((digest, layer_path), target) in layer_digests.iter().zip(layer_paths).zip(&targets) {
+        let mut output = File::create(target).map_err(io_context(format!("Failed to create {}", target.display())))?;
+        let bytes = if raw {
+            io::copy(&mut File::open(layer_path).map_err(io_context(format!("Failed to open layer {}", layer_path.display())))?, &mut output)
+        } else {
+            io::copy(&mut open_layer(layer_path, config)?, &mut output)
+        }
+        .map_err(io_context(format!("Failed to dump layer {} to {}", digest, target.display())))?;
+        config.status(format_args!(
+            "Dumped layer {} to {} ({}{})",
+            digest,
+            target.display(),
+            format_bytes(bytes),
+            if raw { ", as stored" } else { "" }
+        ));
+    }
+    Ok(())
+}
+
+/// `open_layer` on an already open blob, read from its current offset.
+fn decode_layer(mut file: File, layer_path: &Path, config: &Config) -> io::Result<Box<dyn Read>> {
+    let buffer_size = config.tar_buffer_size;
+    if let Some(compression) = config.layer_compression {
+        config.status(format_args!(
+            "Decoding layer {} as {} (--layer-compression overrides detection)",
+            layer_path.display(),
+            compression.name()
+        ));
+        let file = BufReader::with_capacity(buffer_size, file);
+        return match compression {
+            LayerCompression::Gzip => Ok(Box::new(BufReader::with_capacity(buffer_size, GzDecoder::new(file)))),
+            LayerCompression::Zstd => {
+                let command = ["zstd".to_string(), "-dc".to_string()];
+                filter_layer(file.into_inner(), &command, "--layer-compression", buffer_size)
+            }
+            LayerCompression::None => Ok(Box::new(file)),
+        };
+    }
+    let mut header = [0u8; 512];
+    let mut len = 0;
+    while len < header.len() {
+        match file.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let gzip = len >= 2 && header[..2] == [0x1f, 0x8b];
+    let plain_tar = len == header.len() && &header[257..262] == b"ustar";
+    if let Some(filter) = config.tar_filter.as_ref().filter(|_| !gzip && !plain_tar) {
+        config.status(format_args!("Using custom --tar-filter '{}' for layer {}", filter.join(" "), layer_path.display()));
+        return filter_layer(file, filter, "--tar-filter", buffer_size);
+    }
+    let file = BufReader::with_capacity(buffer_size, file);
+    Ok(if gzip { Box::new(BufReader::with_capacity(buffer_size, GzDecoder::new(file))) } else { Box::new(file) })
+}
+
+/// Decode a layer through an external program reading it on stdin; `option` names the flag
+/// that asked for it in errors.
+fn filter_layer(file: File, command: &[String], option: &str, buffer_size: usize) -> io::Result<Box<dyn Read>> {
+    let mut child = spawn_tracked(Command::new(&command[0]).args(&command[1..]).stdin(file).stdout(std::process::Stdio::piped()))
+        .map_err(io_context(format!("Failed to run {} {}", option, command[0])))?;
+    let stdout = child.stdout.take().expect("piped stdout");
+    let program = format!("{} {}", option, command[0]);
+    Ok(Box::new(BufReader::with_capacity(buffer_size, FilterReader { child, stdout, program })))
+}
+
+/// Output of a `--tar-filter` or zstd process; its exit status is checked at end of stream.
+struct FilterReader {
+    child: std::process::Child,
+    stdout: std::process::ChildStdout,
+    /// The flag and program, for errors.
+    program: String,
+}
+
+impl Read for FilterReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("{} failed with {}", self.program, status)));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for FilterReader {
+    // The tar reader stops at the end-of-archive marker, possibly before the filter is done.
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        untrack(self.child.id());
+    }
+}
+
+/// Most symlinks followed while resolving one symlink target, as for the kernel's ELOOP limit.
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+/// Fail if a symlink at `link` (relative to the extraction root `root`) points outside that root.
+///
+/// The target is resolved against what is already on disk under `root`, one component at a time,
+/// so symlinks along the way (in `link`'s own parent too) are followed and a chain of them can't
+/// climb out where each one alone wouldn't. No component may leave the root, and absolute targets
+/// always escape, since on the host they name host paths rather than the image's.
+fn check_symlink_target(root: &Path, link: &Path, target: &Path) -> Result<(), String> {
+    let escapes = || format!("Symlink {} -> {} escapes the extraction root", link.display(), target.display());
+    let mut hops = 0;
+    let parent = resolve_in_root(root, Vec::new(), link.parent().unwrap_or(Path::new("")), &mut hops).ok_or_else(escapes)?;
+    resolve_in_root(root, parent, target, &mut hops).ok_or_else(escapes)?;
+    Ok(())
+}
+
+/// Resolve `path` from the directory `resolved` (components below `root`), following symlinks
+/// found on disk; `None` if it leaves `root`, is absolute, or follows too many symlinks.
+fn resolve_in_root(root: &Path, mut resolved: Vec<std::ffi::OsString>, path: &Path, hops: &mut u32) -> Option<Vec<std::ffi::OsString>> {
+    // Components still to resolve, last first; `None` stands for `..`.
+    let mut pending = Vec::new();
+    let push = |pending: &mut Vec<Option<std::ffi::OsString>>, path: &Path| -> Option<()> {
+        for component in path.components().rev() {
+            match component {
+                std::path::Component::Normal(part) => pending.push(Some(part.to_os_string())),
+                std::path::Component::ParentDir => pending.push(None),
+                std::path::Component::CurDir => {}
+                std::path::Component::RootDir | std::path::Component::Prefix(_) => return None,
+            }
+        }
+        Some(())
+    };
+    push(&mut pending, path)?;
+    while let Some(part) = pending.pop() {
+        let Some(part) = part else {
+            resolved.pop()?;
+            continue;
+        };
+        let candidate = resolved.iter().fold(root.to_path_buf(), |dir, name| dir.join(name)).join(&part);
+        match fs::read_link(&candidate) {
+            Ok(next) => {
+                *hops += 1;
+                if *hops > MAX_SYMLINK_HOPS {
+                    return None;
+                }
+                push(&mut pending, &next)?;
+            }
+            Err(_) => resolved.push(part),
+        }
+    }
+    Some(resolved)
+}
+
+/// Check a layer entry path for `..` and absolute components, which could escape the extraction root.
+///
+/// Such paths fail the extraction unless `--sanitize-paths=strip` is set, in which case the unsafe
+/// components are dropped; returns the relative path and whether it had to be fixed.
+fn sanitize_entry_path(path: &Path, layer_path: &Path, config: &Config) -> Result<(PathBuf, bool), String> {
+    let mut relative = PathBuf::new();
+    let mut stripped = false;
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(part) => relative.push(part),
+            std::path::Component::CurDir => {}
+            _ => stripped = true,
+        }
+    }
+    if stripped {
+        if !config.strip_unsafe_paths {
+            return Err(format!(
+                "Unsafe entry path {} in layer {} (use --sanitize-paths=strip to fix such entries)",
+                path.display(),
+                layer_path.display()
+            ));
+        }
+        config.warn("unsafe-path", format!("Stripped unsafe entry path {} to {}", path.display(), relative.display()));
+    }
+    Ok((relative, stripped))
+}
+
+/// Fail unless each existing directory on the way to `relative` resolves inside `dest`, so
+/// creating and writing the entry can't follow a symlink out of the tree. Symlinks the layer being
+/// extracted wrote itself (those in `written`) aren't followed at all.
+fn check_entry_parents(dest: &Path, relative: &Path, written: &HashSet<PathBuf>) -> io::Result<()> {
+    let Some(parent) = relative.parent() else {
+        return Ok(());
+    };
+    let root = dest.canonicalize().map_err(io_context(format!("Failed to resolve {}", dest.display())))?;
+    let escapes = |through: &Path| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Layer entry {} would be written through {}, which leaves the extraction root", relative.display(), through.display()),
+        )
+    };
+    let mut path = dest.to_path_buf();
+    for component in parent.components() {
+        path.push(component);
+        let Ok(metadata) = path.symlink_metadata() else {
+            // The rest is created below a directory already known to be inside.
+            break;
+        };
+        if metadata.is_symlink() && written.contains(&path) {
+            return Err(escapes(&path));
+        }
+        match path.canonicalize() {
+            Ok(resolved) if resolved.starts_with(&root) => {}
+            _ => return Err(escapes(&path)),
+        }
+    }
+    Ok(())
+}
+
+/// Normalizes a path from a layer tar to a relative path, rejecting any that escape the root.
+fn layer_relative(path: &Path) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(part) => relative.push(part),
+            std::path::Component::CurDir | std::path::Component::RootDir => {}
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+/// Materializes a layer hardlink at `link` to `target`, both relative to `dest`, returning
+/// `false` if the target doesn't exist (yet).
+///
+/// Links are recreated unless `--dereference-hardlinks` is set, with a copy as the fallback
+/// when the filesystem refuses the link. Either way the target is read from where it resolves,
+/// which must be inside `dest`: a symlinked parent could otherwise hand out a host file.
+fn link_layer_entry(dest: &Path, link: &Path, target: &Path, config: &Config) -> io::Result<bool> {
+    let (link, unresolved) = (dest.join(link), dest.join(target));
+    if !unresolved.is_file() {
+        return Ok(false);
+    }
+    let root = dest.canonicalize().map_err(io_context(format!("Failed to resolve {}", dest.display())))?;
+    let target = unresolved.canonicalize().map_err(io_context(format!("Failed to resolve {}", unresolved.display())))?;
+    if !target.starts_with(&root) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Hardlink {} -> {} resolves outside the extraction root", link.display(), unresolved.display()),
+        ));
+    }
+    let (link, target) = (link.as_path(), target.as_path());
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if link.symlink_metadata().is_ok() {
+        fs::remove_file(link)?;
+    }
+    if !config.dereference_hardlinks && fs::hard_link(target, link).is_ok() {
+        return Ok(true);
+    }
+    fs::copy(target, link).map_err(io_context(format!("Failed to copy hardlink target {} to {}", target.display(), link.display())))?;
+    Ok(true)
+}
+
+/// Extract a layer through the `--cache-dir` layer cache, where extracted trees are keyed by digest.
+fn extract_layer_cached(
+    layer_path: &Path,
+    digest: &str,
+    cache_dir: &Path,
+    dest: &Path,
+    merge: &mut LayerMerge,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let entry = cache_dir.join(strip_digest_prefix(digest));
+    if entry.is_dir() {
+        config.log_fmt(format_args!("Using cached layer {}", entry.display()));
+    } else {
+        fs::create_dir_all(cache_dir).map_err(io_context(format!("Failed to create cache directory {}", cache_dir.display())))?;
+        // Extract next to the entry and rename it into place, so an interrupted run can't leave a partial tree.
+        let staging = Builder::new().prefix(".partial-").tempdir_in(cache_dir)?;
+        extract_layer(layer_path, staging.path(), None, config)?;
+        if let Err(e) = fs::rename(staging.path(), &entry) {
+            // Another run may have cached the same layer in the meantime.
+            if !entry.is_dir() {
+                return Err(io_context(format!("Failed to store layer in cache {}", entry.display()))(e).into());
+            }
+        }
+    }
+    // The entry's mtime records its last use for `--cache-max-size` eviction.
+    filetime::set_file_mtime(&entry, FileTime::now())?;
+    link_tree(&entry, dest, merge, config).map_err(io_context(format!("Failed to populate {} from cache", dest.display())))?;
+    Ok(())
+}
+
+/// Recreate the tree at `src` under `dst`, hardlinking files (or copying them across filesystems).
+///
+/// Whiteout markers kept in the cached tree are applied to `dst` instead of being linked.
+fn link_tree(src: &Path, dst: &Path, merge: &mut LayerMerge, config: &Config) -> io::Result<()> {
+    let mut written = HashSet::new();
+    // Outside `--extract-path-prefix`, only the directories leading to it are walked.
+    let entries = WalkDir::new(src).min_depth(1).into_iter().filter_entry(|e| {
+        let relative = e.path().strip_prefix(src).unwrap_or(e.path());
+        under_extract_prefix(relative, config) || (e.file_type().is_dir() && config.extract_prefix.as_ref().is_some_and(|p| p.starts_with(relative)))
+    });
+    for entry in entries {
+        let entry = entry.map_err(io::Error::from)?;
+        let target = dst.join(relative_path(entry.path(), src)?);
+        if entry.file_type().is_file() && apply_whiteout(&target, &written, merge, config)? {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            written.insert(target.clone());
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        if !merge.claim(&target) {
+            continue;
+        }
+        written.insert(target.clone());
+        if target.symlink_metadata().is_ok() {
+            fs::remove_file(&target)?;
+        }
+        if entry.file_type().is_symlink() {
+            let link_target = fs::read_link(entry.path())?;
+            if config.symlink_guard {
+                let relative = relative_path(entry.path(), src)?;
+                check_symlink_target(dst, relative, &link_target).map_err(io::Error::other)?;
+            }
+            std::os::unix::fs::symlink(link_target, &target)?;
+        } else if fs::hard_link(entry.path(), &target).is_err() {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Evict least recently used layers from the cache until it holds at most `max_bytes`.
+fn prune_layer_cache(cache_dir: &Path, max_bytes: u64, config: &Config) -> io::Result<()> {
+    if !cache_dir.is_dir() {
+        return Ok(());
+    }
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || entry.file_name().to_string_lossy().starts_with(".partial-") {
+            continue;
+        }
+        let size: u64 = WalkDir::new(entry.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .sum();
+        entries.push((entry.metadata()?.modified()?, size, entry.path()));
+    }
+    // Newest first: keep entries until one doesn't fit, then evict it and everything older.
+    entries.sort_by_key(|e| std::cmp::Reverse(e.0));
+    let (mut kept, mut evicted, mut freed) = (0, 0, 0);
+    for (_, size, path) in entries {
+        if evicted == 0 && kept + size <= max_bytes {
+            kept += size;
+            continue;
+        }
+        config.log_fmt(format_args!("Evicting cached layer {} ({})", path.display(), format_bytes(size)));
+        fs::remove_dir_all(&path).map_err(io_context(format!("Failed to evict cached layer {}", path.display())))?;
+        evicted += 1;
+        freed += size;
+    }
+    if evicted > 0 {
+        config.status(format_args!("Pruned {} cached layers ({} freed, {} kept)", evicted, format_bytes(freed), format_bytes(kept)));
+    }
+    Ok(())
+}
+
+/// Counters accumulated while copying components into the output directory.
+#[derive(Default)]
+struct CopyStats {
+    components: usize,
+    files: u64,
+    bytes: u64,
+    /// Files replaced by a hardlink to an identical earlier copy (`--dedupe`).
+    deduped: u64,
+    bytes_saved: u64,
+    /// Destination paths of every file written.
+    written: Vec<PathBuf>,
+    /// Component name of each entry in `written`, once `copy_encore_components` has tagged them.
+    origins: Vec<&'static str>,
+    /// Paths skipped because they couldn't be read or copied (`FailurePolicy::Collect`).
+    errors: Vec<String>,
+    /// Files skipped as unmodified since `--since-timestamp`.
+    unchanged: u64,
+    /// Output files left in place by `--incremental` as identical to their source.
+    up_to_date: u64,
+    /// Files skipped for exceeding `--copy-exclude-larger-than`, and their total size.
+    oversized: u64,
+    oversized_bytes: u64,
+    /// File and byte counts per component name, summed over apps and encore directories.
+    by_component: BTreeMap<&'static str, ComponentStats>,
+}
+
+/// Per-component totals reported by `--stats-json`.
+#[derive(Clone, Copy, Default, Serialize)]
+struct ComponentStats {
+    files: u64,
+    bytes: u64,
+}
+
+impl CopyStats {
+    fn merge(&mut self, other: CopyStats) {
+        self.components += other.components;
+        self.files += other.files;
+        self.bytes += other.bytes;
+        self.deduped += other.deduped;
+        self.bytes_saved += other.bytes_saved;
+        self.written.extend(other.written);
+        self.origins.extend(other.origins);
+        self.errors.extend(other.errors);
+        self.unchanged += other.unchanged;
+        self.up_to_date += other.up_to_date;
+        self.oversized += other.oversized;
+        self.oversized_bytes += other.oversized_bytes;
+        for (name, component) in other.by_component {
+            let entry = self.by_component.entry(name).or_default();
+            entry.files += component.files;
+            entry.bytes += component.bytes;
+        }
+    }
+}
+
+/// Wraps an I/O error with the operation and path(s) that triggered it, keeping its kind.
+fn io_context(context: String) -> impl FnOnce(io::Error) -> io::Error {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", context, e))
+}
+
+/// `path` relative to `base`, failing with an error that names both when it isn't under it.
+fn relative_path<'a>(path: &'a Path, base: &Path) -> io::Result<&'a Path> {
+    path.strip_prefix(base)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not under {}", path.display(), base.display())))
+}
+
+/// A `NotFound` error carrying `EncoreError::SourceNotFound` for a missing copy source.
+fn source_not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, EncoreError::SourceNotFound { path: path.to_path_buf(), component: None })
+}
+
+/// Attribute a copy failure to `component`: a missing source names it, other errors are prefixed with it.
+fn component_error(e: io::Error, component: &'static str) -> io::Error {
+    match e.get_ref().and_then(|inner| inner.downcast_ref::<EncoreError>()) {
+        Some(EncoreError::SourceNotFound { path, .. }) => {
+            io::Error::new(e.kind(), EncoreError::SourceNotFound { path: path.clone(), component: Some(component) })
+        }
+        _ => io_context(format!("Failed to copy {}", component))(e),
+    }
+}
+
+/// Attempts per file before a transient error is reported; the delay doubles after each failure.
+const TRANSIENT_RETRIES: u32 = 4;
+const TRANSIENT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Errors a network or FUSE filesystem can return mid-copy that are worth retrying.
+///
+/// Anything else (EACCES, ENOSPC, ...) is permanent and fails on the first attempt.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ESTALE | libc::EINTR | libc::EAGAIN))
+        || matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+}
+
+/// Runs a per-file operation, retrying it with backoff while it fails with a transient error.
+fn retry_transient<T>(what: &Path, config: &Config, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = TRANSIENT_BACKOFF;
+    for attempt in 1.. {
+        match op() {
+            Err(e) if attempt < TRANSIENT_RETRIES && is_transient(&e) => {
+                config.log_fmt(format_args!(
+                    "Transient error on {} (attempt {}/{}), retrying in {:?}: {}",
+                    what.display(),
+                    attempt,
+                    TRANSIENT_RETRIES,
+                    delay,
+                    e
+                ));
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    unreachable!("the retry loop only exits by returning")
+}
+
+/// Applies the access and modification times of `src` to `dst`.
+fn copy_times(src: &Path, dst: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(src)?;
+    let atime = FileTime::from_last_access_time(&metadata);
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    filetime::set_file_times(dst, atime, mtime)
+        .map_err(io_context(format!("Failed to set timestamps on {}", dst.display())))
+}
+
+/// Copies the extended attributes of `src`, POSIX ACLs included, to `dst` for `--copy-attributes`.
+///
+/// An output filesystem without attribute support is warned about once per run; an attribute
+/// that can't be set, such as `security.capability` without CAP_SETFCAP, is warned about per file.
+/// A later `--owner`/`--group` change clears `security.capability` again, as chown always does.
+fn copy_attributes(src: &Path, dst: &Path, config: &Config) -> io::Result<()> {
+    let names = match xattr::list(src) {
+        Ok(names) => names,
+        // Nothing to carry over from a source filesystem without attributes.
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(io_context(format!("Failed to list extended attributes of {}", src.display()))(e)),
+    };
+    for name in names {
+        let Some(value) = xattr::get(src, &name).map_err(io_context(format!("Failed to read extended attributes of {}", src.display())))? else {
+            continue;
+        };
+        if !set_attribute(dst, &name, &value, config) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Sets one extended attribute, warning instead of failing; `false` if the filesystem has no
+/// attribute support, so the remaining attributes needn't be tried.
+fn set_attribute(path: &Path, name: &std::ffi::OsStr, value: &[u8], config: &Config) -> bool {
+    match xattr::set(path, name, value) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            if !config.attributes_unsupported.swap(true, Ordering::Relaxed) {
+                config.warn("xattr", format!("{} doesn't support extended attributes; --copy-attributes has no effect ({})", path.display(), e));
+            }
+            false
+        }
+        Err(e) => {
+            config.warn("xattr", format!("Failed to set attribute {} on {}: {}", name.to_string_lossy(), path.display(), e));
+            true
+        }
+    }
+}
+
+/// Applies the `SCHILY.xattr.*` PAX records of a layer entry to its extracted `path`, so that
+/// `--copy-attributes` has the image's attributes to carry over. The tar crate's own support
+/// aborts the layer on the first attribute the filesystem refuses.
+fn restore_entry_attributes<R: Read>(entry: &mut tar::Entry<'_, R>, path: &Path, config: &Config) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(());
+    };
+    for extension in extensions {
+        let extension = extension?;
+        if let Some(name) = extension.key_bytes().strip_prefix(b"SCHILY.xattr.") {
+            if !set_attribute(path, std::ffi::OsStr::from_bytes(name), extension.value_bytes(), config) {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What a file that can't be read or copied does to the rest of the copy.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FailurePolicy {
+    /// Stop at the first failure and return its error; the default, so a disk error can't
+    /// quietly leave an incomplete output behind.
+    FailFast,
+    /// Skip the file with a `copy-error` warning and record it in `CopyStats::errors`
+    /// (`--keep-going`).
+    Collect,
+}
+
+/// `--on-conflict`: what happens to an existing output file that differs from the incoming one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ConflictPolicy {
+    Overwrite,
+    /// Leave the existing file in place.
+    Skip,
+    /// Keep the existing file and write the incoming one beside it with a `.new` suffix.
+    Rename,
+    /// Fail the copy with `EncoreError::DestinationExists`.
+    Error,
+}
+
+/// Where `src` should be written given what already occupies `dst`, or `None` to leave `dst` alone.
+///
+/// Without `--on-conflict` an existing file is overwritten, except in `--merge` mode, which never
+/// touches files a previous extraction didn't write. Files it did write are always updated, and
+/// an identical file is never a conflict, not even for `--on-conflict error`.
+fn conflict_target(src: &Path, dst: &Path, config: &Config) -> io::Result<Option<PathBuf>> {
+    if dst.symlink_metadata().is_err() || config.managed.as_ref().is_some_and(|managed| managed.contains(dst)) {
+        return Ok(Some(dst.to_path_buf()));
+    }
+    let policy = match config.on_conflict {
+        Some(policy) => policy,
+        None if config.managed.is_some() => {
+            config.trace("keep", format_args!("{} (not written by a previous extraction)", dst.display()));
+            config.warn(
+                "merge-conflict",
+                format!("Not overwriting {}: it was not written by a previous extraction", dst.display()),
+            );
+            return Ok(None);
+        }
+        None => ConflictPolicy::Overwrite,
+    };
+    if policy == ConflictPolicy::Overwrite || same_contents(src, dst)? {
+        return Ok(Some(dst.to_path_buf()));
+    }
+    match policy {
+        ConflictPolicy::Skip => {
+            config.trace("keep", format_args!("{} (differs from {})", dst.display(), src.display()));
+            config.warn("conflict", format!("Not overwriting {}: it differs from the extracted file", dst.display()));
+            Ok(None)
+        }
+        ConflictPolicy::Error => {
+            Err(io::Error::new(io::ErrorKind::AlreadyExists, EncoreError::DestinationExists { path: dst.to_path_buf() }))
+        }
+        _ => {
+            let mut name = dst.file_name().unwrap_or_default().to_os_string();
+            name.push(".new");
+            let renamed = dst.with_file_name(name);
+            config.warn("conflict", format!("{} differs from the extracted file; writing {}", dst.display(), renamed.display()));
+            Ok(Some(renamed))
+        }
+    }
+}
+
+/// Whether two files have identical contents.
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let Ok(b_meta) = fs::metadata(b) else {
+        return Ok(false);
+    };
+    if !b_meta.is_file() || fs::metadata(a)?.len() != b_meta.len() {
+        return Ok(false);
+    }
+    let (mut a, mut b) = (BufReader::new(File::open(a)?), BufReader::new(File::open(b)?));
+    let (mut a_buf, mut b_buf) = ([0u8; 8192], [0u8; 8192]);
+    loop {
+        let n = a.read(&mut a_buf)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut b_buf[..n])?;
+        if a_buf[..n] != b_buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Whether `dst` already holds `src` for `--incremental`: a regular file of the same size and
+/// permissions whose mtime matches, or failing that whose contents do.
+///
+/// A symlink at `dst` is never up to date, even if the file it points to is.
+fn up_to_date(src: &Path, dst: &Path) -> io::Result<bool> {
+    let Ok(dst_meta) = dst.symlink_metadata() else {
+        return Ok(false);
+    };
+    let src_meta = fs::metadata(src)?;
+    if !dst_meta.is_file()
+        || dst_meta.len() != src_meta.len()
+        || dst_meta.permissions().mode() & 0o7777 != src_meta.permissions().mode() & 0o7777
+    {
+        return Ok(false);
+    }
+    if dst_meta.modified()? == src_meta.modified()? {
+        return Ok(true);
+    }
+    same_contents(src, dst)
+}
+
+/// Copies a single file, hardlinking it to an identical file copied earlier when `--dedupe` is on.
+fn copy_file(src: &Path, dst: &Path, config: &Config, stats: &mut CopyStats) -> io::Result<()> {
+    config.claim_destination(src, dst)?;
+    let bytes_before = stats.bytes;
+    if config.no_write {
+        stats.bytes += fs::metadata(src).map_err(io_context(format!("Failed to read {}", src.display())))?.len();
+        stats.files += 1;
+        stats.written.push(dst.to_path_buf());
+        config.file_copied(stats.bytes - bytes_before);
+        config.trace("count", format_args!("{} -> {} (not written)", src.display(), dst.display()));
+        return Ok(());
+    }
+    if let Some(cas) = &config.cas {
+        let (object, fresh) = cas.store(src, dst)?;
+        let size = fs::metadata(&object)?.len();
+        stats.files += 1;
+        stats.bytes += size;
+        if !fresh {
+            stats.deduped += 1;
+            stats.bytes_saved += size;
+        }
+        stats.written.push(object.clone());
+        config.file_copied(stats.bytes - bytes_before);
+        config.trace("store", format_args!("{} -> {} ({})", src.display(), object.display(), dst.display()));
+        return Ok(());
+    }
+    let Some(dst) = conflict_target(src, dst, config)? else {
+        return Ok(());
+    };
+    let dst = dst.as_path();
+    if config.incremental {
+        if up_to_date(src, dst).map_err(io_context(format!("Failed to compare {} with {}", src.display(), dst.display())))? {
+            stats.up_to_date += 1;
+            stats.written.push(dst.to_path_buf());
+            config.file_copied(0);
+            config.trace("keep", format_args!("{} (up to date with {})", dst.display(), src.display()));
+            return Ok(());
+        }
+        // A read-only file can't be reopened for writing and a symlink would be written through,
+        // so either is replaced rather than copied over.
+        if dst.symlink_metadata().is_ok_and(|meta| meta.is_symlink() || meta.permissions().readonly()) {
+            fs::remove_file(dst).map_err(io_context(format!("Failed to remove {}", dst.display())))?;
+        }
+    }
+    // Recorded in the dedupe index only once the copy is complete, so a concurrent copy of the
+    // same content never links to a file still being written.
+    let mut unseen_digest = None;
+    if let Some(index) = &config.dedupe {
+        let digest = sha256::hash_file(src).map_err(io_context(format!("Failed to hash {}", src.display())))?;
+        let existing = index.lock().unwrap().get(&digest).cloned();
+        match existing {
+            Some(stored) => {
+                if dst.exists() {
+                    fs::remove_file(dst).map_err(io_context(format!("Failed to remove {}", dst.display())))?;
+                }
+                match fs::hard_link(&stored, dst) {
+                    Ok(()) => {
+                        let size = fs::metadata(dst)?.len();
+                        stats.files += 1;
+                        stats.bytes += size;
+                        stats.deduped += 1;
+                        stats.bytes_saved += size;
+                        stats.written.push(dst.to_path_buf());
+                        config.file_copied(stats.bytes - bytes_before);
+                        config.trace("link", format_args!("{} -> {} (duplicate content)", dst.display(), stored.display()));
+                        return Ok(());
+                    }
+                    Err(e) => config.warn(
+                        "dedupe",
+                        format!("Failed to hardlink {} to {}: {}", dst.display(), stored.display(), e),
+                    ),
+                }
+            }
+            None => unseen_digest = Some(digest),
+        }
+    }
+    let normalized = match &config.normalize_extensions {
+        Some(extensions) if has_extension(src, extensions) => retry_transient(dst, config, || copy_normalized(src, dst, config.copy_buffer_size()))
+            .map_err(io_context(format!("Failed to copy {} to {}", src.display(), dst.display())))?,
+        _ => None,
+    };
+    match normalized {
+        Some(bytes) => {
+            stats.bytes += bytes;
+            config.trace("normalize", format_args!("{} -> {} (CRLF to LF)", src.display(), dst.display()));
+        }
+        None => {
+            let sparse = if config.copy_sparse {
+                retry_transient(dst, config, || copy_sparse(src, dst, config.copy_buffer_size()))
+                    .map_err(io_context(format!("Failed to copy {} to {}", src.display(), dst.display())))?
+            } else {
+                None
+            };
+            match sparse {
+                Some(bytes) => {
+                    stats.bytes += bytes;
+                    config.trace("sparse", format_args!("{} -> {} (holes preserved)", src.display(), dst.display()));
+                }
+                None => {
+                    let copy = || match config.copy_buffer {
+                        Some(buffer_size) => copy_with_buffer(src, dst, buffer_size),
+                        None => fs::copy(src, dst),
+                    };
+                    stats.bytes += retry_transient(dst, config, copy)
+                        .map_err(io_context(format!("Failed to copy {} to {}", src.display(), dst.display())))?
+                }
+            }
+        }
+    }
+    if let (Some(index), Some(digest)) = (&config.dedupe, unseen_digest) {
+        index.lock().unwrap().entry(digest).or_insert_with(|| dst.to_path_buf());
+    }
+    stats.files += 1;
+    stats.written.push(dst.to_path_buf());
+    config.file_copied(stats.bytes - bytes_before);
+    if config.verbose_copy {
+        config.trace("copy", format_args!("{} -> {} ({})", src.display(), dst.display(), format_bytes(stats.bytes - bytes_before)));
+    } else {
+        config.trace("copy", format_args!("{} -> {}", src.display(), dst.display()));
+    }
+    if config.copy_attributes {
+        copy_attributes(src, dst, config)?;
+    }
+    if config.copy_timestamps {
+        copy_times(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Copies `(src, dst)` pairs on up to `--copy-jobs` worker threads, returning results in input order.
+///
+/// Under `FailurePolicy::FailFast` workers stop picking up files after the first failure; the
+/// files not attempted are absent from the result, which ends at or after the first error.
+fn copy_files(files: &[(PathBuf, PathBuf)], config: &Config) -> Vec<io::Result<CopyStats>> {
+    let workers = config.copy_jobs.min(files.len()).max(1);
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<io::Result<CopyStats>>>> = Mutex::new((0..files.len()).map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                if config.copy_failures == FailurePolicy::FailFast && failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((src, dst)) = files.get(i) else { break };
+                let mut stats = CopyStats::default();
+                let result = check_timeout().and_then(|()| config.copy_slots.run(|| copy_file(src, dst, config, &mut stats))).map(|()| stats);
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().flatten().collect()
+}
+
+/// Copies a directory recursively, skipping paths matched by `--exclude`/`--exclude-from`.
+///
+/// `root` is the output directory `dst` lives in, which `--strip-prefix` is applied relative to;
+/// exclusions match the path under `root` before that relocation.
+fn copy_dir(
+    src: &Path,
+    dst: &Path,
+    root: &Path,
+    config: &Config,
+) -> io::Result<CopyStats> {
+    match fs::metadata(src) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a directory", src.display()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(source_not_found(src)),
+        Err(e) => return Err(io_context(format!("Failed to read {}", src.display()))(e)),
+    }
+    // Entries are relocated from their unrelocated path, so a rewrite is never applied twice.
+    let relocated = config.relocate(root, dst);
+    if config.writes_tree() && config.since_timestamp.is_none() {
+        fs::create_dir_all(&relocated).map_err(io_context(format!("Failed to create directory {}", relocated.display())))?;
+    }
+    let mut stats = CopyStats::default();
+    let mut dirs = vec![(src.to_path_buf(), relocated)];
+    let mut files = Vec::new();
+    for entry in WalkDir::new(src).min_depth(1) {
+        check_timeout()?;
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(src).display().to_string();
+                let e = io_context(format!("Failed to read {}", path))(e.into());
+                if config.copy_failures == FailurePolicy::FailFast {
+                    return Err(e);
+                }
+                config.trace("fail", format_args!("{}", e));
+                config.warn("copy-error", format!("skipping {}", e));
+                stats.errors.push(e.to_string());
+                continue;
+            }
+        };
+        let path = entry.path();
+        let rel_path = relative_path(path, src)?;
+        let unrelocated = dst.join(rel_path);
+        if config.excluded(unrelocated.strip_prefix(root).unwrap_or(rel_path)) {
+            config.log_fmt(format_args!("Skipping excluded: {}", rel_path.display()));
+            config.trace("skip", format_args!("{} (excluded)", path.display()));
+            continue;
+        }
+        let target = config.relocate(root, &unrelocated);
+        if path.is_dir() {
+            // A delta tree only gets the directories its files need, created with them below.
+            if config.since_timestamp.is_some() {
+                dirs.push((path.to_path_buf(), target));
+            } else if config.writes_tree() {
+                fs::create_dir_all(&target)
+                    .map_err(io_context(format!("Failed to create directory {}", target.display())))?;
+                if config.verbose_copy {
+                    config.trace("mkdir", format_args!("{} -> {}", path.display(), target.display()));
+                }
+                dirs.push((path.to_path_buf(), target));
+            }
+        } else {
+            if config.unchanged_since_cutoff(path)? {
+                stats.unchanged += 1;
+                continue;
+            }
+            if let Some(size) = config.oversized(path)? {
+                stats.oversized += 1;
+                stats.oversized_bytes += size;
+                continue;
+            }
+            if let Some(parent) = target.parent().filter(|_| config.writes_tree()) {
+                fs::create_dir_all(parent)
+                    .map_err(io_context(format!("Failed to create directory {}", parent.display())))?;
+            }
+            files.push((path.to_path_buf(), target));
+        }
+    }
+    for copied in copy_files(&files, config) {
+        match copied {
+            Ok(copied) => stats.merge(copied),
+            Err(e) => {
+                config.trace("fail", format_args!("{}", e));
+                // A run stopped by --timeout or Ctrl-C fails the copy under either policy.
+                if config.copy_failures == FailurePolicy::FailFast || check_timeout().is_err() {
+                    return Err(e);
+                }
+                config.warn("copy-error", e.to_string());
+                stats.errors.push(e.to_string());
+            }
+        }
+    }
+    // Directory times are applied last, deepest first, since copying into a directory updates its mtime.
+    if config.copy_timestamps {
+        for (src_dir, dst_dir) in dirs.iter().rev().filter(|(_, dst_dir)| dst_dir.is_dir()) {
+            copy_times(src_dir, dst_dir)?;
+        }
+    }
+    if config.verbose_copy {
+        config.trace("dir", format_args!("{} -> {} ({} files, {})", src.display(), dst.display(), stats.files, format_bytes(stats.bytes)));
+    }
+    Ok(stats)
+}
+
+/// Where a component's source path is resolved from.
+#[derive(Clone, Copy, PartialEq)]
+enum ComponentBase {
+    /// The `encore` directory itself.
+    Encore,
+    /// Each app's `.encore` directory, found at `workspace/apps/<app>/.encore` next to `encore`.
+    AppConfig,
+    /// The `workspace` directory next to `encore`.
+    Workspace,
+}
+
+/// `--layout`: how the components are arranged in the output directory.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum OutputLayout {
+    /// Components at the top of the output directory; the default.
+    Flat,
+    /// Components under `app/`, with a `launch.sh` at the top, so the output can be entered with
+    /// `chroot` or `unshare --root` and the app started from `/launch.sh`.
+    Rootfs,
+}
+
+impl OutputLayout {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "flat" => Ok(Self::Flat),
+            "rootfs" => Ok(Self::Rootfs),
+            _ => Err(format!("Unknown --layout '{}' (expected rootfs or flat)", name)),
+        }
+    }
+
+    /// The directory the components are copied into.
+    fn app_dir(self, output_dir: &Path) -> PathBuf {
+        match self {
+            Self::Flat => output_dir.to_path_buf(),
+            Self::Rootfs => output_dir.join("app"),
+        }
+    }
+}
+
+/// A component `copy_encore_components` knows how to copy into the output directory.
+struct Component {
+    name: &'static str,
+    base: ComponentBase,
+    source: &'static str,
+    target: &'static str,
+    /// Copied unless a `--component` allowlist is given; opt-in components must be named explicitly.
+    default: bool,
+}
+
+const COMPONENTS: &[Component] = &[
+    Component { name: "build", base: ComponentBase::AppConfig, source: "build", target: "artifacts/build", default: true },
+    Component {
+        name: "manifest",
+        base: ComponentBase::AppConfig,
+        source: "manifest.json",
+        target: "artifacts/manifest.json",
+        default: true,
+    },
+    Component { name: "runtimes", base: ComponentBase::Encore, source: "runtimes", target: "runtimes", default: true },
+    Component {
+        name: "build-info",
+        base: ComponentBase::Encore,
+        source: "build-info.json",
+        target: "build-info.json",
+        default: true,
+    },
+    Component {
+        name: "infra-config",
+        base: ComponentBase::Encore,
+        source: "infra.config.json",
+        target: "infra.config.json",
+        default: true,
+    },
+    Component { name: "meta", base: ComponentBase::Encore, source: "meta", target: "meta", default: true },
+    Component {
+        name: "node_modules",
+        base: ComponentBase::Workspace,
+        source: "node_modules",
+        target: "node_modules",
+        default: false,
+    },
+];
+
+/// Component presets selectable by name with `--output-format`; `full` copies the defaults.
+const OUTPUT_PROFILES: &[(&str, &[&str])] = &[
+    ("minimal", &["build", "manifest", "infra-config"]),
+    ("runtime-only", &["runtimes", "meta"]),
+    // Just the metadata deploy automation reads, skipping the build output and runtimes.
+    ("manifest-only", &["manifest", "infra-config", "build-info", "meta"]),
+];
+
+/// Resolve an `--output-format` profile to its component allowlist. `oci` copies the same
+/// components as `full`, packaged as an OCI image layout.
+fn profile_components(profile: &str) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+    if profile == "full" || profile == "oci" {
+        return Ok(None);
+    }
+    match OUTPUT_PROFILES.iter().find(|(name, _)| *name == profile) {
+        Some((_, names)) => Ok(Some(names.iter().map(|n| n.to_string()).collect())),
+        None => Err(format!(
+            "Unknown --output-format '{}' (expected full, minimal, runtime-only, manifest-only or oci)",
+            profile
+        )
+        .into()),
+    }
+}
+
+impl Component {
+    /// Output path of an app component relative to the output directory.
+    fn app_target(&self, app: &str, per_app: bool) -> PathBuf {
+        match Path::new(self.target).strip_prefix("artifacts") {
+            Ok(rest) if per_app => Path::new("artifacts").join(app).join(rest),
+            _ => PathBuf::from(self.target),
+        }
+    }
+    /// Source path relative to the encore directory, as shown by `--list-components`.
+    fn relative_source(&self) -> String {
+        match self.base {
+            ComponentBase::Encore => self.source.to_string(),
+            ComponentBase::AppConfig => format!("../workspace/apps/*/.encore/{}", self.source),
+            ComponentBase::Workspace => format!("../workspace/{}", self.source),
+        }
+    }
+}
+
+/// Print the components the tool knows how to copy, for `--list-components`.
+fn list_components() {
+    for component in COMPONENTS {
+        println!(
+            "{:<14} {} -> {}{}",
+            component.name,
+            component.relative_source(),
+            component.target,
+            if component.default { "" } else { " (opt-in)" }
+        );
+    }
+}
+
+/// Find the apps' `.encore` directories under `workspace/apps`, sorted by app name.
+fn find_app_configs(apps_dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut apps = Vec::new();
+    if !apps_dir.is_dir() {
+        return Ok(apps);
+    }
+    for entry in fs::read_dir(apps_dir).map_err(io_context(format!("Failed to read {}", apps_dir.display())))? {
+        let entry = entry?;
+        let app_config_dir = entry.path().join(".encore");
+        if app_config_dir.is_dir() {
+            apps.push((entry.file_name().to_string_lossy().into_owned(), app_config_dir));
+        }
+    }
+    apps.sort();
+    Ok(apps)
+}
+
+/// The apps to copy (narrowed by `--app`), and whether the image has several and so needs the
+/// per-app `artifacts/<app>/` layout.
+fn selected_apps(apps_dir: &Path, config: &Config) -> io::Result<(Vec<(String, PathBuf)>, bool)> {
+    let mut apps = find_app_configs(apps_dir)?;
+    let per_app = apps.len() > 1;
+    if let Some(app) = &config.app {
+        apps.retain(|(name, _)| name == app);
+        if apps.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("App '{}' not found under {}", app, apps_dir.display()),
+            ));
+        }
+    }
+    Ok((apps, per_app))
+}
+
+/// Write a single-file component straight to stdout, for `--extract-only <component> --stdout`.
+fn print_component(encore_dir: &Path, name: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let component = COMPONENTS.iter().find(|c| c.name == name).ok_or_else(|| format!("Unknown component '{}'", name))?;
+    let parent = encore_dir.parent().unwrap_or(encore_dir);
+    let source = match component.base {
+        ComponentBase::Encore => encore_dir.join(component.source),
+        ComponentBase::Workspace => parent.join("workspace").join(component.source),
+        ComponentBase::AppConfig => {
+            let (apps, _) = selected_apps(&parent.join("workspace/apps"), config)?;
+            match apps.as_slice() {
+                [(_, app_config_dir)] => app_config_dir.join(component.source),
+                [] => return Err(format!("No app .encore directory found for {}", name).into()),
+                _ => return Err(format!("Several apps provide {}; pick one with --app", name).into()),
+            }
+        }
+    };
+    if source.is_dir() {
+        return Err(format!("{} is a directory ({}); extract it without --stdout", name, source.display()).into());
+    }
+    let mut file = File::open(&source).map_err(io_context(format!("{} not found at {}", name, source.display())))?;
+    io::copy(&mut file, &mut io::stdout().lock())?;
+    Ok(())
+}
+
+/// Whether `path`'s extension (case-insensitive) is one of `extensions`.
+fn has_extension(path: &Path, extensions: &HashSet<String>) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|e| extensions.contains(&e.to_lowercase()))
+}
+
+/// Default `--copy-buffer`: the read buffer for copies made through userspace.
+const DEFAULT_COPY_BUFFER: usize = 64 * 1024;
+
+/// Copy `src` to `dst` through a `buffer_size` buffer, with its permissions, for `--copy-buffer`.
+fn copy_with_buffer(src: &Path, dst: &Path, buffer_size: usize) -> io::Result<u64> {
+    let mut input = File::open(src)?;
+    let mut output = File::create(dst)?;
+    let copied = copy_buffered(&mut input, &mut output, buffer_size)?;
+    fs::set_permissions(dst, input.metadata()?.permissions())?;
+    Ok(copied)
+}
+
+/// `io::copy` with a caller-chosen buffer size.
+fn copy_buffered(input: &mut impl Read, output: &mut impl Write, buffer_size: usize) -> io::Result<u64> {
+    let mut buffer = vec![0u8; buffer_size];
+    let mut copied = 0;
+    loop {
+        let n = match input.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        output.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+}
+
+/// Copy a text file with CRLF converted to LF, returning the bytes written.
+///
+/// Returns `None` without writing when the file has no CRLF or looks binary (a NUL byte in
+/// the first 8 KiB), leaving the caller to copy it verbatim. The check and the copy both stream
+/// through a fixed buffer, so a large file never has to fit in memory.
+fn copy_normalized(src: &Path, dst: &Path, buffer_size: usize) -> io::Result<Option<u64>> {
+    let mut input = BufReader::with_capacity(buffer_size, File::open(src)?);
+    let (mut scanned, mut crlf, mut after_cr) = (0, false, false);
+    loop {
+        let chunk = input.fill_buf()?;
+        if chunk.is_empty() {
+            break;
+        }
+        if chunk[..chunk.len().min(8192usize.saturating_sub(scanned))].contains(&0) {
+            return Ok(None);
+        }
+        crlf = crlf || (after_cr && chunk[0] == b'\n') || chunk.windows(2).any(|w| w == b"\r\n");
+        after_cr = chunk.last() == Some(&b'\r');
+        let len = chunk.len();
+        input.consume(len);
+        scanned += len;
+        if crlf && scanned >= 8192 {
+            break;
+        }
+    }
+    if !crlf {
+        return Ok(None);
+    }
+
+    // A CR ending one chunk is held back until the next one shows whether a LF follows it.
+    input.seek(SeekFrom::Start(0))?;
+    let mut output = File::create(dst)?;
+    let mut normalized = Vec::with_capacity(buffer_size);
+    let (mut written, mut pending_cr) = (0, false);
+    loop {
+        let chunk = input.fill_buf()?;
+        if chunk.is_empty() {
+            break;
+        }
+        for &byte in chunk {
+            if pending_cr && byte != b'\n' {
+                normalized.push(b'\r');
+            }
+            pending_cr = byte == b'\r';
+            if !pending_cr {
+                normalized.push(byte);
+            }
+        }
+        let len = chunk.len();
+        input.consume(len);
+        output.write_all(&normalized)?;
+        written += normalized.len() as u64;
+        normalized.clear();
+    }
+    if pending_cr {
+        output.write_all(b"\r")?;
+        written += 1;
+    }
+    fs::set_permissions(dst, fs::metadata(src)?.permissions())?;
+    Ok(Some(written))
+}
+
+/// Copies a sparse file for `--copy-sparse`, walking its data regions with `SEEK_DATA`/`SEEK_HOLE`
+/// and leaving the holes between them unwritten. Returns the file's apparent size.
+///
+/// Returns `None` without writing when the file has no holes (it occupies at least as many blocks
+/// as its size needs) or the filesystem can't report them, leaving the caller to copy it verbatim.
+fn copy_sparse(src: &Path, dst: &Path, buffer_size: usize) -> io::Result<Option<u64>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    let mut input = File::open(src)?;
+    let metadata = input.metadata()?;
+    let len = metadata.len();
+    if metadata.blocks() * 512 >= len {
+        return Ok(None);
+    }
+    let fd = input.as_raw_fd();
+    let seek = |offset: u64, whence: libc::c_int| {
+        // SAFETY: fd is an open file descriptor owned by `input` for the duration of the call.
+        let result = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+        if result < 0 { Err(io::Error::last_os_error()) } else { Ok(result as u64) }
+    };
+    // Probe once before creating anything, so an unsupported filesystem falls back cleanly.
+    match seek(0, libc::SEEK_DATA) {
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+        _ => {}
+    }
+
+    let mut output = File::create(dst)?;
+    let mut offset = 0;
+    while offset < len {
+        let data = match seek(offset, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // Nothing but a hole up to the end of the file.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) => return Err(e),
+        };
+        let hole = seek(data, libc::SEEK_HOLE)?.min(len);
+        input.seek(SeekFrom::Start(data))?;
+        output.seek(SeekFrom::Start(data))?;
+        copy_buffered(&mut (&mut input).take(hole - data), &mut output, buffer_size)?;
+        offset = hole;
+    }
+    // Extends the file over a trailing hole without writing it.
+    output.set_len(len)?;
+    output.set_permissions(metadata.permissions())?;
+    Ok(Some(len))
+}
+
+/// Copy one resolved component source to `target`, which may be a directory or a single file.
+fn copy_component(source: &Path, target: &Path, output_dir: &Path, config: &Config) -> io::Result<CopyStats> {
+    match fs::symlink_metadata(source) {
+        Ok(_) if source.is_dir() => return copy_dir(source, target, output_dir, config),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(source_not_found(source)),
+        Err(e) => return Err(io_context(format!("Failed to read {}", source.display()))(e)),
+    }
+    let mut stats = CopyStats::default();
+    if config.excluded(target.strip_prefix(output_dir).unwrap_or(target)) {
+        config.log_fmt(format_args!("Skipping excluded: {}", source.display()));
+        config.trace("skip", format_args!("{} (excluded)", source.display()));
+        return Ok(stats);
+    }
+    if config.unchanged_since_cutoff(source)? {
+        stats.unchanged += 1;
+        return Ok(stats);
+    }
+    if let Some(size) = config.oversized(source)? {
+        stats.oversized += 1;
+        stats.oversized_bytes += size;
+        return Ok(stats);
+    }
+    let target = config.relocate(output_dir, target);
+    if let Some(parent) = target.parent().filter(|_| config.writes_tree()) {
+        fs::create_dir_all(parent).map_err(io_context(format!("Failed to create directory {}", parent.display())))?;
+    }
+    config.copy_slots.run(|| copy_file(source, &target, config, &mut stats))?;
+    Ok(stats)
+}
+
+/// High-level routine to copy components from the extracted 'encore' directory into the output directory.
+///
+/// A single app keeps the flat `artifacts/` layout; an image with several apps under
+/// `workspace/apps` gets one `artifacts/<app>/` directory per app (narrowed by `--app`).
+/// With `--layout rootfs` all of it goes under `app/`, which excludes and rewrites are relative to.
+fn copy_encore_components(encore_dir: &Path, output_dir: &Path, config: &Config) -> io::Result<CopyStats> {
+    let output_dir = &config.output_layout.app_dir(output_dir);
+    let mut stats = CopyStats::default();
+    if !encore_dir.is_dir() {
+        if let Some(name) = config.required.first() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("encore directory not found at {} (--require {})", encore_dir.display(), name),
+            ));
+        }
+        config.warn("missing-encore", format!("encore directory not found at {}", encore_dir.display()));
+        return Ok(stats);
+    }
+    config.log_fmt(format_args!("Found encore at: {}", encore_dir.display()));
+    let parent = encore_dir.parent().unwrap_or(encore_dir);
+    let apps_dir = parent.join("workspace/apps");
+    let (apps, per_app) = selected_apps(&apps_dir, config)?;
+    if apps.is_empty() && config.only_paths.is_empty() {
+        config.warn(
+            "missing-component",
+            format!(".encore directory not found under {}", apps_dir.join("*/.encore").display()),
+        );
+    }
+
+    let mut jobs = only_path_jobs(encore_dir, output_dir, config);
+    for component in COMPONENTS.iter().filter(|c| config.only_paths.is_empty() && config.copies_component(c)) {
+        let sources: Vec<(PathBuf, PathBuf)> = match component.base {
+            ComponentBase::Encore => vec![(encore_dir.join(component.source), output_dir.join(component.target))],
+            ComponentBase::AppConfig => apps
+                .iter()
+                .map(|(name, app_config_dir)| {
+                    (app_config_dir.join(component.source), output_dir.join(component.app_target(name, per_app)))
+                })
+                .collect(),
+            ComponentBase::Workspace => {
+                vec![(parent.join("workspace").join(component.source), output_dir.join(component.target))]
+            }
+        };
+        if sources.is_empty() && config.required.iter().any(|r| r == component.name) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No app .encore directory found under {} (--require {})", apps_dir.display(), component.name),
+            ));
+        }
+        if let Some(heartbeat) = &config.heartbeat {
+            let files = sources.iter().flat_map(|(source, _)| WalkDir::new(source)).filter_map(|e| e.ok()).filter(|e| !e.file_type().is_dir());
+            heartbeat.total.fetch_add(files.count() as u64, Ordering::Relaxed);
+        }
+        for (source, target) in sources {
+            if !source.exists() {
+                config.missing_component(component.name, format!("{} not found at {}", component.name, source.display()))?;
+                continue;
+            }
+            jobs.push((component, source, target));
+        }
+    }
+
+    let copy = |(component, source, target): &(&Component, PathBuf, PathBuf)| {
+        let copied = copy_component(source, target, output_dir, config).map_err(|e| component_error(e, component.name));
+        if copied.is_ok() {
+            config.log_fmt(format_args!("Copied {} to {}", component.name, target.display()));
+        }
+        copied
+    };
+    let results: Vec<io::Result<CopyStats>> = if config.component_parallel {
+        // Every destination root exists before the copies start, so concurrent copies sharing a
+        // parent (`artifacts/` for build and manifest) never race to create it.
+        if config.writes_tree() && config.since_timestamp.is_none() {
+            for (_, source, target) in &jobs {
+                let root = if source.is_dir() { target.as_path() } else { target.parent().unwrap_or(target) };
+                let root = config.relocate(output_dir, root);
+                fs::create_dir_all(&root).map_err(io_context(format!("Failed to create directory {}", root.display())))?;
+            }
+        }
+        thread::scope(|scope| {
+            let handles: Vec<_> = jobs.iter().map(|job| scope.spawn(move || copy(job))).collect();
+            handles.into_iter().map(|h| h.join().expect("component copy panicked")).collect()
+        })
+    } else {
+        // Sequential copies stop at the first failing component.
+        jobs.iter().map(copy).collect::<io::Result<Vec<_>>>()?.into_iter().map(Ok).collect()
+    };
+    for ((component, source, _), copied) in jobs.iter().zip(results) {
+        let mut copied = copied?;
+        if !config.only_paths.is_empty() {
+            let path = source.strip_prefix(encore_dir).unwrap_or(source);
+            config.status(format_args!("Copied {} ({} files, {})", path.display(), copied.files, format_bytes(copied.bytes)));
+        }
+        copied.origins = vec![component.name; copied.written.len()];
+        let entry = stats.by_component.entry(component.name).or_default();
+        entry.files += copied.files;
+        entry.bytes += copied.bytes;
+        stats.merge(copied);
+        stats.components += 1;
+    }
+    Ok(stats)
+}
+
+/// Stands in for the component of an `--only-path` that no component covers.
+const ONLY_PATH_COMPONENT: Component =
+    Component { name: "only-path", base: ComponentBase::Encore, source: "", target: "", default: false };
+
+/// The copies `--only-path` asks for. A path inside a component's source lands where that
+/// component would put it and counts towards it; any other path keeps its place relative to the
+/// `encore` directory.
+fn only_path_jobs(encore_dir: &Path, output_dir: &Path, config: &Config) -> Vec<(&'static Component, PathBuf, PathBuf)> {
+    let mut jobs = Vec::new();
+    for path in &config.only_paths {
+        let source = encore_dir.join(path);
+        if !source.exists() {
+            config.warn("missing-component", format!("--only-path {} not found at {}", path.display(), source.display()));
+            continue;
+        }
+        let covering = COMPONENTS
+            .iter()
+            .filter(|c| c.base == ComponentBase::Encore)
+            .find_map(|c| path.strip_prefix(c.source).ok().map(|rest| (c, Path::new(c.target).join(rest))));
+        let (component, target) = covering.unwrap_or((&ONLY_PATH_COMPONENT, path.clone()));
+        if let Some(heartbeat) = &config.heartbeat {
+            let files = WalkDir::new(&source).into_iter().filter_map(|e| e.ok()).filter(|e| !e.file_type().is_dir());
+            heartbeat.total.fetch_add(files.count() as u64, Ordering::Relaxed);
+        }
+        jobs.push((component, source, output_dir.join(target)));
+    }
+    jobs
+}
+
+/// Each copied component's path in the output directory, paired with its path in the image.
+fn component_image_paths(encore_dir: &Path, output_dir: &Path, config: &Config) -> io::Result<Vec<(PathBuf, String)>> {
+    let output_dir = &config.output_layout.app_dir(output_dir);
+    let parent = encore_dir.parent().unwrap_or(encore_dir);
+    let (apps, per_app) = selected_apps(&parent.join("workspace/apps"), config)?;
+    let mut copies: Vec<(PathBuf, String)> = Vec::new();
+    for component in COMPONENTS.iter().filter(|c| config.copies_component(c)) {
+        match component.base {
+            ComponentBase::Encore => copies.push((component.target.into(), format!("/encore/{}", component.source))),
+            ComponentBase::AppConfig => {
+                for (name, _) in &apps {
+                    let image_path = format!("/workspace/apps/{}/.encore/{}", name, component.source);
+                    copies.push((component.app_target(name, per_app), image_path));
+                }
+            }
+            ComponentBase::Workspace => copies.push((component.target.into(), format!("/workspace/{}", component.source))),
+        }
+    }
+    Ok(copies
+        .into_iter()
+        .map(|(target, image_path)| (config.relocate(output_dir, &output_dir.join(target)), image_path))
+        .filter(|(target, _)| target.exists())
+        .collect())
+}
+
+/// Write a Dockerfile that rebuilds a slim runtime image from the extracted output.
+///
+/// The output directory is the build context: every copied component is `COPY`'d back to its path
+/// in the original image, and ENV/WORKDIR/EXPOSE/ENTRYPOINT/CMD are carried over from the image config.
+fn write_dockerfile(
+    path: &Path,
+    manifest: &Manifest,
+    encore_dir: &Path,
+    output_dir: &Path,
+    image: &str,
+    base: &str,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut dockerfile = format!("# Generated by encorebaremetal from {}\nFROM {}\n", image, base);
+    for (target, image_path) in component_image_paths(encore_dir, output_dir, config)? {
+        let context_path = target.strip_prefix(output_dir).unwrap_or(&target);
+        dockerfile.push_str(&format!("COPY {} {}\n", context_path.display(), image_path));
+    }
+
+    let image_config = read_image_config(manifest)?;
+    let process = &image_config["config"];
+    for var in process["Env"].as_array().into_iter().flatten().filter_map(|v| v.as_str()) {
+        if let Some((key, value)) = var.split_once('=') {
+            dockerfile.push_str(&format!("ENV {}={}\n", key, serde_json::to_string(value)?));
+        }
+    }
+    if let Some(dir) = process["WorkingDir"].as_str().filter(|d| !d.is_empty()) {
+        dockerfile.push_str(&format!("WORKDIR {}\n", dir));
+    }
+    if let Value::Object(ports) = &process["ExposedPorts"] {
+        for port in ports.keys() {
+            dockerfile.push_str(&format!("EXPOSE {}\n", port));
+        }
+    }
+    for (instruction, key) in [("ENTRYPOINT", "Entrypoint"), ("CMD", "Cmd")] {
+        if let Some(argv) = process[key].as_array().filter(|a| !a.is_empty()) {
+            dockerfile.push_str(&format!("{} {}\n", instruction, serde_json::to_string(argv)?));
+        }
+    }
+
+    fs::write(path, dockerfile).map_err(io_context(format!("Failed to write Dockerfile {}", path.display())))?;
+    config.status(format_args!("Wrote Dockerfile to {} (build context: {})", path.display(), output_dir.display()));
+    Ok(())
+}
+
+/// Package the components copied into `tree` as an OCI image layout in `layout_dir`, for
+/// `--output-format oci`.
+///
+/// The image has a single uncompressed layer holding each component at its path in the source
+/// image, and a config carrying over the source's platform, environment, working directory,
+/// ports and entrypoint, so `skopeo copy oci:<dir> docker://...` can push it as is. The layout
+/// is tagged with the source image's tag.
+fn write_oci_layout(
+    manifest: &Manifest,
+    encore_dir: &Path,
+    tree: &Path,
+    layout_dir: &Path,
+    image: &str,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let blobs = layout_dir.join("blobs/sha256");
+    fs::create_dir_all(&blobs).map_err(io_context(format!("Failed to create directory {}", blobs.display())))?;
+
+    let staging = Builder::new().suffix(".partial").tempfile_in(&blobs)?;
+    let mut builder = tar::Builder::new(staging.as_file());
+    builder.follow_symlinks(false);
+    for (target, image_path) in component_image_paths(encore_dir, tree, config)? {
+        let name = Path::new(image_path.trim_start_matches('/'));
+        for entry in WalkDir::new(&target).sort_by_file_name() {
+            let entry = entry.map_err(io::Error::from)?;
+            let relative = relative_path(entry.path(), &target)?;
+            let name = if relative.as_os_str().is_empty() { name.to_path_buf() } else { name.join(relative) };
+            append_archive_entry(&mut builder, entry.path(), &name, config)
+                .map_err(io_context(format!("Failed to archive {}", entry.path().display())))?;
+        }
+    }
+    builder.into_inner()?;
+    let layer_digest = sha256::hash_file(staging.path())?;
+    let layer_size = staging.as_file().metadata()?.len();
+    // Temp files are created private; blobs are as readable as the rest of the output.
+    staging.as_file().set_permissions(fs::Permissions::from_mode(0o644))?;
+    staging.persist(blobs.join(&layer_digest)).map_err(|e| e.error)?;
+
+    // JSON blobs are addressed by the digest of their exact bytes.
+    let write_blob = |value: &Value| -> io::Result<Value> {
+        let bytes = serde_json::to_vec(value)?;
+        let mut hasher = sha256::Sha256::new();
+        hasher.update(&bytes);
+        let digest = sha256::to_hex(&hasher.finalize());
+        fs::write(blobs.join(&digest), &bytes).map_err(io_context(format!("Failed to write blob {}", digest)))?;
+        Ok(serde_json::json!({ "digest": format!("sha256:{}", digest), "size": bytes.len() }))
+    };
+
+    let source = read_image_config(manifest)?;
+    let process: serde_json::Map<String, Value> = ["Env", "Entrypoint", "Cmd", "WorkingDir", "ExposedPorts"]
+        .into_iter()
+        .filter(|key| !source["config"][key].is_null())
+        .map(|key| (key.to_string(), source["config"][key].clone()))
+        .collect();
+    let created = rfc3339(config.deterministic.unwrap_or_else(SystemTime::now));
+    let mut config_descriptor = write_blob(&serde_json::json!({
+        "created": created,
+        "architecture": source["architecture"],
+        "os": source["os"],
+        "config": process,
+        "rootfs": { "type": "layers", "diff_ids": [format!("sha256:{}", layer_digest)] },
+        "history": [{ "created": created, "created_by": format!("encorebaremetal --output-format oci from {}", image) }],
+    }))?;
+    config_descriptor["mediaType"] = "application/vnd.oci.image.config.v1+json".into();
+    let mut manifest_descriptor = write_blob(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": config_descriptor,
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar",
+            "digest": format!("sha256:{}", layer_digest),
+            "size": layer_size,
+        }],
+    }))?;
+    manifest_descriptor["mediaType"] = "application/vnd.oci.image.manifest.v1+json".into();
+    let tag = image.rsplit_once(':').map(|(_, tag)| tag).filter(|tag| !tag.contains('/')).unwrap_or("latest");
+    manifest_descriptor["annotations"] = serde_json::json!({ "org.opencontainers.image.ref.name": tag });
+
+    let index = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [manifest_descriptor],
+    });
+    let index_path = layout_dir.join("index.json");
+    fs::write(&index_path, serde_json::to_string_pretty(&index)? + "\n").map_err(io_context(format!("Failed to write {}", index_path.display())))?;
+    let marker = layout_dir.join("oci-layout");
+    fs::write(&marker, "{\"imageLayoutVersion\":\"1.0.0\"}\n").map_err(io_context(format!("Failed to write {}", marker.display())))?;
+    config.status(format_args!("Wrote OCI image layout to {} (tag {}, layer {})", layout_dir.display(), tag, format_bytes(layer_size)));
+    Ok(())
+}
+
+/// Replace image paths of copied components in `value` with their paths in the output directory.
+///
+/// A path only matches at the start of `value` or after whitespace, `=` or `:` (so `PATH`-style
+/// lists and `sh -c` strings are covered), and only when followed by its end, `/` or whitespace.
+fn rewrite_image_paths(value: &str, paths: &[(PathBuf, String)]) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    'scan: while !rest.is_empty() {
+        let at_boundary = out.is_empty() || out.ends_with(|c: char| c.is_whitespace() || c == '=' || c == ':');
+        if at_boundary {
+            for (target, image_path) in paths {
+                if let Some(after) = rest.strip_prefix(image_path.as_str()) {
+                    if after.is_empty() || after.starts_with(|c: char| c == '/' || c.is_whitespace()) {
+                        out.push_str(&target.to_string_lossy());
+                        rest = after;
+                        continue 'scan;
+                    }
+                }
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Quote a word for a systemd unit: specifiers (`%`) and variable expansion (`$`) are escaped,
+/// and words that need it are double-quoted.
+fn systemd_quote(word: &str) -> String {
+    let escaped = word.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%").replace('$', "$$");
+    if word.is_empty() || word.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';')) {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+/// Service name and account for `--emit-systemd` (`--systemd-name`, `--systemd-user`).
+struct SystemdService {
+    name: String,
+    user: Option<String>,
+}
+
+/// Write a systemd `.service` unit that runs the extracted app from `output_dir`, for `--emit-systemd`.
+///
+/// ExecStart and Environment come from the image config's entrypoint, command and env, with image
+/// paths of copied components pointed at the output directory, which is also WorkingDirectory.
+fn write_systemd_unit(
+    path: &Path,
+    manifest: &Manifest,
+    encore_dir: &Path,
+    output_dir: &Path,
+    image: &str,
+    service: &SystemdService,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths = component_image_paths(encore_dir, output_dir, config)?;
+    // Longest first, so a nested component wins over the directory containing it.
+    paths.sort_by_key(|(_, image_path)| std::cmp::Reverse(image_path.len()));
+
+    let image_config = read_image_config(manifest)?;
+    let process = &image_config["config"];
+    let argv: Vec<String> = ["Entrypoint", "Cmd"]
+        .iter()
+        .flat_map(|key| process[*key].as_array().cloned().unwrap_or_default())
+        .filter_map(|v| v.as_str().map(|arg| rewrite_image_paths(arg, &paths)))
+        .collect();
+    if argv.is_empty() {
+        return Err(format!("Image {} has no entrypoint or command to run from the systemd unit", image).into());
+    }
+
+    let mut unit = format!(
+        "# Generated by encorebaremetal from {}\n[Unit]\nDescription={} (Encore app from {})\nAfter=network-online.target\nWants=network-online.target\n\n[Service]\nType=simple\n",
+        image, service.name, image
+    );
+    if let Some(user) = &service.user {
+        unit.push_str(&format!("User={}\n", user));
+    }
+    unit.push_str(&format!("WorkingDirectory={}\n", systemd_quote(&output_dir.to_string_lossy())));
+    for var in process["Env"].as_array().into_iter().flatten().filter_map(|v| v.as_str()) {
+        if var.contains('=') {
+            unit.push_str(&format!("Environment={}\n", systemd_quote(&rewrite_image_paths(var, &paths))));
+        }
+    }
+    // The merged runtime env overrides Environment= lines, as EnvironmentFile= settings do.
+    if !config.runtime_env.is_empty() {
+        let env_file = output_dir.join(RUNTIME_ENV_FILE);
+        unit.push_str(&format!("EnvironmentFile={}\n", systemd_quote(&env_file.to_string_lossy())));
+    }
+    let exec: Vec<String> = argv.iter().map(|arg| systemd_quote(arg)).collect();
+    unit.push_str(&format!("ExecStart={}\n", exec.join(" ")));
+    unit.push_str(&format!("SyslogIdentifier={}\nRestart=on-failure\nRestartSec=5\n\n[Install]\nWantedBy=multi-user.target\n", service.name));
+
+    fs::write(path, unit).map_err(io_context(format!("Failed to write systemd unit {}", path.display())))?;
+    config.status(format_args!("Wrote systemd unit {} to {}", service.name, path.display()));
+    Ok(())
+}
+
+/// Quote `word` for a POSIX shell: bare when it's plain, single-quoted otherwise.
+fn shell_quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '_' | '-' | '=' | ':' | ',' | '+' | '@');
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// The process an image config starts: Entrypoint then Cmd, Env and ExposedPorts, with image
+/// paths of copied components rewritten to the paths given.
+struct ImageProcess {
+    argv: Vec<String>,
+    env: Vec<(String, String)>,
+    /// `ExposedPorts` keys, e.g. `8080/tcp`.
+    ports: Vec<String>,
+}
+
+impl ImageProcess {
+    fn read(manifest: &Manifest, paths: &[(PathBuf, String)]) -> Result<Self, Box<dyn std::error::Error>> {
+        let image_config = read_image_config(manifest)?;
+        let process = &image_config["config"];
+        let argv = ["Entrypoint", "Cmd"]
+            .iter()
+            .flat_map(|key| process[*key].as_array().cloned().unwrap_or_default())
+            .filter_map(|v| v.as_str().map(|arg| rewrite_image_paths(arg, paths)))
+            .collect();
+        let env = process["Env"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.as_str()?.split_once('='))
+            .map(|(key, value)| (key.to_string(), rewrite_image_paths(value, paths)))
+            .collect();
+        let ports = process["ExposedPorts"].as_object().map(|ports| ports.keys().cloned().collect()).unwrap_or_default();
+        Ok(Self { argv, env, ports })
+    }
+}
+
+/// `--entrypoint-format`: how `--print-entrypoint` prints the run command.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum EntrypointFormat {
+    /// A `cd <output> && env K=V ... <command>` line.
+    Shell,
+    Json,
+}
+
+impl EntrypointFormat {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "shell" => Ok(Self::Shell),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown --entrypoint-format '{}' (expected shell or json)", name)),
+        }
+    }
+}
+
+/// Print the command that starts the extracted service from `output_dir` to stdout, for process
+/// managers such as pm2 or supervisor: the image's entrypoint with component paths pointed at the
+/// output, its environment with the `--runtime-env` overrides applied, and the ports it exposes.
+fn print_entrypoint(manifest: &Manifest, encore_dir: &Path, output_dir: &Path, image: &str, format: EntrypointFormat, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths = component_image_paths(encore_dir, output_dir, config)?;
+    paths.sort_by_key(|(_, image_path)| std::cmp::Reverse(image_path.len()));
+    let mut process = ImageProcess::read(manifest, &paths)?;
+    if process.argv.is_empty() {
+        return Err(format!("Image {} has no entrypoint or command to print", image).into());
+    }
+    for (key, value) in &config.runtime_env {
+        match process.env.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => existing.clone_from(value),
+            None => process.env.push((key.clone(), value.clone())),
+        }
+    }
+    match format {
+        EntrypointFormat::Json => {
+            let env: serde_json::Map<String, Value> = process.env.into_iter().map(|(key, value)| (key, Value::String(value))).collect();
+            let document = serde_json::json!({
+                "image": image,
+                "working_dir": output_dir.to_string_lossy(),
+                "command": process.argv,
+                "env": env,
+                "ports": process.ports,
+            });
+            println!("{}", serde_json::to_string_pretty(&document)?);
+        }
+        EntrypointFormat::Shell => {
+            if !process.ports.is_empty() {
+                println!("# Listens on {}", process.ports.join(", "));
+            }
+            let words: Vec<String> = process
+                .env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, shell_quote(value)))
+                .chain(process.argv.iter().map(|arg| shell_quote(arg)))
+                .collect();
+            println!("cd {} && env {}", shell_quote(&output_dir.to_string_lossy()), words.join(" "));
+        }
+    }
+    Ok(())
+}
+
+/// Write the `--metadata-out` deploy descriptor: one document with what an orchestrator needs to run
+/// the extracted app, gathered from the image config, `build-info.json` and `infra.config.json`.
+///
+/// Every field is always present, null when its source doesn't have it, so consumers can rely on
+/// the shape. Written as JSON, or YAML with `--metadata-format yaml`.
+#[allow(clippy::too_many_arguments)]
+fn write_deploy_descriptor(
+    path: &Path,
+    manifest: &Manifest,
+    encore_dir: Option<&Path>,
+    output_dir: &Path,
+    image: &str,
+    layer_digests: &[String],
+    layout_schema: Option<&str>,
+    yaml: bool,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app_dir = config.output_layout.app_dir(output_dir);
+    let read_json = |name: &str| -> Value {
+        let path = config.relocate(&app_dir, &app_dir.join(name));
+        File::open(path).ok().and_then(|file| from_reader(BufReader::new(file)).ok()).unwrap_or(Value::Null)
+    };
+    let (build_info, infra) = (read_json("build-info.json"), read_json("infra.config.json"));
+    // Database and topic names are the keys of each server's `databases` and each provider's `topics`.
+    let names = |list: &str, field: &str| -> Value {
+        match infra[list].as_array() {
+            Some(entries) => entries.iter().filter_map(|entry| entry[field].as_object()).flat_map(|map| map.keys().cloned()).map(Value::String).collect(),
+            None => Value::Null,
+        }
+    };
+
+    let process = match encore_dir {
+        Some(encore_dir) => {
+            let mut paths = component_image_paths(encore_dir, output_dir, config)?;
+            paths.sort_by_key(|(_, image_path)| std::cmp::Reverse(image_path.len()));
+            Some(ImageProcess::read(manifest, &paths)?)
+        }
+        None => None,
+    };
+    let entrypoint = match &process {
+        Some(process) if !process.argv.is_empty() => {
+            let env: serde_json::Map<String, Value> = process.env.iter().map(|(key, value)| (key.clone(), Value::String(value.clone()))).collect();
+            serde_json::json!({ "command": process.argv, "env": env, "working_dir": output_dir.to_string_lossy() })
+        }
+        _ => Value::Null,
+    };
+    // `PORT` in the environment wins over the first exposed port.
+    let port = process.as_ref().and_then(|process| {
+        let from_env = process.env.iter().find(|(key, _)| key == "PORT").and_then(|(_, value)| value.parse::<u16>().ok());
+        from_env.or_else(|| process.ports.iter().find_map(|port| port.split('/').next()?.parse::<u16>().ok()))
+    });
+
+    let descriptor = serde_json::json!({
+        "image": image,
+        "layer_digest": layer_digests.last(),
+        "layers": layer_digests,
+        "layout_schema": layout_schema,
+        "output_dir": output_dir,
+        "entrypoint": entrypoint,
+        "port": port,
+        "app_id": infra["metadata"]["app_id"],
+        "env_name": infra["metadata"]["env_name"],
+        "databases": names("sql_servers", "databases"),
+        "pubsub_topics": names("pubsub", "topics"),
+        "encore_version": build_info["encore_compiler_version"],
+        "git_sha": build_info["git_sha"],
+    });
+    let text = if yaml { to_yaml(&descriptor) } else { serde_json::to_string_pretty(&descriptor)? + "\n" };
+    fs::write(path, text).map_err(io_context(format!("Failed to write deploy descriptor {}", path.display())))?;
+    config.status(format_args!("Wrote deploy descriptor to {}", path.display()));
+    Ok(())
+}
+
+/// Write `launch.sh` at the top of a `--layout rootfs` output, starting the app inside the rootfs.
+///
+/// Like the systemd unit, the script runs the image config's entrypoint and command with its env,
+/// but component paths point at their place under `/app` as seen from inside the chroot.
+fn write_launch_script(manifest: &Manifest, encore_dir: &Path, output_dir: &Path, image: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths: Vec<(PathBuf, String)> = component_image_paths(encore_dir, output_dir, config)?
+        .into_iter()
+        .map(|(target, image_path)| (Path::new("/").join(target.strip_prefix(output_dir).unwrap_or(&target)), image_path))
+        .collect();
+    paths.sort_by_key(|(_, image_path)| std::cmp::Reverse(image_path.len()));
+
+    let process = ImageProcess::read(manifest, &paths)?;
+    if process.argv.is_empty() {
+        return Err(format!("Image {} has no entrypoint or command to run from the launch script", image).into());
+    }
+
+    let mut script = format!("#!/bin/sh\n# Generated by encorebaremetal from {}\n# Run inside the rootfs: chroot {} /launch.sh\ncd /app || exit 1\n", image, output_dir.display());
+    for (key, value) in &process.env {
+        script.push_str(&format!("export {}={}\n", key, shell_quote(value)));
+    }
+    let argv: Vec<String> = process.argv.iter().map(|arg| shell_quote(arg)).collect();
+    script.push_str(&format!("exec {}\n", argv.join(" ")));
+
+    let path = output_dir.join("launch.sh");
+    fs::write(&path, script).map_err(io_context(format!("Failed to write launch script {}", path.display())))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    config.status(format_args!("Wrote launch script {} (run with chroot {} /launch.sh)", path.display(), output_dir.display()));
+    Ok(())
+}
+
+/// Name of the runtime env file written to the output for `--runtime-env`/`--runtime-env-file`.
+const RUNTIME_ENV_FILE: &str = "runtime.env";
+
+/// Write the runtime's environment to `runtime.env` in the output, with the overrides merged in.
+///
+/// The baked-in environment is the image config's `Env`, with image paths of copied components
+/// pointed at the output directory. Overrides replace existing keys in place and new keys are
+/// appended; each changed key is reported. The file can be read back by `--env-file`, shells
+/// (`set -a; . runtime.env`) and systemd's `EnvironmentFile=`.
+fn write_runtime_env(manifest: &Manifest, encore_dir: &Path, output_dir: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths = component_image_paths(encore_dir, output_dir, config)?;
+    paths.sort_by_key(|(_, image_path)| std::cmp::Reverse(image_path.len()));
+    let image_config = read_image_config(manifest)?;
+    let mut vars: Vec<(String, String)> = image_config["config"]["Env"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.as_str()?.split_once('='))
+        .map(|(key, value)| (key.to_string(), rewrite_image_paths(value, &paths)))
+        .collect();
+
+    // A key given several times only reports its final value.
+    let mut overrides: Vec<(&String, &String)> = Vec::new();
+    for (key, value) in &config.runtime_env {
+        match overrides.iter_mut().find(|(existing, _)| *existing == key) {
+            Some(entry) => entry.1 = value,
+            None => overrides.push((key, value)),
+        }
+    }
+    for (key, value) in overrides {
+        match vars.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, current)) if current == value => {}
+            Some((_, current)) => {
+                config.status(format_args!("Runtime env: {} overridden (was {})", key, current));
+                *current = value.clone();
+            }
+            None => {
+                config.status(format_args!("Runtime env: {} added", key));
+                vars.push((key.clone(), value.clone()));
+            }
+        }
+    }
+
+    let mut contents = String::new();
+    for (key, value) in &vars {
+        let plain = !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "_-./:,@+=%".contains(c));
+        if plain {
+            contents.push_str(&format!("{}={}\n", key, value));
+        } else {
+            let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('$', "\\$");
+            contents.push_str(&format!("{}=\"{}\"\n", key, escaped));
+        }
+    }
+    let path = output_dir.join(RUNTIME_ENV_FILE);
+    fs::write(&path, contents).map_err(io_context(format!("Failed to write runtime env {}", path.display())))?;
+    config.status(format_args!("Wrote {} runtime env variables to {}", vars.len(), path.display()));
+    Ok(())
+}
+
+/// `--report-format`: how the end-of-run summary is printed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ReportFormat {
+    /// Human-readable status lines.
+    Text,
+    Json,
+    Yaml,
+}
+
+/// End-of-run summary of what was extracted, and the warnings raised on the way.
+#[derive(Serialize)]
+struct RunReport {
+    image: String,
+    /// As text, since a JSON report can't hold a non-UTF-8 path.
+    output_dir: String,
+    layers: Vec<String>,
+    labels: BTreeMap<String, String>,
+    components: usize,
+    files: u64,
+    bytes: u64,
+    /// Copy tuning the run used (`--copy-jobs`, `--copy-buffer`), for reproducing its throughput.
+    copy_jobs: usize,
+    copy_buffer: usize,
+    /// What `detect_layout_schema` made of the image; `None` without an `encore` directory.
+    layout_schema: Option<String>,
+    warnings: Vec<Diagnostic>,
+    /// False for `--no-write`, whose text summary reports a validation instead.
+    #[serde(skip)]
+    written: bool,
+    #[serde(skip)]
+    all_layers: bool,
+}
+
+impl RunReport {
+    /// Print the report in `--report-format`; `--quiet` drops the text summary but not a
+    /// machine-readable report that was asked for.
+    fn print(&self, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+        match config.report_format {
+            ReportFormat::Text if config.quiet => {}
+            ReportFormat::Text => self.print_text(config.color),
+            ReportFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+            ReportFormat::Yaml => print!("{}", to_yaml(&serde_json::to_value(self)?)),
+        }
+        Ok(())
+    }
+
+    fn print_text(&self, color: bool) {
+        if self.written {
+            eprintln!("Process completed! Files extracted to: {}", self.output_dir);
+        } else {
+            eprintln!(
+                "Process completed! Validated {} components ({} files, {}); nothing was written.",
+                self.components,
+                self.files,
+                format_bytes(self.bytes)
+            );
+        }
+        if self.all_layers {
+            if let Some(top) = self.layers.last() {
+                eprintln!("Extracted {} layers; top layer: {}", self.layers.len(), top);
+            }
+        }
+        if let Some(schema) = &self.layout_schema {
+            eprintln!("Layout schema: {}", schema);
+        }
+        if !self.warnings.is_empty() {
+            eprintln!("{}", paint(&format!("{} warnings:", self.warnings.len()), "33", color));
+            for diagnostic in &self.warnings {
+                eprintln!("  - {} {}", paint(&format!("[{}]", diagnostic.kind), "33", color), diagnostic.message);
+            }
+        }
+    }
+}
+
+/// Render a JSON value as block-style YAML. Strings and unusual keys are written double-quoted,
+/// a form whose JSON escapes YAML reads back unchanged.
+fn to_yaml(value: &Value) -> String {
+    let mut out = String::new();
+    write_yaml(value, 0, &mut out);
+    out
+}
+
+fn write_yaml(value: &Value, indent: usize, out: &mut String) {
+    let pad = " ".repeat(indent);
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, child) in map {
+                let plain = key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                let key = if plain && !key.is_empty() { key.clone() } else { Value::from(key.as_str()).to_string() };
+                out.push_str(&format!("{}{}:", pad, key));
+                write_yaml_child(child, indent, out);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for item in items {
+                if is_yaml_block(item) {
+                    // The item's first line goes after the dash: `- key: value`.
+                    let mut nested = String::new();
+                    write_yaml(item, indent + 2, &mut nested);
+                    out.push_str(&format!("{}- {}", pad, &nested[indent + 2..]));
+                } else {
+                    out.push_str(&format!("{}-", pad));
+                    write_yaml_child(item, indent, out);
+                }
+            }
+        }
+        scalar => out.push_str(&format!("{}{}\n", pad, yaml_scalar(scalar))),
+    }
+}
+
+/// Write a mapping value or sequence item: scalars inline, collections on the following lines.
+fn write_yaml_child(value: &Value, indent: usize, out: &mut String) {
+    if is_yaml_block(value) {
+        out.push('\n');
+        write_yaml(value, indent + 2, out);
+    } else {
+        out.push_str(&format!(" {}\n", yaml_scalar(value)));
+    }
+}
+
+fn is_yaml_block(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => !map.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        _ => false,
+    }
+}
+
+fn yaml_scalar(value: &Value) -> String {
+    match value {
+        Value::Object(_) => "{}".to_string(),
+        Value::Array(_) => "[]".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Write per-component copy metrics for `--stats-json`, or append them as one line with `--stats-append`.
+fn write_stats_json(
+    path: &Path,
+    image: &str,
+    layer_digests: &[String],
+    stats: &CopyStats,
+    append: bool,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let report = serde_json::json!({
+        "timestamp": rfc3339(SystemTime::now()),
+        "image": image,
+        "layer": layer_digests.last(),
+        "layers": layer_digests,
+        "components": stats.by_component,
+        "totals": { "components": stats.components, "files": stats.files, "bytes": stats.bytes },
+        "copy": { "jobs": config.copy_jobs, "buffer": config.copy_buffer_size() },
+    });
+    let context = io_context(format!("Failed to write stats to {}", path.display()));
+    if append {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path).map_err(context)?;
+        writeln!(file, "{}", report)?;
+    } else {
+        fs::write(path, serde_json::to_string_pretty(&report)? + "\n").map_err(context)?;
+    }
+    Ok(())
+}
+
+/// Take an exclusive lock on `<temp base>.lock`, so concurrent runs in one directory don't share
+/// the work directory, image tar name or output. Without `--wait` a held lock fails the run.
+///
+/// The lock lives beside the temp base rather than in it, as the temp base is deleted at the end
+/// of a run while the lock is still held. It is released when the returned lock is dropped,
+/// which also happens on the error path and, through the OS, if the process dies.
+///
+/// `cleanup_stage` deletes the lock file while still holding it. A run that was waiting on it
+/// then holds a lock on a file no longer at the path, so it opens the path again and retries.
+fn lock_workspace(temp_base: &Path, wait: bool, config: &Config) -> Result<WorkspaceLock, Box<dyn std::error::Error>> {
+    use std::os::unix::fs::MetadataExt;
+    let mut name = temp_base.as_os_str().to_owned();
+    name.push(".lock");
+    let path = PathBuf::from(name);
+    loop {
+        let file = open_workspace_lock(&path, wait, config)?;
+        let locked = file.metadata()?;
+        if fs::metadata(&path).is_ok_and(|current| (current.dev(), current.ino()) == (locked.dev(), locked.ino())) {
+            config.log_fmt(format_args!("Locked {}", path.display()));
+            return Ok(WorkspaceLock { _file: file, path });
+        }
+    }
+}
+
+/// The workspace lock `lock_workspace` took, and the lock file `cleanup_stage` removes.
+#[derive(Debug)]
+struct WorkspaceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+/// Open the lock file at `path` and lock it, waiting for the holder with `wait`.
+fn open_workspace_lock(path: &Path, wait: bool, config: &Config) -> Result<File, Box<dyn std::error::Error>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(io_context(format!("Failed to open lock file {}", path.display())))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) if wait => {
+            config.status(format_args!("Waiting for another extraction in {} to finish...", path.parent().unwrap_or(path).display()));
+            file.lock().map_err(io_context(format!("Failed to lock {}", path.display())))?;
+        }
+        Err(fs::TryLockError::WouldBlock) => {
+            return Err(format!(
+                "Another extraction is running in {} (lock held on {}); pass --wait to wait for it",
+                path.parent().unwrap_or(path).display(),
+                path.display()
+            )
+            .into());
+        }
+        Err(fs::TryLockError::Error(e)) => return Err(io_context(format!("Failed to lock {}", path.display()))(e).into()),
+    }
+    Ok(file)
+}
+
+/// Empty a temp base left behind by an earlier run, keeping `keep` (the `--resume` state).
+///
+/// Called with the workspace lock held, so whatever is there belongs to a run that crashed, was
+/// killed or kept it with `--keep-temp`; a live run would still hold the lock.
+fn remove_stale_temp(temp_base: &Path, keep: Option<&Path>, config: &Config) -> io::Result<()> {
+    let entries = match fs::read_dir(temp_base) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(io_context(format!("Failed to read {}", temp_base.display()))(e)),
+    };
+    let (mut removed, mut bytes) = (0, 0);
+    for entry in entries {
+        let path = entry?.path();
+        if Some(path.as_path()) == keep {
+            continue;
+        }
+        bytes += WalkDir::new(&path).into_iter().filter_map(|e| e.ok()).filter_map(|e| e.metadata().ok()).filter(|m| m.is_file()).map(|m| m.len()).sum::<u64>();
+        let result = if path.is_dir() && !path.is_symlink() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        result.map_err(io_context(format!("Failed to remove stale {}", path.display())))?;
+        removed += 1;
+    }
+    if removed > 0 {
+        config.status(format_args!(
+            "Removed {} {} ({}) left in {} by an earlier run that didn't clean up",
+            removed,
+            if removed == 1 { "entry" } else { "entries" },
+            format_bytes(bytes),
+            temp_base.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Progress of a `--resume` run, kept in `docker_extract_temp/resume/state.json`.
+///
+/// Only the slow phases are recorded; copying into the output overwrites in place, so it is
+/// simply redone on every attempt.
+#[derive(Default, Serialize, Deserialize)]
+struct ResumeState {
+    image: String,
+    /// The image tar has been built (or pulled) and saved.
+    saved: bool,
+    /// The image tar has been unpacked into the work directory.
+    unpacked: bool,
+    /// Digests of the layers fully extracted into the layer directory, in order.
+    layers: Vec<String>,
+}
+
+impl ResumeState {
+    const FILE: &'static str = "state.json";
+
+    /// Load the state for `image`, starting over if the previous attempt was for another image.
+    fn load(dir: &Path, image: &str, config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = dir.join(Self::FILE);
+        let previous = File::open(&path).ok().and_then(|file| from_reader::<_, ResumeState>(file).ok());
+        match previous {
+            Some(state) if state.image == image => {
+                config.log_fmt(format_args!("Resuming from {} ({} layers done)", path.display(), state.layers.len()));
+                Ok(state)
+            }
+            previous => {
+                if previous.is_some() || dir.exists() {
+                    config.log("Discarding resume state from a different or unreadable attempt.");
+                    fs::remove_dir_all(dir).map_err(io_context(format!("Failed to clear {}", dir.display())))?;
+                }
+                fs::create_dir_all(dir).map_err(io_context(format!("Failed to create {}", dir.display())))?;
+                Ok(Self { image: image.to_string(), ..Self::default() })
+            }
+        }
+    }
+
+    /// Record completed work; a no-op unless `--resume` is on. Written via rename so a crash
+    /// mid-write can't leave a truncated state file.
+    fn save(&self, dir: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(dir) = dir else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir)?;
+        let staging = dir.join(format!("{}.tmp", Self::FILE));
+        fs::write(&staging, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&staging, dir.join(Self::FILE))?;
+        Ok(())
+    }
+}
+
+/// A scratch directory under `base`: a fixed, persistent one under `resume_dir` with `--resume`,
+/// otherwise a fresh temporary directory removed when the returned guard drops (unless `keep`).
+fn scratch_dir(
+    base: &Path,
+    name: &str,
+    resume_dir: Option<&Path>,
+    keep: bool,
+) -> io::Result<(PathBuf, Option<tempfile::TempDir>)> {
+    match resume_dir {
+        Some(dir) => {
+            let path = dir.join(name);
+            fs::create_dir_all(&path)?;
+            Ok((path, None))
+        }
+        None => {
+            let mut temp = Builder::new().prefix(&format!("{}_", name)).tempdir_in(base)?;
+            temp.disable_cleanup(keep);
+            Ok((temp.path().to_path_buf(), Some(temp)))
+        }
+    }
+}
+
+/// Name of the file recording what an extraction wrote into the output directory.
+const EXTRACTION_META: &str = ".extraction-meta.json";
+
+/// Read the output files recorded by a previous extraction, as absolute paths.
+fn read_managed_files(output_dir: &Path) -> Result<HashSet<PathBuf>, Box<dyn std::error::Error>> {
+    let meta_path = output_dir.join(EXTRACTION_META);
+    if !meta_path.exists() {
+        return Ok(HashSet::new());
+    }
+    let meta: Value = from_reader(File::open(&meta_path)?)
+        .map_err(|e| format!("Failed to parse {}: {}", meta_path.display(), e))?;
+    Ok(meta["files"]
+        .as_array()
+        .map(|files| files.iter().filter_map(|f| f.as_str()).map(|f| output_dir.join(f)).collect())
+        .unwrap_or_default())
+}
+
+/// Remove files written by the previous extraction that this run didn't write again.
+fn remove_stale_files(
+    output_dir: &Path,
+    managed: &HashSet<PathBuf>,
+    written: &[PathBuf],
+    config: &Config,
+) -> io::Result<usize> {
+    let written: HashSet<&PathBuf> = written.iter().collect();
+    let mut removed = 0;
+    for path in managed.iter().filter(|p| !written.contains(p)) {
+        if path.is_file() || path.is_symlink() {
+            fs::remove_file(path).map_err(io_context(format!("Failed to remove stale {}", path.display())))?;
+            config.log_fmt(format_args!("Removed stale {}", path.display()));
+            removed += 1;
+            // Drop directories left empty, stopping at the first non-empty one.
+            let mut dir = path.parent();
+            while let Some(d) = dir.filter(|d| *d != output_dir) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Record the extraction's image, layers, `--label`s and written files in `.extraction-meta.json`.
+fn write_extraction_meta(
+    output_dir: &Path,
+    image_tag: &str,
+    layer_digests: &[String],
+    labels: &BTreeMap<String, String>,
+    written: &[PathBuf],
+) -> io::Result<()> {
+    let mut files: Vec<String> = written
+        .iter()
+        .filter_map(|p| p.strip_prefix(output_dir).ok())
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    files.dedup();
+    let meta = serde_json::json!({
+        "image": image_tag,
+        "layers": layer_digests,
+        "labels": labels,
+        "files": files,
+    });
+    let meta_path = output_dir.join(EXTRACTION_META);
+    let file = File::create(&meta_path).map_err(io_context(format!("Failed to create {}", meta_path.display())))?;
+    serde_json::to_writer_pretty(file, &meta).map_err(io::Error::other)
+}
+
+/// Write the `--output-manifest` inventory: one JSON object per copied file with its path relative
+/// to the output directory, size, mode, digest (keyed by the `--hash-algo` name) and the component
+/// it was copied from.
+fn write_output_manifest(path: &Path, output_dir: &Path, stats: &CopyStats, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files: BTreeMap<&Path, &'static str> = BTreeMap::new();
+    for (written, component) in stats.written.iter().zip(&stats.origins) {
+        files.insert(written, component);
+    }
+    let paths: Vec<PathBuf> = files.keys().map(|p| p.to_path_buf()).collect();
+    let digests = config.hash_algo.hash_files(&paths)?;
+    let mut entries = Vec::with_capacity(paths.len());
+    for ((file, component), digest) in files.into_iter().zip(digests) {
+        let meta = fs::metadata(file).map_err(io_context(format!("Failed to stat {}", file.display())))?;
+        let mut entry = serde_json::json!({
+            "path": file.strip_prefix(output_dir).unwrap_or(file).to_string_lossy(),
+            "size": meta.len(),
+            "mode": format!("{:04o}", meta.permissions().mode() & 0o7777),
+            "component": component,
+        });
+        entry[config.hash_algo.name()] = Value::String(digest);
+        entries.push(entry);
+    }
+    fs::write(path, serde_json::to_string_pretty(&entries)? + "\n")
+        .map_err(io_context(format!("Failed to write output manifest {}", path.display())))?;
+    config.status(format_args!("Wrote output manifest for {} files to {}", entries.len(), path.display()));
+    Ok(())
+}
+
+/// Write the `--file-list` index: every path written under the output directory, relative to it,
+/// one per line in byte order, so two runs of the same image give the same file.
+fn write_file_list(path: &Path, output_dir: &Path, stats: &CopyStats, config: &Config) -> io::Result<()> {
+    let files: BTreeSet<String> =
+        stats.written.iter().map(|file| file.strip_prefix(output_dir).unwrap_or(file).to_string_lossy().into_owned()).collect();
+    let mut list = String::new();
+    for file in &files {
+        list.push_str(file);
+        list.push('\n');
+    }
+    fs::write(path, list).map_err(io_context(format!("Failed to write file list {}", path.display())))?;
+    config.status(format_args!("Wrote the list of {} files to {}", files.len(), path.display()));
+    Ok(())
+}
+
+/// `--compress-format`: the compression of the tars `--archive` and `--split-archives` write.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ArchiveFormat {
+    /// In process with flate2; the default, readable everywhere.
+    Gzip,
+    /// Through the `zstd` program, as zstd layers are read; much smaller at high levels.
+    Zstd,
+    /// Uncompressed.
+    Tar,
+}
+
+impl ArchiveFormat {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            "tar" => Ok(Self::Tar),
+            _ => Err(format!("Unknown --compress-format '{}' (expected gzip, zstd or tar)", name)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::Tar => "tar",
+        }
+    }
+
+    /// File name endings, the first being the one written.
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::Gzip => &[".tar.gz", ".tgz"],
+            Self::Zstd => &[".tar.zst", ".tzst"],
+            Self::Tar => &[".tar"],
+        }
+    }
+
+    /// The `--compress-level` range, past which zstd wants `--ultra`; none for a plain tar.
+    fn levels(self) -> Option<(u32, u32)> {
+        match self {
+            Self::Gzip => Some((0, 9)),
+            Self::Zstd => Some((1, 19)),
+            Self::Tar => None,
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Self::Gzip => 6,
+            Self::Zstd => 3,
+            Self::Tar => 0,
+        }
+    }
+
+    fn describe(self, level: u32) -> String {
+        match self {
+            Self::Tar => "uncompressed".to_string(),
+            _ => format!("{} level {}", self.name(), level),
+        }
+    }
+}
+
+/// The compressing writer under an archive's `tar::Builder`.
+enum ArchiveEncoder {
+    Gzip(GzEncoder<File>),
+    /// `zstd` writing to the archive file, fed on its stdin.
+    Zstd(std::process::Child, std::process::ChildStdin),
+    Tar(File),
+}
+
+impl ArchiveEncoder {
+    fn new(file: File, format: ArchiveFormat, level: u32) -> io::Result<Self> {
+        Ok(match format {
+            ArchiveFormat::Gzip => Self::Gzip(GzEncoder::new(file, Compression::new(level))),
+            ArchiveFormat::Zstd => {
+                let mut child = spawn_tracked(
+                    Command::new("zstd").args([format!("-{}", level).as_str(), "-q", "-c"]).stdin(std::process::Stdio::piped()).stdout(file),
+                )
+                .map_err(io_context("Failed to run zstd for --compress-format zstd".to_string()))?;
+                let stdin = child.stdin.take().expect("piped stdin");
+                Self::Zstd(child, stdin)
+            }
+            ArchiveFormat::Tar => Self::Tar(file),
+        })
+    }
+
+    /// Flush the compressed stream, waiting for `zstd` to exit.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish().map(drop),
+            Self::Zstd(mut child, stdin) => {
+                drop(stdin);
+                let status = child.wait();
+                untrack(child.id());
+                match status? {
+                    status if status.success() => Ok(()),
+                    status => Err(io::Error::other(format!("zstd failed ({})", status))),
+                }
+            }
+            Self::Tar(file) => file.sync_all(),
+        }
+    }
+}
+
+impl Write for ArchiveEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(_, stdin) => stdin.write(buf),
+            Self::Tar(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(_, stdin) => stdin.flush(),
+            Self::Tar(file) => file.flush(),
+        }
+    }
+}
+
+/// Pack the output directory into a tar at `archive_path`, compressed as `--compress-format` says.
+///
+/// gzip levels 1-3 are several times faster than 9 on large runtime trees for a modestly larger
+/// archive, 6 (the default) is zlib's usual balance, and 0 stores the data uncompressed. zstd at
+/// 19 is far smaller than gzip at 9 for mostly-JS trees, at a much higher cost to write.
+fn write_archive(output_dir: &Path, archive_path: &Path, config: &Config) -> io::Result<()> {
+    let format = config.archive_format;
+    if !format.extensions().iter().any(|ext| archive_path.to_string_lossy().ends_with(ext)) {
+        config.warn(
+            "archive",
+            format!("{} doesn't end in {} (--compress-format {})", archive_path.display(), format.extensions()[0], format.name()),
+        );
+    }
+    config.log_fmt(format_args!("Writing archive {} ({})...", archive_path.display(), format.describe(config.compression_level)));
+    let file = File::create(archive_path).map_err(io_context(format!("Failed to create {}", archive_path.display())))?;
+    let encoder = ArchiveEncoder::new(file, format, config.compression_level)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    if config.deterministic.is_some() {
+        // `append_dir_all` adds entries in directory order, which differs between filesystems.
+        for entry in WalkDir::new(output_dir).min_depth(1).sort_by_file_name() {
+            let entry = entry.map_err(io::Error::from)?;
+            let relative = relative_path(entry.path(), output_dir)?;
+            append_archive_entry(&mut builder, entry.path(), relative, config)
+                .map_err(io_context(format!("Failed to archive {}", entry.path().display())))?;
+        }
+    } else {
+        builder
+            .append_dir_all("", output_dir)
+            .map_err(io_context(format!("Failed to archive {}", output_dir.display())))?;
+    }
+    builder.into_inner()?.finish()?;
+    config.status(format_args!("Wrote archive {}.", archive_path.display()));
+    Ok(())
+}
+
+/// Validate an `--s3-output` URL, returning it as `s3://bucket` or `s3://bucket/prefix`.
+#[cfg(feature = "s3")]
+fn parse_s3_url(url: &str) -> Result<String, String> {
+    let invalid = || format!("Invalid --s3-output '{}' (expected s3://bucket/prefix)", url);
+    let rest = url.strip_prefix("s3://").ok_or_else(invalid)?;
+    let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    let bucket_char = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.';
+    if bucket.is_empty() || !bucket.chars().all(bucket_char) {
+        return Err(invalid());
+    }
+    let prefix = prefix.trim_matches('/');
+    Ok(if prefix.is_empty() { format!("s3://{}", bucket) } else { format!("s3://{}/{}", bucket, prefix) })
+}
+
+/// Upload the output to `url` for `--s3-output`, keyed by each file's path relative to the output.
+///
+/// Uses the `aws` CLI, like the Docker and Encore steps use theirs, so credentials, region and
+/// profile come from the standard AWS environment and config chain. The local output is kept.
+#[cfg(feature = "s3")]
+fn upload_to_s3(output_dir: &Path, url: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    config.status(format_args!("Uploading {} to {}...", output_dir.display(), url));
+    let mut args: Vec<&OsStr> = ["s3", "cp", "--recursive", "--only-show-errors", "--no-follow-symlinks"].map(OsStr::new).to_vec();
+    args.extend([output_dir.as_os_str(), OsStr::new(url)]);
+    if let Some(endpoint) = &config.s3_endpoint {
+        args.extend([OsStr::new("--endpoint-url"), OsStr::new(endpoint)]);
+    }
+    run_command("aws", &args, None).map_err(|e| format!("Failed to upload the output to {}: {}", url, e))?;
+    let files = WalkDir::new(output_dir).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).count();
+    config.status(format_args!("Uploaded {} files to {}.", thousands(files as u64), url));
+    config.event("uploaded", serde_json::json!({ "url": url, "files": files }));
+    Ok(())
+}
+
+/// Add `path` to an archive as `name`. With `--deterministic` the entry is owned by 0:0 and
+/// carries the run's fixed timestamp, so the archive only depends on the output's contents.
+fn append_archive_entry<W: Write>(builder: &mut tar::Builder<W>, path: &Path, name: &Path, config: &Config) -> io::Result<()> {
+    let Some(epoch) = config.deterministic else {
+        return builder.append_path_with_name(path, name);
+    };
+    let metadata = fs::symlink_metadata(path)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&metadata, tar::HeaderMode::Complete);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(epoch.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+    if metadata.is_symlink() {
+        builder.append_link(&mut header, name, fs::read_link(path)?)
+    } else if metadata.is_file() {
+        builder.append_data(&mut header, name, File::open(path)?)
+    } else {
+        builder.append_data(&mut header, name, io::empty())
+    }
+}
+
+/// Components packed into `base.tar.gz` by `--split-archives` unless `--split-base-components` is given.
+const DEFAULT_SPLIT_BASE: &[&str] = &["runtimes", "node_modules"];
+
+/// Pack the output into `base.tar.gz`, holding the rarely-changing `--split-base-components`, and
+/// `app.tar.gz` with everything else (build, manifests, metadata), in `dir`, or `.tar.zst`/`.tar`
+/// per `--compress-format`. Each archive gets a `<name>.<hash-algo>` checksum file in `sha256sum` format.
+///
+/// Files go by the component they were copied from; files the run didn't copy, such as
+/// `.extraction-meta.json`, go to the app archive.
+fn write_split_archives(output_dir: &Path, dir: &Path, stats: &CopyStats, config: &Config) -> io::Result<()> {
+    fs::create_dir_all(dir).map_err(io_context(format!("Failed to create directory {}", dir.display())))?;
+    let base_files: HashSet<&Path> = stats
+        .written
+        .iter()
+        .zip(&stats.origins)
+        .filter(|(_, component)| config.split_base.iter().any(|name| name == *component))
+        .map(|(path, _)| path.as_path())
+        .collect();
+    let extension = config.archive_format.extensions()[0];
+    let mut archives = [(format!("base{}", extension), Vec::new()), (format!("app{}", extension), Vec::new())];
+    for entry in WalkDir::new(output_dir).min_depth(1).sort_by_file_name() {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_dir() {
+            let archive = if base_files.contains(entry.path()) { 0 } else { 1 };
+            archives[archive].1.push(entry.into_path());
+        }
+    }
+
+    for (name, files) in &archives {
+        let path = dir.join(name);
+        config.log_fmt(format_args!("Writing archive {} ({})...", path.display(), config.archive_format.describe(config.compression_level)));
+        let file = File::create(&path).map_err(io_context(format!("Failed to create {}", path.display())))?;
+        let mut builder = tar::Builder::new(ArchiveEncoder::new(file, config.archive_format, config.compression_level)?);
+        builder.follow_symlinks(false);
+        for file in files {
+            let relative = relative_path(file, output_dir)?;
+            append_archive_entry(&mut builder, file, relative, config).map_err(io_context(format!("Failed to archive {}", file.display())))?;
+        }
+        builder.into_inner()?.finish()?;
+        let digest = config.hash_algo.hash_file(&path)?;
+        let checksum = dir.join(format!("{}.{}", name, config.hash_algo.name()));
+        fs::write(&checksum, format!("{}  {}\n", digest, name)).map_err(io_context(format!("Failed to write {}", checksum.display())))?;
+        config.status(format_args!("Wrote archive {} ({} files, {} {}).", path.display(), files.len(), config.hash_algo.name(), digest));
+    }
+    Ok(())
+}
+
+/// Map each file under `root` to its sha256, keyed by relative path.
+fn tree_digests(root: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(root).min_depth(1) {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.file_type().is_file() && entry.file_name() != EXTRACTION_META {
+            files.push(entry.into_path());
+        }
+    }
+    let digests = sha256::hash_files(&files)?;
+    let mut tree = BTreeMap::new();
+    for (path, digest) in files.iter().zip(digests) {
+        let rel = relative_path(path, root)?;
+        tree.insert(rel.to_string_lossy().into_owned(), digest);
+    }
+    Ok(tree)
+}
+
+/// Fail unless every `--layer-contains` path exists in the extracted layer tree.
+///
+/// Paths are relative to the image root (a leading `/` is ignored); symlinks count even when dangling.
+fn check_layer_contains(layer_dir: &Path, paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut missing = Vec::new();
+    for path in paths {
+        if layer_dir.join(path.trim_start_matches('/')).symlink_metadata().is_err() {
+            missing.push(format!("  {}", path));
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!(
+            "Extracted layers are missing {} of {} --layer-contains paths:\n{}",
+            missing.len(),
+            paths.len(),
+            missing.join("\n")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Duplicate groups listed by `--report-duplicates`, largest waste first.
+const DUPLICATE_REPORT_TOP: usize = 10;
+
+/// Print how much of `root` is duplicated content, to judge whether `--dedupe` or `--cas-output`
+/// is worth enabling.
+///
+/// Only files sharing a size with another are hashed. Hardlinks to one inode are a single copy,
+/// so an output already deduplicated reports nothing left to reclaim.
+fn report_duplicates(root: &Path, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::MetadataExt;
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    let mut inodes = HashSet::new();
+    for entry in WalkDir::new(root).min_depth(1) {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_file() || entry.file_name() == EXTRACTION_META {
+            continue;
+        }
+        let metadata = entry.metadata().map_err(io::Error::from)?;
+        if metadata.len() > 0 && inodes.insert((metadata.dev(), metadata.ino())) {
+            by_size.entry(metadata.len()).or_default().push(entry.into_path());
+        }
+    }
+    let candidates: Vec<(u64, PathBuf)> =
+        by_size.into_iter().filter(|(_, paths)| paths.len() > 1).flat_map(|(size, paths)| paths.into_iter().map(move |p| (size, p))).collect();
+    let paths: Vec<PathBuf> = candidates.iter().map(|(_, path)| path.clone()).collect();
+    config.log_fmt(format_args!("Hashing {} files with a same-size twin", paths.len()));
+
+    let mut groups: HashMap<String, (u64, Vec<PathBuf>)> = HashMap::new();
+    for ((size, path), digest) in candidates.into_iter().zip(sha256::hash_files(&paths)?) {
+        groups.entry(digest).or_insert_with(|| (size, Vec::new())).1.push(path);
+    }
+    let mut groups: Vec<(u64, Vec<PathBuf>)> = groups.into_values().filter(|(_, paths)| paths.len() > 1).collect();
+    for (_, paths) in &mut groups {
+        paths.sort();
+    }
+    let wasted = |(size, paths): &(u64, Vec<PathBuf>)| size * (paths.len() as u64 - 1);
+    groups.sort_by(|a, b| wasted(b).cmp(&wasted(a)).then_with(|| a.1.cmp(&b.1)));
+
+    let reclaimable: u64 = groups.iter().map(wasted).sum();
+    let redundant: usize = groups.iter().map(|(_, paths)| paths.len() - 1).sum();
+    println!(
+        "Duplicate files: {} groups, {} redundant copies, {} reclaimable",
+        groups.len(),
+        redundant,
+        format_bytes(reclaimable)
+    );
+    for group @ (size, paths) in groups.iter().take(DUPLICATE_REPORT_TOP) {
+        println!("  {:>10} wasted  {} copies of {}", format_bytes(wasted(group)), paths.len(), format_bytes(*size));
+        for path in paths {
+            println!("      {}", path.strip_prefix(root).unwrap_or(path).display());
+        }
+    }
+    if groups.len() > DUPLICATE_REPORT_TOP {
+        println!("  ... and {} more groups", groups.len() - DUPLICATE_REPORT_TOP);
+    }
+    Ok(())
+}
+
+/// List the differences between an extracted output and a reference extraction.
+fn compare_trees(actual: &BTreeMap<String, String>, expected: &BTreeMap<String, String>) -> Vec<String> {
+    let mut discrepancies = Vec::new();
+    for (path, digest) in expected {
+        match actual.get(path) {
+            None => discrepancies.push(format!("missing: {}", path)),
+            Some(d) if d != digest => discrepancies.push(format!("differs: {}", path)),
+            Some(_) => {}
+        }
+    }
+    for path in actual.keys().filter(|p| !expected.contains_key(*p)) {
+        discrepancies.push(format!("unexpected: {}", path));
+    }
+    discrepancies
+}
+
+/// Extract a reference image the same way as the main run and diff it against `output_dir`.
+fn compare_with_image(
+    reference: &str,
+    output_dir: &Path,
+    temp_base: &Path,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    config.status(format_args!("Comparing output with reference image {}...", reference));
+    let work = Builder::new().prefix("reference_").tempdir_in(temp_base)?;
+    let tar_path = work.path().join("reference.tar");
+    let image_dir = work.path().join("image");
+    let layer_dir = work.path().join("layer");
+    let reference_output = work.path().join("output");
+    for dir in [&image_dir, &layer_dir, &reference_output] {
+        fs::create_dir_all(dir)?;
+    }
+    docker_save(reference, &tar_path, config)?;
+    run_command("tar", &[OsStr::new("xf"), tar_path.as_os_str()], Some(&image_dir))?;
+    extract_image(&image_dir, &layer_dir, &reference_output, config)?;
+
+    let discrepancies = compare_trees(&tree_digests(output_dir)?, &tree_digests(&reference_output)?);
+    if discrepancies.is_empty() {
+        config.status(format_args!("Output matches reference image {}.", reference));
+        return Ok(());
+    }
+    for discrepancy in &discrepancies {
+        eprintln!("  {}", discrepancy);
+    }
+    Err(format!(
+        "Output in {} differs from reference image {} ({} discrepancies)",
+        output_dir.display(),
+        reference,
+        discrepancies.len()
+    )
+    .into())
+}
+
+/// Extract the selected layers of an unpacked image tar into `layer_dir` and copy the encore
+/// components found there into `output_dir`.
+fn extract_image(image_dir: &Path, layer_dir: &Path, output_dir: &Path, config: &Config) -> Result<CopyStats, Box<dyn std::error::Error>> {
+    let manifest = parse_manifest(&locate_manifest(image_dir, None, config)?)?;
+    let mut merge = LayerMerge::new(config.merge_strategy);
+    for digest in select_layers(&manifest, config)? {
+        merge.start_layer(&digest);
+        extract_layer(&manifest.blob(&digest), layer_dir, Some(&mut merge), config)?;
+    }
+    merge.finish(layer_dir, config)?;
+    let encore_dirs = find_encore_dirs(layer_dir, config);
+    ensure_encore_artifacts(layer_dir, &encore_dirs)?;
+    let mut stats = CopyStats::default();
+    for encore_dir in encore_dirs {
+        stats.merge(copy_encore_components(&encore_dir, output_dir, config)?);
+    }
+    Ok(stats)
+}
+
+/// Set by the SIGINT handler installed in watch mode.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Paths under the watched tree whose churn is produced by builds, not edits.
+const WATCH_IGNORED: &[&str] = &[".git", ".encore", "node_modules", "encore_prod", "docker_extract_temp", "docker_extract_temp.lock"];
+
+/// Options naming a file or directory the run writes, left out of the watch wherever they point.
+const WATCH_OUTPUT_FLAGS: &[&str] = &[
+    "--output-dir",
+    "--tar-path",
+    "--log-file",
+    "--file-list",
+    "--metadata-out",
+    "--dockerfile-out",
+    "--output-manifest",
+    "--emit-systemd",
+    "--archive",
+    "--split-archives",
+    "--dump-layer",
+    "--cache-dir",
+];
+
+/// What `--watch` leaves out of the watched tree: the `WATCH_IGNORED` names, and the canonical
+/// paths of the run's own outputs, so writing them doesn't count as a change.
+struct WatchIgnore {
+    /// The watched directory, canonicalized.
+    root: PathBuf,
+    outputs: Vec<PathBuf>,
+}
+
+impl WatchIgnore {
+    fn new(dir: &Path, args: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let current_dir = env::current_dir()?;
+        let mut outputs = Vec::new();
+        for flag in WATCH_OUTPUT_FLAGS {
+            if let Some(path) = expanded_arg(args, flag)?.filter(|path| path != "-") {
+                outputs.push(canonical_path(&current_dir.join(path)));
+            }
+        }
+        // Without `--tar-path` the image is saved under a name with this process's pid.
+        outputs.push(canonical_path(&current_dir.join(format!("encoredocker-{}.tar", std::process::id()))));
+        Ok(WatchIgnore { root: canonical_path(dir), outputs })
+    }
+
+    /// Whether `path`, relative to the watched directory, is left out of the watch.
+    fn ignores(&self, path: &Path) -> bool {
+        if path.components().any(|c| WATCH_IGNORED.iter().any(|name| c.as_os_str() == *name)) {
+            return true;
+        }
+        let path = self.root.join(path);
+        self.outputs.iter().any(|output| path.starts_with(output))
+    }
+}
+
+/// `path` with symlinks resolved as far as it exists: a file not created yet is canonicalized
+/// through its parent.
+fn canonical_path(path: &Path) -> PathBuf {
+    if let Ok(resolved) = path.canonicalize() {
+        return resolved;
+    }
+    match (path.parent().and_then(|parent| parent.canonicalize().ok()), path.file_name()) {
+        (Some(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Events a `--watch` directory is watched for: anything that creates, edits, renames or
+/// removes an entry.
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_ATTRIB
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+/// How often a watch waiting for events checks for Ctrl-C.
+const WATCH_POLL: Duration = Duration::from_millis(100);
+
+/// What `TreeWatch::next` saw.
+#[derive(Debug, PartialEq)]
+enum WatchEvent {
+    Changed,
+    /// Nothing changed within the wait.
+    Quiet,
+    Interrupted,
+}
+
+/// Changes under a `--watch` directory, read from an inotify watch on it and on every directory
+/// below it that isn't ignored. Directories created later are watched as they appear.
+struct TreeWatch {
+    fd: libc::c_int,
+    root: PathBuf,
+    /// The directory each watch descriptor is on, relative to `root`.
+    dirs: HashMap<libc::c_int, PathBuf>,
+}
+
+impl TreeWatch {
+    fn new(root: &Path, ignore: &WatchIgnore) -> io::Result<Self> {
+        // SAFETY: inotify_init1 has no memory-safety preconditions.
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io_context("Failed to start watching for changes".to_string())(io::Error::last_os_error()));
+        }
+        let mut watch = TreeWatch { fd, root: root.to_path_buf(), dirs: HashMap::new() };
+        watch.add_tree(Path::new(""), ignore)?;
+        Ok(watch)
+    }
+
+    /// Watch the directory `relative` and the ones below it.
+    fn add_tree(&mut self, relative: &Path, ignore: &WatchIgnore) -> io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        let top = self.root.join(relative);
+        let dirs = WalkDir::new(&top).into_iter().filter_entry(|e| {
+            let path = relative.join(e.path().strip_prefix(&top).unwrap_or(e.path()));
+            e.file_type().is_dir() && (path.as_os_str().is_empty() || !ignore.ignores(&path))
+        });
+        for entry in dirs.filter_map(|e| e.ok()) {
+            let path = std::ffi::CString::new(entry.path().as_os_str().as_bytes())?;
+            // SAFETY: `path` is a NUL-terminated string that outlives the call.
+            let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                let e = io::Error::last_os_error();
+                // Removed again before it could be watched.
+                if e.kind() == io::ErrorKind::NotFound {
+                    continue;
+                }
+                return Err(io_context(format!("Failed to watch {}", entry.path().display()))(e));
+            }
+            self.dirs.insert(wd, relative.join(entry.path().strip_prefix(&top).unwrap_or(entry.path())));
+        }
+        Ok(())
+    }
+
+    /// Wait up to `within`, or until Ctrl-C with `None`, for a change that isn't ignored.
+    fn next(&mut self, within: Option<Duration>, ignore: &WatchIgnore) -> io::Result<WatchEvent> {
+        let started = Instant::now();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                return Ok(WatchEvent::Interrupted);
+            }
+            let left = within.map(|within| within.saturating_sub(started.elapsed()));
+            if left == Some(Duration::ZERO) {
+                return Ok(WatchEvent::Quiet);
+            }
+            let step = left.map_or(WATCH_POLL, |left| left.min(WATCH_POLL));
+            let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            // SAFETY: `pollfd` is a single valid pollfd for the duration of the call.
+            let ready = unsafe { libc::poll(&mut pollfd, 1, step.as_millis() as libc::c_int) };
+            // A Ctrl-C during the poll fails it with EINTR, which the next round picks up.
+            if ready <= 0 {
+                continue;
+            }
+            // SAFETY: `buf` is writable for its whole length.
+            let read = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+            if read > 0 && self.changed(&buf[..read as usize], ignore)? {
+                return Ok(WatchEvent::Changed);
+            }
+        }
+    }
+
+    /// Go through a batch of inotify events, watching directories they create; whether any of
+    /// them is a change outside the ignored paths.
+    fn changed(&mut self, events: &[u8], ignore: &WatchIgnore) -> io::Result<bool> {
+        use std::os::unix::ffi::OsStrExt;
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut changed = false;
+        let mut offset = 0;
+        while offset + header <= events.len() {
+            let field = |at: usize| u32::from_ne_bytes(events[offset + at..offset + at + 4].try_into().unwrap());
+            let (wd, mask, len) = (field(0) as libc::c_int, field(4), field(12) as usize);
+            let name: &[u8] = &events[offset + header..(offset + header + len).min(events.len())];
+            let name = std::ffi::OsStr::from_bytes(&name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())]);
+            offset += header + len;
+            // Events were dropped, so something may have changed.
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                changed = true;
+                continue;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&wd);
+                continue;
+            }
+            let Some(dir) = self.dirs.get(&wd) else { continue };
+            let path = dir.join(name);
+            if ignore.ignores(&path) {
+                continue;
+            }
+            if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                self.add_tree(&path, ignore)?;
+            }
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+
+impl Drop for TreeWatch {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned by this watch and closed only here.
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Re-runs the pipeline whenever `--watch <dir>` changes, until interrupted.
+///
+/// Changes are read from inotify, so renames and edits that keep a file's size are seen too; a
+/// run starts once the tree has been quiet for the `--debounce-ms` interval so a burst of saves
+/// triggers a single rebuild. Each run merges into the previous output (`--merge`), rewriting
+/// only the files that changed. Ctrl-C stops the run in flight, killing its subprocesses.
+fn watch(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = PathBuf::from(expanded_arg(args, "--watch")?.ok_or("--watch requires a directory")?);
+    if !dir.is_dir() {
+        return Err(format!("Watch directory {} does not exist", dir.display()).into());
+    }
+    let debounce = match arg_value(args, "--debounce-ms") {
+        Some(v) => Duration::from_millis(v.parse().map_err(|_| format!("Invalid --debounce-ms value: {}", v))?),
+        None => Duration::from_millis(500),
+    };
+    // SAFETY: `on_interrupt` only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as *const () as libc::sighandler_t);
+    }
+    // The handler can't take the `CHILDREN` lock, so this thread kills the run's subprocesses.
+    thread::spawn(|| loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            kill_children();
+        }
+        thread::sleep(WATCH_POLL);
+    });
+
+    let ignore = WatchIgnore::new(&dir, args)?;
+    let mut tree = TreeWatch::new(&dir, &ignore)?;
+    loop {
+        // A failed run is reported but doesn't end the loop; the next edit may fix it.
+        if let Err(e) = run(args) {
+            eprintln!("Error: {}", e);
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            break;
+        }
+        eprintln!("Watching {} for changes (Ctrl-C to stop)...", dir.display());
+        if tree.next(None, &ignore)? == WatchEvent::Interrupted {
+            break;
+        }
+        // Debounce: wait until the tree stops changing before rebuilding.
+        loop {
+            match tree.next(Some(debounce), &ignore)? {
+                WatchEvent::Changed => continue,
+                WatchEvent::Quiet => break,
+                WatchEvent::Interrupted => {
+                    eprintln!("Stopping watch.");
+                    return Ok(());
+                }
+            }
+        }
+        eprintln!("Change detected in {}, re-extracting...", dir.display());
+    }
+    eprintln!("Stopping watch.");
+    Ok(())
+}
+
+/// Returns `args` without any occurrence of the valued option `flag` (both `flag v` and `flag=v`).
+fn without_flag(args: &[String], flag: &str) -> Vec<String> {
+    let prefix = format!("{}=", flag);
+    let mut kept = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == flag {
+            iter.next();
+        } else if !arg.starts_with(&prefix) {
+            kept.push(arg.clone());
+        }
+    }
+    kept
+}
+
+/// Images named by repeated `--image` flags and `--images-from <file>` (one per line, `#` comments).
+fn batch_images(args: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut images = Vec::new();
+    for image in arg_values(args, "--image") {
+        images.push(expand_env(&image, has_flag(args, "--allow-unset"))?);
+    }
+    if let Some(list) = arg_value(args, "--images-from") {
+        let contents = fs::read_to_string(&list).map_err(|e| format!("Failed to read --images-from {}: {}", list, e))?;
+        images.extend(
+            contents
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or("").trim())
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+    }
+    Ok(images)
+}
+
+/// Run the pipeline once per image, each into `<output-dir>/<image>`, and report a tally.
+fn run_batch(args: &[String], images: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let output_root = arg_value(args, "--output-dir").unwrap_or_else(|| "encore_prod".to_string());
+    let mut base_args = without_flag(args, "--image");
+    base_args = without_flag(&base_args, "--images-from");
+    base_args = without_flag(&base_args, "--output-dir");
+    let mut failed = Vec::new();
+    for image in images {
+        let subdir: String = image.chars().map(|c| if matches!(c, '/' | ':' | '@') { '_' } else { c }).collect();
+        let mut image_args = base_args.clone();
+        image_args.extend(["--image".to_string(), image.clone()]);
+        image_args.extend(["--output-dir".to_string(), Path::new(&output_root).join(subdir).display().to_string()]);
+        eprintln!("==> {}", image);
+        if let Err(e) = run(&image_args) {
+            eprintln!("Error: {}: {}", image, e);
+            failed.push(image.as_str());
+        }
+    }
+    eprintln!("Batch: {} succeeded, {} failed", images.len() - failed.len(), failed.len());
+    if !failed.is_empty() {
+        return Err(format!("Failed images: {}", failed.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// Read the first member of the image tar at `tar_path` whose path (without a leading `./`)
+/// satisfies `wanted`, without unpacking anything else.
+fn read_tar_member(tar_path: &Path, wanted: impl Fn(&Path) -> bool) -> io::Result<Option<(PathBuf, Vec<u8>)>> {
+    let file = File::open(tar_path).map_err(io_context(format!("Failed to open {}", tar_path.display())))?;
+    let mut archive = tar::Archive::new(file);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let path = path.strip_prefix(".").unwrap_or(&path).to_path_buf();
+        if entry.header().entry_type().is_file() && wanted(&path) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            return Ok(Some((path, contents)));
+        }
+    }
+    Ok(None)
+}
+
+/// Locate the image config blob in an image tar through its `manifest.json` (or OCI `index.json`).
+fn image_config_from_tar(tar_path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
+    let named = |name: &'static str| move |path: &Path| path.file_name().is_some_and(|n| n == name);
+    let blob = |root: &Path, digest: &str| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let path = root.join("blobs/sha256").join(strip_digest_prefix(digest));
+        let found = read_tar_member(tar_path, |p| p == path)?;
+        Ok(found.ok_or_else(|| format!("Blob {} not found in {}", path.display(), tar_path.display()))?.1)
+    };
+    if let Some((path, contents)) = read_tar_member(tar_path, named("manifest.json"))? {
+        let root = path.parent().unwrap_or(Path::new(""));
+        let manifest: Vec<Value> = serde_json::from_slice(&contents)?;
+        let config = manifest.first().and_then(|m| m["Config"].as_str()).ok_or("No Config found in manifest")?;
+        let config_path = root.join(config);
+        let (_, contents) = read_tar_member(tar_path, |p| p == config_path)?
+            .ok_or_else(|| format!("Image config {} not found in {}", config_path.display(), tar_path.display()))?;
+        return Ok(serde_json::from_slice(&contents)?);
+    }
+    let (path, contents) = read_tar_member(tar_path, named("index.json"))?
+        .ok_or_else(|| format!("No manifest.json or index.json found in {}", tar_path.display()))?;
+    let root = path.parent().unwrap_or(Path::new(""));
+    let mut document: Value = serde_json::from_slice(&contents)?;
+    while document.get("layers").is_none() {
+        let digest = document["manifests"][0]["digest"].as_str().ok_or("No manifests found in OCI index")?;
+        document = serde_json::from_slice(&blob(root, digest)?)?;
+    }
+    let digest = document["config"]["digest"].as_str().ok_or("No config found in OCI manifest")?;
+    Ok(serde_json::from_slice(&blob(root, digest)?)?)
+}
+
+/// `show-config <image|tar>`: pretty-print an image's config (Entrypoint, Cmd, Env, ...) to stdout.
+///
+/// An existing file is read as an image tar; anything else is saved from Docker first.
+fn show_config(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let target = args.get(2).ok_or("Usage: show-config <image|tar>")?;
+    let config = Config::new(args)?;
+    let image_config = if Path::new(target).is_file() {
+        image_config_from_tar(Path::new(target))?
+    } else {
+        let temp = tempfile::tempdir()?;
+        let tar_path = temp.path().join("image.tar");
+        check_docker_daemon(config.runtime)?;
+        docker_save(target, &tar_path, &config)?;
+        image_config_from_tar(&tar_path)?
+    };
+    println!("{}", serde_json::to_string_pretty(&image_config)?);
+    Ok(())
+}
+
+/// An output directory that is a symlink (to a mounted volume, say) is only written through
+/// with `--follow-output-symlink`, and then at its target, so nothing operates on the link itself.
+fn resolve_output_symlink(output: PathBuf, follow: bool, config: &Config) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let Ok(link) = fs::read_link(&output) else {
+        return Ok(output);
+    };
+    if !follow {
+        return Err(format!(
+            "Output directory {} is a symlink to {} (pass --follow-output-symlink to write into its target)",
+            output.display(),
+            link.display()
+        )
+        .into());
+    }
+    let target = fs::canonicalize(&output)
+        .map_err(|e| format!("Output directory {} is a symlink to {}, which can't be resolved: {}", output.display(), link.display(), e))?;
+    if !target.is_dir() {
+        return Err(format!("Output directory {} is a symlink to {}, which is not a directory", output.display(), target.display()).into());
+    }
+    config.status(format_args!("Output directory {} is a symlink; writing into {}", output.display(), target.display()));
+    Ok(target)
+}
+
+/// Metadata files `inspect` reads out of the selected layers' `encore` directory.
+const INSPECT_METADATA: &[&str] = &["build-info.json", "infra.config.json", "meta"];
+
+/// `inspect (--from-tar <tar> | --image <ref>)`: print what the pipeline would work from as one
+/// JSON document on stdout: the manifest, the layers with sizes, the layers selected, and the
+/// Encore metadata files in those layers.
+///
+/// The layers are only streamed through for the metadata files, never unpacked.
+fn inspect(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::new(args)?;
+    let temp = tempfile::tempdir()?;
+    let (source, tar_path) = match (arg_value(args, "--from-tar"), expanded_arg(args, "--image")?) {
+        (Some(_), Some(_)) => return Err("inspect takes --from-tar or --image, not both".into()),
+        (Some(tar), None) => (tar.clone(), PathBuf::from(tar)),
+        (None, Some(image)) => {
+            let image = image.trim().to_string();
+            validate_image_reference(&image)?;
+            let tar_path = temp.path().join("image.tar");
+            check_docker_daemon(config.runtime)?;
+            docker_save(&image, &tar_path, &config)?;
+            (image, tar_path)
+        }
+        (None, None) => return Err("Usage: inspect --from-tar <tar> | --image <ref>".into()),
+    };
+    let image_dir = temp.path().join("image");
+    fs::create_dir_all(&image_dir)?;
+    run_command("tar", &[OsStr::new("xf"), tar_path.as_os_str()], Some(&image_dir))?;
+    let manifest_path = locate_manifest(&image_dir, arg_value(args, "--manifest-path").as_deref(), &config)?;
+    let document: Value = from_reader(BufReader::new(File::open(&manifest_path)?))?;
+    let manifest = parse_manifest(&manifest_path)?;
+    let mut layers = Vec::new();
+    for digest in &manifest.layers {
+        let size = match manifest.layer_sizes.iter().find(|(d, _)| d == digest) {
+            Some((_, size)) => *size,
+            None => fs::metadata(manifest.blob(digest)).map_err(io_context(format!("Failed to read layer {}", digest)))?.len(),
+        };
+        layers.push(serde_json::json!({ "digest": digest, "size": size }));
+    }
+    let selected = select_layers(&manifest, &config)?;
+
+    // Later layers win, as they would in the extracted tree.
+    let mut metadata = serde_json::Map::new();
+    for digest in &selected {
+        for_each_layer_entry(&manifest.blob(digest), &config, |mut entry| {
+            let path = entry.path()?.into_owned();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()).filter(|n| INSPECT_METADATA.contains(n)) else {
+                return Ok(());
+            };
+            if !entry.header().entry_type().is_file() || path.parent().and_then(Path::file_name).is_none_or(|dir| dir != "encore") {
+                return Ok(());
+            }
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            let value = match serde_json::from_slice(&contents) {
+                Ok(value) if name.ends_with(".json") => value,
+                _ => match String::from_utf8(contents) {
+                    Ok(text) => Value::String(text),
+                    Err(e) => serde_json::json!({ "binary": true, "size": e.as_bytes().len() }),
+                },
+            };
+            metadata.insert(name.to_string(), value);
+            Ok(())
+        })?;
+    }
+
+    let report = serde_json::json!({
+        "source": source,
+        "manifest_format": manifest.format.name(),
+        "manifest": document,
+        "repo_tags": manifest.repo_tags,
+        "layers": layers,
+        "selected_layers": selected,
+        "metadata": metadata,
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// The `--selftest` fixture layer: each file's path in the image, path in the output and contents.
+const SELFTEST_FILES: &[(&str, &str, &str)] = &[
+    ("encore/runtimes/js/encore-runtime", "runtimes/js/encore-runtime", "#!/bin/sh\necho selftest\n"),
+    ("encore/build-info.json", "build-info.json", "{\"encore_compiler_version\":\"v1.40.0\",\"git_sha\":\"selftest\"}\n"),
+    (
+        "encore/infra.config.json",
+        "infra.config.json",
+        "{\"metadata\":{\"app_id\":\"selftest\",\"env_name\":\"local\"},\"sql_servers\":[],\"pubsub\":[],\"secrets\":{}}\n",
+    ),
+    ("encore/meta", "meta", "selftest-meta\n"),
+    ("workspace/apps/selftest/.encore/build/combined/main.mjs", "artifacts/build/combined/main.mjs", "console.log(\"selftest\");\n"),
+    ("workspace/apps/selftest/.encore/manifest.json", "artifacts/manifest.json", "{}\n"),
+];
+
+fn append_fixture<W: Write>(builder: &mut tar::Builder<W>, path: &str, data: &[u8], mode: u32) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(mode);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+/// Build the `--selftest` image tar in memory, laid out like `docker save` output: a manifest,
+/// a config blob and one gzip layer holding `SELFTEST_FILES`.
+fn selftest_image() -> io::Result<Vec<u8>> {
+    let mut layer = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (image_path, _, contents) in SELFTEST_FILES {
+        let mode = if image_path.ends_with("encore-runtime") { 0o755 } else { 0o644 };
+        append_fixture(&mut layer, image_path, contents.as_bytes(), mode)?;
+    }
+    let layer = layer.into_inner()?.finish()?;
+    let image_config = serde_json::to_vec(&serde_json::json!({
+        "architecture": host_docker_arch(),
+        "os": "linux",
+        "config": { "Cmd": ["/encore/runtimes/js/encore-runtime"] },
+        "rootfs": { "type": "layers", "diff_ids": [] },
+    }))?;
+    let digest = |data: &[u8]| {
+        let mut hasher = sha256::Sha256::new();
+        hasher.update(data);
+        sha256::to_hex(&hasher.finalize())
+    };
+    let (layer_digest, config_digest) = (digest(&layer), digest(&image_config));
+    let manifest = serde_json::to_vec(&serde_json::json!([{
+        "Config": format!("blobs/sha256/{}", config_digest),
+        "RepoTags": ["encorebaremetal-selftest:latest"],
+        "Layers": [format!("blobs/sha256/{}", layer_digest)],
+        "LayerSources": {
+            format!("sha256:{}", layer_digest): {
+                "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                "size": layer.len(),
+                "digest": format!("sha256:{}", layer_digest),
+            },
+        },
+    }]))?;
+
+    let mut image = tar::Builder::new(Vec::new());
+    append_fixture(&mut image, "manifest.json", &manifest, 0o644)?;
+    append_fixture(&mut image, &format!("blobs/sha256/{}", config_digest), &image_config, 0o644)?;
+    append_fixture(&mut image, &format!("blobs/sha256/{}", layer_digest), &layer, 0o644)?;
+    image.into_inner()
+}
+
+/// `--selftest`: run the unpack, parse, extract and copy pipeline on a small fixture image built
+/// into the binary and check that the expected files come out intact.
+///
+/// Neither Docker nor Encore is needed, only the `tar` a normal run uses. Settings are the
+/// defaults, so flags meant for real runs can't change the expected layout.
+fn selftest(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::new(&args[..1])?;
+    let scratch = Builder::new().prefix("encorebaremetal-selftest-").tempdir()?;
+    let tar_path = scratch.path().join("image.tar");
+    let image_dir = scratch.path().join("image");
+    let layer_dir = scratch.path().join("layer");
+    let output_dir = scratch.path().join("output");
+    for dir in [&image_dir, &layer_dir, &output_dir] {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&tar_path, selftest_image()?)?;
+    run_command("tar", &[OsStr::new("xf"), tar_path.as_os_str()], Some(&image_dir))?;
+    let stats = extract_image(&image_dir, &layer_dir, &output_dir, &config)?;
+
+    // The check lines go to stdout, unlike the status lines `config` colors for stderr.
+    let color = color_enabled(args, io::stdout().is_terminal());
+    let mut failures = 0;
+    for (_, output_path, contents) in SELFTEST_FILES {
+        let path = output_dir.join(output_path);
+        let problem = match fs::read(&path) {
+            Err(_) => Some("missing"),
+            Ok(data) if data != contents.as_bytes() => Some("contents differ"),
+            Ok(_) if output_path.ends_with("encore-runtime") && fs::metadata(&path)?.permissions().mode() & 0o111 == 0 => {
+                Some("not executable")
+            }
+            Ok(_) => None,
+        };
+        match problem {
+            Some(problem) => {
+                failures += 1;
+                println!("  {} {} ({})", paint("FAIL", "31", color), output_path, problem);
+            }
+            None => println!("  {} {}", paint("ok  ", "32", color), output_path),
+        }
+    }
+    if failures > 0 {
+        return Err(format!("Selftest failed: {} of {} checks", failures, SELFTEST_FILES.len()).into());
+    }
+    println!("{} ({} files extracted)", paint("Selftest passed", "32", color), stats.files);
+    Ok(())
+}
+
+/// Usage line of the `bench` subcommand.
+const BENCH_USAGE: &str = "Usage: bench <image.tar> [--iterations N] [--warmup N] [--unpack shell|crate]";
+
+/// `bench <image.tar>`: time the extraction pipeline (unpack, layer extraction, component copy)
+/// against a saved image tar, reporting mean/median/p95 wall time and throughput.
+///
+/// Every run works in a fresh scratch directory under the current directory, which is removed
+/// outside the timed section. OS cache effects are deliberately out of scope: after the warmup
+/// runs the tar and its blobs are normally in the page cache, so the numbers measure warm
+/// extraction. Drop caches between runs (`echo 3 > /proc/sys/vm/drop_caches`) to time cold reads.
+fn bench(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let tar_path = args.get(2).filter(|a| !a.starts_with("--")).ok_or(BENCH_USAGE)?;
+    if !Path::new(tar_path).is_file() {
+        return Err(format!("Image tar not found: {}", tar_path).into());
+    }
+    let count = |flag: &str, default: usize| -> Result<usize, String> {
+        match arg_value(args, flag) {
+            Some(v) => v.parse().map_err(|_| format!("Invalid {} value: {}", flag, v)),
+            None => Ok(default),
+        }
+    };
+    let iterations = count("--iterations", 5)?;
+    if iterations == 0 {
+        return Err("--iterations must be at least 1".into());
+    }
+    let warmup = count("--warmup", 1)?;
+    // `shell` is the `tar xf` used by a normal run; `crate` is the in-process unpack of `--tar-stdin`.
+    let crate_unpack = match arg_value(args, "--unpack").as_deref() {
+        None | Some("shell") => false,
+        Some("crate") => true,
+        Some(other) => return Err(format!("Invalid --unpack value: {} (expected shell or crate)", other).into()),
+    };
+    let tar_bytes = fs::metadata(tar_path)?.len();
+    let base = env::current_dir()?;
+
+    println!(
+        "Benchmarking {} ({}) with {} unpack: {} warmup + {} timed runs...",
+        tar_path,
+        format_bytes(tar_bytes),
+        if crate_unpack { "crate" } else { "shell" },
+        warmup,
+        iterations
+    );
+    let mut times = Vec::with_capacity(iterations);
+    let mut files = 0;
+    for run in 0..warmup + iterations {
+        // A fresh config per run, so the dedupe index and destination claims don't carry over.
+        let config = Config::new(args)?;
+        let scratch = Builder::new().prefix("bench_").tempdir_in(&base)?;
+        let image_dir = scratch.path().join("image");
+        let layer_dir = scratch.path().join("layer");
+        let output_dir = scratch.path().join("output");
+        for dir in [&image_dir, &layer_dir, &output_dir] {
+            fs::create_dir_all(dir)?;
+        }
+
+        let start = Instant::now();
+        if crate_unpack {
+            let file = File::open(tar_path).map_err(io_context(format!("Failed to open {}", tar_path)))?;
+            tar::Archive::new(BufReader::new(file)).unpack(&image_dir)?;
+        } else {
+            run_command("tar", &["xf", tar_path], Some(&image_dir))?;
+        }
+        let stats = extract_image(&image_dir, &layer_dir, &output_dir, &config)?;
+        let elapsed = start.elapsed();
+        drop(scratch);
+
+        if run < warmup {
+            println!("  warmup {}: {:.3}s", run + 1, elapsed.as_secs_f64());
+            continue;
+        }
+        println!("  run {}: {:.3}s, {} files", run + 1 - warmup, elapsed.as_secs_f64(), thousands(stats.files));
+        files = stats.files;
+        times.push(elapsed);
+    }
+
+    times.sort();
+    let secs: Vec<f64> = times.iter().map(Duration::as_secs_f64).collect();
+    let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+    let mid = secs.len() / 2;
+    let median = if secs.len().is_multiple_of(2) { (secs[mid - 1] + secs[mid]) / 2.0 } else { secs[mid] };
+    // Nearest-rank percentile.
+    let p95 = secs[(secs.len() * 95).div_ceil(100) - 1];
+    println!("Wall time: mean {:.3}s, median {:.3}s, p95 {:.3}s", mean, median, p95);
+    println!(
+        "Throughput (median): {}/s of image tar, {} files/s",
+        format_bytes((tar_bytes as f64 / median) as u64),
+        thousands((files as f64 / median) as u64)
+    );
+    Ok(())
+}
+
+/// The `--log-file`, shared by the console tee threads and the lines only the log receives.
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Append a line to the `--log-file`, if there is one. Used for debug and trace lines the
+/// console doesn't show; everything the console shows reaches the log through `ConsoleTee`.
+fn log_to_file(args: std::fmt::Arguments<'_>) {
+    if let Some(file) = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let _ = writeln!(file, "{}", args);
+    }
+}
+
+/// Copies everything written to stdout and stderr, by this process or a child inheriting them,
+/// into the `--log-file`.
+///
+/// Each descriptor is replaced by a pipe, drained by a thread that passes the bytes on to the
+/// original descriptor and appends them to the log. As stdout is then a pipe, automatic
+/// `--color` is off, which keeps escape codes out of the log. `finish` must run before exiting,
+/// or the output still in the pipes is lost.
+struct ConsoleTee {
+    /// Each redirected descriptor with a duplicate of the one it replaced.
+    saved: Vec<(libc::c_int, libc::c_int)>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ConsoleTee {
+    fn start(path: &Path) -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+        let file = File::create(path).map_err(io_context(format!("Failed to create log file {}", path.display())))?;
+        *LOG_FILE.lock().unwrap() = Some(file);
+        let mut tee = ConsoleTee { saved: Vec::new(), threads: Vec::new() };
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            let mut pipe = [0; 2];
+            // SAFETY: plain descriptor calls on descriptors this process owns; each one checked.
+            let (reader, console) = unsafe {
+                if libc::pipe(pipe.as_mut_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let (saved, console) = (libc::dup(fd), libc::dup(fd));
+                if saved < 0 || console < 0 || libc::dup2(pipe[1], fd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                libc::close(pipe[1]);
+                tee.saved.push((fd, saved));
+                (File::from_raw_fd(pipe[0]), File::from_raw_fd(console))
+            };
+            tee.threads.push(thread::spawn(move || {
+                let (mut reader, mut console) = (reader, console);
+                let mut buf = [0u8; 8192];
+                while let Ok(n @ 1..) = reader.read(&mut buf) {
+                    let _ = console.write_all(&buf[..n]);
+                    if let Some(file) = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+                        let _ = file.write_all(&buf[..n]);
+                    }
+                }
+            }));
+        }
+        Ok(tee)
+    }
+
+    /// Restore the original descriptors and wait for the threads to drain the pipes.
+    fn finish(self) {
+        let _ = io::stdout().flush();
+        for (fd, saved) in self.saved {
+            // SAFETY: `saved` is a descriptor `start` duplicated and nothing else uses.
+            unsafe {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+        }
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The command-line entry point: run with the process arguments and exit non-zero on failure.
+pub fn cli_main() {
+    let args: Vec<String> = env::args().collect();
+    let tee = match arg_value(&args, "--log-file") {
+        Some(path) => match ConsoleTee::start(Path::new(&path)) {
+            Ok(tee) => Some(tee),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let result = match arg_value(&args, "--timeout") {
+        Some(budget) => match parse_duration(&budget) {
+            Ok(budget) => run_with_timeout(&args, budget),
+            Err(e) => Err(format!("Invalid --timeout value: {}", e).into()),
+        },
+        None => dispatch(&args),
+    };
+    // Report errors with their Display form so path context isn't buried in Debug output.
+    if let Err(e) = &result {
+        eprintln!("{} {}", paint("Error:", "31", color_enabled(&args, io::stderr().is_terminal())), e);
+        if let Some(hook) = arg_value(&args, "--on-error-hook") {
+            run_error_hook(&hook, &e.to_string());
+        }
+    }
+    if let Some(tee) = tee {
+        tee.finish();
+    }
+    if result.is_err() {
+        std::process::exit(if TIMED_OUT.load(Ordering::SeqCst) { EXIT_TIMEOUT } else { 1 });
+    }
+}
+
+/// Run the subcommand or mode the arguments select.
+fn dispatch(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if args.get(1).is_some_and(|a| a == "show-config") {
+        show_config(args)
+    } else if args.get(1).is_some_and(|a| a == "bench") {
+        bench(args)
+    } else if args.get(1).is_some_and(|a| a == "inspect") {
+        inspect(args)
+    } else if args.get(1).is_some_and(|a| a == "doctor") {
+        doctor(args)
+    } else if has_flag(args, "--selftest") {
+        selftest(args)
+    } else if has_flag(args, "--watch") {
+        watch(args)
+    } else if has_flag(args, "--images-from") || arg_values(args, "--image").len() > 1 {
+        batch_images(args).and_then(|images| run_batch(args, &images))
+    } else {
+        run(args)
+    }
+}
+
+/// How long a timed-out run gets to notice and clean up its scratch space before exiting anyway.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// `--timeout`: run `dispatch` on a worker and give up once `budget` has passed.
+///
+/// Expiry kills the subprocess in flight, which fails the stage waiting on it, and in-process
+/// extraction and copying stop at their next entry or file. The pipeline then removes its scratch
+/// space, and an output directory the run created, before the error is returned. A run that
+/// hasn't returned within `TIMEOUT_GRACE` has the paths in `TIMEOUT_REMOVALS` removed for it.
+fn run_with_timeout(args: &[String], budget: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let worker_args = args.to_vec();
+    // Errors cross threads as strings; only their Display form is ever reported.
+    thread::spawn(move || {
+        let _ = sender.send(dispatch(&worker_args).map_err(|e| e.to_string()));
+    });
+    match receiver.recv_timeout(budget) {
+        Ok(result) => result.map_err(Into::into),
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Err("The run panicked".into()),
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            TIMED_OUT.store(true, Ordering::SeqCst);
+            kill_children();
+            if receiver.recv_timeout(TIMEOUT_GRACE).is_err() {
+                kill_children();
+                for path in TIMEOUT_REMOVALS.lock().unwrap_or_else(|e| e.into_inner()).drain(..) {
+                    let _ = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+                }
+            }
+            Err(format!("Run exceeded --timeout {}", arg_value(args, "--timeout").unwrap_or_default()).into())
+        }
+    }
+}
+
+/// Pipeline phase the run is in, reported to `--on-error-hook` as `ENCORE_ERROR_PHASE`.
+static PHASE: Mutex<&str> = Mutex::new("setup");
+
+fn enter_phase(phase: &'static str) {
+    *PHASE.lock().unwrap() = phase;
+}
+
+/// Run the `--on-error-hook` command through `sh -c` with the error in `ENCORE_ERROR` and the
+/// phase it happened in in `ENCORE_ERROR_PHASE`. The hook failing is only reported, so the
+/// original error still decides the exit status.
+fn run_error_hook(hook: &str, error: &str) {
+    let phase = *PHASE.lock().unwrap_or_else(|e| e.into_inner());
+    let status = Command::new("sh").args(["-c", hook]).env("ENCORE_ERROR", error).env("ENCORE_ERROR_PHASE", phase).status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Warning: --on-error-hook '{}' failed with {}", hook, status),
+        Err(e) => eprintln!("Warning: Failed to run --on-error-hook '{}': {}", hook, e),
+    }
+}
+
+/// Image tag used without `--image` when the project doesn't name its app.
+const FALLBACK_IMAGE_TAG: &str = "my_image:latest";
+
+/// The image tag for a run without `--image`: `<app>:latest`, named after the app `id` in the
+/// project's `encore.app` or the `app_id` in its `build-info.json`, falling back to
+/// `FALLBACK_IMAGE_TAG` with a warning when neither names one.
+///
+/// `encore.app` may carry `//` comment lines, which are dropped before parsing. The name is
+/// lowercased and anything a Docker repository name can't hold becomes `-`.
+fn default_image_tag(project_dir: &Path, config: &Config) -> String {
+    let read_field = |file: &str, key: &str| -> Option<String> {
+        let contents = fs::read_to_string(project_dir.join(file)).ok()?;
+        let json: String = contents.lines().filter(|line| !line.trim_start().starts_with("//")).collect::<Vec<_>>().join("\n");
+        let document: Value = serde_json::from_str(&json).ok()?;
+        let name: String = document[key]
+            .as_str()?
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '-' })
+            .collect();
+        let name = name.trim_matches(|c: char| !c.is_ascii_alphanumeric());
+        (!name.is_empty()).then(|| name.to_string())
+    };
+    for (file, key) in [("encore.app", "id"), ("build-info.json", "app_id")] {
+        if let Some(app) = read_field(file, key) {
+            let tag = format!("{}:latest", app);
+            config.status(format_args!("Using image tag {} from {} (pass --image to override)", tag, file));
+            return tag;
+        }
+    }
+    let message = format!("No app name found in encore.app or build-info.json; using image tag {} (pass --image)", FALLBACK_IMAGE_TAG);
+    if config.tar_stdin {
+        // A piped-in image is only named by the tag, never built or pulled by it.
+        config.log(&message);
+    } else {
+        config.warn("default-image", message);
+    }
+    FALLBACK_IMAGE_TAG.to_string()
+}
+
+/// Check `reference` against Docker's reference grammar, `[registry[:port]/]path[:tag][@digest]`,
+/// so a name `docker` would reject fails before the build rather than in the middle of it.
+///
+/// The first `/`-separated component is a registry if it has a `.` or `:` or is `localhost`.
+/// Path components are lowercase alphanumerics joined by `.`, `_`, `__` or runs of `-`.
+fn validate_image_reference(reference: &str) -> Result<(), EncoreError> {
+    let invalid = |reason: String| EncoreError::InvalidImageReference { reference: reference.to_string(), reason };
+    let (name_and_tag, digest) = match reference.split_once('@') {
+        Some((name_and_tag, digest)) => (name_and_tag, Some(digest)),
+        None => (reference, None),
+    };
+    // A `:` after the last `/` starts the tag; one before it is a registry port.
+    let (name, tag) = match name_and_tag.rfind(':') {
+        Some(i) if !name_and_tag[i..].contains('/') => (&name_and_tag[..i], Some(&name_and_tag[i + 1..])),
+        _ => (name_and_tag, None),
+    };
+    if name.is_empty() {
+        return Err(invalid("missing repository name".to_string()));
+    }
+    if name.len() > 255 {
+        return Err(invalid("repository name is longer than 255 characters".to_string()));
+    }
+    let mut path: Vec<&str> = name.split('/').collect();
+    if path.len() > 1 && (path[0].contains(['.', ':']) || path[0] == "localhost") {
+        let registry = path.remove(0);
+        let (host, port) = match registry.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (registry, None),
+        };
+        let host_ok = !host.is_empty()
+            && host.split('.').all(|label| {
+                !label.is_empty() && !label.starts_with('-') && !label.ends_with('-') && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        let port_ok = port.is_none_or(|port| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()));
+        if !host_ok || !port_ok {
+            return Err(invalid(format!("invalid registry '{}' (expected host[:port])", registry)));
+        }
+    }
+    for component in path {
+        if component.is_empty() {
+            return Err(invalid("repository name has an empty path component".to_string()));
+        }
+        if component.chars().any(|c| c.is_ascii_uppercase()) {
+            let suggestion = format!("{}{}", name.to_lowercase(), &reference[name.len()..]);
+            return Err(invalid(format!("repository name must be lowercase (try '{}')", suggestion)));
+        }
+        let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+        let valid = component.starts_with(alphanumeric)
+            && component.ends_with(alphanumeric)
+            && component.split(alphanumeric).all(|sep| matches!(sep, "" | "." | "_" | "__") || sep.chars().all(|c| c == '-'));
+        if !valid {
+            return Err(invalid(format!(
+                "invalid repository name component '{}' (lowercase letters and digits, separated by '.', '_', '__' or '-')",
+                component
+            )));
+        }
+    }
+    if let Some(tag) = tag {
+        let valid = (1..=128).contains(&tag.len())
+            && !tag.starts_with(['.', '-'])
+            && tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+        if !valid {
+            return Err(invalid(format!(
+                "invalid tag '{}' (up to 128 letters, digits, '_', '.' and '-', not starting with '.' or '-')",
+                tag
+            )));
+        }
+    }
+    if let Some(digest) = digest {
+        let valid = match digest.split_once(':') {
+            Some((algorithm, hex)) => {
+                !algorithm.is_empty()
+                    && algorithm.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '+' | '.' | '_' | '-'))
+                    && hex.len() >= 32
+                    && hex.chars().all(|c| c.is_ascii_hexdigit())
+                    && (algorithm != "sha256" || hex.len() == 64)
+            }
+            None => false,
+        };
+        if !valid {
+            return Err(invalid(format!("invalid digest '{}' (expected sha256:<64 hex digits>)", digest)));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a pipeline stage hands over to the next one, or ends the run early (`--extract-only`,
+/// `--print-layer-tree`, ...) with only the cleanup left to do.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Flow {
+    Continue,
+    Stop,
+}
+
+/// A step of the pipeline `run` goes through, reading what earlier stages left in the context.
+type Stage = fn(&mut Context) -> Result<Flow, Box<dyn std::error::Error>>;
+
+/// The settings and paths of a single run, shared by the pipeline stages, along with each
+/// stage's results for the ones after it.
+struct Context<'a> {
+    args: &'a [String],
+    config: Config,
+    current_dir: PathBuf,
+    /// Tag Docker operations use.
+    image_tag: String,
+    /// Name recorded in provenance metadata (`--rename-image`).
+    recorded_image: String,
+    /// Where the tree is copied to; a staging tree once the copy stage sees `--output-format oci`.
+    final_output: PathBuf,
+    /// Where the OCI image layout is written, with `--output-format oci`.
+    layout_dir: Option<PathBuf>,
+    temp_base: PathBuf,
+    tar_path: PathBuf,
+    /// Both intermediates are deleted once the run succeeds unless asked to keep them.
+    keep_temp: bool,
+    keep_tar: bool,
+    /// With --resume, scratch state lives at a fixed path that survives a failed run.
+    resume_dir: Option<PathBuf>,
+    resume: ResumeState,
+    cache_dir: Option<PathBuf>,
+    cache_max_size: Option<u64>,
+    encore_version: Option<version::VersionReq>,
+    pull_policy: PullPolicy,
+    /// Public key the image's cosign signature is checked against (`--verify-signatures`).
+    #[cfg(feature = "cosign")]
+    signing_key: Option<PathBuf>,
+    /// The image is built by this run, rather than pulled, found locally or piped in.
+    built: bool,
+    /// Images this run created: both after a build, the image after a pull.
+    removed_images: Vec<String>,
+    /// `--keep-image`: leave the images this run created in place.
+    keep_image: bool,
+    /// The images the acquire stage left in place and those it removed, for the lifecycle summary.
+    kept_images: Vec<String>,
+    gone_images: Vec<String>,
+    /// Scratch directories, removed when the context drops unless kept.
+    scratch_guards: Vec<tempfile::TempDir>,
+    /// Set by the extract stage.
+    manifest: Option<Manifest>,
+    layer_digests: Vec<String>,
+    layer_dir: PathBuf,
+    /// Set by the locate stage.
+    encore_dirs: Vec<PathBuf>,
+    layout_schema: Option<String>,
+    /// Set by the copy stage.
+    stats: CopyStats,
+    /// Every stage ran; cleanup after an early stop is quiet.
+    finished: bool,
+    /// The copy stage began writing the output directory.
+    output_started: bool,
+    /// The output directory didn't exist before the copy stage created it.
+    created_output: bool,
+    /// Held until the context drops, after the scratch directories: see `lock_workspace`.
+    workspace_lock: WorkspaceLock,
+}
+
+impl<'a> Context<'a> {
+    /// Read the run's settings and take the workspace lock, or `None` when the arguments ask
+    /// for something other than an extraction (`--list-components`, `--probe-only`, ...).
+    ///
+    /// `current_dir` is the base directory: relative paths in the arguments, the default output
+    /// and the scratch space are all resolved against it.
+    fn new(args: &'a [String], current_dir: PathBuf) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let config = Config::new(args)?;
+        if has_flag(args, "--list-components") {
+            list_components();
+            return Ok(None);
+        }
+        let image_tag = match expanded_arg(args, "--image")? {
+            Some(image) => {
+                // Stray whitespace, e.g. from a `$(...)` substitution, is dropped rather than rejected.
+                let image = image.trim().to_string();
+                validate_image_reference(&image)?;
+                image
+            }
+            None => default_image_tag(&current_dir, &config),
+        };
+        // Name recorded in provenance metadata; Docker operations always use the real tag.
+        let recorded_image = expanded_arg(args, "--rename-image")?.unwrap_or_else(|| image_tag.clone());
+
+        config.log_fmt(format_args!("Current working directory: {}", current_dir.display()));
+
+        // `extracted_output` is where older releases wrote; it may hold anything by now, so it is
+        // only removed when asked to.
+        let old_output = current_dir.join("extracted_output");
+        if old_output.exists() {
+            if has_flag(args, "--clean") {
+                config.status(format_args!("Removing old extracted_output directory (--clean)..."));
+                fs::remove_dir_all(&old_output).map_err(io_context(format!("Failed to remove {}", old_output.display())))?;
+            } else {
+                config.log_fmt(format_args!("Leaving {} in place (use --clean to remove it)", old_output.display()));
+            }
+        }
+
+        // With `--output-dir -` nothing is written; the default path is only used in messages.
+        let final_output = match expanded_arg(args, "--output-dir")?.filter(|dir| dir != "-") {
+            Some(dir) => current_dir.join(dir),
+            None => current_dir.join("encore_prod"),
+        };
+        let final_output = resolve_output_symlink(final_output, has_flag(args, "--follow-output-symlink"), &config)?;
+
+        if has_flag(args, "--probe-only") {
+            probe_prerequisites(&final_output, &current_dir, min_free_space(args)?, args, &config)?;
+            return Ok(None);
+        }
+
+        let encore_version = arg_value(args, "--encore-version").map(|v| version::VersionReq::parse(&v)).transpose()?;
+
+        let cache_dir = expanded_arg(args, "--cache-dir")?.map(|dir| current_dir.join(dir));
+        let cache_max_size: Option<u64> = match arg_value(args, "--cache-max-size") {
+            Some(v) => Some(parse_size(&v).map_err(|e| format!("Invalid --cache-max-size value: {}", e))?),
+            None => None,
+        };
+        if has_flag(args, "--cache-prune") {
+            let cache_dir = cache_dir.ok_or("--cache-prune requires --cache-dir")?;
+            // Without a size limit, pruning empties the cache.
+            prune_layer_cache(&cache_dir, cache_max_size.unwrap_or(0), &config)?;
+            return Ok(None);
+        }
+
+        let temp_base = current_dir.join("docker_extract_temp");
+        let workspace_lock = lock_workspace(&temp_base, has_flag(args, "--wait"), &config)?;
+        let resume_dir = has_flag(args, "--resume").then(|| temp_base.join("resume"));
+        remove_stale_temp(&temp_base, resume_dir.as_deref(), &config)?;
+        let resume = match &resume_dir {
+            Some(dir) => ResumeState::load(dir, &image_tag, &config)?,
+            None => ResumeState::default(),
+        };
+
+        // Define tar file path; the pid keeps concurrent runs in one directory from sharing it.
+        let tar_path = match (expanded_arg(args, "--tar-path")?, &resume_dir) {
+            (Some(path), _) => current_dir.join(path),
+            (None, Some(dir)) => dir.join("image.tar"),
+            (None, None) => current_dir.join(format!("encoredocker-{}.tar", std::process::id())),
+        };
+
+        // Digest references are immutable registry images: pull them instead of building.
+        let pinned = parse_digest_reference(&image_tag)?.is_some();
+        let pull_policy = match arg_value(args, "--pull-policy") {
+            Some(name) => PullPolicy::parse(&name)?,
+            None if pinned => PullPolicy::Always,
+            None => PullPolicy::Build,
+        };
+        if pinned && pull_policy == PullPolicy::Build {
+            return Err(format!("{} is a digest reference and can't be built; pick another --pull-policy", image_tag).into());
+        }
+        let built = !config.tar_stdin && pull_policy == PullPolicy::Build;
+        #[cfg(feature = "cosign")]
+        let signing_key = match expanded_arg(args, "--cosign-key")? {
+            Some(key) if has_flag(args, "--verify-signatures") => Some(current_dir.join(key)),
+            Some(_) => return Err("--cosign-key requires --verify-signatures".into()),
+            None if has_flag(args, "--verify-signatures") => return Err("--verify-signatures requires --cosign-key <public key>".into()),
+            None => None,
+        };
+        #[cfg(feature = "cosign")]
+        if signing_key.is_some() && (built || config.tar_stdin) {
+            return Err("--verify-signatures checks a registry image; use a --pull-policy other than build, without --tar-stdin".into());
+        }
+        #[cfg(not(feature = "cosign"))]
+        if let Some(flag) = ["--verify-signatures", "--cosign-key"].iter().find(|flag| has_flag(args, flag)) {
+            return Err(format!("{} needs encorebaremetal built with the cosign feature", flag).into());
+        }
+        let removed_images = if built { vec!["node:slim".to_string(), image_tag.clone()] } else { Vec::new() };
+
+        Ok(Some(Context {
+            args,
+            config,
+            current_dir,
+            image_tag,
+            recorded_image,
+            final_output,
+            layout_dir: None,
+            temp_base,
+            tar_path,
+            keep_temp: has_flag(args, "--keep-temp"),
+            keep_tar: has_flag(args, "--keep-tar"),
+            resume_dir,
+            resume,
+            cache_dir,
+            cache_max_size,
+            encore_version,
+            pull_policy,
+            #[cfg(feature = "cosign")]
+            signing_key,
+            built,
+            removed_images,
+            keep_image: has_flag(args, "--keep-image"),
+            kept_images: Vec::new(),
+            gone_images: Vec::new(),
+            scratch_guards: Vec::new(),
+            manifest: None,
+            layer_digests: Vec::new(),
+            layer_dir: PathBuf::new(),
+            encore_dirs: Vec::new(),
+            layout_schema: None,
+            stats: CopyStats::default(),
+            finished: false,
+            output_started: false,
+            created_output: false,
+            workspace_lock,
+        }))
+    }
+
+    /// A scratch directory under the temp base, kept alive as long as the context.
+    fn scratch_dir(&mut self, name: &str) -> io::Result<PathBuf> {
+        let (dir, guard) = scratch_dir(&self.temp_base, name, self.resume_dir.as_deref(), self.keep_temp)?;
+        self.scratch_guards.extend(guard);
+        Ok(dir)
+    }
+
+    /// The manifest parsed by the extract stage.
+    fn manifest(&self) -> &Manifest {
+        self.manifest.as_ref().expect("the extract stage parses the manifest")
+    }
+
+    /// Remove the images this run created, or keep them with `--keep-image`.
+    fn remove_images(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.keep_image {
+            self.kept_images.append(&mut self.removed_images);
+            return Ok(());
+        }
+        let images: Vec<&str> = self.removed_images.iter().map(String::as_str).collect();
+        docker_remove(&images, &self.config)?;
+        self.gone_images.append(&mut self.removed_images);
+        Ok(())
+    }
+
+    /// One line naming the images kept and removed, or `None` when the run handled no image.
+    fn image_lifecycle(&self) -> Option<String> {
+        let list = |verb: &str, images: &[String]| {
+            let noun = if images.len() == 1 { "image" } else { "images" };
+            format!("{} {} {}", verb, noun, images.join(", "))
+        };
+        let kept = (!self.kept_images.is_empty()).then(|| list("Kept", &self.kept_images));
+        let gone = (!self.gone_images.is_empty()).then(|| list(if kept.is_some() { "removed" } else { "Removed" }, &self.gone_images));
+        let parts: Vec<String> = kept.into_iter().chain(gone).collect();
+        (!parts.is_empty()).then(|| parts.join("; "))
+    }
+}
+
+/// The stages of an extraction, in order: acquire the image tar, extract its layers, locate the
+/// `encore` directories, copy their components and finalize the output. Stages that don't
+/// apply to a run are left out; cleanup always runs last once the stages succeed.
+struct Pipeline {
+    stages: Vec<(&'static str, Stage)>,
+}
+
+impl Pipeline {
+    fn new(ctx: &Context) -> Self {
+        let mut stages: Vec<(&'static str, Stage)> = Vec::new();
+        // A piped-in image tar has nothing to acquire.
+        if !ctx.config.tar_stdin {
+            stages.push(("acquire", acquire_stage));
+        }
+        stages.push(("extract", extract_stage));
+        stages.push(("locate", locate_stage));
+        stages.push(("copy", copy_stage));
+        stages.push(("finalize", finalize_stage));
+        Pipeline { stages }
+    }
+
+    /// Run the stages until one stops early, then clean up. A failed stage skips the cleanup,
+    /// leaving the intermediates for inspection, unless it failed because `--timeout` ran out or
+    /// Ctrl-C stopped a `--watch` run.
+    fn run(&self, ctx: &mut Context) -> Result<(), Box<dyn std::error::Error>> {
+        {
+            let mut removals = TIMEOUT_REMOVALS.lock().unwrap();
+            removals.clear();
+            removals.extend((!ctx.keep_temp).then(|| ctx.temp_base.clone()));
+            removals.extend((!ctx.keep_tar).then(|| ctx.tar_path.clone()));
+        }
+        let result = self.run_stages(ctx);
+        TIMEOUT_REMOVALS.lock().unwrap().clear();
+        if result.is_ok() {
+            if let Some(on_progress) = &ctx.config.on_progress {
+                let files = ctx.config.progress_files.load(Ordering::Relaxed);
+                let bytes = ctx.config.progress_bytes.load(Ordering::Relaxed);
+                on_progress(&ProgressEvent::Finished { files, bytes });
+            }
+        }
+        result
+    }
+
+    fn run_stages(&self, ctx: &mut Context) -> Result<(), Box<dyn std::error::Error>> {
+        for (name, stage) in &self.stages {
+            if let Err(e) = check_timeout() {
+                timeout_cleanup(ctx);
+                return Err(format!("{} before the {} stage", e, name).into());
+            }
+            ctx.config.log_fmt(format_args!("Pipeline stage: {}", name));
+            match stage(ctx) {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Stop) => return cleanup_stage(ctx),
+                Err(e) => {
+                    if check_timeout().is_err() {
+                        timeout_cleanup(ctx);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        ctx.finished = true;
+        cleanup_stage(ctx)
+    }
+}
+
+/// Clean up after `--timeout` or Ctrl-C stopped the run before finalize did. The output is missing at
+/// least its metadata, so one this run created is removed, and a pre-existing one is reported.
+fn timeout_cleanup(ctx: &mut Context) {
+    if ctx.output_started {
+        if ctx.created_output {
+            let _ = fs::remove_dir_all(&ctx.final_output);
+        } else {
+            ctx.config.warn("timeout", format!("{} is incomplete; the run was stopped while writing it", ctx.final_output.display()));
+        }
+    }
+    let _ = cleanup_stage(ctx);
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    enter_phase("setup");
+    match Context::new(args, env::current_dir()?)? {
+        Some(mut ctx) => Pipeline::new(&ctx).run(&mut ctx),
+        None => Ok(()),
+    }
+}
+
+/// Run the extraction in `dir` with the command-line flags in `args` (without the program name),
+/// reporting progress to `on_progress`, which takes the place of `--progress-json` when given.
+pub fn extract_encore(dir: &Path, args: &[String], on_progress: Option<Box<ProgressCallback>>) -> Result<(), Box<dyn std::error::Error>> {
+    extract_with(dir, args, on_progress, Pipeline::new)
+}
+
+/// `extract_encore` with the stages `pipeline` picks for the run.
+fn extract_with(dir: &Path, args: &[String], on_progress: Option<Box<ProgressCallback>>, pipeline: fn(&Context) -> Pipeline) -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::iter::once("encorebaremetal".to_string()).chain(args.iter().cloned()).collect();
+    enter_phase("setup");
+    match Context::new(&args, dir.to_path_buf())? {
+        Some(mut ctx) => {
+            if on_progress.is_some() {
+                ctx.config.on_progress = on_progress;
+            }
+            pipeline(&ctx).run(&mut ctx)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Build, pull or find the image and save it to the tar, unless a resumed run already did.
+fn acquire_stage(ctx: &mut Context) -> Result<Flow, Box<dyn std::error::Error>> {
+    if ctx.resume.saved && ctx.tar_path.exists() {
+        ctx.config.status(format_args!("Resuming with saved image tar {}", ctx.tar_path.display()));
+        return Ok(Flow::Continue);
+    }
+    let (config, image_tag) = (&ctx.config, ctx.image_tag.as_str());
+    enter_phase("build");
+    check_docker_daemon(config.runtime)?;
+    #[cfg(feature = "cosign")]
+    if let Some(key) = &ctx.signing_key {
+        verify_signature(image_tag, key, config)?;
+    }
+    let pull = match ctx.pull_policy {
+        PullPolicy::Build => false,
+        PullPolicy::Always => true,
+        PullPolicy::IfNotPresent => !docker_image_exists(image_tag, config.runtime),
+        PullPolicy::Never if docker_image_exists(image_tag, config.runtime) => false,
+        PullPolicy::Never => return Err(format!("Image {} isn't present locally (--pull-policy never)", image_tag).into()),
+    };
+    config.event("build_started", serde_json::json!({ "image": image_tag, "pull": pull }));
+    if pull {
+        docker_pull(image_tag, config)?;
+        ctx.removed_images = vec![image_tag.to_string()];
+    } else if ctx.built {
+        if has_flag(ctx.args, "--pre-clean-images") {
+            docker_prune_images(config)?;
+        }
+        let _encore_path = docker_build(image_tag, config)?;
+    } else {
+        config.status(format_args!("Using local Docker image {}", image_tag));
+        ctx.kept_images.push(image_tag.to_string());
+    }
+    config.event("build_finished", serde_json::json!({ "image": image_tag }));
+    enter_phase("save");
+    for attempt in 0.. {
+        docker_save(image_tag, &ctx.tar_path, config)?;
+        match probe_image_tar(&ctx.tar_path, config) {
+            Ok(()) => break,
+            Err(e) if attempt < config.save_retries => {
+                config.warn("save", format!("{}; saving the image again (retry {}/{})", e, attempt + 1, config.save_retries));
+            }
+            Err(e) => {
+                let retried = if attempt == 0 { String::new() } else { format!(", also after {} {}", attempt, if attempt == 1 { "retry" } else { "retries" }) };
+                return Err(format!("{}{} (a full disk during docker save truncates the tar)", e, retried).into());
+            }
+        }
+    }
+    config.event("image_saved", serde_json::json!({ "tar": ctx.tar_path.to_string_lossy() }));
+    // With --tag-with-digest the image must survive until the layer digest is known.
+    if !(config.tag_with_digest && ctx.built) {
+        ctx.remove_images()?;
+    }
+    ctx.resume.saved = true;
+    ctx.resume.save(ctx.resume_dir.as_deref())?;
+    Ok(Flow::Continue)
+}
+
+/// Unpack the image tar, parse its manifest, then select and extract the layers.
+fn extract_stage(ctx: &mut Context) -> Result<Flow, Box<dyn std::error::Error>> {
+    let args = ctx.args;
+    // Create a temporary directory for extraction.
+    enter_phase("unpack");
+    fs::create_dir_all(&ctx.temp_base)?;
+    let work_dir = ctx.scratch_dir("work")?;
+    ctx.config.log_fmt(format_args!("Temporary directory created: {}", work_dir.display()));
+
+    // Extract tar into temporary directory.
+    if ctx.resume.unpacked {
+        ctx.config.log("Image tar already unpacked, skipping.");
+    } else if ctx.config.tar_stdin {
+        if let Some(digest) = unpack_stdin(&work_dir, &ctx.config)? {
+            check_tar_sha256(&digest, "image tar from stdin", &ctx.config)?;
+        }
+    } else {
+        if ctx.config.hashes_tar() {
+            let digest = sha256::hash_file(&ctx.tar_path)?;
+            check_tar_sha256(&digest, &ctx.tar_path.display().to_string(), &ctx.config)?;
+        }
+        run_command("tar", &[OsStr::new("xf"), ctx.tar_path.as_os_str()], Some(&work_dir))?;
+    }
+    ctx.resume.unpacked = true;
+    ctx.resume.save(ctx.resume_dir.as_deref())?;
+
+    // Parse manifest.json.
+    enter_phase("extract");
+    let manifest = parse_manifest(&locate_manifest(&work_dir, arg_value(args, "--manifest-path").as_deref(), &ctx.config)?)?;
+    ctx.config.log_fmt(format_args!("Manifest format: {:?}", manifest.format));
+    if !manifest.repo_tags.is_empty() {
+        ctx.config.log_fmt(format_args!("Image tags in manifest: {}", manifest.repo_tags.join(", ")));
+    }
+    if has_flag(args, "--deterministic") {
+        ctx.config.deterministic = Some(deterministic_epoch(&manifest)?);
+    }
+    let config = &ctx.config;
+    // An image without layers (`FROM scratch`) only gets its metadata with --allow-empty.
+    if manifest.layers.is_empty() && has_flag(args, "--allow-empty") {
+        if !config.no_write {
+            fs::create_dir_all(&ctx.final_output)?;
+            write_extraction_meta(&ctx.final_output, &ctx.recorded_image, &[], &config.labels, &[])?;
+            if let Some(epoch) = config.deterministic {
+                normalize_output_times(&ctx.final_output, epoch)?;
+            }
+        }
+        config.status(format_args!("Image {} has no layers; nothing to extract (--allow-empty).", ctx.recorded_image));
+        let report = RunReport {
+            image: ctx.recorded_image.clone(),
+            output_dir: ctx.final_output.to_string_lossy().into_owned(),
+            layers: Vec::new(),
+            labels: config.labels.clone(),
+            components: 0,
+            files: 0,
+            bytes: 0,
+            copy_jobs: config.copy_jobs,
+            copy_buffer: config.copy_buffer_size(),
+            layout_schema: None,
+            warnings: config.diagnostics(),
+            written: !config.no_write,
+            all_layers: false,
+        };
+        report.print(config)?;
+        return Ok(Flow::Stop);
+    }
+    let layer_digests = select_layers(&manifest, config)?;
+    if let (true, Some(since)) = (layer_digests.is_empty(), &config.since) {
+        config.status(format_args!("No layers changed since {}; nothing to extract", since));
+        return Ok(Flow::Stop);
+    }
+    config.event("layer_selected", serde_json::json!({ "layers": layer_digests }));
+    let strict_arch = has_flag(args, "--strict-arch");
+    if strict_arch || has_flag(args, "--resolve-runtime-arch") {
+        check_runtime_arch(&manifest, strict_arch, config)?;
+    }
+
+    if config.tag_with_digest && ctx.built {
+        if let Some(digest) = layer_digests.last() {
+            docker_tag_digest(&ctx.image_tag, digest, config)?;
+        }
+        ctx.remove_images()?;
+    }
+    let config = &ctx.config;
+
+    let layer_paths: Vec<PathBuf> = layer_digests.iter().map(|digest| manifest.blob(digest)).collect();
+    if has_flag(args, "--report-layer-histogram") {
+        print_layer_histogram(&manifest, &layer_digests, config)?;
+    }
+    if has_flag(args, "--print-layer-tree") {
+        for (layer_digest, layer_path) in layer_digests.iter().zip(&layer_paths) {
+            print_layer_tree(layer_path, layer_digest, config)?;
+        }
+    }
+    if has_flag(args, "--print-layer-tree") || has_flag(args, "--report-layer-histogram") {
+        return Ok(Flow::Stop);
+    }
+    if let Some(dest) = expanded_arg(args, "--dump-layer")? {
+        dump_layers(&layer_digests, &layer_paths, &ctx.current_dir.join(dest), has_flag(args, "--dump-layer-raw"), config)?;
+    }
+    if !has_flag(args, "--ignore-disk-check") {
+        check_disk_space(&layer_paths, &ctx.temp_base, &ctx.final_output, config)?;
+    }
+
+    // Extract the selected layers in order, so later layers overwrite earlier ones.
+    // Images we didn't build are untrusted: their symlinks must stay inside the tree.
+    if !ctx.built && !ctx.config.symlink_guard {
+        ctx.config.log("Untrusted image source; enabling --fail-on-symlink-escape.");
+        ctx.config.symlink_guard = true;
+    }
+    let layer_dir = ctx.scratch_dir("layer")?;
+    let config = &ctx.config;
+    let mut merge = LayerMerge::new(config.merge_strategy);
+    for (layer_digest, layer_path) in layer_digests.iter().zip(&layer_paths) {
+        if ctx.resume.layers.contains(layer_digest) {
+            config.log_fmt(format_args!("Layer {} already extracted, skipping.", layer_digest));
+            continue;
+        }
+        merge.start_layer(layer_digest);
+        match &ctx.cache_dir {
+            Some(cache_dir) => extract_layer_cached(layer_path, layer_digest, cache_dir, &layer_dir, &mut merge, config)?,
+            None => extract_layer(layer_path, &layer_dir, Some(&mut merge), config)?,
+        }
+        ctx.resume.layers.push(layer_digest.clone());
+        ctx.resume.save(ctx.resume_dir.as_deref())?;
+    }
+    merge.finish(&layer_dir, config)?;
+    if let (Some(cache_dir), Some(max_bytes)) = (&ctx.cache_dir, ctx.cache_max_size) {
+        prune_layer_cache(cache_dir, max_bytes, config)?;
+    }
+    config.event("layers_extracted", serde_json::json!({ "count": layer_digests.len() }));
+    check_layer_contains(&layer_dir, &config.layer_contains)?;
+
+    ctx.manifest = Some(manifest);
+    ctx.layer_digests = layer_digests;
+    ctx.layer_dir = layer_dir;
+    Ok(Flow::Continue)
+}
+
+/// Find the `encore` directories in the extracted layers, failing if there is nothing to copy.
+/// `--extract-only` copies or prints its component here and skips the rest of the pipeline
+/// (metadata, checks, archive, ...).
+fn locate_stage(ctx: &mut Context) -> Result<Flow, Box<dyn std::error::Error>> {
+    let config = &ctx.config;
+    if let Some(name) = arg_value(ctx.args, "--extract-only") {
+        let encore_dirs = find_encore_dirs(&ctx.layer_dir, config);
+        let encore_dir = encore_dirs.first().ok_or("No 'encore' directory found in the extracted layers")?;
+        if has_flag(ctx.args, "--stdout") {
+            print_component(encore_dir, &name, config)?;
+        } else {
+            fs::create_dir_all(&ctx.final_output)?;
+            let stats = copy_encore_components(encore_dir, &ctx.final_output, config)?;
+            config.status(format_args!("Extracted {} ({} files, {} bytes) to {}", name, stats.files, stats.bytes, ctx.final_output.display()));
+        }
+        return Ok(Flow::Stop);
+    }
+
+    // Checked before the output is created, so a layer without Encore artifacts leaves nothing behind.
+    let encore_dirs = find_encore_dirs(&ctx.layer_dir, config);
+    ensure_encore_artifacts(&ctx.layer_dir, &encore_dirs)?;
+    if let Some(encore_dir) = encore_dirs.first() {
+        ctx.layout_schema = Some(detect_layout_schema(ctx.manifest(), encore_dir, config)?);
+    }
+    ctx.encore_dirs = encore_dirs;
+    Ok(Flow::Continue)
+}
+
+/// Create the output and copy the components of every `encore` directory into it.
+fn copy_stage(ctx: &mut Context) -> Result<Flow, Box<dyn std::error::Error>> {
+    // `--output-format oci` copies into a staging tree, packaged into the output once it's complete.
+    if ctx.config.oci_layout {
+        let staging = ctx.temp_base.join("oci-rootfs");
+        ctx.layout_dir = Some(std::mem::replace(&mut ctx.final_output, staging));
+    }
+    let final_output = &ctx.final_output;
+
+    // Create final output directory.
+    enter_phase("copy");
+    if !ctx.config.no_write {
+        // The staging tree of `--output-format oci` lives in the temp base, removed with it.
+        if !ctx.config.oci_layout {
+            ctx.output_started = true;
+            ctx.created_output = !final_output.exists();
+            if ctx.created_output {
+                TIMEOUT_REMOVALS.lock().unwrap().push(final_output.clone());
+            }
+        }
+        fs::create_dir_all(final_output)?;
+        ctx.config.log_fmt(format_args!("Created output directory: {}", final_output.display()));
+    }
+    if ctx.config.merge {
+        ctx.config.managed = Some(read_managed_files(final_output)?);
+    }
+    if has_flag(ctx.args, "--cas-output") {
+        ctx.config.cas = Some(CasStore::new(final_output, ctx.config.hash_algo)?);
+    }
+    let config = &ctx.config;
+
+    // Attempt to copy required components from the 'encore' directory.
+    let mut stats = CopyStats::default();
+    if config.all_apps {
+        for (encore_dir, name) in ctx.encore_dirs.iter().zip(app_output_names(&ctx.layer_dir, &ctx.encore_dirs)?) {
+            let app_output = final_output.join(&name);
+            let relative = encore_dir.strip_prefix(&ctx.layer_dir).unwrap_or(encore_dir);
+            config.status(format_args!("Extracting app {} from /{} into {}", name, relative.display(), app_output.display()));
+            if !config.no_write {
+                fs::create_dir_all(&app_output).map_err(io_context(format!("Failed to create directory {}", app_output.display())))?;
+            }
+            stats.merge(copy_encore_components(encore_dir, &app_output, config)?);
+        }
+    } else {
+        for encore_dir in &ctx.encore_dirs {
+            stats.merge(copy_encore_components(encore_dir, final_output, config)?);
+        }
+    }
+
+    config.log_fmt(format_args!(
+        "Copied {} components ({} files, {} bytes)",
+        stats.components, stats.files, stats.bytes
+    ));
+    config.event(
+        "files_copied",
+        serde_json::json!({ "components": stats.components, "files": stats.files, "bytes": stats.bytes }),
+    );
+    ctx.stats = stats;
+    Ok(Flow::Continue)
+}
+
+/// Summarize the copy, write metadata and the requested extra outputs, run the checks, upload,
+/// and print the run report.
+fn finalize_stage(ctx: &mut Context) -> Result<Flow, Box<dyn std::error::Error>> {
+    let (args, config, stats, manifest) = (ctx.args, &ctx.config, &ctx.stats, ctx.manifest());
+    let (current_dir, final_output, recorded_image) = (&ctx.current_dir, &ctx.final_output, &ctx.recorded_image);
+    enter_phase("finalize");
+    if config.since_timestamp.is_some() {
+        config.status(format_args!("Skipped {} files unchanged since --since-timestamp.", stats.unchanged));
+    }
+    if config.incremental {
+        config.status(format_args!("Left {} up-to-date files in place.", stats.up_to_date));
+    }
+    if let Some(limit) = config.max_file_size {
+        config.status(format_args!(
+            "Skipped {} files ({}) larger than {}.",
+            stats.oversized,
+            format_bytes(stats.oversized_bytes),
+            format_bytes(limit)
+        ));
+    }
+    if config.dedupe.is_some() {
+        config.status(format_args!(
+            "Deduplicated {} files with hardlinks, saving {} bytes.",
+            stats.deduped, stats.bytes_saved
+        ));
+    }
+    if let Some(cas) = &config.cas {
+        let tree = cas.write_tree()?;
+        config.status(format_args!(
+            "Stored {} files as {} {} objects ({} duplicates, {} bytes saved); index in {}",
+            stats.files,
+            stats.files - stats.deduped,
+            cas.algo.name(),
+            stats.deduped,
+            stats.bytes_saved,
+            tree.display()
+        ));
+    }
+    if let Some(managed) = &config.managed {
+        let removed = remove_stale_files(final_output, managed, &stats.written, config)?;
+        config.log_fmt(format_args!("Removed {} stale files from the previous extraction", removed));
+    }
+    if !config.runtime_env.is_empty() {
+        let encore_dir = ctx.encore_dirs.first().ok_or("--runtime-env needs an extracted encore directory")?;
+        write_runtime_env(manifest, encore_dir, final_output, config)?;
+    }
+    apply_ownership(final_output, &stats.written, config)?;
+    if !config.no_write {
+        write_extraction_meta(final_output, recorded_image, &ctx.layer_digests, &config.labels, &stats.written)?;
+        if let Some(epoch) = config.deterministic {
+            normalize_output_times(final_output, epoch)?;
+        }
+    }
+    if let Some(manifest_out) = expanded_arg(args, "--output-manifest")? {
+        write_output_manifest(&current_dir.join(manifest_out), final_output, stats, config)?;
+    }
+    if let Some(file_list) = expanded_arg(args, "--file-list")? {
+        write_file_list(&current_dir.join(file_list), final_output, stats, config)?;
+    }
+    if config.fail_if_empty && (stats.components == 0 || stats.files < config.min_files) {
+        return Err(format!(
+            "Extraction produced {} components and {} files in {} (expected at least {} files)",
+            stats.components,
+            stats.files,
+            final_output.display(),
+            config.min_files
+        )
+        .into());
+    }
+
+    if let Some(stats_path) = expanded_arg(args, "--stats-json")? {
+        let append = has_flag(args, "--stats-append");
+        write_stats_json(&current_dir.join(stats_path), recorded_image, &ctx.layer_digests, stats, append, config)?;
+    }
+
+    // The checks read the components where `--layout` put them.
+    let app_dir = &config.output_layout.app_dir(final_output);
+    if has_flag(args, "--verify-entrypoint") {
+        verify_entrypoint(manifest, app_dir, config)?;
+    }
+    if has_flag(args, "--runtime-check-deps") {
+        check_runtime_deps(app_dir, config)?;
+    }
+    if let Some(requirement) = &ctx.encore_version {
+        check_encore_version(app_dir, requirement, has_flag(args, "--strict-version"), config)?;
+    }
+    if has_flag(args, "--validate-infra") {
+        validate_infra(app_dir, config)?;
+    }
+    if config.output_layout == OutputLayout::Rootfs && !config.no_write {
+        let encore_dir = ctx.encore_dirs.first().ok_or("--layout rootfs needs an extracted encore directory")?;
+        write_launch_script(manifest, encore_dir, final_output, recorded_image, config)?;
+    }
+    if has_flag(args, "--report-duplicates") {
+        report_duplicates(final_output, config)?;
+    }
+
+    if let Some(dockerfile) = expanded_arg(args, "--dockerfile-out")? {
+        let encore_dir = ctx.encore_dirs.first().ok_or("--dockerfile-out needs an extracted encore directory")?;
+        let base = arg_value(args, "--dockerfile-base").unwrap_or_else(|| "node:slim".to_string());
+        write_dockerfile(
+            &current_dir.join(dockerfile),
+            manifest,
+            encore_dir,
+            final_output,
+            recorded_image,
+            &base,
+            config,
+        )?;
+    }
+
+    if let Some(unit) = expanded_arg(args, "--emit-systemd")? {
+        let encore_dir = ctx.encore_dirs.first().ok_or("--emit-systemd needs an extracted encore directory")?;
+        // The service is named after the image's repository unless --systemd-name is given.
+        let name = arg_value(args, "--systemd-name").unwrap_or_else(|| {
+            let repository = recorded_image.rsplit_once(':').filter(|(_, tag)| !tag.contains('/')).map_or(recorded_image.as_str(), |(repo, _)| repo);
+            repository.rsplit('/').next().unwrap_or(repository).to_string()
+        });
+        write_systemd_unit(
+            &current_dir.join(unit),
+            manifest,
+            encore_dir,
+            final_output,
+            recorded_image,
+            &SystemdService { name, user: arg_value(args, "--systemd-user") },
+            config,
+        )?;
+    }
+
+    if let Some(archive) = expanded_arg(args, "--archive")? {
+        write_archive(final_output, &current_dir.join(archive), config)?;
+    }
+    if let Some(dir) = expanded_arg(args, "--split-archives")? {
+        write_split_archives(final_output, &current_dir.join(dir), stats, config)?;
+    }
+    if let Some(reference) = arg_value(args, "--compare-with-image") {
+        compare_with_image(&reference, final_output, &ctx.temp_base, config)?;
+    }
+    let final_output = match &ctx.layout_dir {
+        Some(layout_dir) => {
+            let encore_dir = ctx.encore_dirs.first().ok_or("--output-format oci needs an extracted encore directory")?;
+            write_oci_layout(manifest, encore_dir, final_output, layout_dir, recorded_image, config)?;
+            layout_dir
+        }
+        None => final_output,
+    };
+    #[cfg(feature = "s3")]
+    if let Some(url) = &config.s3_output {
+        upload_to_s3(final_output, url, config)?;
+    }
+
+    config.event("done", serde_json::json!({ "output_dir": final_output.to_string_lossy() }));
+    let report = RunReport {
+        image: recorded_image.clone(),
+        output_dir: final_output.to_string_lossy().into_owned(),
+        layers: ctx.layer_digests.clone(),
+        labels: config.labels.clone(),
+        components: stats.components,
+        files: stats.files,
+        bytes: stats.bytes,
+        copy_jobs: config.copy_jobs,
+        copy_buffer: config.copy_buffer_size(),
+        layout_schema: ctx.layout_schema.clone(),
+        warnings: config.diagnostics(),
+        written: !config.no_write,
+        all_layers: config.all_layers,
+    };
+    if let Some(metadata_out) = expanded_arg(args, "--metadata-out")? {
+        write_deploy_descriptor(
+            &current_dir.join(metadata_out),
+            manifest,
+            ctx.encore_dirs.first().map(PathBuf::as_path),
+            final_output,
+            recorded_image,
+            &ctx.layer_digests,
+            ctx.layout_schema.as_deref(),
+            arg_value(args, "--metadata-format").as_deref() == Some("yaml"),
+            config,
+        )?;
+    }
+    if let Some(format) = config.print_entrypoint {
+        let encore_dir = ctx.encore_dirs.first().ok_or("--print-entrypoint needs an extracted encore directory")?;
+        print_entrypoint(manifest, encore_dir, final_output, recorded_image, format, config)?;
+    }
+    report.print(config)?;
+    Ok(Flow::Continue)
+}
+
+/// Remove the temporary directories and the image tar, unless asked to keep them. Only a run
+/// that went through every stage says what it removes or keeps.
+fn cleanup_stage(ctx: &mut Context) -> Result<(), Box<dyn std::error::Error>> {
+    if ctx.finished {
+        if let Some(lifecycle) = ctx.image_lifecycle() {
+            ctx.config.status(format_args!("{}", lifecycle));
+        }
+    }
+    if ctx.keep_temp {
+        if ctx.finished {
+            ctx.config.status(format_args!("Keeping temporary files in {}", ctx.temp_base.display()));
+        }
+    } else {
+        if ctx.finished {
+            ctx.config.status(format_args!("Cleaning up temporary files..."));
+        }
+        // A run stopped by --timeout while acquiring the image never created it.
+        if ctx.temp_base.exists() {
+            fs::remove_dir_all(&ctx.temp_base)?;
+        }
+    }
+    if ctx.tar_path.exists() {
+        if ctx.keep_tar {
+            if ctx.finished {
+                ctx.config.status(format_args!("Keeping tar file {}", ctx.tar_path.display()));
+            }
+        } else {
+            if ctx.finished {
+                ctx.config.status(format_args!("Removing tar file..."));
+            }
+            fs::remove_file(&ctx.tar_path)?;
+        }
+    }
+    let lock = &ctx.workspace_lock.path;
+    fs::remove_file(lock).or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) }).map_err(io_context(format!("Failed to remove {}", lock.display())))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(flags: &[&str]) -> Config {
+        let args: Vec<String> = std::iter::once("encorebaremetal").chain(flags.iter().copied()).map(String::from).collect();
+        Config::new(&args).unwrap()
+    }
+
+    /// A file no one can read: mode 000, or for root, which that doesn't stop, a symlink to
+    /// `/proc/self/mem`, whose first page is never mapped.
+    fn unreadable_file(path: &Path) {
+        fs::write(path, "secret").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o000)).unwrap();
+        if File::open(path).is_ok() {
+            fs::remove_file(path).unwrap();
+            std::os::unix::fs::symlink("/proc/self/mem", path).unwrap();
+        }
+    }
+
+    fn source_with_unreadable_file() -> tempfile::TempDir {
+        let src = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("a.js"), "a").unwrap();
+        fs::write(src.path().join("b.js"), "b").unwrap();
+        unreadable_file(&src.path().join("locked.js"));
+        src
+    }
+
+    #[test]
+    fn fail_fast_returns_the_copy_error() {
+        let (src, out) = (source_with_unreadable_file(), tempfile::tempdir().unwrap());
+        let config = config(&[]);
+        assert!(config.copy_failures == FailurePolicy::FailFast);
+        let dst = out.path().join("app");
+        let Err(e) = copy_dir(src.path(), &dst, out.path(), &config) else { panic!("the copy succeeded") };
+        assert!(e.to_string().contains("locked.js"), "{}", e);
+    }
+
+    #[test]
+    fn collect_records_the_error_and_copies_the_rest() {
+        let (src, out) = (source_with_unreadable_file(), tempfile::tempdir().unwrap());
+        let config = config(&["--keep-going"]);
+        assert!(config.copy_failures == FailurePolicy::Collect);
+        let dst = out.path().join("app");
+        let stats = copy_dir(src.path(), &dst, out.path(), &config).unwrap();
+        assert_eq!(stats.errors.len(), 1);
+        assert!(stats.errors[0].contains("locked.js"), "{}", stats.errors[0]);
+        assert_eq!(stats.files, 2);
+        assert_eq!(fs::read_to_string(dst.join("a.js")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dst.join("b.js")).unwrap(), "b");
+    }
+
+    /// Stands in for `acquire_stage`, saving the selftest image where the image tar goes.
+    fn fixture_acquire(ctx: &mut Context) -> Result<Flow, Box<dyn std::error::Error>> {
+        fs::write(&ctx.tar_path, selftest_image()?)?;
+        Ok(Flow::Continue)
+    }
+
+    #[test]
+    fn pipeline_runs_the_stages_after_a_swapped_acquire() {
+        let work = tempfile::tempdir().unwrap();
+        let args: Vec<String> = ["encorebaremetal", "--image", "fixture:latest", "--pull-policy", "never"].map(String::from).to_vec();
+        let mut ctx = Context::new(&args, work.path().to_path_buf()).unwrap().unwrap();
+        let mut pipeline = Pipeline::new(&ctx);
+        let names: Vec<&str> = pipeline.stages.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["acquire", "extract", "locate", "copy", "finalize"]);
+        pipeline.stages[0].1 = fixture_acquire;
+        pipeline.run(&mut ctx).unwrap();
+
+        assert_eq!(ctx.layer_digests.len(), 1, "extract");
+        assert_eq!(ctx.encore_dirs.len(), 1, "locate");
+        assert_eq!(ctx.stats.files, SELFTEST_FILES.len() as u64, "copy");
+        for (_, output_path, contents) in SELFTEST_FILES {
+            assert_eq!(fs::read_to_string(ctx.final_output.join(output_path)).unwrap(), *contents, "{}", output_path);
+        }
+        assert!(ctx.finished);
+        assert!(!ctx.temp_base.exists() && !ctx.tar_path.exists(), "cleanup");
+        assert!(!ctx.workspace_lock.path.exists(), "lock file");
+    }
+
+    #[test]
+    fn a_waiting_run_relocks_a_lock_file_removed_under_it() {
+        use std::os::unix::fs::MetadataExt;
+        let work = tempfile::tempdir().unwrap();
+        let (temp_base, config) = (work.path().join("docker_extract_temp"), config(&[]));
+        let held = lock_workspace(&temp_base, false, &config).unwrap();
+        assert!(lock_workspace(&temp_base, false, &config).unwrap_err().to_string().contains("pass --wait"));
+        let waiter = thread::scope(|scope| {
+            let waiter = scope.spawn(|| lock_workspace(&temp_base, true, &config).unwrap());
+            thread::sleep(Duration::from_millis(100));
+            fs::remove_file(&held.path).unwrap();
+            drop(held);
+            waiter.join().unwrap()
+        });
+        let (locked, current) = (waiter._file.metadata().unwrap(), fs::metadata(&waiter.path).unwrap());
+        assert_eq!((locked.dev(), locked.ino()), (current.dev(), current.ino()));
+    }
+
+    #[test]
+    fn pipeline_runs_in_a_non_utf8_directory() {
+        use std::os::unix::ffi::OsStrExt;
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path().join(OsStr::from_bytes(b"caf\xe9"));
+        fs::create_dir(&dir).unwrap();
+        let args: Vec<String> = ["encorebaremetal", "--image", "fixture:latest", "--pull-policy", "never"].map(String::from).to_vec();
+        let mut ctx = Context::new(&args, dir.clone()).unwrap().unwrap();
+        assert!(ctx.tar_path.to_str().is_none());
+        let mut pipeline = Pipeline::new(&ctx);
+        pipeline.stages[0].1 = fixture_acquire;
+        pipeline.run(&mut ctx).unwrap();
+        assert_eq!(ctx.stats.files, SELFTEST_FILES.len() as u64);
+        assert!(ctx.final_output.starts_with(&dir));
+    }
+
+    /// Lay out a `docker save` image in `dir` with two layer blobs of 3 and 5 bytes and a config
+    /// blob, under a manifest whose entry gets `fields` merged in; returns the manifest path.
+    fn saved_image(dir: &Path, fields: Value) -> PathBuf {
+        let blobs = dir.join("blobs/sha256");
+        fs::create_dir_all(&blobs).unwrap();
+        for (digest, contents) in [("aaaa", "abc"), ("bbbb", "abcde"), ("cccc", "{}")] {
+            fs::write(blobs.join(digest), contents).unwrap();
+        }
+        let mut entry = serde_json::json!({
+            "Config": "blobs/sha256/cccc",
+            "RepoTags": ["fixture:latest"],
+            "Layers": ["blobs/sha256/aaaa", "blobs/sha256/bbbb"],
+        });
+        for (key, value) in fields.as_object().unwrap() {
+            entry[key] = value.clone();
+        }
+        let manifest_path = dir.join("manifest.json");
+        fs::write(&manifest_path, serde_json::to_vec(&[entry]).unwrap()).unwrap();
+        manifest_path
+    }
+
+    #[test]
+    fn manifest_with_a_missing_config_blob_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = saved_image(dir.path(), serde_json::json!({ "Config": "blobs/sha256/dddd" }));
+        let e = parse_manifest(&manifest_path).err().expect("a missing config blob is an error").to_string();
+        assert!(e.starts_with("Image config dddd listed in the manifest is missing from the image tar"), "{}", e);
+    }
+
+    fn layer_sizes(fields: Value) -> Vec<(String, u64)> {
+        let dir = tempfile::tempdir().unwrap();
+        parse_manifest(&saved_image(dir.path(), fields)).unwrap().layer_sizes
+    }
+
+    #[test]
+    fn layer_sizes_fall_back_to_the_blobs_without_layer_sources() {
+        let from_files = vec![("aaaa".to_string(), 3), ("bbbb".to_string(), 5)];
+        assert_eq!(layer_sizes(serde_json::json!({})), from_files);
+        assert_eq!(layer_sizes(serde_json::json!({ "LayerSources": {} })), from_files);
+        assert_eq!(layer_sizes(serde_json::json!({ "LayerSources": null })), from_files);
+    }
+
+    #[test]
+    fn layer_sizes_mix_recorded_and_blob_sizes() {
+        let sources = serde_json::json!({ "LayerSources": { "sha256:bbbb": { "size": 500 } } });
+        assert_eq!(layer_sizes(sources), vec![("aaaa".to_string(), 3), ("bbbb".to_string(), 500)]);
+    }
+
+    #[test]
+    fn null_repo_tags_are_no_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = parse_manifest(&saved_image(dir.path(), serde_json::json!({ "RepoTags": null }))).unwrap();
+        assert!(manifest.repo_tags.is_empty());
+        assert_eq!(manifest.layers, ["aaaa", "bbbb"]);
+    }
+
+    /// Copy `incoming` over an existing `app.js` holding "existing" under `--on-conflict policy`,
+    /// returning the copy's result and the directory holding `app.js`.
+    fn copy_over(policy: &str, incoming: &str) -> (io::Result<()>, tempfile::TempDir) {
+        let (src, out) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        fs::write(src.path().join("app.js"), incoming).unwrap();
+        fs::write(out.path().join("app.js"), "existing").unwrap();
+        let config = config(&["--on-conflict", policy]);
+        let result = copy_file(&src.path().join("app.js"), &out.path().join("app.js"), &config, &mut CopyStats::default());
+        (result, out)
+    }
+
+    #[test]
+    fn conflict_overwrite_replaces_the_destination() {
+        let (result, out) = copy_over("overwrite", "incoming");
+        result.unwrap();
+        assert_eq!(fs::read_to_string(out.path().join("app.js")).unwrap(), "incoming");
+    }
+
+    #[test]
+    fn conflict_skip_leaves_the_destination() {
+        let (result, out) = copy_over("skip", "incoming");
+        result.unwrap();
+        assert_eq!(fs::read_to_string(out.path().join("app.js")).unwrap(), "existing");
+        assert!(!out.path().join("app.js.new").exists());
+    }
+
+    #[test]
+    fn conflict_rename_writes_beside_the_destination() {
+        let (result, out) = copy_over("rename", "incoming");
+        result.unwrap();
+        assert_eq!(fs::read_to_string(out.path().join("app.js")).unwrap(), "existing");
+        assert_eq!(fs::read_to_string(out.path().join("app.js.new")).unwrap(), "incoming");
+    }
+
+    #[test]
+    fn conflict_error_fails_with_destination_exists() {
+        let (result, out) = copy_over("error", "incoming");
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        let inner = e.get_ref().and_then(|inner| inner.downcast_ref::<EncoreError>());
+        assert!(matches!(inner, Some(EncoreError::DestinationExists { path }) if *path == out.path().join("app.js")), "{}", e);
+        assert_eq!(fs::read_to_string(out.path().join("app.js")).unwrap(), "existing");
+    }
+
+    #[test]
+    fn identical_file_is_never_a_conflict() {
+        for policy in ["overwrite", "skip", "rename", "error"] {
+            let (result, out) = copy_over(policy, "existing");
+            result.unwrap();
+            assert!(!out.path().join("app.js.new").exists(), "{}", policy);
+            let dst = out.path().join("app.js");
+            let target = conflict_target(&dst, &dst, &config(&["--on-conflict", policy])).unwrap();
+            assert_eq!(target, Some(dst), "{}", policy);
+        }
+    }
+
+    /// Write a layer tar of `(path, type, contents or link target)` entries. Names are written
+    /// raw, so `..` paths that `tar::Builder` would refuse get through.
+    fn write_layer(path: &Path, entries: &[(&str, tar::EntryType, &str)]) {
+        let mut layer = tar::Builder::new(File::create(path).unwrap());
+        for (name, kind, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_mode(if *kind == tar::EntryType::Directory { 0o755 } else { 0o644 });
+            let contents = if *kind == tar::EntryType::Regular { data.as_bytes() } else { &[][..] };
+            if *kind != tar::EntryType::Regular && *kind != tar::EntryType::Directory {
+                header.as_gnu_mut().unwrap().linkname[..data.len()].copy_from_slice(data.as_bytes());
+            }
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            layer.append(&header, contents).unwrap();
+        }
+        layer.finish().unwrap();
+    }
+
+    #[test]
+    fn stripped_entry_is_not_written_through_an_earlier_symlink() {
+        let (work, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (layer, dest) = (work.path().join("layer.tar"), work.path().join("root"));
+        fs::create_dir(&dest).unwrap();
+        let outside_path = outside.path().to_str().unwrap();
+        write_layer(&layer, &[("w", tar::EntryType::Symlink, outside_path), ("../w/pwned", tar::EntryType::Regular, "PWNED")]);
+        let e = extract_layer(&layer, &dest, None, &config(&["--sanitize-paths=strip"])).unwrap_err();
+        assert!(e.to_string().contains("leaves the extraction root"), "{}", e);
+        assert!(!outside.path().join("pwned").exists());
+    }
+
+    #[test]
+    fn hardlink_cannot_read_through_a_symlinked_parent() {
+        let (work, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (layer, dest) = (work.path().join("layer.tar"), work.path().join("root"));
+        fs::create_dir(&dest).unwrap();
+        fs::write(outside.path().join("secret.txt"), "HOST-SECRET").unwrap();
+        write_layer(
+            &layer,
+            &[
+                ("w", tar::EntryType::Symlink, outside.path().to_str().unwrap()),
+                ("encore/runtimes/leak", tar::EntryType::Link, "w/secret.txt"),
+            ],
+        );
+        let e = extract_layer(&layer, &dest, None, &config(&[])).unwrap_err();
+        assert!(e.to_string().contains("resolves outside the extraction root"), "{}", e);
+        assert!(!dest.join("encore/runtimes/leak").exists());
+    }
+
+    #[test]
+    fn symlink_targets_are_resolved_through_existing_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("x")).unwrap();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("x/y")).unwrap();
+        let cases = [
+            ("x/y", "..", true),
+            ("a/b/c", "../../x", true),
+            ("a/b/c", "../../..", false),
+            ("a/b/c", "/etc/passwd", false),
+            // `x/y` leads back to the root, so the link sits at the root and `..` climbs out.
+            ("x/y/x/y/x/y/x/y/w", "../../../../../../../..", false),
+            ("x/y/x/y/w", "..", false),
+            ("x/y/x/y/w", "a/b", true),
+            // Through the chain `x/y/..` is the root's parent, nested inside an otherwise fine path.
+            ("a/w", "../x/y/../a", false),
+            ("a/w", "../x/y/a/b/../..", true),
+        ];
+        for (link, target, inside) in cases {
+            let result = check_symlink_target(root, Path::new(link), Path::new(target));
+            assert_eq!(result.is_ok(), inside, "{} -> {}: {:?}", link, target, result);
+        }
+    }
+
+    #[test]
+    fn symlink_loops_are_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink("b", root.path().join("a")).unwrap();
+        std::os::unix::fs::symlink("a", root.path().join("b")).unwrap();
+        assert!(check_symlink_target(root.path(), Path::new("c"), Path::new("a/file")).is_err());
+    }
+
+    #[test]
+    fn watch_ignores_the_runs_own_outputs() {
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path();
+        fs::write(dir.join("app.ts"), "export {}").unwrap();
+        let log = dir.join("logs/build.log");
+        fs::create_dir(dir.join("logs")).unwrap();
+        let args: Vec<String> = ["encorebaremetal", "--watch", ".", "--log-file", log.to_str().unwrap(), "--tar-path", dir.join("image.tar").to_str().unwrap()]
+            .map(String::from)
+            .to_vec();
+        let ignore = WatchIgnore::new(dir, &args).unwrap();
+        assert!(ignore.ignores(Path::new("logs/build.log")));
+        assert!(ignore.ignores(Path::new("image.tar")));
+        assert!(ignore.ignores(Path::new("node_modules/x/index.js")));
+        assert!(!ignore.ignores(Path::new("app.ts")));
+        assert!(!ignore.ignores(Path::new("logs/other.log")));
+
+    }
+
+    #[test]
+    fn tree_watch_sees_edits_outside_the_ignored_paths() {
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path();
+        fs::write(dir.join("app.ts"), "export {}").unwrap();
+        let log = dir.join("build.log");
+        let args: Vec<String> = ["encorebaremetal", "--watch", ".", "--log-file", log.to_str().unwrap()].map(String::from).to_vec();
+        let ignore = WatchIgnore::new(dir, &args).unwrap();
+        let mut tree = TreeWatch::new(dir, &ignore).unwrap();
+        let quiet = Some(Duration::from_millis(200));
+
+        fs::write(&log, "a line the run logged").unwrap();
+        fs::create_dir_all(dir.join("node_modules/x")).unwrap();
+        assert_eq!(tree.next(quiet, &ignore).unwrap(), WatchEvent::Quiet);
+        // Same size, so only an event tells it apart.
+        fs::write(dir.join("app.ts"), "export []").unwrap();
+        assert_eq!(tree.next(quiet, &ignore).unwrap(), WatchEvent::Changed);
+        fs::rename(dir.join("app.ts"), dir.join("main.ts")).unwrap();
+        assert_eq!(tree.next(quiet, &ignore).unwrap(), WatchEvent::Changed);
+        // A new directory is watched as it appears.
+        fs::create_dir(dir.join("src")).unwrap();
+        assert_eq!(tree.next(quiet, &ignore).unwrap(), WatchEvent::Changed);
+        assert_eq!(tree.next(quiet, &ignore).unwrap(), WatchEvent::Quiet);
+        fs::write(dir.join("src/api.ts"), "export {}").unwrap();
+        assert_eq!(tree.next(quiet, &ignore).unwrap(), WatchEvent::Changed);
+    }
+
+    #[test]
+    fn extract_encore_reports_progress_ending_in_finished() {
+        fn fixture_pipeline(ctx: &Context) -> Pipeline {
+            let mut pipeline = Pipeline::new(ctx);
+            pipeline.stages[0].1 = fixture_acquire;
+            pipeline
+        }
+        let work = tempfile::tempdir().unwrap();
+        let events: std::sync::Arc<Mutex<Vec<String>>> = Default::default();
+        let seen = std::sync::Arc::clone(&events);
+        let on_progress: Box<ProgressCallback> = Box::new(move |event| {
+            seen.lock().unwrap().push(match event {
+                ProgressEvent::Phase { phase, .. } => phase.to_string(),
+                ProgressEvent::Copied { files, bytes } => format!("copied {} {}", files, bytes),
+                ProgressEvent::Finished { files, bytes } => format!("finished {} {}", files, bytes),
+            });
+        });
+        let args = ["--image", "fixture:latest", "--pull-policy", "never"].map(String::from);
+        extract_with(work.path(), &args, Some(on_progress), fixture_pipeline).unwrap();
+
+        let events = events.lock().unwrap();
+        let bytes: usize = SELFTEST_FILES.iter().map(|(_, _, contents)| contents.len()).sum();
+        assert_eq!(events.last().unwrap(), &format!("finished {} {}", SELFTEST_FILES.len(), bytes), "{:?}", events);
+        assert!(events.iter().any(|e| e == "layers_extracted"), "{:?}", events);
+    }
+
+    /// Peak resident memory of this process so far, from `VmHWM` in `/proc/self/status`.
+    fn peak_rss() -> u64 {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|line| line.starts_with("VmHWM:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse::<u64>().unwrap() * 1024
+    }
+
+    /// An endless stream of CRLF-terminated text lines.
+    struct CrlfLines;
+
+    impl Read for CrlfLines {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = b"text\r\n"[i % 6];
+            }
+            Ok(buf.len() - buf.len() % 6)
+        }
+    }
+
+    #[test]
+    fn large_layers_extract_and_normalize_in_bounded_memory() {
+        const SIZE: u64 = 384 << 20;
+        let work = tempfile::tempdir().unwrap();
+        let (layer, dest) = (work.path().join("layer.tar.gz"), work.path().join("root"));
+        let mut builder = tar::Builder::new(GzEncoder::new(File::create(&layer).unwrap(), Compression::fast()));
+        let mut header = tar::Header::new_gnu();
+        header.set_path("app/big.txt").unwrap();
+        header.set_size(SIZE);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, CrlfLines.take(SIZE)).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+        fs::create_dir(&dest).unwrap();
+
+        let before = peak_rss();
+        extract_layer(&layer, &dest, None, &config(&[])).unwrap();
+        let big = dest.join("app/big.txt");
+        assert_eq!(fs::metadata(&big).unwrap().len(), SIZE);
+        assert_eq!(copy_normalized(&big, &work.path().join("big.txt"), 64 * 1024).unwrap(), Some(SIZE / 6 * 5));
+        let growth = peak_rss().saturating_sub(before);
+        assert!(growth < 32 << 20, "peak RSS grew by {} bytes for a {} byte layer", growth, SIZE);
+    }
+
+    /// A layer with `dirs` created under it and `files` written empty, for `is_encore_dir`.
+    fn encore_layer(dirs: &[&str], files: &[&str]) -> tempfile::TempDir {
+        let layer = tempfile::tempdir().unwrap();
+        for dir in dirs {
+            fs::create_dir_all(layer.path().join(dir)).unwrap();
+        }
+        for file in files {
+            fs::write(layer.path().join(file), "").unwrap();
+        }
+        layer
+    }
+
+    #[test]
+    fn encore_dirs_need_a_sentinel_outside_node_modules() {
+        let cases: &[(&str, &[&str], &[&str], bool)] = &[
+            ("encore", &["encore/runtimes"], &[], true),
+            ("app/encore", &["app/encore", "app/.encore"], &["app/.encore/manifest.json"], true),
+            ("encore", &["encore", "workspace/apps/web/.encore"], &["workspace/apps/web/.encore/manifest.json"], true),
+            ("node_modules/encore", &["node_modules/encore/runtimes"], &[], false),
+            ("app/node_modules/x/encore", &["app/node_modules/x/encore/runtimes"], &[], false),
+            ("encore", &["encore"], &["encore/runtimes"], false),
+            ("app/encore", &["app/encore", "app/.encore"], &[], false),
+            ("encore", &["encore", "workspace/apps/web/.encore"], &[], false),
+            ("encore2", &["encore2/runtimes"], &[], false),
+        ];
+        for (dir, dirs, files, expected) in cases {
+            let layer = encore_layer(dirs, files);
+            assert_eq!(is_encore_dir(&layer.path().join(dir), layer.path()), *expected, "{} with {:?} {:?}", dir, dirs, files);
+        }
+    }
+
+    #[test]
+    fn encore_dirs_are_found_through_a_symlinked_bin() {
+        let layer = encore_layer(&["usr/bin/encore/runtimes", "usr/lib/encore"], &[]);
+        std::os::unix::fs::symlink("usr/bin", layer.path().join("bin")).unwrap();
+        assert!(is_encore_dir(&layer.path().join("bin/encore"), layer.path()));
+        // A sentinel that is itself a dangling symlink doesn't count.
+        std::os::unix::fs::symlink("../missing", layer.path().join("usr/lib/encore/runtimes")).unwrap();
+        assert!(!is_encore_dir(&layer.path().join("usr/lib/encore"), layer.path()));
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn s3_urls_are_normalized_or_rejected() {
+        let cases = [
+            ("s3://bucket", Some("s3://bucket")),
+            ("s3://my-bucket.v2/releases/", Some("s3://my-bucket.v2/releases")),
+            ("s3://bucket//a/b//", Some("s3://bucket/a/b")),
+            ("s3://", None),
+            ("s3:///prefix", None),
+            ("s3://Bucket/prefix", None),
+            ("https://bucket/prefix", None),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(parse_s3_url(url).ok().as_deref(), expected, "{}", url);
+        }
+    }
+
+    #[cfg(not(feature = "s3"))]
+    #[test]
+    fn s3_flags_need_the_s3_feature() {
+        let args = ["encorebaremetal", "--s3-output", "s3://bucket"].map(String::from);
+        let e = Config::new(&args).err().expect("--s3-output is refused without the s3 feature");
+        assert_eq!(e.to_string(), "--s3-output needs encorebaremetal built with the s3 feature");
+    }
+
+    #[cfg(not(feature = "cosign"))]
+    #[test]
+    fn signature_flags_need_the_cosign_feature() {
+        let work = tempfile::tempdir().unwrap();
+        let args = ["encorebaremetal", "--image", "registry.example/app:1", "--pull-policy", "always", "--verify-signatures"].map(String::from);
+        let e = Context::new(&args, work.path().to_path_buf()).err().expect("--verify-signatures is refused without the cosign feature");
+        assert_eq!(e.to_string(), "--verify-signatures needs encorebaremetal built with the cosign feature");
+    }
+
+    #[test]
+    fn compress_flags_take_their_earlier_names_too() {
+        for flags in [["--compress-format", "zstd", "--compress-level", "19"], ["--archive-format", "zstd", "--compression-level", "19"]] {
+            let config = config(&flags);
+            assert_eq!((config.archive_format, config.compression_level), (ArchiveFormat::Zstd, 19), "{:?}", flags);
+        }
+        assert_eq!(config(&["--compress-format", "gzip"]).compression_level, 6);
+        let cases: [(&[&str], &str); 3] = [
+            (&["--compress-format", "gzip", "--compress-level", "10"], "Invalid --compress-level value: 10 (expected 0-9 for gzip)"),
+            (&["--compress-format", "tar", "--compress-level", "1"], "--compress-level doesn't apply to --compress-format tar"),
+            (&["--compress-format", "xz"], "Unknown --compress-format 'xz' (expected gzip, zstd or tar)"),
+        ];
+        for (flags, expected) in cases {
+            let args: Vec<String> = std::iter::once("encorebaremetal").chain(flags.iter().copied()).map(String::from).collect();
+            assert_eq!(Config::new(&args).err().expect("an invalid combination").to_string(), expected, "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn image_references_follow_the_docker_grammar() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let valid = [
+            "app".to_string(),
+            "app:latest".to_string(),
+            "my-org/my_app__v2:1.40.0-rc.1".to_string(),
+            "a--b.c/d".to_string(),
+            "localhost/app".to_string(),
+            "localhost:5000/team/app:dev".to_string(),
+            "registry.example.com/app".to_string(),
+            "10.0.0.1:5000/app".to_string(),
+            format!("app@{}", digest),
+            format!("ghcr.io/org/app:v1@{}", digest),
+            format!("app:{}", "t".repeat(128)),
+        ];
+        for reference in &valid {
+            assert!(validate_image_reference(reference).is_ok(), "{}", reference);
+        }
+        let rejected = [
+            ("", "missing repository name"),
+            (":latest", "missing repository name"),
+            ("App:1", "repository name must be lowercase (try 'app:1')"),
+            ("org//app", "repository name has an empty path component"),
+            ("app/", "repository name has an empty path component"),
+            ("-app", "invalid repository name component '-app'"),
+            ("app.", "invalid repository name component 'app.'"),
+            ("a..b", "invalid repository name component 'a..b'"),
+            ("a___b", "invalid repository name component 'a___b'"),
+            ("-bad.example/app", "invalid registry '-bad.example'"),
+            ("registry.example:port/app", "invalid registry 'registry.example:port'"),
+            ("app:", "invalid tag ''"),
+            ("app:.hidden", "invalid tag '.hidden'"),
+            ("app:a/b", "invalid registry 'app:a'"),
+            ("app@sha256:abc", "invalid digest 'sha256:abc'"),
+            ("app@md5", "invalid digest 'md5'"),
+        ];
+        for (reference, reason) in rejected {
+            let e = validate_image_reference(reference).expect_err(reference).to_string();
+            assert!(e.starts_with(&format!("Invalid --image '{}': {}", reference, reason)), "{}: {}", reference, e);
+        }
+        assert!(validate_image_reference(&format!("app:{}", "t".repeat(129))).is_err());
+        assert!(validate_image_reference(&"a".repeat(256)).is_err());
+    }
+
+    #[test]
+    fn sizes_parse_with_binary_and_decimal_units() {
+        let valid = [
+            ("512", 512),
+            ("0", 0),
+            (" 20 b ", 20),
+            ("10K", 10 << 10),
+            ("10MiB", 10 << 20),
+            ("10m", 10 << 20),
+            ("1.5G", 3 << 29),
+            ("2TiB", 2 << 40),
+            ("200kb", 200_000),
+            ("3MB", 3_000_000),
+            ("1.5gb", 1_500_000_000),
+            ("1tb", 1_000_000_000_000),
+        ];
+        for (input, expected) in valid {
+            assert_eq!(parse_size(input), Ok(expected), "{}", input);
+        }
+        let rejected = [
+            ("", "'' is not a size"),
+            ("MiB", "'MiB' is not a size"),
+            ("-5", "'-5' is not a size"),
+            ("1.2.3G", "'1.2.3G' is not a size"),
+            ("10 PB", "unknown unit 'pb' in '10 PB'"),
+            ("1e3", "unknown unit 'e3' in '1e3'"),
+            ("20000000T", "'20000000T' is too large"),
+        ];
+        for (input, expected) in rejected {
+            assert_eq!(parse_size(input).unwrap_err(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn durations_parse_with_an_optional_unit() {
+        let valid = [
+            ("90", Duration::from_secs(90)),
+            ("90s", Duration::from_secs(90)),
+            (" 2 S ", Duration::from_secs(2)),
+            ("500ms", Duration::from_millis(500)),
+            ("15m", Duration::from_secs(900)),
+            ("15min", Duration::from_secs(900)),
+            ("1.5h", Duration::from_secs(5_400)),
+            ("0", Duration::ZERO),
+        ];
+        for (input, expected) in valid {
+            assert_eq!(parse_duration(input), Ok(expected), "{}", input);
+        }
+        let rejected = [
+            ("", "'' is not a duration"),
+            ("s", "'s' is not a duration"),
+            ("-1s", "'-1s' is not a duration"),
+            ("1.2.3", "'1.2.3' is not a duration"),
+            ("2d", "unknown unit 'd' in '2d' (expected ms, s, m or h)"),
+            ("99999999999999999999h", "'99999999999999999999h' is too long"),
+        ];
+        for (input, expected) in rejected {
+            assert_eq!(parse_duration(input).unwrap_err(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn cache_max_size_takes_a_size() {
+        let work = tempfile::tempdir().unwrap();
+        let cases = [
+            ("10G", Ok(10 << 30)),
+            ("512MB", Ok(512_000_000)),
+            ("ten", Err("Invalid --cache-max-size value: 'ten' is not a size")),
+            ("5 parsecs", Err("Invalid --cache-max-size value: unknown unit 'parsecs' in '5 parsecs'")),
+        ];
+        for (value, expected) in cases {
+            let args = ["encorebaremetal", "--image", "fixture:latest", "--pull-policy", "never", "--cache-dir", "cache", "--cache-max-size", value].map(String::from);
+            let result = Context::new(&args, work.path().to_path_buf()).map(|ctx| ctx.unwrap().cache_max_size);
+            match (result, expected) {
+                (Ok(size), Ok(expected)) => assert_eq!(size, Some(expected), "{}", value),
+                (Err(e), Err(expected)) => assert_eq!(e.to_string(), expected, "{}", value),
+                (Ok(size), Err(_)) => panic!("{} was accepted as {:?}", value, size),
+                (Err(e), Ok(_)) => panic!("{} was rejected: {}", value, e),
+            }
+        }
+    }
+
+    #[test]
+    fn rfc3339_timestamps_parse_to_their_instant() {
+        let at = |secs: u64, nanos: u32| SystemTime::UNIX_EPOCH + Duration::new(secs, nanos);
+        let valid = [
+            ("1970-01-01T00:00:00Z", at(0, 0)),
+            ("2024-05-01T12:00:00Z", at(1_714_564_800, 0)),
+            ("2024-05-01 12:00:00z", at(1_714_564_800, 0)),
+            ("2024-05-01T14:00:00.5+02:00", at(1_714_564_800, 500_000_000)),
+            ("2024-05-01T06:30:00-05:30", at(1_714_564_800, 0)),
+            ("2000-02-29T00:00:00Z", at(951_782_400, 0)),
+            ("2016-12-31T23:59:60Z", at(1_483_228_800, 0)),
+            ("2024-05-01T12:00:00.123456789123Z", at(1_714_564_800, 123_456_789)),
+            ("1969-12-31T23:59:59Z", SystemTime::UNIX_EPOCH - Duration::from_secs(1)),
+        ];
+        for (input, expected) in valid {
+            assert_eq!(parse_rfc3339(input), Ok(expected), "{}", input);
+        }
+        let rejected = [
+            "",
+            "2024-05-01",
+            "2024-5-01T12:00:00Z",
+            "24-05-01T12:00:00Z",
+            "2024-13-01T12:00:00Z",
+            "2024-05-00T12:00:00Z",
+            "2024-05-01T24:00:00Z",
+            "2024-05-01T12:60:00Z",
+            "2024-05-01T12:00:61Z",
+            "2024-05-01T12:00Z",
+            "2024-05-01T12:00:00",
+            "2024-05-01T12:00:00.Z",
+            "2024-05-01T12:00:00.5aZ",
+            "2024-05-01T12:00:00+0200",
+            "2024-05-01T12:00:00+2:00",
+            "2024-05-01T+1:00:00Z",
+        ];
+        for input in rejected {
+            let e = parse_rfc3339(input).unwrap_err();
+            assert_eq!(e, format!("'{}' (expected an RFC 3339 timestamp like 2024-05-01T12:00:00Z)", input));
+        }
+    }
+
+    #[test]
+    fn globs_match_within_a_component() {
+        let cases = [
+            ("*.map", "app.js.map", true),
+            ("*.map", "app.js", false),
+            ("*", "", true),
+            ("", "", true),
+            ("", "a", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("a*b*c", "aXXbYYc", true),
+            ("a*b*c", "aXXbYY", false),
+            ("*test*", "my-test-file", true),
+            ("**", "anything", true),
+            ("é?", "éa", true),
+            ("exact", "exact", true),
+            ("exact", "Exact", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(glob_match(pattern, text), expected, "{} against {}", pattern, text);
+        }
+    }
+
+    #[test]
+    fn exclude_patterns_match_components_or_anchored_paths() {
+        let cases = [
+            ("*.map", "encore/build/app.js.map", true),
+            ("node_modules", "encore/node_modules/x/index.js", true),
+            ("node_modules", "encore/my_node_modules/x.js", false),
+            ("encore/runtimes/js/test", "encore/runtimes/js/test/a.js", true),
+            ("/encore/runtimes/js/test/", "encore/runtimes/js/test", true),
+            ("encore/runtimes/js/test", "other/encore/runtimes/js/test", false),
+            ("encore/*/js", "encore/runtimes/js/x", true),
+            ("encore/*/js", "encore/runtimes", false),
+            ("./encore//meta", "encore/meta", true),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(ExcludePattern::parse(pattern).unwrap().matches(Path::new(path)), expected, "{} against {}", pattern, path);
+        }
+        for pattern in ["", "/", " ", "./", "encore/../secrets", ".."] {
+            assert_eq!(ExcludePattern::parse(pattern).err(), Some(format!("'{}'", pattern)), "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn env_files_parse_quotes_comments_and_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("build.env");
+        fs::write(
+            &path,
+            concat!(
+                "# build settings\n",
+                "\n",
+                "PLAIN=value\n",
+                "export EXPORTED = spaced out \n",
+                "COMMENTED=kept # dropped\n",
+                "HASH=a#b\n",
+                "DOUBLE=\"line\\none \\\"quoted\\\" \\\\ # kept\"\n",
+                "SINGLE='$literal \\n # kept'\n",
+                "EMPTY=\n",
+                "_UNDER_9=x=y\n",
+            ),
+        )
+        .unwrap();
+        let expected = [
+            ("PLAIN", "value"),
+            ("EXPORTED", "spaced out"),
+            ("COMMENTED", "kept"),
+            ("HASH", "a#b"),
+            ("DOUBLE", "line\none \"quoted\" \\ # kept"),
+            ("SINGLE", "$literal \\n # kept"),
+            ("EMPTY", ""),
+            ("_UNDER_9", "x=y"),
+        ];
+        let vars = parse_env_file(&path).unwrap();
+        assert_eq!(vars, expected.map(|(key, value)| (key.to_string(), value.to_string())));
+
+        let rejected = [
+            ("JUST_A_KEY", "expected KEY=value"),
+            ("9LIVES=x", "invalid variable name '9LIVES'"),
+            ("MY-VAR=x", "invalid variable name 'MY-VAR'"),
+            ("=x", "invalid variable name ''"),
+            ("OPEN=\"abc", "unterminated double quote"),
+            ("OPEN='abc", "unterminated single quote"),
+        ];
+        for (line, reason) in rejected {
+            fs::write(&path, format!("OK=1\n{}\n", line)).unwrap();
+            let e = parse_env_file(&path).unwrap_err().to_string();
+            assert_eq!(e, format!("{}:2: {}", path.display(), reason), "{}", line);
+        }
+        let missing = dir.path().join("missing.env");
+        assert!(parse_env_file(&missing).unwrap_err().to_string().starts_with(&format!("Failed to read env file {}", missing.display())));
+    }
+
+    #[test]
+    fn build_args_split_like_a_shell() {
+        let valid: [(&str, &[&str]); 10] = [
+            ("", &[]),
+            ("   ", &[]),
+            ("--config prod.json", &["--config", "prod.json"]),
+            ("  -v\t--quiet \n", &["-v", "--quiet"]),
+            (r#"--config "prod env.json""#, &["--config", "prod env.json"]),
+            (r#"--name 'it''s' --x="a b"c"#, &["--name", "its", "--x=a bc"]),
+            (r#"'$HOME \n' "tab\"bed" "back\\slash""#, &["$HOME \\n", "tab\"bed", "back\\slash"]),
+            (r#"one\ word \"quoted\""#, &["one word", "\"quoted\""]),
+            (r#""" ''"#, &["", ""]),
+            (r#"a""b"#, &["ab"]),
+        ];
+        for (value, expected) in valid {
+            assert_eq!(split_build_args(value).unwrap(), expected, "{}", value);
+        }
+        let rejected = [
+            (r#"--config "prod"#, "unterminated double quote"),
+            ("--name 'it", "unterminated single quote"),
+            (r#"--x "a\"#, "unterminated double quote"),
+            ("--x a\\", "trailing backslash"),
+        ];
+        for (value, problem) in rejected {
+            assert_eq!(split_build_args(value).unwrap_err().to_string(), format!("Invalid --build-args '{}': {}", value, problem), "{}", value);
+        }
+    }
+
+    #[test]
+    fn env_references_expand_or_are_reported() {
+        // PATH is set for any test run; the other name is one nothing sets.
+        let path = env::var("PATH").unwrap();
+        let unset = "ENCOREBAREMETAL_TEST_UNSET_VARIABLE";
+        let valid = [
+            ("plain", false, "plain".to_string()),
+            ("", false, String::new()),
+            ("${PATH}", false, path.clone()),
+            ("a-${PATH}-b-${PATH}", false, format!("a-{}-b-{}", path, path)),
+            ("$PATH {PATH} $", false, "$PATH {PATH} $".to_string()),
+            (&format!("out/${{{}}}/x", unset), true, "out//x".to_string()),
+        ];
+        for (input, allow_unset, expected) in valid {
+            assert_eq!(expand_env(input, allow_unset).unwrap(), expected, "{}", input);
+        }
+        let missing = format!("img:${{{}}}", unset);
+        let rejected = [
+            ("${PATH", false, "Unterminated variable reference in '${PATH'".to_string()),
+            ("${PATH", true, "Unterminated variable reference in '${PATH'".to_string()),
+            ("a${}b", true, "Invalid variable name '' in 'a${}b'".to_string()),
+            ("${MY-VAR}", true, "Invalid variable name 'MY-VAR' in '${MY-VAR}'".to_string()),
+            (&missing, false, format!("Environment variable {} used in '{}' is not set (pass --allow-unset to allow)", unset, missing)),
+        ];
+        for (input, allow_unset, expected) in rejected {
+            assert_eq!(expand_env(input, allow_unset).unwrap_err().to_string(), expected, "{}", input);
+        }
+    }
+}
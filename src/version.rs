@@ -0,0 +1,305 @@
+//! Minimal semver versions and Cargo-style version requirements for `--encore-version`.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+/// A `major.minor.patch[-pre][+build]` version; a leading `v` is accepted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Vec<String>,
+}
+
+impl Version {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let trimmed = input.trim();
+        let core = trimmed.strip_prefix('v').unwrap_or(trimmed);
+        let core = core.split_once('+').map_or(core, |(core, _build)| core);
+        let (numbers, pre) = match core.split_once('-') {
+            Some((numbers, pre)) => (numbers, pre.split('.').map(str::to_string).collect()),
+            None => (core, Vec::new()),
+        };
+        let parts: Vec<&str> = numbers.split('.').collect();
+        if parts.len() != 3 {
+            return Err(format!("Invalid version '{}' (expected major.minor.patch)", input));
+        }
+        let number = |s: &str| s.parse::<u64>().map_err(|_| format!("Invalid version '{}'", input));
+        Ok(Self { major: number(parts[0])?, minor: number(parts[1])?, patch: number(parts[2])?, pre })
+    }
+
+    fn triple(&self) -> (u64, u64, u64) {
+        (self.major, self.minor, self.patch)
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.triple().cmp(&other.triple()).then_with(|| compare_pre(&self.pre, &other.pre))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            write!(f, "-{}", self.pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+/// Pre-release precedence: a release sorts after any of its pre-releases, numeric identifiers
+/// compare numerically and sort before alphanumeric ones.
+fn compare_pre(a: &[String], b: &[String]) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => return Ordering::Equal,
+        (true, false) => return Ordering::Greater,
+        (false, true) => return Ordering::Less,
+        _ => {}
+    }
+    for (x, y) in a.iter().zip(b) {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// A comma-separated list of comparators (`>=1.40, <2`, `^1.40.0`, `~1.40`, `=1.40.1`, `1.40.*`),
+/// all of which must hold. A bare version means `^version`, as in Cargo.
+///
+/// Also as in Cargo, a pre-release version only matches if one of the comparators names a
+/// pre-release of the same `major.minor.patch`: `>=1.40.0-beta` matches `1.40.0-rc.1` but not
+/// `1.41.0-beta`, and `>=1.40` matches neither.
+#[derive(Debug)]
+pub struct VersionReq {
+    /// Inclusive/exclusive bounds, as (lower, upper) pairs with `None` for unbounded.
+    ranges: Vec<(Option<Bound>, Option<Bound>)>,
+    source: String,
+}
+
+#[derive(Debug)]
+struct Bound {
+    version: Version,
+    inclusive: bool,
+}
+
+impl VersionReq {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut ranges = Vec::new();
+        for comparator in input.split(',').map(str::trim) {
+            if comparator.is_empty() {
+                return Err(format!("Invalid version requirement '{}'", input));
+            }
+            ranges.push(parse_comparator(comparator).map_err(|e| format!("Invalid version requirement '{}': {}", input, e))?);
+        }
+        Ok(Self { ranges, source: input.trim().to_string() })
+    }
+
+    pub fn matches(&self, version: &Version) -> bool {
+        if !version.pre.is_empty() {
+            let names_pre = |b: &Option<Bound>| b.as_ref().is_some_and(|b| !b.version.pre.is_empty() && b.version.triple() == version.triple());
+            if !self.ranges.iter().any(|(lower, upper)| names_pre(lower) || names_pre(upper)) {
+                return false;
+            }
+        }
+        self.ranges.iter().all(|(lower, upper)| {
+            let above = lower.as_ref().is_none_or(|b| if b.inclusive { version >= &b.version } else { version > &b.version });
+            let below = upper.as_ref().is_none_or(|b| if b.inclusive { version <= &b.version } else { version < &b.version });
+            above && below
+        })
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn version(major: u64, minor: u64, patch: u64) -> Version {
+    Version { major, minor, patch, pre: Vec::new() }
+}
+
+fn inclusive(version: Version) -> Option<Bound> {
+    Some(Bound { version, inclusive: true })
+}
+
+fn exclusive(version: Version) -> Option<Bound> {
+    Some(Bound { version, inclusive: false })
+}
+
+/// The next value of a version component, for the exclusive ceilings of ranges.
+fn bump(component: u64) -> Result<u64, String> {
+    component.checked_add(1).ok_or_else(|| format!("version component {} is too large", component))
+}
+
+fn parse_comparator(comparator: &str) -> Result<(Option<Bound>, Option<Bound>), String> {
+    let op_len = comparator.find(|c: char| c.is_ascii_digit() || c == '*' || c == 'v').unwrap_or(comparator.len());
+    let (op, rest) = comparator.split_at(op_len);
+    let rest = rest.strip_prefix('v').unwrap_or(rest);
+    if rest.is_empty() {
+        return Err(format!("missing version in '{}'", comparator));
+    }
+
+    // Partial versions: missing or `*` components are wildcards.
+    let (numbers, pre) = match rest.split_once('-') {
+        Some((numbers, pre)) => (numbers, pre.split('.').map(str::to_string).collect()),
+        None => (rest, Vec::new()),
+    };
+    let is_wildcard = |part: &str| matches!(part, "*" | "x" | "X");
+    let mut parts = Vec::new();
+    for part in numbers.split('.') {
+        if is_wildcard(part) {
+            break;
+        }
+        parts.push(part.parse::<u64>().map_err(|_| format!("invalid number '{}'", part))?);
+    }
+    if parts.len() > 3 {
+        return Err(format!("too many components in '{}'", comparator));
+    }
+    let (major, minor, patch) = (parts.first().copied(), parts.get(1).copied(), parts.get(2).copied());
+    let floor = Version { major: major.unwrap_or(0), minor: minor.unwrap_or(0), patch: patch.unwrap_or(0), pre };
+
+    // Exclusive upper bound of the range the partial version covers, e.g. `1.40` -> `<1.41.0`.
+    let partial_ceiling = || -> Result<Option<Bound>, String> {
+        Ok(match (major, minor, patch) {
+            (Some(major), None, _) => exclusive(version(bump(major)?, 0, 0)),
+            (Some(major), Some(minor), None) => exclusive(version(major, bump(minor)?, 0)),
+            _ => None,
+        })
+    };
+
+    // A bare wildcard like `1.40.*` pins the components it names, unlike a bare `1.40` (caret).
+    let op = match op.trim() {
+        "" if numbers.split('.').any(is_wildcard) => "=",
+        op => op,
+    };
+    Ok(match op {
+        "" | "^" => {
+            let Some(major) = major else {
+                return Ok((None, None));
+            };
+            let ceiling = match (major, minor, patch) {
+                (0, Some(0), Some(patch)) => version(0, 0, bump(patch)?),
+                (0, Some(minor), _) => version(0, bump(minor)?, 0),
+                (0, None, _) => version(1, 0, 0),
+                (major, _, _) => version(bump(major)?, 0, 0),
+            };
+            (inclusive(floor), exclusive(ceiling))
+        }
+        "~" => {
+            let Some(major) = major else {
+                return Ok((None, None));
+            };
+            let ceiling = match minor {
+                Some(minor) => version(major, bump(minor)?, 0),
+                None => version(bump(major)?, 0, 0),
+            };
+            (inclusive(floor), exclusive(ceiling))
+        }
+        "=" => match patch {
+            Some(_) => (inclusive(floor.clone()), inclusive(floor)),
+            None if major.is_none() => (None, None),
+            None => (inclusive(floor), partial_ceiling()?),
+        },
+        ">=" => (inclusive(floor), None),
+        ">" => match (major, minor, patch) {
+            (Some(_), Some(_), Some(_)) => (exclusive(floor), None),
+            _ => (partial_ceiling()?.map(|b| Bound { inclusive: true, ..b }), None),
+        },
+        "<" => (None, exclusive(floor)),
+        "<=" => match (major, minor, patch) {
+            (Some(_), Some(_), Some(_)) => (None, inclusive(floor)),
+            _ => (None, partial_ceiling()?),
+        },
+        other => return Err(format!("unknown operator '{}'", other)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requirements_match_like_cargo() {
+        let cases = [
+            ("^1.40.2", &["1.40.2", "1.41.0", "1.99.99"][..], &["1.40.1", "2.0.0", "1.40.3-beta"][..]),
+            ("1.40", &["1.40.0", "1.55.1"], &["1.39.9", "2.0.0"]),
+            ("^0.4.1", &["0.4.1", "0.4.9"], &["0.4.0", "0.5.0"]),
+            ("^0.0.3", &["0.0.3"], &["0.0.4", "0.0.2"]),
+            ("^0", &["0.0.0", "0.9.9"], &["1.0.0"]),
+            ("~1.40.2", &["1.40.2", "1.40.9"], &["1.41.0", "1.40.1"]),
+            ("~1", &["1.0.0", "1.99.0"], &["2.0.0"]),
+            ("=1.40.1", &["1.40.1", "v1.40.1+build.5"], &["1.40.2", "1.40.0"]),
+            ("=1.40", &["1.40.0", "1.40.7"], &["1.41.0"]),
+            ("1.40.*", &["1.40.0", "1.40.7"], &["1.41.0", "1.39.0"]),
+            ("1.x", &["1.0.0", "1.99.0"], &["2.0.0", "0.9.0"]),
+            ("*", &["0.0.1", "99.0.0"], &["1.0.0-alpha"]),
+            (">1.40", &["1.41.0"], &["1.40.9", "1.40.0"]),
+            (">1", &["2.0.0"], &["1.99.0"]),
+            (">1.40.1", &["1.40.2"], &["1.40.1"]),
+            ("<=1.40", &["1.40.9", "1.0.0"], &["1.41.0"]),
+            ("<=1", &["1.99.0"], &["2.0.0"]),
+            ("<=1.40.1", &["1.40.1"], &["1.40.2"]),
+            ("<1.40", &["1.39.9"], &["1.40.0"]),
+            (">=1.40, <2", &["1.40.0", "1.99.0"], &["1.39.0", "2.0.0", "2.0.0-alpha"]),
+            (">=1.40.0-beta.2", &["1.40.0-beta.2", "1.40.0-beta.11", "1.40.0-rc.1", "1.40.0", "1.41.0"], &["1.40.0-beta.1", "1.40.0-alpha", "1.41.0-beta"]),
+            ("^1.40.0-rc.1", &["1.40.0-rc.1", "1.40.0", "1.50.0"], &["1.40.0-beta", "1.40.1-rc.1", "2.0.0"]),
+            ("=1.40.0-rc.1", &["1.40.0-rc.1"], &["1.40.0-rc.2", "1.40.0"]),
+            (">=1.40", &["1.40.0"], &["1.40.1-rc.1", "1.41.0-beta"]),
+        ];
+        for (requirement, matching, rejected) in cases {
+            let req = VersionReq::parse(requirement).unwrap();
+            for version in matching {
+                assert!(req.matches(&Version::parse(version).unwrap()), "{} should match {}", requirement, version);
+            }
+            for version in rejected {
+                assert!(!req.matches(&Version::parse(version).unwrap()), "{} shouldn't match {}", requirement, version);
+            }
+        }
+    }
+
+    #[test]
+    fn pre_releases_order_by_identifier() {
+        let ordered = ["1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-alpha.beta", "1.0.0-beta", "1.0.0-beta.2", "1.0.0-beta.11", "1.0.0-rc.1", "1.0.0", "1.0.1"];
+        for pair in ordered.windows(2) {
+            assert!(Version::parse(pair[0]).unwrap() < Version::parse(pair[1]).unwrap(), "{} < {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn invalid_requirements_are_rejected() {
+        let max = u64::MAX;
+        let cases = [
+            ("", "Invalid version requirement ''"),
+            (">=1.40,", "Invalid version requirement '>=1.40,'"),
+            (">=", "missing version in '>='"),
+            ("^1.a", "invalid number 'a'"),
+            ("1.2.3.4", "too many components in '1.2.3.4'"),
+            ("!1.2", "unknown operator '!'"),
+            (&format!("^{}", max), "version component 18446744073709551615 is too large"),
+            (&format!("~1.{}", max), "version component 18446744073709551615 is too large"),
+            (&format!("^0.0.{}", max), "version component 18446744073709551615 is too large"),
+        ];
+        for (requirement, expected) in cases {
+            let e = VersionReq::parse(requirement).unwrap_err();
+            assert!(e.contains(expected), "{}: {}", requirement, e);
+        }
+        assert!(VersionReq::parse(&format!("<={}.2", max - 1)).is_ok());
+    }
+}